@@ -15,6 +15,15 @@
 /// - Access to BAM header information.
 /// - Multi-threaded reading via `set_threads`.
 /// - Reference genome specification with `set_reference`.
+/// - Input format detection with `detect_format`.
+/// - Index lookup with `resolve_index`.
+/// - Reference lookup from the `@SQ UR:` tag with `reference_from_header`,
+///   and recording one with `header_with_reference`.
+/// - Output sanity checks with `validate`.
+/// - Per-contig index counts via `index_stats`.
+/// - Coordinate merging of two record streams with `merge_headers` and
+///   `MergingWriter`.
+/// - Copying records into per-region outputs with `RegionSplitWriter`.
 ///
 /// # Usage
 /// Implement the `BamReader` trait for custom BAM readers, or use the provided
@@ -35,9 +44,262 @@
 ///     // process record
 /// }
 /// ```
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+use crate::region::Region;
 use rust_htslib::bam::{self, FetchDefinition, HeaderView, IndexedReader, Read, Record};
-use std::path::Path;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+
+/// Detects whether the file at `path` is CRAM or BAM from its magic bytes.
+///
+/// Anything that does not start with the CRAM magic number is reported as BAM.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<bam::Format> {
+    let mut magic = [0u8; 4];
+    let mut file = std::fs::File::open(path)?;
+    let n = std::io::Read::read(&mut file, &mut magic)?;
+    if n == magic.len() && &magic == b"CRAM" {
+        Ok(bam::Format::Cram)
+    } else {
+        Ok(bam::Format::Bam)
+    }
+}
+
+/// Finds the index for the alignment file at `path`, by the format of its
+/// contents.
+///
+/// For CRAM the candidates are `<path>.crai` and `<stem>.crai`. For BAM they
+/// are `<path>.csi`, `<path>.bai` and `<stem>.bai`; when both a CSI and a BAI
+/// index exist the CSI index is preferred only if it is newer than the BAI
+/// index, otherwise the BAI index is used.
+///
+/// # Returns
+/// The path of the chosen index, or `None` if no index was found or `path`
+/// can't be read.
+pub fn resolve_index<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let with_suffix = |suffix: &str| {
+        let mut s = path.as_os_str().to_owned();
+        s.push(suffix);
+        PathBuf::from(s)
+    };
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+
+    if detect_format(path).ok()? == bam::Format::Cram {
+        return [with_suffix(".crai"), path.with_extension("crai")]
+            .into_iter()
+            .find(|p| p.is_file());
+    }
+    let csi = Some(with_suffix(".csi")).filter(|p| p.is_file());
+    let bai = [with_suffix(".bai"), path.with_extension("bai")]
+        .into_iter()
+        .find(|p| p.is_file());
+    match (csi, bai) {
+        (Some(csi), Some(bai)) => {
+            if modified(&csi) > modified(&bai) {
+                Some(csi)
+            } else {
+                Some(bai)
+            }
+        }
+        (Some(csi), None) => Some(csi),
+        (None, Some(bai)) => Some(bai),
+        (None, None) => None,
+    }
+}
+
+/// Checks that `header` has at least one `@SQ` line.
+///
+/// Without a sequence dictionary no region can be looked up, so this is
+/// reported up front rather than as a missing contig.
+///
+/// # Errors
+/// Returns [`Error::NoReferenceSequences`] if `header` has no targets.
+pub fn check_has_references(header: &HeaderView) -> Result<()> {
+    if header.target_count() == 0 {
+        return Err(Error::NoReferenceSequences);
+    }
+    Ok(())
+}
+
+/// Returns the reference recorded in the `UR:` tag of the first `@SQ` line of
+/// `header`.
+///
+/// A `file://` prefix is removed; other URL schemes are not supported.
+///
+/// # Errors
+/// Returns an error if the first `@SQ` line has no `UR:` tag, or if it does
+/// not name an existing local file.
+pub fn reference_from_header(header: &HeaderView) -> Result<PathBuf> {
+    let text = String::from_utf8_lossy(header.as_bytes());
+    let ur = text
+        .lines()
+        .find(|line| line.starts_with("@SQ\t"))
+        .and_then(|line| line.split('\t').find_map(|field| field.strip_prefix("UR:")))
+        .ok_or_else(|| Error::Reference {
+            msg: "The first @SQ header line has no UR: tag".to_string(),
+        })?;
+    let path = PathBuf::from(ur.strip_prefix("file://").unwrap_or(ur));
+    if !path.is_file() {
+        return Err(Error::Reference {
+            msg: format!("Reference {ur} from the @SQ UR: tag is not a readable file"),
+        });
+    }
+    Ok(path)
+}
+
+/// Returns a copy of `header` with a `UR:` tag naming `reference` on every
+/// `@SQ` line that does not already have one.
+///
+/// htslib writes a CRAM header, and looks up its reference sequences, as soon
+/// as the file is opened, before a reference can be set on the writer.
+/// Without a `UR:` (or `M5:`) tag it falls back to embedding the reference in
+/// the output.
+pub fn header_with_reference(header: &bam::Header, reference: &Path) -> Result<bam::Header> {
+    let reference = reference.canonicalize()?;
+    let text = String::from_utf8_lossy(&header.to_bytes())
+        .lines()
+        .map(|line| {
+            if line.starts_with("@SQ\t") && !line.split('\t').any(|f| f.starts_with("UR:")) {
+                format!("{line}\tUR:file://{}\n", reference.display())
+            } else {
+                format!("{line}\n")
+            }
+        })
+        .collect::<String>();
+    Ok(bam::Header::from_template(&HeaderView::from_bytes(
+        text.as_bytes(),
+    )))
+}
+
+/// Appends a `@PG` line recording that sequintools `version` was run as
+/// `command_line`, as samtools does.
+///
+/// If `header` already has a `@PG` line with the ID `sequintools`, from an
+/// earlier run, a numeric suffix keeps the new ID unique. The new line's `PP:`
+/// tag names the last program already in the header.
+pub fn push_program_record(header: &mut bam::Header, version: &str, command_line: &str) {
+    let ids = header
+        .to_hashmap()
+        .get("PG")
+        .into_iter()
+        .flatten()
+        .filter_map(|record| record.get("ID").cloned())
+        .collect::<Vec<_>>();
+    let id = std::iter::once("sequintools".to_string())
+        .chain((1..).map(|n| format!("sequintools.{n}")))
+        .find(|id| !ids.contains(id))
+        .expect("an unused program ID");
+    let mut record = format!("PG\tID:{id}\tPN:sequintools");
+    if let Some(previous) = ids.last() {
+        record.push_str(&format!("\tPP:{previous}"));
+    }
+    record.push_str(&format!("\tVN:{version}\tCL:{command_line}"));
+    header.push_record(&bam::header::HeaderRecord::new(record.as_bytes()));
+}
+
+/// The problems found by [`validate`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Validation {
+    /// Descriptions of the first problems found, up to the limit asked for.
+    pub issues: Vec<String>,
+    /// The number of problems found, including those not described.
+    pub count: usize,
+}
+
+impl Validation {
+    /// Returns true if no problems were found.
+    pub fn passed(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Counts a problem, describing it if fewer than `max_issues` are.
+    fn add(&mut self, max_issues: usize, issue: impl FnOnce() -> String) {
+        if self.issues.len() < max_issues {
+            self.issues.push(issue());
+        }
+        self.count += 1;
+    }
+}
+
+/// Checks that an alignment file is well formed.
+///
+/// The file is read sequentially, so no index is needed. See
+/// [`validate_records`] for the checks made.
+///
+/// # Returns
+/// The first `max_issues` problems found and the number of them all.
+pub fn validate<P: AsRef<Path>>(
+    path: P,
+    reference: Option<&Path>,
+    max_issues: usize,
+) -> Result<Validation> {
+    let mut reader = bam::Reader::from_path(path)?;
+    if let Some(reference) = reference {
+        reader.set_reference(reference)?;
+    }
+    let header = reader.header().clone();
+    validate_records(&header, reader.records(), max_issues)
+}
+
+/// Checks that records are coordinate-sorted and that every read and mate
+/// contig exists in `header`, describing at most `max_issues` problems so
+/// that a badly broken file doesn't fill memory.
+///
+/// Unplaced reads (tid -1) are allowed, but only after all placed reads.
+pub fn validate_records<I>(header: &HeaderView, records: I, max_issues: usize) -> Result<Validation>
+where
+    I: IntoIterator<Item = std::result::Result<Record, rust_htslib::errors::Error>>,
+{
+    let n_targets = header.target_count() as i64;
+    let mut validation = Validation::default();
+    let mut prev: Option<(i64, i64)> = None;
+    for (i, result) in records.into_iter().enumerate() {
+        let record = result?;
+        let qname = String::from_utf8_lossy(record.qname());
+        let (tid, pos) = (record.tid() as i64, record.pos());
+        if tid < -1 || tid >= n_targets {
+            validation.add(max_issues, || {
+                format!("record {i} ({qname}) has tid {tid}, which is not in the header")
+            });
+        }
+        let mtid = record.mtid() as i64;
+        if mtid < -1 || mtid >= n_targets {
+            validation.add(max_issues, || {
+                format!("record {i} ({qname}) has mate tid {mtid}, which is not in the header")
+            });
+        }
+        let key = sort_key(record.tid(), pos);
+        if prev.is_some_and(|prev| key < prev) {
+            validation.add(max_issues, || {
+                format!("record {i} ({qname}) at tid {tid} pos {pos} is not coordinate-sorted")
+            });
+        }
+        prev = Some(key);
+    }
+    Ok(validation)
+}
+
+/// Returns the coordinate sort key of a read at `tid` and `pos`.
+///
+/// Unplaced reads sort after every placed read.
+fn sort_key(tid: i32, pos: i64) -> (i64, i64) {
+    (if tid < 0 { i64::MAX } else { tid as i64 }, pos)
+}
+
+/// Returns the 0-based start of a record, or `None` if its position is
+/// negative (e.g. an unplaced read with `pos` -1).
+///
+/// Code that works with unsigned coordinates should skip records for which
+/// this returns `None` rather than casting `pos` directly.
+pub(crate) fn record_start_u64(record: &Record) -> Option<u64> {
+    u64::try_from(record.pos()).ok()
+}
+
+/// Returns true if a record is a singleton: a paired read whose mate is
+/// unmapped.
+pub(crate) fn is_singleton(record: &Record) -> bool {
+    record.is_paired() && record.is_mate_unmapped()
+}
 
 /// A trait for reading BAM files, providing an interface for accessing records,
 /// headers, and controlling reading behavior.
@@ -55,6 +317,7 @@ use std::path::Path;
 /// - `records(&mut self)`: Returns an iterator over records in the current region or file.
 /// - `set_threads(&mut self, n)`: Sets the number of threads for reading.
 /// - `set_reference(&mut self, reference)`: Sets the reference genome for the reader.
+/// - `index_stats(&mut self)`: Returns per-contig read counts from the index.
 pub trait BamReader {
     /// Associated iterator type for records
     type RecordsIter<'a>: Iterator<Item = std::result::Result<Record, rust_htslib::errors::Error>>
@@ -81,6 +344,36 @@ pub trait BamReader {
         &mut self,
         reference: P,
     ) -> std::result::Result<(), rust_htslib::errors::Error>;
+
+    /// Returns `(mapped, unmapped, length)` for each contig in header order,
+    /// like `samtools idxstats`. Unplaced unmapped reads are not included.
+    ///
+    /// The default counts every record, leaving the whole file fetched.
+    /// Readers with an index should override it to read the index's counts.
+    fn index_stats(
+        &mut self,
+    ) -> std::result::Result<Vec<(u64, u64, u64)>, rust_htslib::errors::Error> {
+        let header = self.header();
+        let mut stats = (0..header.target_count())
+            .map(|tid| (0, 0, header.target_len(tid).unwrap_or(0)))
+            .collect::<Vec<_>>();
+        self.fetch(FetchDefinition::All)?;
+        for result in self.records() {
+            let record = result?;
+            let Some(stat) = usize::try_from(record.tid())
+                .ok()
+                .and_then(|tid| stats.get_mut(tid))
+            else {
+                continue;
+            };
+            if record.is_unmapped() {
+                stat.1 += 1;
+            } else {
+                stat.0 += 1;
+            }
+        }
+        Ok(stats)
+    }
 }
 
 /// BAM/CRAM reader implementation using rust-htslib's IndexedReader.
@@ -90,8 +383,21 @@ pub struct HtslibBamReader {
 
 impl HtslibBamReader {
     /// Create a new HtslibBamReader from a file path
+    ///
+    /// The index is located with [`resolve_index`], falling back to htslib's
+    /// own lookup if none of the candidates exist.
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let reader = IndexedReader::from_path(path)?;
+        match resolve_index(&path) {
+            Some(index) => Self::from_path_and_index(path.as_ref(), &index),
+            None => Ok(Self {
+                reader: IndexedReader::from_path(path)?,
+            }),
+        }
+    }
+
+    /// Create a new HtslibBamReader from a file path and an explicit index
+    pub fn from_path_and_index<P: AsRef<Path>>(path: P, index: P) -> Result<Self> {
+        let reader = IndexedReader::from_path_and_index(path, index)?;
         Ok(Self { reader })
     }
 }
@@ -129,6 +435,18 @@ impl BamReader for HtslibBamReader {
     ) -> std::result::Result<(), rust_htslib::errors::Error> {
         self.reader.set_reference(reference)
     }
+
+    /// Returns per-contig read counts from the index
+    fn index_stats(
+        &mut self,
+    ) -> std::result::Result<Vec<(u64, u64, u64)>, rust_htslib::errors::Error> {
+        let stats = self.reader.index_stats()?;
+        Ok(stats
+            .into_iter()
+            .filter(|&(tid, ..)| tid >= 0)
+            .map(|(_, length, mapped, unmapped)| (mapped, unmapped, length))
+            .collect())
+    }
 }
 
 #[cfg(any(test, feature = "test-util"))]
@@ -167,6 +485,7 @@ pub struct MockBamReader {
     records: Vec<Record>,
     header: HeaderView,
     fetch_state: FetchState,
+    fetch_count: usize,
 }
 
 #[cfg(any(test, feature = "test-util"))]
@@ -204,8 +523,14 @@ impl MockBamReader {
             records,
             header: header_view,
             fetch_state: FetchState::All,
+            fetch_count: 0,
         }
     }
+
+    /// Returns the number of times `fetch` has been called.
+    pub fn fetch_count(&self) -> usize {
+        self.fetch_count
+    }
 }
 
 #[cfg(any(test, feature = "test-util"))]
@@ -236,6 +561,7 @@ impl BamReader for MockBamReader {
         &mut self,
         definition: T,
     ) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.fetch_count += 1;
         let fetch_def = definition.into();
         match fetch_def {
             FetchDefinition::All => self.fetch_state = FetchState::All,
@@ -279,7 +605,10 @@ impl BamReader for MockBamReader {
                 FetchState::Unmapped => record.is_unmapped(),
                 FetchState::Contig(tid) => record.tid() == tid,
                 FetchState::Region(tid, beg, end) => {
-                    record.tid() == tid && record.pos() >= beg as i64 && record.pos() < end as i64
+                    // Like htslib, return every record overlapping the region,
+                    // not just those starting in it.
+                    let read_end = record.cigar().end_pos().max(record.pos() + 1);
+                    record.tid() == tid && record.pos() < end as i64 && read_end > beg as i64
                 }
             })
             .cloned()
@@ -355,6 +684,239 @@ impl BamWriter for HtslibBamWriter {
     }
 }
 
+/// Builds a header holding the union of the contigs of `primary` and `other`.
+///
+/// The result is a copy of `primary` with the contigs only found in `other`
+/// appended, so tids from `primary` are unchanged.
+///
+/// # Returns
+/// The merged header and, for each tid of `other`, its tid in the merged
+/// header.
+///
+/// # Errors
+/// Returns an error if a contig is in both headers with different lengths, or
+/// if the contigs of `other` would not keep their relative order in the merged
+/// header: the headers must list their shared contigs in the same order, with
+/// any contigs only found in `other` after them. Otherwise the merged stream
+/// would not be sorted.
+pub fn merge_headers(primary: &HeaderView, other: &HeaderView) -> Result<(bam::Header, Vec<i32>)> {
+    let mut header = bam::Header::from_template(primary);
+    let mut next_tid = primary.target_count() as i32;
+    let mut tid_map = Vec::with_capacity(other.target_count() as usize);
+    for (tid, name) in other.target_names().into_iter().enumerate() {
+        let len = other.target_len(tid as u32).unwrap_or(0);
+        match primary.tid(name) {
+            Some(primary_tid) => {
+                let primary_len = primary.target_len(primary_tid).unwrap_or(0);
+                if primary_len != len {
+                    return Err(Error::Merge {
+                        msg: format!(
+                            "Contig {} has length {primary_len} in one header and {len} in the other",
+                            String::from_utf8_lossy(name)
+                        ),
+                    });
+                }
+                tid_map.push(primary_tid as i32);
+            }
+            None => {
+                let mut record = bam::header::HeaderRecord::new(b"SQ");
+                record.push_tag(b"SN", std::str::from_utf8(name)?);
+                record.push_tag(b"LN", len);
+                header.push_record(&record);
+                tid_map.push(next_tid);
+                next_tid += 1;
+            }
+        }
+    }
+    if let Some(i) = tid_map.windows(2).position(|pair| pair[1] < pair[0]) {
+        let names = other.target_names();
+        return Err(Error::Merge {
+            msg: format!(
+                "Contig {} follows {} in one header but comes before it in the merged header; \
+                 shared contigs must be in the same order in both headers",
+                String::from_utf8_lossy(names[i + 1]),
+                String::from_utf8_lossy(names[i]),
+            ),
+        });
+    }
+    Ok((header, tid_map))
+}
+
+/// A writer that merges a second coordinate-sorted stream of records into the
+/// records written to it.
+///
+/// Records written are passed through to the inner writer, preceded by every
+/// record from `others` that sorts before them. Tids of `others` are
+/// translated with a map such as the one returned by [`merge_headers`]. Call
+/// [`MergingWriter::finish`] to write the remaining records.
+///
+/// Both streams must be coordinate-sorted for the output to be.
+pub struct MergingWriter<W, I>
+where
+    I: Iterator<Item = std::result::Result<Record, rust_htslib::errors::Error>>,
+{
+    writer: W,
+    others: Peekable<I>,
+    tid_map: Vec<i32>,
+}
+
+impl<W, I> MergingWriter<W, I>
+where
+    W: BamWriter,
+    I: Iterator<Item = std::result::Result<Record, rust_htslib::errors::Error>>,
+{
+    /// Create a new MergingWriter
+    pub fn new(writer: W, others: I, tid_map: Vec<i32>) -> Self {
+        Self {
+            writer,
+            others: others.peekable(),
+            tid_map,
+        }
+    }
+
+    /// Writes every pending record from `others` that sorts before `key`, or
+    /// all of them if `key` is `None`.
+    fn write_others(
+        &mut self,
+        key: Option<(i64, i64)>,
+    ) -> std::result::Result<(), rust_htslib::errors::Error> {
+        while let Some(next) = self.others.peek() {
+            if let (Ok(record), Some(key)) = (next, key) {
+                if sort_key(map_tid(&self.tid_map, record.tid()), record.pos()) > key {
+                    break;
+                }
+            }
+            let mut record = self.others.next().expect("peeked record")?;
+            record.set_tid(map_tid(&self.tid_map, record.tid()));
+            record.set_mtid(map_tid(&self.tid_map, record.mtid()));
+            self.writer.write(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining records from `others` and returns the inner
+    /// writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_others(None)?;
+        Ok(self.writer)
+    }
+}
+
+/// Translates `tid` with `tid_map`, leaving unplaced reads (tid -1) alone.
+fn map_tid(tid_map: &[i32], tid: i32) -> i32 {
+    if tid < 0 {
+        tid
+    } else {
+        tid_map.get(tid as usize).copied().unwrap_or(-1)
+    }
+}
+
+impl<W, I> BamWriter for MergingWriter<W, I>
+where
+    W: BamWriter,
+    I: Iterator<Item = std::result::Result<Record, rust_htslib::errors::Error>>,
+{
+    fn write(&mut self, record: &Record) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.write_others(Some(sort_key(record.tid(), record.pos())))?;
+        self.writer.write(record)
+    }
+
+    /// Sets the number of threads to use for writing
+    fn set_threads(&mut self, n: usize) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.writer.set_threads(n)
+    }
+
+    /// Sets the reference FASTA file for CRAM/BAM decoding
+    fn set_reference<P: AsRef<Path>>(
+        &mut self,
+        reference: P,
+    ) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.writer.set_reference(reference)
+    }
+}
+
+/// A writer that also copies each record into the writer of every region it
+/// overlaps.
+///
+/// Every record is passed through to the inner writer. Records overlapping
+/// none of the regions are only written there.
+pub struct RegionSplitWriter<W, S> {
+    writer: W,
+    regions: Vec<(i32, i64, i64, S)>,
+}
+
+impl<W, S> RegionSplitWriter<W, S>
+where
+    W: BamWriter,
+    S: BamWriter,
+{
+    /// Create a new RegionSplitWriter, resolving region contigs in `header`
+    ///
+    /// # Errors
+    /// Returns an error if a region's contig is not in `header`.
+    pub fn new(writer: W, header: &HeaderView, regions: Vec<(Region, S)>) -> Result<Self> {
+        let regions = regions
+            .into_iter()
+            .map(|(region, region_writer)| {
+                let tid = header
+                    .tid(region.contig.as_bytes())
+                    .ok_or_else(|| Error::Region {
+                        msg: format!("Chromosome {} not found in BAM header", region.contig),
+                    })?;
+                Ok((
+                    tid as i32,
+                    region.beg as i64,
+                    region.end as i64,
+                    region_writer,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { writer, regions })
+    }
+
+    /// Returns the inner writer, closing the region writers.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W, S> BamWriter for RegionSplitWriter<W, S>
+where
+    W: BamWriter,
+    S: BamWriter,
+{
+    fn write(&mut self, record: &Record) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.writer.write(record)?;
+        if record.is_unmapped() {
+            return Ok(());
+        }
+        let Some(beg) = record_start_u64(record) else {
+            return Ok(());
+        };
+        let (tid, beg) = (record.tid(), beg as i64);
+        let end = record.cigar().end_pos().max(beg + 1);
+        for (region_tid, region_beg, region_end, region_writer) in &mut self.regions {
+            if *region_tid == tid && beg < *region_end && end > *region_beg {
+                region_writer.write(record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the number of threads to use for writing
+    fn set_threads(&mut self, n: usize) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.writer.set_threads(n)
+    }
+
+    /// Sets the reference FASTA file for CRAM/BAM decoding
+    fn set_reference<P: AsRef<Path>>(
+        &mut self,
+        reference: P,
+    ) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.writer.set_reference(reference)
+    }
+}
+
 /// Mock implementation of `BamWriter` for testing purposes.
 ///
 /// This struct simulates the behavior of a BAM/CRAM writer and is intended for
@@ -377,9 +939,12 @@ impl BamWriter for HtslibBamWriter {
 /// let written_records = writer.records();
 /// assert_eq!(written_records.len(), 1);
 /// ```
+///
+/// Use [`MockBamWriter::failing_after`] to test how write errors are handled.
 #[cfg(any(test, feature = "test-util"))]
 pub struct MockBamWriter {
     records: Vec<Record>,
+    fail_after: Option<usize>,
 }
 
 #[cfg(any(test, feature = "test-util"))]
@@ -388,6 +953,17 @@ impl MockBamWriter {
     pub fn new() -> Self {
         Self {
             records: Vec::new(),
+            fail_after: None,
+        }
+    }
+
+    /// Create a MockBamWriter whose `write` fails with
+    /// `rust_htslib::errors::Error::WriteRecord` once `n` records have been
+    /// written
+    pub fn failing_after(n: usize) -> Self {
+        Self {
+            records: Vec::new(),
+            fail_after: Some(n),
         }
     }
 
@@ -412,6 +988,9 @@ impl Default for MockBamWriter {
 #[cfg(any(test, feature = "test-util"))]
 impl BamWriter for MockBamWriter {
     fn write(&mut self, record: &Record) -> std::result::Result<(), rust_htslib::errors::Error> {
+        if self.fail_after.is_some_and(|n| self.records.len() >= n) {
+            return Err(rust_htslib::errors::Error::WriteRecord);
+        }
         self.records.push(record.clone());
         Ok(())
     }
@@ -624,6 +1203,37 @@ mod tests {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    fn test_mock_bam_reader_index_stats() {
+        let mut unmapped = create_mock_record(0, 500, "unmapped");
+        unmapped.set_unmapped();
+        let records = vec![
+            create_mock_record(0, 100, "read1"),
+            create_mock_record(0, 200, "read2"),
+            unmapped,
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read3"),
+        ];
+        let mut mock_reader = MockBamReader::new(records, None);
+        let stats = mock_reader.index_stats().unwrap();
+        assert_eq!(
+            stats,
+            vec![(2, 1, 5000), (0, 0, 5000), (0, 0, 5000), (1, 0, 9800)]
+        );
+    }
+
+    #[test]
+    fn test_mock_bam_writer_failing_after() {
+        let mut writer = MockBamWriter::failing_after(2);
+        let record = Record::new();
+        assert!(writer.write(&record).is_ok());
+        assert!(writer.write(&record).is_ok());
+        assert!(matches!(
+            writer.write(&record),
+            Err(rust_htslib::errors::Error::WriteRecord)
+        ));
+        assert_eq!(writer.records().len(), 2);
+    }
+
     #[test]
     fn test_mock_bam_writer_default() {
         let writer = MockBamWriter::default();
@@ -769,6 +1379,370 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_htslib_bam_reader_index_stats() {
+        let mut reader = HtslibBamReader::from_path("testdata/calibrated.bam").unwrap();
+        let stats = reader.index_stats().unwrap();
+        assert_eq!(stats.len(), reader.header().target_count() as usize);
+        for (tid, &(_, _, length)) in stats.iter().enumerate() {
+            assert_eq!(Some(length), reader.header().target_len(tid as u32));
+        }
+
+        // The index counts agree with reading every placed record.
+        reader.fetch(FetchDefinition::All).unwrap();
+        let mut expected = vec![(0, 0); stats.len()];
+        for record in reader.records() {
+            let record = record.unwrap();
+            if record.tid() >= 0 {
+                let counts = &mut expected[record.tid() as usize];
+                if record.is_unmapped() {
+                    counts.1 += 1;
+                } else {
+                    counts.0 += 1;
+                }
+            }
+        }
+        let actual = stats
+            .iter()
+            .map(|&(mapped, unmapped, _)| (mapped, unmapped))
+            .collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+        assert!(actual.iter().any(|&(mapped, _)| mapped > 0));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            detect_format("testdata/calibrated.bam").unwrap(),
+            bam::Format::Bam
+        );
+        assert_eq!(
+            detect_format("testdata/calibrated.cram").unwrap(),
+            bam::Format::Cram
+        );
+        assert!(detect_format("nonexistent.bam").is_err());
+    }
+
+    #[test]
+    fn test_header_with_reference() {
+        let mut header = bam::Header::new();
+        header.push_record(&bam::header::HeaderRecord::new(b"HD\tVN:1.6"));
+        header.push_record(&bam::header::HeaderRecord::new(b"SQ\tSN:chr1\tLN:5000"));
+        header.push_record(&bam::header::HeaderRecord::new(
+            b"SQ\tSN:chr2\tLN:5000\tUR:other.fasta",
+        ));
+        let reference = "testdata/genome_with_sequins.fasta";
+        let header = header_with_reference(&header, Path::new(reference)).unwrap();
+        let reference = std::fs::canonicalize(reference).unwrap();
+        assert_eq!(
+            String::from_utf8(header.to_bytes()).unwrap(),
+            format!(
+                "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:5000\tUR:file://{}\n@SQ\tSN:chr2\tLN:5000\tUR:other.fasta",
+                reference.display()
+            )
+        );
+        // The first @SQ line now names the reference.
+        assert_eq!(
+            reference_from_header(&HeaderView::from_header(&header)).unwrap(),
+            reference
+        );
+    }
+
+    #[test]
+    fn test_push_program_record() {
+        let mut header = bam::Header::new();
+        header.push_record(&bam::header::HeaderRecord::new(b"HD\tVN:1.6"));
+        push_program_record(&mut header, "1.0", "sequintools calibrate in.bam");
+        push_program_record(&mut header, "1.1", "sequintools calibrate out.bam");
+        assert_eq!(
+            String::from_utf8(header.to_bytes()).unwrap(),
+            "@HD\tVN:1.6\n\
+            @PG\tID:sequintools\tPN:sequintools\tVN:1.0\tCL:sequintools calibrate in.bam\n\
+            @PG\tID:sequintools.1\tPN:sequintools\tPP:sequintools\tVN:1.1\tCL:sequintools calibrate out.bam"
+        );
+    }
+
+    #[test]
+    fn test_reference_from_header() {
+        let header_with = |sq: &str| {
+            let mut header = bam::Header::new();
+            header.push_record(&bam::header::HeaderRecord::new(sq.as_bytes()));
+            header.push_record(&bam::header::HeaderRecord::new(
+                b"SQ\tSN:chr2\tLN:5000\tUR:other.fasta",
+            ));
+            HeaderView::from_header(&header)
+        };
+        let reference = std::fs::canonicalize("testdata/genome_with_sequins.fasta").unwrap();
+
+        let header = header_with(&format!("SQ\tSN:chr1\tLN:5000\tUR:{}", reference.display()));
+        assert_eq!(reference_from_header(&header).unwrap(), reference);
+        let header = header_with(&format!(
+            "SQ\tSN:chr1\tLN:5000\tUR:file://{}",
+            reference.display()
+        ));
+        assert_eq!(reference_from_header(&header).unwrap(), reference);
+
+        // Only the first @SQ line is used.
+        let header = header_with("SQ\tSN:chr1\tLN:5000");
+        assert!(matches!(
+            reference_from_header(&header),
+            Err(Error::Reference { .. })
+        ));
+        let header = header_with("SQ\tSN:chr1\tLN:5000\tUR:/nonexistent/ref.fasta");
+        assert!(matches!(
+            reference_from_header(&header),
+            Err(Error::Reference { .. })
+        ));
+    }
+
+    #[test]
+    fn test_record_start_u64() {
+        assert_eq!(
+            record_start_u64(&create_mock_record(0, 0, "read1")),
+            Some(0)
+        );
+        assert_eq!(
+            record_start_u64(&create_mock_record(0, 42, "read1")),
+            Some(42)
+        );
+        assert_eq!(record_start_u64(&create_mock_record(0, -1, "read1")), None);
+    }
+
+    #[test]
+    fn test_validate_sorted_output() {
+        let validation = validate("testdata/calibrated.bam", None, 10).unwrap();
+        assert!(validation.passed(), "{validation:?}");
+    }
+
+    #[test]
+    fn test_validate_records_malformed() {
+        let reader = MockBamReader::new(vec![], None);
+        let header = reader.header().clone();
+        let mut bad_mate = create_mock_record(0, 300, "bad_mate");
+        bad_mate.set_mtid(42);
+        let mut unplaced = create_mock_record(-1, -1, "unplaced");
+        unplaced.set_unmapped();
+        let records = vec![
+            create_mock_record(0, 200, "read1"),
+            create_mock_record(0, 100, "unsorted"),
+            bad_mate,
+            create_mock_record(99, 100, "bad_tid"),
+            unplaced,
+            create_mock_record(1, 100, "after_unplaced"),
+        ];
+        let validation =
+            validate_records(&header, records.clone().into_iter().map(Ok), 10).unwrap();
+        assert_eq!(validation.count, 4, "{validation:?}");
+        let issues = validation.issues;
+        assert_eq!(issues.len(), 4, "{issues:?}");
+        assert!(issues[0].contains("unsorted") && issues[0].contains("not coordinate-sorted"));
+        assert!(issues[1].contains("bad_mate") && issues[1].contains("mate tid 42"));
+        assert!(issues[2].contains("bad_tid") && issues[2].contains("tid 99"));
+        assert!(issues[3].contains("after_unplaced"));
+
+        let sorted = vec![
+            create_mock_record(0, 100, "read1"),
+            create_mock_record(3, 50, "read2"),
+        ];
+        let validation = validate_records(&header, sorted.into_iter().map(Ok), 10).unwrap();
+        assert!(validation.passed());
+
+        // Only the first problems are described, but all are counted.
+        let validation = validate_records(&header, records.into_iter().map(Ok), 2).unwrap();
+        assert_eq!(validation.count, 4);
+        assert_eq!(validation.issues.len(), 2);
+        assert!(validation.issues[1].contains("bad_mate"));
+    }
+
+    #[test]
+    fn test_resolve_index_prefers_newer_csi() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = std::env::temp_dir().join(format!("sequintools-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let bam_path = dir.join("sample.bam");
+        let bai = dir.join("sample.bam.bai");
+        let csi = dir.join("sample.bam.csi");
+        std::fs::copy("testdata/calibrated.bam", &bam_path).unwrap();
+        std::fs::copy("testdata/calibrated.bam.bai", &bai).unwrap();
+        assert_eq!(resolve_index(&bam_path), Some(bai.clone()));
+
+        std::fs::write(&csi, b"csi").unwrap();
+        let set_modified = |path: &Path, secs: u64| {
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        // Both present: the CSI index is only used if it is newer.
+        set_modified(&bai, 2_000_000_000);
+        set_modified(&csi, 1_000_000_000);
+        assert_eq!(resolve_index(&bam_path), Some(bai.clone()));
+        assert!(HtslibBamReader::from_path(&bam_path).is_ok());
+
+        set_modified(&csi, 2_000_000_001);
+        assert_eq!(resolve_index(&bam_path), Some(csi));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolve_index(&bam_path), None);
+    }
+
+    #[test]
+    fn test_resolve_index_cram() {
+        let dir = std::env::temp_dir().join(format!("sequintools-crai-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let cram_path = dir.join("sample.cram");
+        std::fs::copy("testdata/calibrated.cram", &cram_path).unwrap();
+        // A stray BAI index is not used for a CRAM file.
+        std::fs::write(dir.join("sample.bai"), b"bai").unwrap();
+        std::fs::write(dir.join("sample.cram.bai"), b"bai").unwrap();
+        assert_eq!(resolve_index(&cram_path), None);
+
+        let stem_crai = dir.join("sample.crai");
+        std::fs::write(&stem_crai, b"crai").unwrap();
+        assert_eq!(resolve_index(&cram_path), Some(stem_crai));
+        let crai = dir.join("sample.cram.crai");
+        std::fs::write(&crai, b"crai").unwrap();
+        assert_eq!(resolve_index(&cram_path), Some(crai));
+
+        // The format comes from the contents, not the extension.
+        let named_bam = dir.join("named.bam");
+        std::fs::copy("testdata/calibrated.cram", &named_bam).unwrap();
+        std::fs::write(dir.join("named.bam.bai"), b"bai").unwrap();
+        assert_eq!(resolve_index(&named_bam), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merging_writer() {
+        let primary = MockBamReader::new(vec![], None);
+        let mut sample_header = bam::Header::new();
+        sample_header.push_record(&bam::header::HeaderRecord::new(b"SQ\tSN:chr1\tLN:5000"));
+        sample_header.push_record(&bam::header::HeaderRecord::new(b"SQ\tSN:chr20\tLN:8000"));
+        let sample_header = HeaderView::from_header(&sample_header);
+
+        let (header, tid_map) = merge_headers(primary.header(), &sample_header).unwrap();
+        assert_eq!(tid_map, vec![0, 4]);
+        let header = HeaderView::from_header(&header);
+        assert_eq!(header.target_count(), 5);
+        assert_eq!(header.tid(b"chr20"), Some(4));
+        assert_eq!(header.target_len(4), Some(8000));
+
+        let mut sample_mate_on_chr20 = create_mock_record(0, 300, "s2");
+        sample_mate_on_chr20.set_mtid(1);
+        let sample_records = vec![
+            create_mock_record(0, 50, "s1"),
+            sample_mate_on_chr20,
+            create_mock_record(1, 10, "s3"),
+        ];
+        let mut writer = MergingWriter::new(
+            MockBamWriter::new(),
+            sample_records.into_iter().map(Ok),
+            tid_map,
+        );
+        writer
+            .write(&create_mock_record(CHRQ_MIRROR_TID, 100, "q1"))
+            .unwrap();
+        writer
+            .write(&create_mock_record(CHRQ_MIRROR_TID, 200, "q2"))
+            .unwrap();
+        let writer = writer.finish().unwrap();
+
+        let names = writer
+            .records()
+            .iter()
+            .map(|r| String::from_utf8_lossy(r.qname()).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["s1", "s2", "q1", "q2", "s3"]);
+        assert_eq!(writer.records()[1].mtid(), 4);
+        assert_eq!(writer.records()[4].tid(), 4);
+        let records = writer.records().iter().cloned().map(Ok);
+        assert!(validate_records(&header, records, 10).unwrap().passed());
+    }
+
+    #[test]
+    fn test_merge_headers_length_mismatch() {
+        let primary = MockBamReader::new(vec![], None);
+        let mut other = bam::Header::new();
+        other.push_record(&bam::header::HeaderRecord::new(b"SQ\tSN:chr1\tLN:4000"));
+        let result = merge_headers(primary.header(), &HeaderView::from_header(&other));
+        assert!(matches!(result, Err(Error::Merge { .. })));
+    }
+
+    #[test]
+    fn test_merge_headers_order_mismatch() {
+        let primary = MockBamReader::new(vec![], None);
+        let header = |lines: &[&[u8]]| {
+            let mut header = bam::Header::new();
+            for line in lines {
+                header.push_record(&bam::header::HeaderRecord::new(line));
+            }
+            HeaderView::from_header(&header)
+        };
+
+        let swapped = header(&[b"SQ\tSN:chr2\tLN:5000", b"SQ\tSN:chr1\tLN:5000"]);
+        let err = merge_headers(primary.header(), &swapped).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "merge error: Contig chr1 follows chr2 in one header but comes before it in \
+             the merged header; shared contigs must be in the same order in both headers"
+        );
+
+        // A contig only in the other header is appended, so it can't come
+        // before a shared one.
+        let new_first = header(&[b"SQ\tSN:chr20\tLN:8000", b"SQ\tSN:chr1\tLN:5000"]);
+        assert!(merge_headers(primary.header(), &new_first).is_err());
+
+        // Shared contigs may be missing from either header.
+        let subset = header(&[b"SQ\tSN:chr1\tLN:5000", b"SQ\tSN:chr3\tLN:5000"]);
+        let (_, tid_map) = merge_headers(primary.header(), &subset).unwrap();
+        assert_eq!(tid_map, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_region_split_writer() {
+        let reader = MockBamReader::new(vec![], None);
+        let regions = vec![
+            (
+                Region::new("chrQ_mirror", 100, 200, "region1"),
+                MockBamWriter::new(),
+            ),
+            (
+                Region::new("chrQ_mirror", 300, 400, "region2"),
+                MockBamWriter::new(),
+            ),
+        ];
+        let mut writer =
+            RegionSplitWriter::new(MockBamWriter::new(), reader.header(), regions).unwrap();
+        let records = [
+            create_mock_record(CHRQ_MIRROR_TID, 100, "in_region1"),
+            create_mock_record(CHRQ_MIRROR_TID, 150, "in_region1_too"),
+            // 250-349 only overlaps region2.
+            create_mock_record(CHRQ_MIRROR_TID, 250, "in_region2"),
+            create_mock_record(CHRQ_MIRROR_TID, 5000, "in_neither"),
+            create_mock_record(0, 100, "other_contig"),
+        ];
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let counts = writer
+            .regions
+            .iter()
+            .map(|(.., region_writer)| region_writer.records().len())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![2, 1]);
+        assert_eq!(writer.into_inner().records().len(), records.len());
+
+        let missing = vec![(
+            Region::new("chrZ", 100, 200, "region1"),
+            MockBamWriter::new(),
+        )];
+        assert!(RegionSplitWriter::new(MockBamWriter::new(), reader.header(), missing).is_err());
+    }
+
     #[test]
     fn test_htslib_bam_reader_creation_non_existant() {
         // This test requires an actual BAM file, so we'll test the error case
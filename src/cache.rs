@@ -0,0 +1,221 @@
+//! # Cache Module
+//!
+//! This module provides an on-disk cache of per-region mean coverage, so that
+//! repeated calibrations of the same BAM do not recompute it.
+//!
+//! ## Structs
+//!
+//! - `CoverageCache`: A directory of JSON cache entries for one BAM file.
+//! - `CoverageSettings`: The settings coverage is counted with.
+//!
+//! ## Keys
+//!
+//! An entry is keyed by the canonical BAM path, its modification time, the
+//! `CoverageSettings` the coverage was counted with and a hash of the region
+//! set. Touching or replacing the BAM changes the key, so stale entries are
+//! never read.
+//!
+//! ## Tests
+//!
+//! Contains unit tests for the JSON round trip and cache invalidation.
+use crate::coverage::ReadFilters;
+use crate::errors::Result;
+use crate::region::Region;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The settings region coverage is counted with. Each is part of a cache
+/// entry's key, so coverage counted differently is never read back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageSettings {
+    pub min_mapq: u8,
+    /// Bases trimmed from each end of a region.
+    pub flank: u64,
+    pub read_filters: ReadFilters,
+}
+
+/// A cache of per-region mean coverage for one BAM file.
+#[derive(Debug)]
+pub struct CoverageCache {
+    dir: PathBuf,
+    bam_key: String,
+}
+
+/// The contents of a cache entry file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    coverage: BTreeMap<String, f64>,
+}
+
+impl CoverageCache {
+    /// Creates a cache in `dir` for the BAM at `bam_path`, creating the
+    /// directory if needed.
+    pub fn new(dir: &Path, bam_path: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mtime = fs::metadata(bam_path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let bam_key = format!("{}:{mtime}", fs::canonicalize(bam_path)?.display());
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            bam_key,
+        })
+    }
+
+    /// Returns the cache key for a set of regions counted with `settings`.
+    fn key(&self, regions: &[Region], settings: &CoverageSettings) -> String {
+        let mut context = md5::Context::new();
+        context.consume(self.bam_key.as_bytes());
+        // The debug form names every setting, so one added later is part of
+        // the key without changing this.
+        context.consume(format!("\n{settings:?}").as_bytes());
+        for region in regions {
+            context.consume(
+                format!(
+                    "\n{}\t{}\t{}\t{}",
+                    region.contig, region.beg, region.end, region.name
+                )
+                .as_bytes(),
+            );
+        }
+        format!("{:x}", context.finalize())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// Loads the coverage of `regions` counted with `settings`, if there is an
+    /// entry for them.
+    ///
+    /// Unreadable or malformed entries are treated as a cache miss.
+    pub fn load(
+        &self,
+        regions: &[Region],
+        settings: &CoverageSettings,
+    ) -> Option<HashMap<String, f64>> {
+        let key = self.key(regions, settings);
+        let json = fs::read_to_string(self.entry_path(&key)).ok()?;
+        let entry = serde_json::from_str::<Entry>(&json).ok()?;
+        (entry.key == key).then(|| entry.coverage.into_iter().collect())
+    }
+
+    /// Stores the coverage of `regions` counted with `settings`.
+    pub fn store(
+        &self,
+        regions: &[Region],
+        settings: &CoverageSettings,
+        coverage: &HashMap<String, f64>,
+    ) -> Result<()> {
+        let key = self.key(regions, settings);
+        let path = self.entry_path(&key);
+        let entry = Entry {
+            key,
+            coverage: coverage
+                .iter()
+                .map(|(name, &mean)| (name.clone(), mean))
+                .collect(),
+        };
+        // Write to a temporary file first so that a concurrent or interrupted
+        // run never leaves a partial entry behind.
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&entry)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sequintools-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn settings() -> CoverageSettings {
+        CoverageSettings {
+            min_mapq: 0,
+            flank: 0,
+            read_filters: ReadFilters::default(),
+        }
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let dir = temp_dir("round-trip");
+        let bam = dir.join("sample.bam");
+        fs::write(&bam, b"bam").unwrap();
+        let regions = vec![Region::new("chr1", 0, 100, "region1")];
+        let coverage = HashMap::from([
+            ("region1".to_string(), 12.5),
+            ("odd \"name\"\\\t".to_string(), 0.1 + 0.2),
+        ]);
+        let cache = CoverageCache::new(&dir.join("cache"), &bam).unwrap();
+        cache.store(&regions, &settings(), &coverage).unwrap();
+        assert_eq!(cache.load(&regions, &settings()), Some(coverage));
+
+        // A truncated entry is a miss.
+        let path = cache.entry_path(&cache.key(&regions, &settings()));
+        let json = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &json[..json.len() / 2]).unwrap();
+        assert!(cache.load(&regions, &settings()).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_store_and_load() {
+        let dir = temp_dir("store");
+        let bam = dir.join("sample.bam");
+        fs::write(&bam, b"bam").unwrap();
+        let regions = vec![Region::new("chr1", 0, 100, "region1")];
+        let coverage = HashMap::from([("region1".to_string(), 3.0)]);
+
+        let cache = CoverageCache::new(&dir.join("cache"), &bam).unwrap();
+        assert!(cache.load(&regions, &settings()).is_none());
+        cache.store(&regions, &settings(), &coverage).unwrap();
+        assert_eq!(cache.load(&regions, &settings()), Some(coverage));
+
+        // A different region set is a different entry.
+        let other = vec![Region::new("chr1", 0, 200, "region1")];
+        assert!(cache.load(&other, &settings()).is_none());
+
+        // So is coverage counted with different settings.
+        let min_mapq = CoverageSettings {
+            min_mapq: 10,
+            ..settings()
+        };
+        assert!(cache.load(&regions, &min_mapq).is_none());
+        let flank = CoverageSettings {
+            flank: 50,
+            ..settings()
+        };
+        assert!(cache.load(&regions, &flank).is_none());
+        let read_filters = CoverageSettings {
+            read_filters: ReadFilters::none(),
+            ..settings()
+        };
+        assert!(cache.load(&regions, &read_filters).is_none());
+
+        // Changing the BAM modification time invalidates the entry.
+        let file = fs::File::options().write(true).open(&bam).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let cache = CoverageCache::new(&dir.join("cache"), &bam).unwrap();
+        assert!(cache.load(&regions, &settings()).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
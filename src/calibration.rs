@@ -2,26 +2,57 @@
 //!
 //! This module provides functionality for calibrating BAM files based on coverage
 //! requirements. It supports different calibration modes: fixed coverage,
-//! sample mean coverage, and sample profile matching.
+//! sample mean coverage, contig mean coverage, and sample profile matching.
 //!
 //! The main entry point is the [`calibrate`] function, which processes a BAM file
-//! and writes the calibrated output to another BAM file.
+//! and writes the calibrated output to another BAM file. Whatever the mode,
+//! reads are written in the order of the input, so a coordinate-sorted input
+//! gives a coordinate-sorted output that can be indexed directly.
 
-use crate::bam::{BamReader, BamWriter};
+use crate::bam::{is_singleton, record_start_u64, BamReader, BamWriter};
+use crate::cache::{CoverageCache, CoverageSettings};
 use crate::coverage;
 use crate::errors::{Error, Result};
-use crate::region::Region;
+use crate::progress::Progress;
+use crate::region::{self, Region};
+use crate::timing::{self, Timings};
+use rand::rngs::SysRng;
 use rand::seq::IteratorRandom;
-use rand::{RngExt, SeedableRng};
+use rand::{RngExt, SeedableRng, TryRng};
 use rand_pcg::Pcg32;
-use rust_htslib::bam::{FetchDefinition, Record};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{FetchDefinition, HeaderView, Record};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Aux tag holding a calibrated read's downsampling probability, as a float,
+/// when [`Config::annotate_probability`] is set.
+pub const PROBABILITY_TAG: &[u8] = b"ds";
+
+/// Aux tag marking how each written read got to the output, as a character,
+/// when [`Config::tag_origin`] is set: [`ORIGIN_CALIBRATED`] for reads kept by
+/// calibration and [`ORIGIN_UNCALIBRATED`] for copied reads.
+pub const ORIGIN_TAG: &[u8] = b"cb";
+/// [`ORIGIN_TAG`] value of reads kept by calibration.
+pub const ORIGIN_CALIBRATED: u8 = b'C';
+/// [`ORIGIN_TAG`] value of reads copied without calibration.
+pub const ORIGIN_UNCALIBRATED: u8 = b'U';
+
+/// Aux tag set to 1, as an integer, on every mapped read on a target contig
+/// kept by calibration when [`Config::tag_calibrated`] is set. Copied reads,
+/// and mates of calibrated reads that are unmapped or on another contig, are
+/// written without it.
+pub const CALIBRATED_TAG: &[u8] = b"XC";
 
 /// Represents the different modes for calibration.
 ///
 /// Each variant specifies a different strategy for determining how to downsample
 /// reads in the target regions.
+#[derive(Debug)]
 pub enum CalibrationMode<'a> {
     /// Calibrate to a fixed fold coverage.
     ///
@@ -29,6 +60,14 @@ pub enum CalibrationMode<'a> {
     /// - `fold_coverage`: The desired fold coverage for all target regions.
     /// - `seed`: Random seed for reproducible downsampling.
     FixedCoverage { fold_coverage: u64, seed: u64 },
+    /// Calibrate each target region to its own fold coverage,
+    /// [`Region::target`].
+    ///
+    /// # Fields
+    /// - `default_coverage`: The fold coverage of target regions without a
+    ///   target of their own.
+    /// - `seed`: Random seed for reproducible downsampling.
+    PerRegionCoverage { default_coverage: u64, seed: u64 },
     /// Calibrate based on the mean coverage of sample regions.
     ///
     /// # Fields
@@ -38,6 +77,31 @@ pub enum CalibrationMode<'a> {
         sample_regions: &'a [Region],
         seed: u64,
     },
+    /// Calibrate based on a percentile of the coverage of sample regions.
+    ///
+    /// # Fields
+    /// - `sample_regions`: Regions to sample coverage from.
+    /// - `percentile`: Percentile of each sample region's per-base depth used
+    ///   as the target, in `[0, 100]`.
+    /// - `seed`: Random seed for reproducible downsampling.
+    SamplePercentileCoverage {
+        sample_regions: &'a [Region],
+        percentile: f64,
+        seed: u64,
+    },
+    /// Calibrate every target region to the mean depth of a main-genome contig.
+    ///
+    /// # Fields
+    /// - `contig`: Name of the contig whose mean depth is used as the target.
+    /// - `seed`: Random seed for reproducible downsampling.
+    ContigMeanCoverage { contig: &'a str, seed: u64 },
+    /// Calibrate every target region to a number of read pairs.
+    ///
+    /// # Fields
+    /// - `target_pairs`: The number of read pairs (read groups) to keep from
+    ///   each target region. Regions with fewer keep all of them.
+    /// - `seed`: Random seed for reproducible downsampling.
+    FixedReadCount { target_pairs: u64, seed: u64 },
     /// Calibrate by matching the coverage profile of sample regions.
     ///
     /// # Fields
@@ -45,25 +109,456 @@ pub enum CalibrationMode<'a> {
     /// - `flank`: Number of bases to flank the regions.
     /// - `window_size`: Size of windows for profile matching.
     /// - `min_mapq`: Minimum mapping quality for reads.
+    /// - `profile_smoothing`: Width of the moving average applied to the
+    ///   sample window counts (1 disables smoothing).
+    /// - `keep_mates`: Write both mates of a selected pair even when one of
+    ///   them starts outside the target region it was selected in.
+    /// - `profile_target`: The per-window profile to select reads to.
+    /// - `mate_mapq_policy`: Which mates of a sample read must pass
+    ///   `min_mapq` for it to count.
+    /// - `profile_json`: Write the [`RegionProfile`] of each target region to
+    ///   this file.
+    /// - `profile_csv`: Read the sample window read starts from this file,
+    ///   see [`load_profile_csv`], instead of counting them.
     /// - `seed`: Random seed for reproducible downsampling.
     SampleProfile {
         sample_regions: &'a [Region],
         window_size: u64,
         min_mapq: u8,
+        profile_smoothing: u64,
+        keep_mates: bool,
+        profile_target: ProfileTarget,
+        mate_mapq_policy: MateMapqPolicy,
+        profile_json: Option<&'a Path>,
+        profile_csv: Option<&'a Path>,
         seed: u64,
     },
 }
 
+impl CalibrationMode<'_> {
+    /// The random seed of the mode.
+    pub fn seed(&self) -> u64 {
+        match self {
+            CalibrationMode::FixedCoverage { seed, .. }
+            | CalibrationMode::PerRegionCoverage { seed, .. }
+            | CalibrationMode::SampleMeanCoverage { seed, .. }
+            | CalibrationMode::SamplePercentileCoverage { seed, .. }
+            | CalibrationMode::ContigMeanCoverage { seed, .. }
+            | CalibrationMode::FixedReadCount { seed, .. }
+            | CalibrationMode::SampleProfile { seed, .. } => *seed,
+        }
+    }
+}
+
+/// The fold coverage target regions are calibrated to when there are no
+/// sample regions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FoldCoverage {
+    /// The same fold coverage for every region.
+    All(f64),
+    /// Each region's [`Region::target`], or this default for regions without
+    /// one.
+    PerRegion(f64),
+}
+
+impl FoldCoverage {
+    /// The fold coverage to calibrate `region` to.
+    fn for_region(self, region: &Region) -> f64 {
+        match self {
+            FoldCoverage::All(coverage) => coverage,
+            FoldCoverage::PerRegion(default) => region.target.map_or(default, |target| target.0),
+        }
+    }
+}
+
+/// The per-window coverage profile that profile matching selects reads to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProfileTarget {
+    /// The mirrored read-start profile of the sample region.
+    #[default]
+    Sample,
+    /// A flat profile: every window gets the sample region's mean number of
+    /// read starts per window.
+    Uniform,
+}
+
+/// Which mates of a pair must pass the minimum mapping quality for a sample
+/// read to count towards the profile matching read starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MateMapqPolicy {
+    /// Only the read itself must pass.
+    #[default]
+    Either,
+    /// The read and, if it is paired with a mapped mate, its mate must pass.
+    /// The mate's mapping quality is taken from the read's `MQ` tag.
+    Both,
+}
+
+/// Calibration parameters, with the same defaults as the command line.
+///
+/// A `Config` is the single place to set calibration options; the
+/// [`CalibrationMode`] to run is derived from it with [`Config::mode`].
+///
+/// # Example
+/// ```ignore
+/// let config = Config {
+///     fold_coverage: 30,
+///     ..Config::default()
+/// };
+/// let mode = config.mode(None)?;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Bases trimmed from each end of the target and sample regions.
+    pub flank: u64,
+    /// Random seed for reproducible downsampling. See [`resolve_seed`] for
+    /// choosing a random one.
+    pub seed: u64,
+    /// Combined with `seed` to draw an independent, but still reproducible,
+    /// replicate from the same seed. 0 leaves the seed unchanged. See
+    /// [`salted_seed`].
+    pub seed_salt: u64,
+    /// Target fold coverage when no sample regions or contig are given.
+    pub fold_coverage: u64,
+    /// Calibrate each target region to its own [`Region::target`] instead,
+    /// using `fold_coverage` for regions without one. Cannot be combined with
+    /// sample regions or a target contig.
+    pub per_region_coverage: bool,
+    /// Window size for profile matching.
+    pub window_size: u64,
+    /// Minimum mapping quality of sample reads in profile matching.
+    pub min_mapq: u8,
+    /// Width of the moving average applied to sample window counts.
+    pub profile_smoothing: u64,
+    /// Write both mates of a pair selected in profile matching.
+    pub keep_mates: bool,
+    /// The profile that profile matching selects reads to.
+    pub profile_target: ProfileTarget,
+    /// Which mates must pass `min_mapq` in profile matching.
+    pub mate_mapq_policy: MateMapqPolicy,
+    /// Write the per-window profile matching of each target region to this
+    /// file as JSON. See [`write_profile_json`].
+    pub profile_json: Option<PathBuf>,
+    /// Read the sample window read starts of profile matching from this CSV
+    /// file instead of counting them. See [`load_profile_csv`].
+    pub profile_csv: Option<PathBuf>,
+    /// Exclude mapped reads outside the target contigs from the output.
+    pub exclude_uncalibrated_reads: bool,
+    /// Exclude unmapped reads from the output, unless they are the mate of a
+    /// calibrated read.
+    pub exclude_unmapped_reads: bool,
+    /// Only copy the uncalibrated reads of these contigs, dropping those of
+    /// every other contig that holds no target region. `None` copies every
+    /// contig. Unplaced unmapped reads are filtered by
+    /// `exclude_unmapped_reads` alone.
+    pub passthrough_contigs: Option<Vec<String>>,
+    /// Write only the calibrated reads on the target contigs. Overrides
+    /// `exclude_uncalibrated_reads` and `exclude_unmapped_reads`, and also
+    /// drops reads that are only kept because they share a name with a
+    /// calibrated read, such as mates on other contigs or unmapped.
+    pub decoy_only: bool,
+    /// Tag each calibrated read with its region's downsampling probability in
+    /// the [`PROBABILITY_TAG`] aux tag. Not supported by profile matching.
+    pub annotate_probability: bool,
+    /// Tag every written read with [`ORIGIN_TAG`], marking it as calibrated or
+    /// copied. In profile matching the reads it selects are the calibrated
+    /// ones.
+    pub tag_origin: bool,
+    /// Tag every read kept by calibration, including the reads profile
+    /// matching selects, with [`CALIBRATED_TAG`].
+    pub tag_calibrated: bool,
+    /// Copy the reads of target regions with less coverage than their target,
+    /// instead of failing, until the target is about reached. See
+    /// [`write_upsampled_copies`]. Not supported by profile matching.
+    pub allow_upsample: bool,
+    /// Whether singleton reads in target regions, paired reads whose mate is
+    /// unmapped, can be selected. `None` keeps the behaviour of earlier
+    /// versions: a singleton is only selected if its unmapped mate is placed
+    /// on a target contig. See [`selectable`]. Not supported by profile
+    /// matching.
+    pub keep_singletons: Option<bool>,
+    /// Use the experimental sample profile matching.
+    pub experimental: bool,
+    /// Calibrate to the mean depth of this contig.
+    pub target_contig: Option<String>,
+    /// Calibrate to this percentile of each sample region's coverage instead
+    /// of its mean.
+    pub target_percentile: Option<f64>,
+    /// Calibrate every target region to this many read pairs instead of a
+    /// fold coverage. Cannot be combined with sample regions, a target contig
+    /// or per-region coverage.
+    pub target_reads: Option<u64>,
+    /// Report progress through the target regions, with an ETA, to standard
+    /// error.
+    pub progress: bool,
+    /// Only calibrate the target regions whose name starts with this prefix.
+    /// Reads of the other target regions are copied uncalibrated.
+    pub name_prefix: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            flank: 500,
+            seed: 5678,
+            seed_salt: 0,
+            fold_coverage: 40,
+            per_region_coverage: false,
+            window_size: 100,
+            min_mapq: 10,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            profile_json: None,
+            profile_csv: None,
+            exclude_uncalibrated_reads: false,
+            exclude_unmapped_reads: false,
+            passthrough_contigs: None,
+            decoy_only: false,
+            annotate_probability: false,
+            tag_origin: false,
+            tag_calibrated: false,
+            allow_upsample: false,
+            keep_singletons: None,
+            experimental: false,
+            target_contig: None,
+            target_percentile: None,
+            target_reads: None,
+            progress: false,
+            name_prefix: None,
+        }
+    }
+}
+
+impl Config {
+    /// Splits `target_regions` into the regions to calibrate and those passed
+    /// through uncalibrated, according to [`Config::name_prefix`].
+    pub fn split_targets(&self, target_regions: &[Region]) -> (Vec<Region>, Vec<Region>) {
+        match &self.name_prefix {
+            Some(prefix) => region::partition_by_name_prefix(target_regions, prefix),
+            None => (target_regions.to_vec(), Vec::new()),
+        }
+    }
+
+    /// Returns the calibration mode for this configuration.
+    ///
+    /// Sample regions take precedence over a target contig, which takes
+    /// precedence over a target read count, then a fixed fold coverage, which
+    /// is per region if [`Config::per_region_coverage`] is set.
+    ///
+    /// # Errors
+    /// Returns an error if experimental profile matching or a target
+    /// percentile is requested without sample regions, if the target
+    /// percentile is outside `[0, 100]`, or if per-region coverage or a target
+    /// read count is combined with sample regions or a target contig.
+    pub fn mode<'a>(&'a self, sample_regions: Option<&'a [Region]>) -> Result<CalibrationMode<'a>> {
+        let seed = salted_seed(self.seed, self.seed_salt);
+        if let Some(percentile) = self.target_percentile {
+            if !(0.0..=100.0).contains(&percentile) {
+                return Err(Error::Calibration {
+                    msg: format!("Target percentile must be in [0, 100], got {percentile}"),
+                });
+            }
+            if sample_regions.is_none() {
+                return Err(Error::Calibration {
+                    msg: "A target percentile requires sample regions. Please provide a sample BED file."
+                        .to_string(),
+                });
+            }
+        }
+        if self.per_region_coverage
+            && (sample_regions.is_some() || self.target_contig.is_some() || self.experimental)
+        {
+            return Err(Error::Calibration {
+                msg: "Per-region target coverage cannot be combined with sample regions or a target contig."
+                    .to_string(),
+            });
+        }
+        if self.target_reads.is_some()
+            && (sample_regions.is_some()
+                || self.target_contig.is_some()
+                || self.experimental
+                || self.per_region_coverage)
+        {
+            return Err(Error::Calibration {
+                msg: "A target read count cannot be combined with sample regions, a target contig or per-region coverage."
+                    .to_string(),
+            });
+        }
+        let mode = match (sample_regions, &self.target_contig) {
+            (Some(sample_regions), _) if self.experimental => CalibrationMode::SampleProfile {
+                sample_regions,
+                window_size: self.window_size,
+                min_mapq: self.min_mapq,
+                profile_smoothing: self.profile_smoothing,
+                keep_mates: self.keep_mates,
+                profile_target: self.profile_target,
+                mate_mapq_policy: self.mate_mapq_policy,
+                profile_json: self.profile_json.as_deref(),
+                profile_csv: self.profile_csv.as_deref(),
+                seed,
+            },
+            _ if self.experimental => return Err(Error::Calibration {
+                msg:
+                    "SampleProfile mode requires sample regions. Please provide a sample BED file."
+                        .to_string(),
+            }),
+            (Some(sample_regions), _) if self.target_percentile.is_some() => {
+                CalibrationMode::SamplePercentileCoverage {
+                    sample_regions,
+                    percentile: self.target_percentile.unwrap_or_default(),
+                    seed,
+                }
+            }
+            (Some(sample_regions), _) => CalibrationMode::SampleMeanCoverage {
+                sample_regions,
+                seed,
+            },
+            (None, Some(contig)) => CalibrationMode::ContigMeanCoverage { contig, seed },
+            (None, None) if self.target_reads.is_some() => CalibrationMode::FixedReadCount {
+                target_pairs: self.target_reads.unwrap_or_default(),
+                seed,
+            },
+            (None, None) if self.per_region_coverage => CalibrationMode::PerRegionCoverage {
+                default_coverage: self.fold_coverage,
+                seed,
+            },
+            (None, None) => CalibrationMode::FixedCoverage {
+                fold_coverage: self.fold_coverage,
+                seed,
+            },
+        };
+        Ok(mode)
+    }
+}
+
+/// Returns the seed to downsample replicate `salt` of a run seeded with
+/// `seed` with.
+///
+/// The salt is mixed with SplitMix64 before it is combined with the seed, so
+/// consecutive salts give unrelated seeds. A salt of 0 returns `seed`
+/// unchanged.
+pub fn salted_seed(seed: u64, salt: u64) -> u64 {
+    if salt == 0 {
+        return seed;
+    }
+    let mut z = salt.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    seed ^ z ^ (z >> 31)
+}
+
+/// Returns the seed to downsample with for a requested `seed`.
+///
+/// Non-zero seeds are returned unchanged. Zero means "random": a non-zero seed
+/// is drawn from the operating system's random number generator. Callers
+/// should report the returned seed so that the run can be reproduced.
+///
+/// # Errors
+/// Returns an error if the operating system cannot provide random data.
+pub fn resolve_seed(seed: u64) -> Result<u64> {
+    let mut seed = seed;
+    while seed == 0 {
+        seed = SysRng.try_next_u64().map_err(|e| Error::Calibration {
+            msg: format!("Failed to choose a random seed: {e}"),
+        })?;
+    }
+    Ok(seed)
+}
+
+/// Calibrates a BAM file using the mode and options of a [`Config`].
+///
+/// This is the preferred entry point: the calibration mode and the output
+/// filters all come from `config`, so they cannot disagree.
+///
+/// # Arguments
+/// - `reader`: A mutable reference to a BAM reader.
+/// - `writer`: A mutable reference to a BAM writer.
+/// - `target_regions`: A slice of regions to calibrate.
+/// - `sample_regions`: Optional sample regions, already trimmed.
+/// - `config`: Calibration options.
+/// - `cache`: Optional cache of per-region mean coverage.
+pub fn calibrate_with_config<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    target_regions: &[Region],
+    sample_regions: Option<&[Region]>,
+    config: &Config,
+    cache: Option<&CoverageCache>,
+) -> Result<()>
+where
+    R: BamReader,
+    W: BamWriter,
+{
+    calibrate_with_timings(
+        reader,
+        writer,
+        target_regions,
+        sample_regions,
+        config,
+        cache,
+        None,
+    )
+}
+
+/// [`calibrate_with_config`], recording the time taken by each stage of the
+/// calibration in `timings`: working out the downsampling probabilities
+/// (`probabilities`), selecting the reads to keep (`selection`, which also
+/// covers the probabilities when each region is fetched once) or profile
+/// matching (`profile matching`), and the final pass writing the output
+/// (`write`).
+pub fn calibrate_with_timings<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    target_regions: &[Region],
+    sample_regions: Option<&[Region]>,
+    config: &Config,
+    cache: Option<&CoverageCache>,
+    timings: Option<&mut Timings>,
+) -> Result<()>
+where
+    R: BamReader,
+    W: BamWriter,
+{
+    // Sample regions are matched to targets by name, so they share the prefix.
+    let sample_regions = sample_regions.map(|regions| config.split_targets(regions).0);
+    let mode = config.mode(sample_regions.as_deref())?;
+    let (target_regions, passthrough_regions) = config.split_targets(target_regions);
+    let mut progress = config
+        .progress
+        .then(|| Progress::for_regions(&target_regions));
+    let plan = Plan {
+        target_regions: &target_regions,
+        passthrough_regions: &passthrough_regions,
+        mode,
+    };
+    calibrate_with_progress(
+        reader,
+        writer,
+        plan,
+        config,
+        cache,
+        progress.as_mut(),
+        timings,
+    )
+}
+
 /// Calibrates a BAM file by downsampling reads in target regions according to the specified mode.
 ///
 /// This function processes the input BAM file, applies calibration to the specified target regions,
 /// and writes the result to the output BAM file. It also copies uncalibrated contigs and unmapped reads.
+/// The output keeps the order of the input.
 ///
 /// # Arguments
 /// - `reader`: A mutable reference to a BAM reader.
 /// - `writer`: A mutable reference to a BAM writer.
 /// - `target_regions`: A slice of regions to calibrate.
 /// - `mode`: The calibration mode to use.
+/// - `exclude_uncalibrated_reads`: Drop reads outside the target contigs,
+///   including unmapped reads. Use [`calibrate_with_config`] to drop them
+///   separately.
+/// - `cache`: Optional cache of per-region mean coverage.
 ///
 /// # Returns
 /// A `Result` indicating success or failure.
@@ -76,385 +571,1347 @@ pub fn calibrate<R, W>(
     target_regions: &[Region],
     mode: CalibrationMode,
     exclude_uncalibrated_reads: bool,
+    cache: Option<&CoverageCache>,
 ) -> Result<()>
 where
     R: BamReader,
     W: BamWriter,
 {
-    let sequin_chromosomes = target_regions
-        .iter()
-        .map(|r| r.contig.as_bytes())
-        .collect::<HashSet<_>>();
+    let plan = Plan {
+        target_regions,
+        passthrough_regions: &[],
+        mode,
+    };
+    let config = Config {
+        exclude_uncalibrated_reads,
+        exclude_unmapped_reads: exclude_uncalibrated_reads,
+        ..Config::default()
+    };
+    calibrate_with_progress(reader, writer, plan, &config, cache, None, None)
+}
 
-    let sequin_tids = reader
-        .header()
-        .target_names()
+/// The regions and mode of a calibration by [`calibrate_with_progress`].
+struct Plan<'a> {
+    /// Regions to calibrate.
+    target_regions: &'a [Region],
+    /// Regions on the sequin contigs that are not calibrated. Their reads are
+    /// copied like reads outside the sequin contigs.
+    passthrough_regions: &'a [Region],
+    mode: CalibrationMode<'a>,
+}
+
+/// Calibrates the regions of `plan` in its mode, filtering and tagging the
+/// reads written as the output options of `config` say, and advancing
+/// `progress` by the length of each target region as it is calibrated.
+///
+/// With `decoy_only`, nothing but the mapped, kept reads on the target contigs
+/// is written. With `passthrough_contigs`, only the uncalibrated reads on those
+/// contigs, the target contigs and unplaced unmapped reads are copied. The
+/// mode, flank and region options of `config` are not used: `plan` already
+/// holds their result.
+fn calibrate_with_progress<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    plan: Plan,
+    config: &Config,
+    cache: Option<&CoverageCache>,
+    progress: Option<&mut Progress>,
+    mut timings: Option<&mut Timings>,
+) -> Result<()>
+where
+    R: BamReader,
+    W: BamWriter,
+{
+    let Plan {
+        target_regions,
+        passthrough_regions,
+        mode,
+    } = plan;
+    let decoy_only = config.decoy_only;
+    let annotate_probability = config.annotate_probability;
+    let allow_upsample = config.allow_upsample;
+    // Contigs holding any target region, calibrated or not.
+    let decoy_tids = sequin_tids(
+        reader.header(),
+        &[target_regions, passthrough_regions].concat(),
+    );
+    let sequin_tids = sequin_tids(reader.header(), target_regions);
+    let passthrough = passthrough_regions
         .iter()
-        .enumerate()
-        .filter_map(|(tid, name)| {
-            if sequin_chromosomes.contains(name) {
-                Some(tid as i32)
-            } else {
-                None
-            }
+        .filter_map(|r| {
+            let tid = reader.header().tid(r.contig.as_bytes())? as i32;
+            Some((tid, r.beg as i64, r.end as i64))
         })
-        .collect::<HashSet<_>>();
+        .collect::<Vec<_>>();
+    let copied_tids = config
+        .passthrough_contigs
+        .as_deref()
+        .map(|contigs| passthrough_tids(reader.header(), contigs))
+        .transpose()?;
+    // Whether a read starting at `pos` on `tid` is copied as uncalibrated:
+    // it is off the sequin contigs or in a region that is not calibrated.
+    let uncalibrated = |tid: i32, pos: i64| {
+        !decoy_tids.contains(&tid)
+            || passthrough
+                .iter()
+                .any(|&(t, beg, end)| t == tid && pos >= beg && pos < end)
+    };
 
     let mut keep = HashSet::new();
+    // The records chosen by profile matching, which selects individual
+    // records rather than whole read groups.
+    let mut selected = SelectedRecords::default();
+    // The downsampling probability of each kept read, when annotating or
+    // upsampling.
+    let mut probabilities = (annotate_probability || allow_upsample).then(HashMap::new);
+    let seed = mode.seed();
+
+    let selection = Selection {
+        keep: &mut keep,
+        sequin_tids: &sequin_tids,
+        seed,
+        keep_singletons: config.keep_singletons,
+        read_probabilities: probabilities.as_mut(),
+        progress,
+    };
+    let by_coverage = |reader: &mut R, goal, selection, timings| {
+        calibrate_by_fixed_coverage(
+            reader,
+            target_regions,
+            goal,
+            allow_upsample,
+            cache,
+            selection,
+            timings,
+        )
+    };
 
     match mode {
-        CalibrationMode::FixedCoverage {
-            fold_coverage,
-            seed,
+        CalibrationMode::FixedCoverage { fold_coverage, .. } => {
+            let goal = CoverageGoal::Fold(FoldCoverage::All(fold_coverage as f64));
+            by_coverage(reader, goal, selection, timings.as_deref_mut())?;
+        }
+        CalibrationMode::PerRegionCoverage {
+            default_coverage, ..
         } => {
-            calibrate_by_fixed_coverage(
-                reader,
-                target_regions,
-                None,
-                &mut keep,
-                &sequin_tids,
-                fold_coverage,
-                seed,
-            )?;
+            let goal = CoverageGoal::Fold(FoldCoverage::PerRegion(default_coverage as f64));
+            by_coverage(reader, goal, selection, timings.as_deref_mut())?;
         }
-        CalibrationMode::SampleMeanCoverage {
+        CalibrationMode::SampleMeanCoverage { sample_regions, .. } => {
+            let goal = CoverageGoal::Sample {
+                regions: sample_regions,
+                percentile: None,
+            };
+            by_coverage(reader, goal, selection, timings.as_deref_mut())?;
+        }
+        CalibrationMode::SamplePercentileCoverage {
             sample_regions,
-            seed,
+            percentile,
+            ..
         } => {
-            calibrate_by_fixed_coverage(
-                reader,
-                target_regions,
-                Some(sample_regions),
-                &mut keep,
-                &sequin_tids,
-                0,
-                seed,
-            )?;
+            let goal = CoverageGoal::Sample {
+                regions: sample_regions,
+                percentile: Some(percentile),
+            };
+            by_coverage(reader, goal, selection, timings.as_deref_mut())?;
+        }
+        CalibrationMode::ContigMeanCoverage { contig, .. } => {
+            let depth = timing::time(timings.as_deref_mut(), "probabilities", || {
+                coverage::contig_mean_depth(reader, contig, 0)
+            })?;
+            eprintln!("Calibrating to the mean depth of {contig} ({depth:.2}).");
+            let goal = CoverageGoal::Fold(FoldCoverage::All(depth));
+            by_coverage(reader, goal, selection, timings.as_deref_mut())?;
+        }
+        CalibrationMode::FixedReadCount { target_pairs, .. } => {
+            let region_probabilities =
+                timing::time(timings.as_deref_mut(), "probabilities", || {
+                    read_count_probabilities(
+                        reader,
+                        target_regions,
+                        target_pairs,
+                        &sequin_tids,
+                        config.keep_singletons,
+                    )
+                })?;
+            timing::time(timings.as_deref_mut(), "selection", || {
+                select_reads(reader, target_regions, &region_probabilities, selection)
+            })?;
         }
         CalibrationMode::SampleProfile {
             sample_regions,
             window_size,
             min_mapq,
+            profile_smoothing,
+            keep_mates,
+            profile_target,
+            mate_mapq_policy,
+            profile_json,
+            profile_csv,
             seed,
         } => {
+            let sample_starts = match profile_csv {
+                Some(path) => {
+                    let file = std::fs::File::open(path)?;
+                    Some(load_profile_csv(std::io::BufReader::new(file))?)
+                }
+                None => None,
+            };
             let args = SampleProfileParams {
                 window_size,
                 min_mapq,
+                profile_smoothing,
+                keep_mates,
+                profile_target,
+                mate_mapq_policy,
+                sample_starts: sample_starts.as_ref(),
                 seed,
             };
-            calibrate_by_sample_profile(reader, writer, target_regions, sample_regions, &args)?;
+            let profiles = timing::time(timings.as_deref_mut(), "profile matching", || {
+                calibrate_by_sample_profile(
+                    reader,
+                    &mut selected,
+                    target_regions,
+                    sample_regions,
+                    &args,
+                    selection.progress,
+                )
+            })?;
+            if let Some(path) = profile_json {
+                let file = std::fs::File::create(path)?;
+                write_profile_json(&profiles, std::io::BufWriter::new(file))?;
+            }
         }
     }
+    // Every mode writes its reads in this single pass over the input, so the
+    // output is in the input's order: coordinate-sorted if the input is.
+    let write_start = Instant::now();
     reader.fetch(FetchDefinition::All)?;
     for result in reader.records() {
-        let record = result?;
-        let record_is_on_sequin_decoy = sequin_tids.contains(&record.tid());
-        let mate_is_on_sequin_decoy = sequin_tids.contains(&record.mtid());
-        if keep.contains(record.qname()) {
+        let mut record = result?;
+        if decoy_only && (record.is_unmapped() || !sequin_tids.contains(&record.tid())) {
+            continue;
+        }
+        if keep.contains(record.qname()) || selected.contains(&record) {
             // If the read is part of a read group selected to keep, write it
             // regardless of anything else.
+            let probability = probabilities
+                .as_ref()
+                .and_then(|p| p.get(record.qname()))
+                .copied();
+            if let Some(probability) = probability.filter(|_| annotate_probability) {
+                // Replace any tag left by an earlier calibration.
+                let _ = record.remove_aux(PROBABILITY_TAG);
+                record.push_aux(PROBABILITY_TAG, Aux::Float(probability as f32))?;
+            }
+            if config.tag_origin {
+                set_origin(&mut record, ORIGIN_CALIBRATED)?;
+            }
+            // Mates kept with a calibrated read, but unmapped or on another
+            // contig, were not calibrated themselves.
+            if config.tag_calibrated && !record.is_unmapped() && sequin_tids.contains(&record.tid())
+            {
+                let _ = record.remove_aux(CALIBRATED_TAG);
+                record.push_aux(CALIBRATED_TAG, Aux::I32(1))?;
+            }
             writer.write(&record)?;
-        } else if !record_is_on_sequin_decoy
-            && !mate_is_on_sequin_decoy
-            && !exclude_uncalibrated_reads
+            if let Some(probability) = probability.filter(|&p| allow_upsample && p > 1.0) {
+                write_upsampled_copies(writer, &record, probability, seed)?;
+            }
+        } else if !decoy_only
+            && uncalibrated(record.tid(), record.pos())
+            && uncalibrated(record.mtid(), record.mpos())
         {
-            // If we are keeping uncalibrated reads, and the mate is not mapped
-            // to a Sequin decoy, write it to the output.
-            writer.write(&record)?;
+            // If we are keeping uncalibrated reads, and neither the read nor
+            // its mate is in a calibrated Sequin region, write it to the
+            // output. Unmapped reads are filtered separately from mapped ones,
+            // and reads placed on contigs outside the passthrough allowlist
+            // are dropped.
+            let exclude = if record.is_unmapped() {
+                config.exclude_unmapped_reads
+            } else {
+                config.exclude_uncalibrated_reads
+            };
+            let tid = record.tid();
+            let dropped_contig = copied_tids
+                .as_ref()
+                .is_some_and(|tids| tid >= 0 && !decoy_tids.contains(&tid) && !tids.contains(&tid));
+            if !exclude && !dropped_contig {
+                if config.tag_origin {
+                    set_origin(&mut record, ORIGIN_UNCALIBRATED)?;
+                }
+                writer.write(&record)?;
+            }
         }
     }
+    if let Some(timings) = timings {
+        timings.record("write", write_start.elapsed());
+    }
 
     Ok(())
 }
 
-/// Calibrates by fixed coverage or sample mean coverage.
+/// Records chosen by profile matching, collected in place of writing them
+/// so that they are written in input order by the final pass of
+/// [`calibrate_with_progress`].
 ///
-/// This function determines downsampling probabilities based on target and
-/// sample regions, then subsamples reads in the target regions accordingly. If
-/// it decides to keep a read group it inserts the name into the `keep` set.
-/// This can then be used to filter the calibrated reads.
-///
-/// # Arguments
-/// - `reader`: A mutable reference to a BAM reader.
-/// - `target_regions`: Regions to calibrate.
-/// - `sample_regions`: Optional sample regions for mean coverage.
-/// - `keep`: A mutable set to store names of read groups to keep.
-/// - `sequin_tids`: Set of TIDs corresponding to Sequin chromosomes.
-/// - `fold_coverage`: Desired fold coverage (ignored if sample_regions is provided).
-/// - `seed`: Random seed for downsampling.
-///
-/// # Returns
-/// A `Result` indicating success or failure.
-fn calibrate_by_fixed_coverage<R>(
-    reader: &mut R,
-    target_regions: &[Region],
-    sample_regions: Option<&[Region]>,
-    keep: &mut HashSet<Vec<u8>>,
-    sequin_tids: &HashSet<i32>,
-    fold_coverage: u64,
-    seed: u64,
-) -> Result<()>
-where
-    R: BamReader,
-{
-    let probabilities = determine_downsampling_probabilities(
-        reader,
-        target_regions,
-        sample_regions,
-        fold_coverage,
-    )?;
+/// A record is identified by its name, flags and start, which tells the
+/// mates of a pair and the secondary alignments of a read apart.
+#[derive(Default)]
+struct SelectedRecords {
+    keys: HashSet<(Vec<u8>, u16, i32, i64)>,
+}
 
-    let mut rng = Pcg32::seed_from_u64(seed);
-    let mut considered = HashSet::new();
-    for region in target_regions {
-        reader.fetch((&region.contig, region.beg, region.end))?;
-        for result in reader.records() {
-            let record = result?;
-            // If the mate is mapped to a non-sequin chromosome, skip it. These
-            // are artefacts, and we shouldn't keep them.
-            if sequin_tids.contains(&record.mtid()) {
-                let probability =
-                    probabilities
-                        .get(&region.name)
-                        .cloned()
-                        .ok_or_else(|| Error::Calibration {
-                            msg: format!(
-                                "No downsampling probability found for region {} {:?}",
-                                region.name, probabilities
-                            ),
-                        })?;
-                subsample(&record, keep, &considered, probability, &mut rng);
-            }
-            considered.insert(record.qname().to_vec());
-        }
+impl SelectedRecords {
+    fn key(record: &Record) -> (Vec<u8>, u16, i32, i64) {
+        (
+            record.qname().to_vec(),
+            record.flags(),
+            record.tid(),
+            record.pos(),
+        )
     }
 
-    Ok(())
+    fn contains(&self, record: &Record) -> bool {
+        !self.keys.is_empty() && self.keys.contains(&Self::key(record))
+    }
 }
 
-/// Determines downsampling probabilities for each target region.
-///
-/// # Arguments
-/// - `reader`: A mutable reference to a BAM reader.
-/// - `target_regions`: Regions to calibrate.
-/// - `sample_regions`: Optional sample regions.
-/// - `fold_coverage`: Desired fold coverage.
+impl BamWriter for SelectedRecords {
+    fn write(&mut self, record: &Record) -> std::result::Result<(), rust_htslib::errors::Error> {
+        self.keys.insert(Self::key(record));
+        Ok(())
+    }
+
+    fn set_threads(&mut self, _n: usize) -> std::result::Result<(), rust_htslib::errors::Error> {
+        Ok(())
+    }
+
+    fn set_reference<P: AsRef<Path>>(
+        &mut self,
+        _reference: P,
+    ) -> std::result::Result<(), rust_htslib::errors::Error> {
+        Ok(())
+    }
+}
+
+/// Writes the extra copies of a kept `record` from a region upsampled with
+/// `probability`, above 1.
 ///
-/// # Returns
-/// A `Result` containing a map of region names to downsampling probabilities.
-fn determine_downsampling_probabilities<R: BamReader>(
-    reader: &mut R,
-    target_regions: &[Region],
-    sample_regions: Option<&[Region]>,
-    fold_coverage: u64,
-) -> Result<HashMap<String, f64>> {
-    let target_means = regions_coverage(reader, target_regions)?;
-    let sample_means = if let Some(sample_regions) = sample_regions {
-        regions_coverage(reader, sample_regions)?
-    } else {
-        // If no sample regions are provided, we just use the provided fold
-        // coverage for all targets.
-        target_means
-            .keys()
-            .map(|k| (k.clone(), fold_coverage as f64))
-            .collect()
-    };
-    let probabilities: HashMap<String, f64> = target_means
+/// Each read group is written `probability` times on average: the fractional
+/// part is drawn once per read name, so every read of a group gets the same
+/// number of copies. Copy `k` is named `<qname>_up<k>`, so the copies of a
+/// pair are still mates, and is flagged as a duplicate.
+fn write_upsampled_copies<W: BamWriter>(
+    writer: &mut W,
+    record: &Record,
+    probability: f64,
+    seed: u64,
+) -> Result<()> {
+    // A stable FNV-1a hash of the name, so that the draw does not depend on
+    // the order reads are seen in.
+    let name_hash = record
+        .qname()
         .iter()
-        .map(|(name, &target_mean)| {
-            let sample_mean =
-                sample_means
-                    .get(name)
-                    .cloned()
-                    .ok_or_else(|| Error::Calibration {
-                        msg: format!("No sample mean coverage found for target region {}", name),
-                    })?;
-            if target_mean == 0.0 {
-                return Err(Error::Calibration {
-                    msg: format!("Target mean coverage for region {} is zero", name),
-                });
-            }
-            if target_mean < sample_mean {
-                return Err(Error::Calibration {
-                    msg: format!(
-                        "Target mean coverage for region {} is less than sample mean coverage ({target_mean} < {sample_mean}",
-                        name
-                    ),
-                });
+        .fold(0xcbf2_9ce4_8422_2325u64, |h, &b| {
+            (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    let mut rng = Pcg32::seed_from_u64(salted_seed(seed, name_hash));
+    let mut copies = probability.trunc() as u64 - 1;
+    if rng.random::<f64>() < probability.fract() {
+        copies += 1;
+    }
+    for k in 1..=copies {
+        let mut copy = record.clone();
+        let mut qname = record.qname().to_vec();
+        qname.extend_from_slice(format!("_up{k}").as_bytes());
+        copy.set_qname(&qname);
+        copy.set_duplicate();
+        writer.write(&copy)?;
+    }
+    Ok(())
+}
+
+/// Sets the [`ORIGIN_TAG`] of `record` to `origin`, replacing any tag left by
+/// an earlier calibration.
+fn set_origin(record: &mut Record, origin: u8) -> Result<()> {
+    let _ = record.remove_aux(ORIGIN_TAG);
+    record.push_aux(ORIGIN_TAG, Aux::Char(origin))?;
+    Ok(())
+}
+
+/// Returns the TIDs of the contigs holding `target_regions`.
+fn sequin_tids(header: &HeaderView, target_regions: &[Region]) -> HashSet<i32> {
+    let sequin_chromosomes = target_regions
+        .iter()
+        .map(|r| r.contig.as_bytes())
+        .collect::<HashSet<_>>();
+    header
+        .target_names()
+        .iter()
+        .enumerate()
+        .filter_map(|(tid, name)| {
+            if sequin_chromosomes.contains(name) {
+                Some(tid as i32)
+            } else {
+                None
             }
-            let prob = sample_mean / target_mean;
-            Ok((name.clone(), prob))
         })
-        .collect::<Result<_>>()?;
-    Ok(probabilities)
+        .collect()
 }
 
-/// Calculates the mean coverage of each region.
-///
-/// # Arguments
-/// - `reader`: A mutable reference to a BAM reader.
-/// - `regions`: Regions to calculate coverage for.
+/// Returns the TIDs of the `contigs` allowed by
+/// [`Config::passthrough_contigs`].
 ///
-/// # Returns
-/// A `Result` containing a map of region names to mean coverage values.
-fn regions_coverage<R: BamReader>(
-    reader: &mut R,
-    regions: &[Region],
-) -> Result<HashMap<String, f64>> {
-    let min_mapq = 0;
-    let flank = 0;
-    let coverage = regions
+/// # Errors
+/// Returns an error naming the first contig not in the header.
+fn passthrough_tids(header: &HeaderView, contigs: &[String]) -> Result<HashSet<i32>> {
+    contigs
         .iter()
-        .map(|region| {
-            let region_coverage = coverage::coverage_for_region(reader, region, min_mapq, flank)?;
-            let mean = region_coverage.mean().unwrap_or(0.0);
-            Ok((region.name.clone(), mean as f64))
+        .map(|contig| {
+            header
+                .tid(contig.as_bytes())
+                .map(|tid| tid as i32)
+                .ok_or_else(|| Error::Calibration {
+                    msg: format!("Passthrough contig {contig} not found in BAM header"),
+                })
         })
-        .collect::<Result<_>>()?;
-    Ok(coverage)
+        .collect()
 }
 
-/// Decides whether to keep a read based on downsampling probability.
-///
-/// This function uses a hash to ensure consistent decisions for paired reads.
+/// The predicted outcome of calibrating one target region, from
+/// [`report_with_config`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionReport {
+    /// The target region, with its flank already removed.
+    pub region: Region,
+    /// Mean coverage of the region before calibration.
+    pub mean_coverage: f64,
+    /// Probability of keeping each read group in the region.
+    pub probability: f64,
+    /// Reads in the region considered for downsampling.
+    pub reads: u64,
+    /// Reads in the region that calibration keeps. Kept reads' mates outside
+    /// the region are written too but not counted here.
+    pub kept_reads: u64,
+}
+
+impl RegionReport {
+    /// The expected mean coverage of the region after calibration.
+    pub fn expected_coverage(&self) -> f64 {
+        self.mean_coverage * self.probability
+    }
+}
+
+/// Runs the analysis of [`calibrate_with_config`] without writing any reads.
 ///
-/// # Arguments
-/// - `record`: The BAM record to consider.
-/// - `hash`: A mutable hash map for tracking read names.
-/// - `threshold`: The downsampling probability threshold.
-/// - `rng`: A mutable random number generator.
+/// The downsampling probability of each target region is determined and reads
+/// are selected exactly as a real run would, so the kept read counts match the
+/// output of [`calibrate_with_config`] with the same `config`.
 ///
-/// # Returns
-/// `true` if the read should be kept, `false` otherwise.
-fn subsample(
-    record: &Record,
-    hash: &mut HashSet<Vec<u8>>,
-    considered: &HashSet<Vec<u8>>,
-    threshold: f64,
-    rng: &mut Pcg32,
-) -> bool {
-    let qname = record.qname().to_vec();
-    match hash.contains(&qname) {
-        true => {
-            return true;
+/// # Errors
+/// Returns an error for the same reasons as [`calibrate_with_config`], or if
+/// `config` selects experimental profile matching, which has no per-region
+/// probability.
+pub fn report_with_config<R: BamReader>(
+    reader: &mut R,
+    target_regions: &[Region],
+    sample_regions: Option<&[Region]>,
+    config: &Config,
+    cache: Option<&CoverageCache>,
+) -> Result<Vec<RegionReport>> {
+    let (target_regions, _) = config.split_targets(target_regions);
+    let target_regions = target_regions.as_slice();
+    let sample_regions = sample_regions.map(|regions| config.split_targets(regions).0);
+    let sequin_tids = sequin_tids(reader.header(), target_regions);
+    let by_coverage = |reader: &mut R, sample_regions, sample_percentile, fold_coverage| {
+        target_means_and_probabilities(
+            reader,
+            target_regions,
+            sample_regions,
+            sample_percentile,
+            fold_coverage,
+            config.allow_upsample,
+            cache,
+        )
+    };
+    let ((means, probabilities), seed) = match config.mode(sample_regions.as_deref())? {
+        CalibrationMode::FixedCoverage {
+            fold_coverage,
+            seed,
+        } => (
+            by_coverage(reader, None, None, FoldCoverage::All(fold_coverage as f64))?,
+            seed,
+        ),
+        CalibrationMode::PerRegionCoverage {
+            default_coverage,
+            seed,
+        } => (
+            by_coverage(
+                reader,
+                None,
+                None,
+                FoldCoverage::PerRegion(default_coverage as f64),
+            )?,
+            seed,
+        ),
+        CalibrationMode::SampleMeanCoverage {
+            sample_regions,
+            seed,
+        } => (
+            by_coverage(reader, Some(sample_regions), None, FoldCoverage::All(0.0))?,
+            seed,
+        ),
+        CalibrationMode::SamplePercentileCoverage {
+            sample_regions,
+            percentile,
+            seed,
+        } => (
+            by_coverage(
+                reader,
+                Some(sample_regions),
+                Some(percentile),
+                FoldCoverage::All(0.0),
+            )?,
+            seed,
+        ),
+        CalibrationMode::ContigMeanCoverage { contig, seed } => {
+            let depth = coverage::contig_mean_depth(reader, contig, 0)?;
+            (
+                by_coverage(reader, None, None, FoldCoverage::All(depth))?,
+                seed,
+            )
         }
-        false => {
-            if considered.contains(&qname) {
-                return false;
-            }
+        CalibrationMode::FixedReadCount { target_pairs, seed } => {
+            let probabilities = read_count_probabilities(
+                reader,
+                target_regions,
+                target_pairs,
+                &sequin_tids,
+                config.keep_singletons,
+            )?;
+            let means = regions_coverage(reader, target_regions, cache)?;
+            ((means, probabilities), seed)
+        }
+        CalibrationMode::SampleProfile { .. } => {
+            return Err(Error::Calibration {
+                msg: "A report is not available for sample profile matching".to_string(),
+            })
         }
     };
-    let rand = rng.random::<f64>();
-    if rand <= threshold {
-        hash.insert(record.qname().to_vec());
-        return true;
+    let mut keep = HashSet::new();
+    let selection = Selection {
+        keep: &mut keep,
+        sequin_tids: &sequin_tids,
+        seed,
+        keep_singletons: config.keep_singletons,
+        read_probabilities: None,
+        progress: None,
+    };
+    select_reads(reader, target_regions, &probabilities, selection)?;
+
+    let mut reports = Vec::with_capacity(target_regions.len());
+    for region in target_regions {
+        let (mut reads, mut kept_reads) = (0, 0);
+        reader.fetch((&region.contig, region.beg, region.end))?;
+        for result in reader.records() {
+            let record = result?;
+            if selectable(&record, &sequin_tids, config.keep_singletons) {
+                reads += 1;
+                if keep.contains(record.qname()) {
+                    kept_reads += 1;
+                }
+            }
+        }
+        reports.push(RegionReport {
+            region: region.clone(),
+            mean_coverage: means.get(&region.name).copied().unwrap_or_default(),
+            probability: probabilities.get(&region.name).copied().unwrap_or_default(),
+            reads,
+            kept_reads,
+        });
     }
-    false
+    Ok(reports)
 }
 
-/// Parameters for sample profile calibration.
-struct SampleProfileParams {
-    /// Size of windows for profile matching.
-    window_size: u64,
-    /// Minimum mapping quality.
-    min_mapq: u8,
-    /// Random seed.
+/// Writes region reports as CSV, one row per target region.
+pub fn write_report<W: Write>(reports: &[RegionReport], mut dest: W) -> Result<()> {
+    writeln!(
+        dest,
+        "name,chrom,beg,end,mean,probability,expected_mean,reads,kept_reads"
+    )?;
+    for report in reports {
+        writeln!(
+            dest,
+            "{},{},{},{},{:.2},{:.4},{:.2},{},{}",
+            report.region.name,
+            report.region.contig,
+            report.region.beg,
+            report.region.end,
+            report.mean_coverage,
+            report.probability,
+            report.expected_coverage(),
+            report.reads,
+            report.kept_reads
+        )?;
+    }
+    Ok(())
+}
+
+/// What [`calibrate_by_fixed_coverage`] calibrates each target region to.
+#[derive(Debug, Clone, Copy)]
+enum CoverageGoal<'a> {
+    /// A fold coverage.
+    Fold(FoldCoverage),
+    /// The coverage of the sample region of the same name: its mean, or this
+    /// percentile of it.
+    Sample {
+        regions: &'a [Region],
+        percentile: Option<f64>,
+    },
+}
+
+/// Where the downsampling modes record the reads they select, and how they
+/// select them.
+struct Selection<'a> {
+    /// Names of the read groups to keep.
+    keep: &'a mut HashSet<Vec<u8>>,
+    /// Contigs holding the calibrated target regions.
+    sequin_tids: &'a HashSet<i32>,
+    /// Random seed for downsampling.
     seed: u64,
+    /// Whether singletons can be selected, see [`selectable`].
+    keep_singletons: Option<bool>,
+    /// Optional map in which to record the downsampling probability of each
+    /// read group added to `keep`.
+    read_probabilities: Option<&'a mut HashMap<Vec<u8>, f64>>,
+    /// Optional progress to advance as each region is calibrated.
+    progress: Option<&'a mut Progress>,
 }
 
-/// Calibrates by matching the coverage profile of sample regions.
+/// Calibrates by fixed coverage or sample mean coverage.
 ///
-/// This function attempts to replicate the read start profile of sample regions
-/// in the target regions using windowed downsampling.
+/// This function determines downsampling probabilities based on target and
+/// sample regions, then subsamples reads in the target regions accordingly. If
+/// it decides to keep a read group it inserts the name into `selection.keep`.
+/// This can then be used to filter the calibrated reads.
 ///
 /// # Arguments
 /// - `reader`: A mutable reference to a BAM reader.
-/// - `writer`: A mutable reference to a BAM writer.
 /// - `target_regions`: Regions to calibrate.
-/// - `sample_regions`: Sample regions to match.
-/// - `args`: Parameters for calibration.
+/// - `goal`: The coverage to calibrate each region to.
+/// - `allow_upsample`: Upsample regions below their target instead of failing.
+/// - `cache`: Optional cache of per-region mean coverage.
+/// - `selection`: Where to record the selected reads, and how to select them.
+/// - `timings`: Optional timings to record each stage in.
 ///
 /// # Returns
 /// A `Result` indicating success or failure.
-fn calibrate_by_sample_profile<R, W>(
+fn calibrate_by_fixed_coverage<R>(
     reader: &mut R,
-    writer: &mut W,
     target_regions: &[Region],
-    sample_regions: &[Region],
-    args: &SampleProfileParams,
+    goal: CoverageGoal,
+    allow_upsample: bool,
+    cache: Option<&CoverageCache>,
+    selection: Selection,
+    mut timings: Option<&mut Timings>,
 ) -> Result<()>
 where
     R: BamReader,
-    W: BamWriter,
 {
-    let cal_contigs = target_regions
-        .iter()
-        .map(|r| r.contig.as_str())
-        .collect::<Vec<_>>();
-    let hdr = reader.header().to_owned();
+    let (sample_regions, sample_percentile, fold_coverage) = match goal {
+        CoverageGoal::Fold(fold_coverage) => (None, None, fold_coverage),
+        CoverageGoal::Sample {
+            regions,
+            percentile,
+        } => (Some(regions), percentile, FoldCoverage::All(0.0)),
+    };
+    // Without sample regions or cached means, the target coverage can be
+    // measured in the same fetch as the selection.
+    if sample_regions.is_none() && cache.is_none() {
+        return timing::time(timings, "selection", || {
+            select_reads_single_pass(
+                reader,
+                target_regions,
+                fold_coverage,
+                allow_upsample,
+                selection,
+            )
+        });
+    }
+    let probabilities = timing::time(timings.as_deref_mut(), "probabilities", || {
+        determine_downsampling_probabilities(
+            reader,
+            target_regions,
+            sample_regions,
+            sample_percentile,
+            fold_coverage,
+            allow_upsample,
+            cache,
+        )
+    })?;
+    timing::time(timings, "selection", || {
+        select_reads(reader, target_regions, &probabilities, selection)
+    })
+}
 
-    let target_names: Vec<&str> = hdr
-        .target_names()
-        .iter()
-        .map(|name| {
-            std::str::from_utf8(name).map_err(|e| Error::Calibration {
-                msg: format!("Invalid UTF-8 in target name: {}", e),
-            })
-        })
-        .collect::<Result<_>>()?;
+/// Subsamples the reads in each target region with its downsampling
+/// probability, inserting the names of the read groups to keep into `keep`.
+///
+/// See [`calibrate_by_fixed_coverage`] for the arguments.
+fn select_reads<R: BamReader>(
+    reader: &mut R,
+    target_regions: &[Region],
+    probabilities: &HashMap<String, f64>,
+    selection: Selection,
+) -> Result<()> {
+    let Selection {
+        keep,
+        sequin_tids,
+        seed,
+        keep_singletons,
+        mut read_probabilities,
+        mut progress,
+    } = selection;
+    let mut rng = Pcg32::seed_from_u64(seed);
+    let mut considered = HashSet::new();
+    for region in target_regions {
+        // Number of random draws skipped by the fast path for this region.
+        let mut skipped_draws = 0;
+        reader.fetch((&region.contig, region.beg, region.end))?;
+        for result in reader.records() {
+            let record = result?;
+            // If the mate is mapped to a non-sequin chromosome, skip it. These
+            // are artefacts, and we shouldn't keep them.
+            if selectable(&record, sequin_tids, keep_singletons) {
+                let probability =
+                    probabilities
+                        .get(&region.name)
+                        .cloned()
+                        .ok_or_else(|| Error::Calibration {
+                            msg: format!(
+                                "No downsampling probability found for region {} {:?}",
+                                region.name, probabilities
+                            ),
+                        })?;
+                if select_read(
+                    &record,
+                    probability,
+                    keep,
+                    &considered,
+                    &mut rng,
+                    read_probabilities.as_deref_mut(),
+                ) {
+                    skipped_draws += 1;
+                }
+            }
+            considered.insert(record.qname().to_vec());
+        }
+        // Keep the random stream in step with the draws `subsample` would have
+        // made so the output is identical to the slow path.
+        rng.advance(skipped_draws * RNG_STEPS_PER_DRAW);
+        advance_progress(progress.as_deref_mut(), region);
+    }
 
-    let sample_region_map = sample_regions
-        .iter()
-        .map(|r| (r.name.clone(), r))
-        .collect::<HashMap<_, _>>();
+    Ok(())
+}
 
-    for i in 0..hdr.target_count() {
-        let contig = target_names[i as usize];
-        if !cal_contigs.contains(&contig) {
-            continue;
+/// [`select_reads`] for regions calibrated to a fixed fold coverage, fetching
+/// each region once.
+///
+/// The reads of each region are held in memory while its mean coverage, and
+/// so its downsampling probability, is measured from them, and are then
+/// subsampled. The kept reads are the same as those of
+/// [`determine_downsampling_probabilities`] followed by [`select_reads`].
+fn select_reads_single_pass<R: BamReader>(
+    reader: &mut R,
+    target_regions: &[Region],
+    fold_coverage: FoldCoverage,
+    allow_upsample: bool,
+    selection: Selection,
+) -> Result<()> {
+    let Selection {
+        keep,
+        sequin_tids,
+        seed,
+        keep_singletons,
+        mut read_probabilities,
+        mut progress,
+    } = selection;
+    let mut rng = Pcg32::seed_from_u64(seed);
+    let mut considered = HashSet::new();
+    let mut records = Vec::new();
+    for region in target_regions {
+        reader.fetch((&region.contig, region.beg, region.end))?;
+        records.clear();
+        for result in reader.records() {
+            records.push(result?);
         }
-        calibrate_regions(
-            reader,
-            writer,
-            target_regions,
-            &sample_region_map,
-            args.window_size,
-            args.min_mapq,
-            args.seed,
+        let target_mean = coverage::coverage_for_records(&records, region)
+            .mean()
+            .unwrap_or(0.0) as f64;
+        let probability = downsampling_probability(
+            &region.name,
+            target_mean,
+            fold_coverage.for_region(region),
+            allow_upsample,
         )?;
+        let mut skipped_draws = 0;
+        for record in &records {
+            if selectable(record, sequin_tids, keep_singletons)
+                && select_read(
+                    record,
+                    probability,
+                    keep,
+                    &considered,
+                    &mut rng,
+                    read_probabilities.as_deref_mut(),
+                )
+            {
+                skipped_draws += 1;
+            }
+            considered.insert(record.qname().to_vec());
+        }
+        rng.advance(skipped_draws * RNG_STEPS_PER_DRAW);
+        advance_progress(progress.as_deref_mut(), region);
     }
+
     Ok(())
 }
 
-/// Calibrates individual regions by matching sample profiles.
+/// Returns true if a read in a target region can be selected: it is unpaired,
+/// or its mate is on a sequin contig.
 ///
-/// If the regions need clipping (that is, removing flanking bases), this should
-/// be done prior to calling this function.
-///
-/// # Arguments
+/// A singleton, a paired read whose mate is unmapped, is selectable if
+/// `keep_singletons` is `Some(true)` and never if it is `Some(false)`. With
+/// `None` it falls under the mate rule, so it is only selectable if its mate
+/// is placed next to it.
+fn selectable(record: &Record, sequin_tids: &HashSet<i32>, keep_singletons: Option<bool>) -> bool {
+    match keep_singletons {
+        _ if !record.is_paired() => true,
+        Some(keep) if is_singleton(record) => keep,
+        _ => sequin_tids.contains(&record.mtid()),
+    }
+}
+
+/// Subsamples `record` with `probability`, recording the probability of newly
+/// kept reads in `read_probabilities`.
+///
+/// # Returns
+/// `true` if a random draw was skipped because the probability is at least 1.
+fn select_read(
+    record: &Record,
+    probability: f64,
+    keep: &mut HashSet<Vec<u8>>,
+    considered: &HashSet<Vec<u8>>,
+    rng: &mut Pcg32,
+    read_probabilities: Option<&mut HashMap<Vec<u8>, f64>>,
+) -> bool {
+    let skipped = if probability >= 1.0 {
+        keep_unconditionally(record, keep, considered)
+    } else {
+        subsample(record, keep, considered, probability, rng);
+        false
+    };
+    if let Some(read_probabilities) = read_probabilities {
+        if keep.contains(record.qname()) {
+            read_probabilities
+                .entry(record.qname().to_vec())
+                .or_insert(probability);
+        }
+    }
+    skipped
+}
+
+/// Advances `progress`, if any, past `region` and reports it.
+fn advance_progress(progress: Option<&mut Progress>, region: &Region) {
+    if let Some(progress) = progress {
+        progress.advance(region.length());
+        progress.report(&region.name);
+    }
+}
+
+/// Number of PCG32 steps consumed by each `f64` drawn in [`subsample`].
+const RNG_STEPS_PER_DRAW: u64 = 2;
+
+/// Keeps a read without a random draw, for regions that need no downsampling.
+///
+/// The same rules as [`subsample`] apply: reads already kept stay kept, and
+/// reads previously considered and rejected stay rejected.
+///
+/// # Returns
+/// `true` if `subsample` would have drawn a random number for this read.
+fn keep_unconditionally(
+    record: &Record,
+    hash: &mut HashSet<Vec<u8>>,
+    considered: &HashSet<Vec<u8>>,
+) -> bool {
+    let qname = record.qname();
+    if hash.contains(qname) || considered.contains(qname) {
+        return false;
+    }
+    hash.insert(qname.to_vec());
+    true
+}
+
+/// Determines downsampling probabilities for each target region.
+///
+/// # Arguments
+/// - `reader`: A mutable reference to a BAM reader.
+/// - `target_regions`: Regions to calibrate.
+/// - `sample_regions`: Optional sample regions.
+/// - `sample_percentile`: Use this percentile of each sample region's
+///   coverage as its target instead of the mean.
+/// - `fold_coverage`: Desired fold coverage.
+/// - `cache`: Optional cache of per-region mean coverage.
+///
+/// # Returns
+/// A `Result` containing a map of region names to downsampling probabilities.
+fn determine_downsampling_probabilities<R: BamReader>(
+    reader: &mut R,
+    target_regions: &[Region],
+    sample_regions: Option<&[Region]>,
+    sample_percentile: Option<f64>,
+    fold_coverage: FoldCoverage,
+    allow_upsample: bool,
+    cache: Option<&CoverageCache>,
+) -> Result<HashMap<String, f64>> {
+    let (_, probabilities) = target_means_and_probabilities(
+        reader,
+        target_regions,
+        sample_regions,
+        sample_percentile,
+        fold_coverage,
+        allow_upsample,
+        cache,
+    )?;
+    Ok(probabilities)
+}
+
+/// Like [`determine_downsampling_probabilities`], also returning the mean
+/// coverage of each target region.
+fn target_means_and_probabilities<R: BamReader>(
+    reader: &mut R,
+    target_regions: &[Region],
+    sample_regions: Option<&[Region]>,
+    sample_percentile: Option<f64>,
+    fold_coverage: FoldCoverage,
+    allow_upsample: bool,
+    cache: Option<&CoverageCache>,
+) -> Result<(HashMap<String, f64>, HashMap<String, f64>)> {
+    if let Some(sample_regions) = sample_regions {
+        check_sample_names(target_regions, sample_regions)?;
+    }
+    let target_means = regions_coverage(reader, target_regions, cache)?;
+    let sample_means = if let Some(sample_regions) = sample_regions {
+        match sample_percentile {
+            // The cache only holds means, so percentiles are always computed.
+            Some(p) => regions_statistic(reader, sample_regions, |c| c.percentile(p))?,
+            None => regions_coverage(reader, sample_regions, cache)?,
+        }
+    } else {
+        // If no sample regions are provided, we just use the provided fold
+        // coverage for all targets.
+        target_regions
+            .iter()
+            .map(|region| (region.name.clone(), fold_coverage.for_region(region)))
+            .collect()
+    };
+    let probabilities: HashMap<String, f64> = target_means
+        .iter()
+        .map(|(name, &target_mean)| {
+            let sample_mean =
+                sample_means
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| Error::Calibration {
+                        msg: format!("No sample mean coverage found for target region {}", name),
+                    })?;
+            let prob = downsampling_probability(name, target_mean, sample_mean, allow_upsample)?;
+            Ok((name.clone(), prob))
+        })
+        .collect::<Result<_>>()?;
+    Ok((target_means, probabilities))
+}
+
+/// The probability of keeping each read of the target region `name`, to bring
+/// its coverage from `target_mean` down to `sample_mean`.
+///
+/// With `allow_upsample`, a target region with less coverage than the sample
+/// gets a probability above 1: the number of copies of each read to write.
+///
+/// # Errors
+/// Returns an error if the target region has no coverage, or less than the
+/// sample without `allow_upsample`.
+fn downsampling_probability(
+    name: &str,
+    target_mean: f64,
+    sample_mean: f64,
+    allow_upsample: bool,
+) -> Result<f64> {
+    if target_mean == 0.0 {
+        return Err(Error::Calibration {
+            msg: format!("Target mean coverage for region {} is zero", name),
+        });
+    }
+    if target_mean < sample_mean && !allow_upsample {
+        return Err(Error::Calibration {
+            msg: format!(
+                "Target mean coverage for region {} is less than sample mean coverage ({target_mean} < {sample_mean})",
+                name
+            ),
+        });
+    }
+    Ok(sample_mean / target_mean)
+}
+
+/// Returns the probability of keeping each read pair in every target region
+/// so that about `target_pairs` are kept from it, for
+/// [`CalibrationMode::FixedReadCount`].
+///
+/// A region's pairs are the read groups [`select_reads`] draws from, the
+/// distinct names of its selectable reads. Regions with no more than
+/// `target_pairs` keep every pair, with a probability of 1.
+fn read_count_probabilities<R: BamReader>(
+    reader: &mut R,
+    target_regions: &[Region],
+    target_pairs: u64,
+    sequin_tids: &HashSet<i32>,
+    keep_singletons: Option<bool>,
+) -> Result<HashMap<String, f64>> {
+    let mut probabilities = HashMap::new();
+    let mut names = HashSet::new();
+    for region in target_regions {
+        names.clear();
+        reader.fetch((&region.contig, region.beg, region.end))?;
+        for result in reader.records() {
+            let record = result?;
+            if selectable(&record, sequin_tids, keep_singletons) {
+                names.insert(record.qname().to_vec());
+            }
+        }
+        let observed_pairs = names.len() as f64;
+        let probability = if observed_pairs == 0.0 {
+            1.0
+        } else {
+            (target_pairs as f64 / observed_pairs).min(1.0)
+        };
+        probabilities.insert(region.name.clone(), probability);
+    }
+    Ok(probabilities)
+}
+
+/// Checks that `target_regions` and `sample_regions` have the same names, as
+/// each target is calibrated against the sample region of the same name.
+///
+/// # Errors
+/// Returns an error listing, in BED order, every target name missing from
+/// the sample regions and every sample name missing from the targets.
+fn check_sample_names(target_regions: &[Region], sample_regions: &[Region]) -> Result<()> {
+    let no_sample = names_missing_from(target_regions, sample_regions);
+    let no_target = names_missing_from(sample_regions, target_regions);
+    if no_sample.is_empty() && no_target.is_empty() {
+        return Ok(());
+    }
+    let mut problems = Vec::new();
+    if !no_sample.is_empty() {
+        problems.push(format!(
+            "target regions with no sample region: {}",
+            no_sample.join(", ")
+        ));
+    }
+    if !no_target.is_empty() {
+        problems.push(format!(
+            "sample regions with no target region: {}",
+            no_target.join(", ")
+        ));
+    }
+    Err(Error::Calibration {
+        msg: format!(
+            "Target and sample region names differ ({})",
+            problems.join("; ")
+        ),
+    })
+}
+
+/// Returns the distinct names of `regions`, in order, that no region in
+/// `other` has.
+fn names_missing_from<'a>(regions: &'a [Region], other: &[Region]) -> Vec<&'a str> {
+    let other = other
+        .iter()
+        .map(|r| r.name.as_str())
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    regions
+        .iter()
+        .map(|r| r.name.as_str())
+        .filter(|name| !other.contains(name) && seen.insert(*name))
+        .collect()
+}
+
+/// The settings calibration counts region coverage with: every read counts,
+/// and target regions have had their flanks trimmed already.
+///
+/// Duplicates are counted too, as they always have been, so calibrated
+/// coverage keeps its meaning. `bedcov` excludes them by default, so on data
+/// with duplicates its mean coverage is lower than the coverage calibration
+/// measured unless it is run with `--keep-duplicates`.
+fn measured_coverage() -> CoverageSettings {
+    CoverageSettings {
+        min_mapq: 0,
+        flank: 0,
+        read_filters: coverage::ReadFilters::none(),
+    }
+}
+
+/// Calculates the mean coverage of each region.
+///
+/// # Arguments
+/// - `reader`: A mutable reference to a BAM reader.
+/// - `regions`: Regions to calculate coverage for.
+/// - `cache`: Optional cache; on a hit the BAM is not read.
+///
+/// # Returns
+/// A `Result` containing a map of region names to mean coverage values.
+fn regions_coverage<R: BamReader>(
+    reader: &mut R,
+    regions: &[Region],
+    cache: Option<&CoverageCache>,
+) -> Result<HashMap<String, f64>> {
+    let settings = measured_coverage();
+    if let Some(coverage) = cache.and_then(|cache| cache.load(regions, &settings)) {
+        return Ok(coverage);
+    }
+    let coverage = regions_statistic(reader, regions, |c| c.mean())?;
+    if let Some(cache) = cache {
+        cache.store(regions, &settings, &coverage)?;
+    }
+    Ok(coverage)
+}
+
+/// Calculates a statistic of the coverage of each region, counted with
+/// [`measured_coverage`]. Regions for which `statistic` is undefined are given
+/// zero.
+fn regions_statistic<R, F>(
+    reader: &mut R,
+    regions: &[Region],
+    statistic: F,
+) -> Result<HashMap<String, f64>>
+where
+    R: BamReader,
+    F: Fn(&coverage::RegionCoverage) -> Option<f32>,
+{
+    let settings = measured_coverage();
+    regions
+        .iter()
+        .map(|region| {
+            let region_coverage = coverage::coverage_for_region(
+                reader,
+                region,
+                settings.min_mapq,
+                settings.flank,
+                &settings.read_filters,
+            )?;
+            let value = statistic(&region_coverage).unwrap_or(0.0);
+            Ok((region.name.clone(), value as f64))
+        })
+        .collect()
+}
+
+/// Decides whether to keep a read based on downsampling probability.
+///
+/// This function uses a hash to ensure consistent decisions for paired reads.
+///
+/// # Arguments
+/// - `record`: The BAM record to consider.
+/// - `hash`: A mutable hash map for tracking read names.
+/// - `threshold`: The downsampling probability threshold.
+/// - `rng`: A mutable random number generator.
+///
+/// # Returns
+/// `true` if the read should be kept, `false` otherwise.
+fn subsample(
+    record: &Record,
+    hash: &mut HashSet<Vec<u8>>,
+    considered: &HashSet<Vec<u8>>,
+    threshold: f64,
+    rng: &mut Pcg32,
+) -> bool {
+    let qname = record.qname().to_vec();
+    match hash.contains(&qname) {
+        true => {
+            return true;
+        }
+        false => {
+            if considered.contains(&qname) {
+                return false;
+            }
+        }
+    };
+    let rand = rng.random::<f64>();
+    if rand <= threshold {
+        hash.insert(record.qname().to_vec());
+        return true;
+    }
+    false
+}
+
+/// How profile matching calibrated one target region, from
+/// [`CalibrationMode::SampleProfile`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionProfile {
+    /// Name of the target region.
+    pub name: String,
+    /// Read starts in each window of the sample region, after smoothing or
+    /// flattening by [`ProfileTarget`].
+    pub sample_starts: Vec<usize>,
+    /// `sample_starts` reversed, the profile the target windows are matched
+    /// to.
+    pub rev_sample_starts: Vec<usize>,
+    /// Reads selected in each window of the target region. Each paired read
+    /// selects its mate too.
+    pub selected: Vec<usize>,
+}
+
+/// A [`RegionProfile`] as written by [`write_profile_json`].
+#[derive(Serialize)]
+struct ProfileJson<'a> {
+    name: &'a str,
+    sample_starts: &'a [usize],
+    rev_sample_starts: &'a [usize],
+    selected: &'a [usize],
+}
+
+/// The object written by [`write_profile_json`].
+#[derive(Serialize)]
+struct ProfilesJson<'a> {
+    regions: Vec<ProfileJson<'a>>,
+}
+
+/// Writes region profiles as a JSON object with one entry per target region.
+pub fn write_profile_json<W: Write>(profiles: &[RegionProfile], mut dest: W) -> Result<()> {
+    let regions = profiles
+        .iter()
+        .map(|profile| ProfileJson {
+            name: &profile.name,
+            sample_starts: &profile.sample_starts,
+            rev_sample_starts: &profile.rev_sample_starts,
+            selected: &profile.selected,
+        })
+        .collect();
+    serde_json::to_writer(&mut dest, &ProfilesJson { regions })?;
+    writeln!(dest)?;
+    dest.flush()?;
+    Ok(())
+}
+
+/// Parameters for sample profile calibration.
+#[derive(Clone, Copy)]
+struct SampleProfileParams<'a> {
+    /// Size of windows for profile matching.
+    window_size: u64,
+    /// Minimum mapping quality.
+    min_mapq: u8,
+    /// Width of the moving average applied to sample window counts.
+    profile_smoothing: u64,
+    /// Reconcile selected read names against every record on the target
+    /// contigs so that both mates of a selected pair are written.
+    keep_mates: bool,
+    /// The profile to select reads to.
+    profile_target: ProfileTarget,
+    /// Which mates of a sample read must pass `min_mapq`.
+    mate_mapq_policy: MateMapqPolicy,
+    /// Precomputed read starts in each window of the sample regions, by
+    /// region name, used instead of counting them.
+    sample_starts: Option<&'a HashMap<String, Vec<usize>>>,
+    /// Random seed.
+    seed: u64,
+}
+
+/// Calibrates by matching the coverage profile of sample regions.
+///
+/// This function attempts to replicate the read start profile of sample regions
+/// in the target regions using windowed downsampling.
+///
+/// # Arguments
+/// - `reader`: A mutable reference to a BAM reader.
+/// - `writer`: A mutable reference to a BAM writer.
+/// - `target_regions`: Regions to calibrate.
+/// - `sample_regions`: Sample regions to match.
+/// - `args`: Parameters for calibration.
+/// - `progress`: Optional progress to advance as each region is calibrated.
+///
+/// # Returns
+/// A `Result` containing the profile of each calibrated target region.
+fn calibrate_by_sample_profile<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    target_regions: &[Region],
+    sample_regions: &[Region],
+    args: &SampleProfileParams,
+    progress: Option<&mut Progress>,
+) -> Result<Vec<RegionProfile>>
+where
+    R: BamReader,
+    W: BamWriter,
+{
+    // Calibrate the regions contig by contig, in header order, skipping those
+    // on contigs the header doesn't have. Each region is calibrated once.
+    let hdr = reader.header();
+    let mut regions = target_regions
+        .iter()
+        .filter_map(|r| hdr.tid(r.contig.as_bytes()).map(|tid| (tid, r.clone())))
+        .collect::<Vec<_>>();
+    regions.sort_by_key(|(tid, _)| *tid);
+    let regions = regions.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+
+    let sample_region_map = sample_regions
+        .iter()
+        .map(|r| (r.name.clone(), r))
+        .collect::<HashMap<_, _>>();
+
+    calibrate_regions(reader, writer, &regions, &sample_region_map, args, progress)
+}
+
+/// Calibrates individual regions by matching sample profiles.
+///
+/// If the regions need clipping (that is, removing flanking bases), this should
+/// be done prior to calling this function.
+///
+/// # Arguments
 /// - `reader`: A mutable reference to a BAM reader.
 /// - `writer`: A mutable reference to a BAM writer.
 /// - `target_regions`: Target regions to calibrate.
 /// - `sample_region_map`: Map of sample regions.
-/// - `window_size`: Size of windows.
-/// - `min_mapq`: Minimum mapping quality.
-/// - `seed`: Random seed.
+/// - `params`: Parameters for calibration.
+/// - `progress`: Optional progress to advance as each region is calibrated.
 ///
 /// # Returns
-/// A `Result` indicating success or failure.
+/// A `Result` containing the profile of each target region.
 fn calibrate_regions<R, W>(
     reader: &mut R,
     writer: &mut W,
     target_regions: &[Region],
     sample_region_map: &HashMap<String, &Region>,
-    window_size: u64,
-    min_mapq: u8,
-    seed: u64,
-) -> Result<()>
+    params: &SampleProfileParams,
+    mut progress: Option<&mut Progress>,
+) -> Result<Vec<RegionProfile>>
 where
     R: BamReader,
     W: BamWriter,
 {
+    let SampleProfileParams {
+        window_size,
+        min_mapq,
+        profile_smoothing,
+        keep_mates,
+        profile_target,
+        mate_mapq_policy,
+        sample_starts: precomputed,
+        seed,
+    } = *params;
+    let mut reconcile_names = HashSet::new();
+    let mut profiles = Vec::new();
     for target_region in target_regions {
         eprintln!("Calibrating region {}.", target_region.name);
         let sample_region =
@@ -470,9 +1927,24 @@ where
         // window of the sample region. This is intentional. The Sequin (target)
         // regions are the mirror of the sample region; therefore, we want to
         // mimic the coverage profile in reverse.
-        let sample_starts = window_starts(reader, sample_region, window_size, min_mapq)?;
-        let rev_sample_starts = sample_starts.into_iter().rev().collect::<Vec<_>>();
-
+        let sample_starts = match precomputed {
+            Some(precomputed) => {
+                precomputed_window_starts(precomputed, sample_region, window_size)?
+            }
+            None => window_starts(
+                reader,
+                sample_region,
+                window_size,
+                min_mapq,
+                mate_mapq_policy,
+            )?,
+        };
+        let sample_starts = match profile_target {
+            ProfileTarget::Sample => smooth_counts(&sample_starts, profile_smoothing as usize),
+            ProfileTarget::Uniform => uniform_counts(&sample_starts),
+        };
+        let rev_sample_starts = sample_starts.iter().rev().copied().collect::<Vec<_>>();
+
         let records = records_that_start_in_region(
             reader,
             target_region.contig.as_str(),
@@ -481,29 +1953,24 @@ where
         )?;
 
         let mut keep_names = HashSet::new();
+        let mut selected = Vec::new();
         for (i, window_beg) in (target_region.beg..target_region.end)
             .step_by(window_size as usize)
             .enumerate()
         {
             let window_end = window_beg + window_size - 1;
 
-            // Divide by 2 because each read is part of a pair. When we include
-            // one we automatically include the other; therefore, we will have
-            // twice the coverage we intended.
-            let n_starts = rev_sample_starts[i] / 2;
-
             // These are the records that *start* in the current window.
             let region_records = records
                 .iter()
                 .filter(|record| {
-                    if let Ok(pos) = u64::try_from(record.pos()) {
-                        pos >= window_beg && pos <= window_end
-                    } else {
-                        false
-                    }
+                    record_start_u64(record)
+                        .is_some_and(|pos| pos >= window_beg && pos <= window_end)
                 })
                 .collect::<Vec<_>>();
-            let numbers = choose_from(region_records.len() as u64, n_starts as u64, seed);
+            let n_starts = records_to_select(rev_sample_starts[i], &region_records);
+            let numbers = choose_from(region_records.len() as u64, n_starts, seed);
+            selected.push(numbers.len());
             for idx in &numbers {
                 let record = region_records[*idx as usize];
                 let qname =
@@ -511,6 +1978,17 @@ where
                 keep_names.insert(qname);
             }
         }
+        advance_progress(progress.as_deref_mut(), target_region);
+        profiles.push(RegionProfile {
+            name: target_region.name.clone(),
+            sample_starts,
+            rev_sample_starts,
+            selected,
+        });
+        if keep_mates {
+            reconcile_names.extend(keep_names);
+            continue;
+        }
         for record in &records {
             let qname = String::from_utf8(record.qname().to_vec()).map_err(|e| e.utf8_error())?;
             if keep_names.contains(&qname) {
@@ -518,7 +1996,141 @@ where
             }
         }
     }
-    Ok(())
+    if keep_mates {
+        // Only reads starting inside a target region are candidates for
+        // selection, so a mate starting elsewhere (another region, or
+        // between regions) would otherwise be dropped. Scan every record on
+        // the target contigs instead.
+        let mut contigs = Vec::new();
+        for region in target_regions {
+            if !contigs.contains(&region.contig.as_str()) {
+                contigs.push(region.contig.as_str());
+            }
+        }
+        for contig in contigs {
+            reader.fetch(contig)?;
+            for result in reader.records() {
+                let record = result?;
+                let qname =
+                    std::str::from_utf8(record.qname()).map_err(|e| Error::Calibration {
+                        msg: format!("Invalid UTF-8 in read name: {e}"),
+                    })?;
+                if reconcile_names.contains(qname) {
+                    writer.write(&record)?;
+                }
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+/// Returns how many of the `candidates` starting in a window to select so
+/// that about `wanted_reads` reads are written.
+///
+/// Selecting a paired read also selects its mate, so it writes two reads and
+/// an unpaired read one. The number is scaled by the share of paired
+/// candidates: half of `wanted_reads` when every read is paired, as profile
+/// matching always assumed, and all of them for single-end data.
+fn records_to_select(wanted_reads: usize, candidates: &[&Record]) -> u64 {
+    let paired = candidates.iter().filter(|r| r.is_paired()).count();
+    let paired_fraction = if candidates.is_empty() {
+        1.0
+    } else {
+        paired as f64 / candidates.len() as f64
+    };
+    (wanted_reads as f64 / (1.0 + paired_fraction)).floor() as u64
+}
+
+/// Loads precomputed sample window read starts for profile matching from a
+/// CSV file with a `region,window_index,starts` header and one line per
+/// window of each sample region.
+///
+/// Windows are numbered from 0, at the start of the sample region after any
+/// flank is trimmed, in the layout [`window_starts`] counts them in.
+///
+/// # Returns
+/// The read starts in each window, by region name.
+///
+/// # Errors
+/// Returns an error if the header or a line is malformed, or if the windows
+/// of a region are not numbered 0 to one less than their count.
+pub fn load_profile_csv<R: BufRead>(reader: R) -> Result<HashMap<String, Vec<usize>>> {
+    let mut lines = reader.lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if header.trim_end() != "region,window_index,starts" {
+        return Err(Error::Calibration {
+            msg: format!(
+                "Profile CSV header must be region,window_index,starts, got {}",
+                header.trim_end()
+            ),
+        });
+    }
+    let mut windows: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_no = i + 2;
+        let invalid = || Error::Calibration {
+            msg: format!("Invalid profile CSV line {line_no}: {line}"),
+        };
+        let fields = line.trim_end().split(',').collect::<Vec<_>>();
+        let [region, index, starts] = fields.as_slice() else {
+            return Err(invalid());
+        };
+        let index = index.parse::<usize>().map_err(|_| invalid())?;
+        let starts = starts.parse::<usize>().map_err(|_| invalid())?;
+        windows
+            .entry(region.to_string())
+            .or_default()
+            .push((index, starts));
+    }
+    windows
+        .into_iter()
+        .map(|(region, mut windows)| {
+            windows.sort_unstable();
+            if windows
+                .iter()
+                .enumerate()
+                .any(|(i, (index, _))| i != *index)
+            {
+                return Err(Error::Calibration {
+                    msg: format!(
+                        "Profile CSV windows of region {region} must be numbered 0 to {}",
+                        windows.len() - 1
+                    ),
+                });
+            }
+            let starts = windows.into_iter().map(|(_, starts)| starts).collect();
+            Ok((region, starts))
+        })
+        .collect()
+}
+
+/// Returns the precomputed read starts in each window of `region`, checking
+/// that there is one for each of its windows of `window_size` bases.
+fn precomputed_window_starts(
+    precomputed: &HashMap<String, Vec<usize>>,
+    region: &Region,
+    window_size: u64,
+) -> Result<Vec<usize>> {
+    let starts = precomputed
+        .get(&region.name)
+        .ok_or_else(|| Error::Calibration {
+            msg: format!("No windows for region {} in the profile CSV", region.name),
+        })?;
+    let expected = region.length().div_ceil(window_size);
+    if starts.len() as u64 != expected {
+        return Err(Error::Calibration {
+            msg: format!(
+                "Profile CSV has {} windows for region {}, but it has {expected} windows of {window_size} bases",
+                starts.len(),
+                region.name
+            ),
+        });
+    }
+    Ok(starts.clone())
 }
 
 /// Returns the number of read starts in each window of a region.
@@ -528,6 +2140,7 @@ where
 /// - `region`: The region to analyze.
 /// - `window_size`: Size of windows.
 /// - `min_mapq`: Minimum mapping quality.
+/// - `policy`: Which mates must pass `min_mapq`.
 ///
 /// # Returns
 /// A `Result` containing a vector of start counts per window.
@@ -536,6 +2149,7 @@ fn window_starts<R: BamReader>(
     region: &Region,
     window_size: u64,
     min_mapq: u8,
+    policy: MateMapqPolicy,
 ) -> Result<Vec<usize>> {
     let mut starts = Vec::new();
     for beg in (region.beg..region.end).step_by(window_size as usize) {
@@ -543,145 +2157,1304 @@ fn window_starts<R: BamReader>(
         let n = starts_in(
             reader,
             &Region {
-                contig: region.contig.to_owned(),
                 beg,
                 end,
-                name: region.name.to_owned(),
+                ..region.clone()
             },
             min_mapq,
+            policy,
         )?;
         starts.push(n);
     }
     Ok(starts)
 }
 
-/// Counts the number of read starts in a region.
-///
-/// # Arguments
-/// - `reader`: A mutable reference to a BAM reader.
-/// - `region`: The region to count in.
-/// - `min_mapq`: Minimum mapping quality.
-///
-/// # Returns
-/// A `Result` containing the count of read starts.
-fn starts_in<R: BamReader>(reader: &mut R, region: &Region, min_mapq: u8) -> Result<usize> {
-    let (beg, end) = (region.beg as i64, region.end as i64);
-    reader.fetch((&region.contig, region.beg, region.end))?;
-    let mut n = 0;
-    for result in reader.records() {
-        let record = result?;
-        if record.pos() >= beg && record.pos() <= end && record.mapq() >= min_mapq {
-            n += 1;
+/// Applies a centred moving average of width `width` to a vector of counts.
+///
+/// Windows at either end of the vector are truncated, so each value is the
+/// rounded mean of the counts that fall within the window. A width of 0 or 1
+/// returns the counts unchanged.
+///
+/// # Arguments
+/// - `counts`: Per-window counts to smooth.
+/// - `width`: Width of the moving average.
+///
+/// # Returns
+/// A vector of smoothed counts, the same length as `counts`.
+fn smooth_counts(counts: &[usize], width: usize) -> Vec<usize> {
+    if width <= 1 {
+        return counts.to_vec();
+    }
+    let left = (width - 1) / 2;
+    let right = width / 2;
+    (0..counts.len())
+        .map(|i| {
+            let beg = i.saturating_sub(left);
+            let end = (i + right + 1).min(counts.len());
+            let n = end - beg;
+            let total = counts[beg..end].iter().sum::<usize>();
+            (total + n / 2) / n
+        })
+        .collect()
+}
+
+/// Replaces every count with the rounded mean count, for a flat profile with
+/// the same total.
+fn uniform_counts(counts: &[usize]) -> Vec<usize> {
+    if counts.is_empty() {
+        return vec![];
+    }
+    let n = counts.len();
+    let mean = (counts.iter().sum::<usize>() + n / 2) / n;
+    vec![mean; n]
+}
+
+/// Counts the number of read starts in a region.
+///
+/// # Arguments
+/// - `reader`: A mutable reference to a BAM reader.
+/// - `region`: The region to count in.
+/// - `min_mapq`: Minimum mapping quality.
+/// - `policy`: Which mates must pass `min_mapq`.
+///
+/// # Returns
+/// A `Result` containing the count of read starts.
+fn starts_in<R: BamReader>(
+    reader: &mut R,
+    region: &Region,
+    min_mapq: u8,
+    policy: MateMapqPolicy,
+) -> Result<usize> {
+    reader.fetch((&region.contig, region.beg, region.end))?;
+    let mut n = 0;
+    for result in reader.records() {
+        let record = result?;
+        let Some(pos) = record_start_u64(&record) else {
+            continue;
+        };
+        if pos >= region.beg && pos <= region.end && passes_mapq(&record, min_mapq, policy)? {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Returns whether `record`, and its mate if `policy` requires, has a mapping
+/// quality of at least `min_mapq`.
+///
+/// # Errors
+/// Returns an error if `policy` is [`MateMapqPolicy::Both`] and a paired read
+/// with a mapped mate has no `MQ` tag.
+fn passes_mapq(record: &Record, min_mapq: u8, policy: MateMapqPolicy) -> Result<bool> {
+    if record.mapq() < min_mapq {
+        return Ok(false);
+    }
+    if policy == MateMapqPolicy::Either || !record.is_paired() || record.is_mate_unmapped() {
+        return Ok(true);
+    }
+    let mate_mapq = match record.aux(b"MQ") {
+        Ok(Aux::U8(v)) => v as i64,
+        Ok(Aux::I8(v)) => v as i64,
+        Ok(Aux::U16(v)) => v as i64,
+        Ok(Aux::I16(v)) => v as i64,
+        Ok(Aux::U32(v)) => v as i64,
+        Ok(Aux::I32(v)) => v as i64,
+        _ => {
+            return Err(Error::Calibration {
+                msg: format!(
+                    "Read {} has no MQ tag with its mate's mapping quality, which \
+                     requiring both mates to pass the minimum mapping quality needs \
+                     (add it with samtools fixmate -m)",
+                    String::from_utf8_lossy(record.qname())
+                ),
+            })
+        }
+    };
+    Ok(mate_mapq >= min_mapq as i64)
+}
+
+/// Retrieves all records that start within a specified region.
+///
+/// # Arguments
+/// - `reader`: A mutable reference to a BAM reader.
+/// - `contig`: The contig name.
+/// - `beg`: Start position.
+/// - `end`: End position.
+///
+/// # Returns
+/// A `Result` containing a vector of BAM records.
+fn records_that_start_in_region<R: BamReader>(
+    reader: &mut R,
+    contig: &str,
+    beg: u64,
+    end: u64,
+) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    reader.fetch((contig, beg, end))?;
+    for result in reader.records() {
+        let record = result?;
+        let Some(pos) = record_start_u64(&record) else {
+            continue;
+        };
+        if pos < beg || pos > end {
+            continue;
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Randomly selects a subset of indices from a range.
+///
+/// If `n` is greater than `size`, all indices are returned.
+///
+/// # Arguments
+/// - `size`: The total number of items.
+/// - `n`: The number to select.
+/// - `seed`: Random seed.
+///
+/// # Returns
+/// A `Result` containing the selected indices.
+fn choose_from(size: u64, n: u64, seed: u64) -> Vec<u64> {
+    let mut rng = Pcg32::seed_from_u64(seed);
+    (0..size).sample(&mut rng, n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::{MockBamReader, MockBamWriter};
+    use crate::region::{Region, TargetCoverage};
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use rust_htslib::bam::Record;
+    use std::collections::HashMap;
+
+    const CHR1_TID: i32 = 0;
+    const CHR2_TID: i32 = 1;
+    const CHRQ_MIRROR_TID: i32 = 3;
+
+    /// Helper function to create a mock BAM record
+    fn create_mock_record(tid: i32, pos: i64, qname: &str) -> Record {
+        let mut record = Record::new();
+        record.set_tid(tid);
+        record.set_pos(pos);
+        record.unset_unmapped();
+        record.set_qname(qname.as_bytes());
+        // let seq = b"A".repeat(100);
+        let cigar_string = CigarString(vec![Cigar::Match(100)]);
+        record.set_cigar(Some(&cigar_string));
+        record.set_mtid(tid);
+        record
+    }
+
+    /// Like [`create_mock_record`], for one mate of a pair.
+    fn create_paired_mock_record(tid: i32, pos: i64, qname: &str) -> Record {
+        let mut record = create_mock_record(tid, pos, qname);
+        record.set_paired();
+        record
+    }
+
+    #[test]
+    fn test_config_default_mode() {
+        let config = Config::default();
+        assert!(matches!(
+            config.mode(None).unwrap(),
+            CalibrationMode::FixedCoverage {
+                fold_coverage: 40,
+                seed: 5678
+            }
+        ));
+    }
+
+    #[test]
+    fn test_config_override_fields() {
+        let sample_regions = vec![Region::new("chr1", 0, 100, "region1")];
+        let config = Config {
+            seed: 1,
+            target_contig: Some("chr20".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(config.flank, Config::default().flank);
+        assert!(matches!(
+            config.mode(None).unwrap(),
+            CalibrationMode::ContigMeanCoverage {
+                contig: "chr20",
+                seed: 1
+            }
+        ));
+        assert!(matches!(
+            config.mode(Some(&sample_regions)).unwrap(),
+            CalibrationMode::SampleMeanCoverage { seed: 1, .. }
+        ));
+
+        let config = Config {
+            experimental: true,
+            window_size: 50,
+            ..Config::default()
+        };
+        assert!(matches!(
+            config.mode(Some(&sample_regions)).unwrap(),
+            CalibrationMode::SampleProfile {
+                window_size: 50,
+                ..
+            }
+        ));
+        assert!(config.mode(None).is_err());
+    }
+
+    #[test]
+    fn test_config_per_region_coverage_mode() {
+        let config = Config {
+            per_region_coverage: true,
+            fold_coverage: 30,
+            ..Config::default()
+        };
+        assert!(matches!(
+            config.mode(None).unwrap(),
+            CalibrationMode::PerRegionCoverage {
+                default_coverage: 30,
+                seed: 5678
+            }
+        ));
+        let sample_regions = vec![Region::new("chr1", 0, 100, "region1")];
+        assert!(config.mode(Some(&sample_regions)).is_err());
+        let config = Config {
+            target_contig: Some("chr20".to_string()),
+            ..config
+        };
+        assert!(config.mode(None).is_err());
+    }
+
+    #[test]
+    fn test_determine_downsampling_probabilities_per_region() {
+        let mut records = Vec::new();
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("a{i}")));
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 300, &format!("b{i}")));
+        }
+        let mut targeted = Region::new("chrQ_mirror", 300, 400, "region2");
+        targeted.target = Some(TargetCoverage(20.0));
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1"), targeted];
+        let mut reader = create_mock_reader_with_records(records);
+        let probabilities = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            FoldCoverage::PerRegion(10.0),
+            false,
+            None,
+        )
+        .unwrap();
+        // The untargeted region falls back to the default coverage.
+        assert_eq!(probabilities["region1"], 0.25);
+        assert_eq!(probabilities["region2"], 0.5);
+    }
+
+    /// Helper function to create a mock reader with records
+    fn create_mock_reader_with_records(records: Vec<Record>) -> MockBamReader {
+        // Use default header to avoid header format issues
+        MockBamReader::new(records, None)
+    }
+
+    #[test]
+    fn test_calibrate_fixed_coverage_mode() {
+        let records = vec![
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read1"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read2"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read3"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read4"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read5"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read6"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read7"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read8"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read9"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read10"),
+        ];
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let mode = CalibrationMode::FixedCoverage {
+            fold_coverage: 10,
+            seed: 42,
+        };
+
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false, None);
+        assert!(result.is_ok());
+
+        // Should have processed records (may be 0 or more)
+        let _ = writer.records();
+    }
+
+    #[test]
+    fn test_calibrate_exclude_uncalibrated_reads_all_modes() {
+        let mut records = (0..20)
+            .map(|i| create_mock_record(CHR1_TID, 100 + i, &format!("s{i}")))
+            .collect::<Vec<_>>();
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+        let modes = || {
+            [
+                CalibrationMode::FixedCoverage {
+                    fold_coverage: 10,
+                    seed: 42,
+                },
+                CalibrationMode::SampleMeanCoverage {
+                    sample_regions: &sample_regions,
+                    seed: 42,
+                },
+                CalibrationMode::ContigMeanCoverage {
+                    contig: "chr1",
+                    seed: 42,
+                },
+                CalibrationMode::SampleProfile {
+                    sample_regions: &sample_regions,
+                    window_size: 10,
+                    min_mapq: 0,
+                    profile_smoothing: 1,
+                    keep_mates: false,
+                    profile_target: ProfileTarget::Sample,
+                    mate_mapq_policy: MateMapqPolicy::Either,
+                    profile_json: None,
+                    profile_csv: None,
+                    seed: 42,
+                },
+            ]
+        };
+        for exclude in [false, true] {
+            for mode in modes() {
+                let name = format!("{mode:?}");
+                let mut reader = create_mock_reader_with_records(records.clone());
+                let mut writer = MockBamWriter::new();
+                calibrate(
+                    &mut reader,
+                    &mut writer,
+                    &target_regions,
+                    mode,
+                    exclude,
+                    None,
+                )
+                .unwrap();
+                let uncalibrated = writer
+                    .records()
+                    .iter()
+                    .filter(|r| r.tid() == CHR1_TID)
+                    .count();
+                let expected = if exclude { 0 } else { 20 };
+                assert_eq!(uncalibrated, expected, "{name}, exclude = {exclude}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_calibrate_with_config_unmapped_reads_independent() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        let mut unmapped = create_mock_record(-1, -1, "unmapped");
+        unmapped.set_unmapped();
+        records.push(unmapped);
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        for exclude_uncalibrated_reads in [false, true] {
+            for exclude_unmapped_reads in [false, true] {
+                let config = Config {
+                    flank: 0,
+                    fold_coverage: 10,
+                    exclude_uncalibrated_reads,
+                    exclude_unmapped_reads,
+                    ..Config::default()
+                };
+                let mut reader = create_mock_reader_with_records(records.clone());
+                let mut writer = MockBamWriter::new();
+                calibrate_with_config(
+                    &mut reader,
+                    &mut writer,
+                    &target_regions,
+                    None,
+                    &config,
+                    None,
+                )
+                .unwrap();
+                let written = |name: &[u8]| writer.records().iter().any(|r| r.qname() == name);
+                let name = format!(
+                    "exclude_uncalibrated_reads = {exclude_uncalibrated_reads}, \
+                     exclude_unmapped_reads = {exclude_unmapped_reads}"
+                );
+                assert_eq!(written(b"sample"), !exclude_uncalibrated_reads, "{name}");
+                assert_eq!(written(b"unmapped"), !exclude_unmapped_reads, "{name}");
+                assert!(written(b"q0"), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_calibrate_with_config_decoy_only() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        let mut unmapped = create_mock_record(-1, -1, "unmapped");
+        unmapped.set_unmapped();
+        records.push(unmapped);
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+            // A read on another contig, and an unmapped one, sharing the name
+            // of a sequin read.
+            records.push(create_mock_record(CHR1_TID, 1000, &format!("q{i}")));
+            let mut unmapped_mate = create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}"));
+            unmapped_mate.set_unmapped();
+            records.push(unmapped_mate);
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            decoy_only: true,
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            None,
+            &config,
+            None,
+        )
+        .unwrap();
+        assert_eq!(writer.records().len(), 10);
+        for record in writer.records() {
+            assert_eq!(record.tid(), CHRQ_MIRROR_TID);
+            assert!(!record.is_unmapped());
+            assert!(record.qname().starts_with(b"q"));
+        }
+    }
+
+    #[test]
+    fn test_calibrate_with_config_name_prefix() {
+        let mut records = vec![];
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("sg{i}")));
+            let mut other = create_mock_record(CHRQ_MIRROR_TID, 1000, &format!("other{i}"));
+            other.set_mpos(1050);
+            records.push(other);
+        }
+        let target_regions = vec![
+            Region::new("chrQ_mirror", 100, 200, "SG_1"),
+            Region::new("chrQ_mirror", 1000, 1100, "other"),
+        ];
+        for exclude_uncalibrated_reads in [false, true] {
+            let config = Config {
+                flank: 0,
+                fold_coverage: 10,
+                exclude_uncalibrated_reads,
+                name_prefix: Some("SG_".to_string()),
+                ..Config::default()
+            };
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                None,
+                &config,
+                None,
+            )
+            .unwrap();
+            let written = |prefix: &[u8]| {
+                writer
+                    .records()
+                    .iter()
+                    .filter(|r| r.qname().starts_with(prefix))
+                    .count()
+            };
+            // The SG_ region is downsampled from 40X to about 10X; the other
+            // region is copied as is.
+            let calibrated = written(b"sg");
+            assert!((1..40).contains(&calibrated), "{calibrated} reads kept");
+            let expected = if exclude_uncalibrated_reads { 0 } else { 40 };
+            assert_eq!(written(b"other"), expected);
+
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let reports =
+                report_with_config(&mut reader, &target_regions, None, &config, None).unwrap();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].region.name, "SG_1");
+        }
+    }
+
+    #[test]
+    fn test_calibrate_with_config_annotate_probability() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            annotate_probability: true,
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records.clone());
+        let expected = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            FoldCoverage::All(10.0),
+            false,
+            None,
+        )
+        .unwrap()["region1"];
+        assert_eq!(expected, 0.25);
+        let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            None,
+            &config,
+            None,
+        )
+        .unwrap();
+        let calibrated = writer
+            .records()
+            .iter()
+            .filter(|r| r.tid() == CHRQ_MIRROR_TID)
+            .collect::<Vec<_>>();
+        assert!(!calibrated.is_empty());
+        for record in calibrated {
+            assert_eq!(
+                record.aux(PROBABILITY_TAG).unwrap(),
+                Aux::Float(expected as f32)
+            );
+        }
+        let sample = writer
+            .records()
+            .iter()
+            .find(|r| r.qname() == b"sample")
+            .unwrap();
+        assert!(sample.aux(PROBABILITY_TAG).is_err());
+    }
+
+    #[test]
+    fn test_calibrate_with_config_tag_origin() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            tag_origin: true,
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            None,
+            &config,
+            None,
+        )
+        .unwrap();
+        let origin = |record: &Record| match record.aux(ORIGIN_TAG).unwrap() {
+            Aux::Char(origin) => origin,
+            aux => panic!("unexpected {aux:?}"),
+        };
+        let (calibrated, copied): (Vec<_>, Vec<_>) = writer
+            .records()
+            .iter()
+            .partition(|r| r.tid() == CHRQ_MIRROR_TID);
+        assert!(!calibrated.is_empty());
+        assert!(calibrated.iter().all(|r| origin(r) == ORIGIN_CALIBRATED));
+        assert_eq!(copied.len(), 1);
+        assert_eq!(copied[0].qname(), b"sample");
+        assert_eq!(origin(copied[0]), ORIGIN_UNCALIBRATED);
+    }
+
+    #[test]
+    fn test_calibrate_with_config_tag_calibrated() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let mut unmapped = create_mock_record(-1, -1, "unmapped");
+        unmapped.set_unmapped();
+        records.push(unmapped);
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            tag_calibrated: true,
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            None,
+            &config,
+            None,
+        )
+        .unwrap();
+        let (calibrated, copied): (Vec<_>, Vec<_>) = writer
+            .records()
+            .iter()
+            .partition(|r| r.tid() == CHRQ_MIRROR_TID);
+        assert!(!calibrated.is_empty() && calibrated.len() < 40);
+        assert!(calibrated
+            .iter()
+            .all(|r| r.aux(CALIBRATED_TAG).unwrap() == Aux::I32(1)));
+        let copied_names = copied.iter().map(|r| r.qname()).collect::<Vec<_>>();
+        assert_eq!(copied_names, [b"sample".as_slice(), b"unmapped"]);
+        assert!(copied.iter().all(|r| r.aux(CALIBRATED_TAG).is_err()));
+    }
+
+    #[test]
+    fn test_calibrate_with_config_tag_calibrated_mates() {
+        // Singletons whose unmapped mate is placed next to them, and pairs
+        // with a mate on chr2: the mates are written with the calibrated
+        // reads but are not tagged.
+        let mut records = Vec::new();
+        for i in 0..20 {
+            let mut read = create_paired_mock_record(CHRQ_MIRROR_TID, 100, &format!("s{i}"));
+            read.set_mate_unmapped();
+            let mut mate = create_paired_mock_record(CHRQ_MIRROR_TID, 100, &format!("s{i}"));
+            mate.set_unmapped();
+            records.extend([read, mate]);
+            let mut read = create_paired_mock_record(CHRQ_MIRROR_TID, 120, &format!("p{i}"));
+            read.set_mtid(CHRQ_MIRROR_TID);
+            let mut mate = create_paired_mock_record(CHR2_TID, 500, &format!("p{i}"));
+            mate.set_mtid(CHRQ_MIRROR_TID);
+            records.extend([read, mate]);
+        }
+        records.sort_by_key(|r| (r.tid(), r.pos()));
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            tag_calibrated: true,
+            keep_singletons: Some(true),
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            None,
+            &config,
+            None,
+        )
+        .unwrap();
+        let tagged = |r: &Record| r.aux(CALIBRATED_TAG).is_ok();
+        let (calibrated, mates): (Vec<_>, Vec<_>) = writer
+            .records()
+            .iter()
+            .partition(|r| r.tid() == CHRQ_MIRROR_TID && !r.is_unmapped());
+        assert!(!calibrated.is_empty());
+        assert!(calibrated.iter().all(|r| tagged(r)));
+        assert!(mates.iter().any(|r| r.is_unmapped()));
+        assert!(mates.iter().any(|r| r.tid() == CHR2_TID));
+        assert!(mates.iter().all(|r| !tagged(r)));
+    }
+
+    #[test]
+    fn test_calibrate_with_config_passthrough_contigs() {
+        // chr1, chr2 and chr3 are uncalibrated; only chr1 and chr3 are
+        // allowed through.
+        let mut records = (0..3)
+            .map(|tid| create_mock_record(tid, 100, &format!("sample{tid}")))
+            .collect::<Vec<_>>();
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let mut unmapped = create_mock_record(-1, -1, "unmapped");
+        unmapped.set_unmapped();
+        records.push(unmapped);
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let written = |passthrough_contigs: Option<Vec<String>>| {
+            let config = Config {
+                flank: 0,
+                fold_coverage: 10,
+                passthrough_contigs,
+                ..Config::default()
+            };
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                None,
+                &config,
+                None,
+            )
+            .map(|()| {
+                writer
+                    .records()
+                    .iter()
+                    .filter(|r| !r.qname().starts_with(b"q"))
+                    .map(|r| String::from_utf8(r.qname().to_vec()).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            written(None).unwrap(),
+            ["sample0", "sample1", "sample2", "unmapped"]
+        );
+        let allowed = vec!["chr1".to_string(), "chr3".to_string()];
+        assert_eq!(
+            written(Some(allowed)).unwrap(),
+            ["sample0", "sample2", "unmapped"]
+        );
+        let err = written(Some(vec!["chrX".to_string()])).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Passthrough contig chrX not found in BAM header"));
+    }
+
+    #[test]
+    fn test_calibrate_with_config_keep_singletons() {
+        let mut records = Vec::new();
+        for i in 0..10 {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}"));
+            record.set_paired();
+            record.set_mtid(CHRQ_MIRROR_TID);
+            record.set_mpos(100);
+            records.push(record);
+        }
+        // A singleton whose unmapped mate is placed next to it, and one whose
+        // mate is unplaced.
+        let mut placed = create_mock_record(CHRQ_MIRROR_TID, 100, "placed");
+        placed.set_paired();
+        placed.set_mate_unmapped();
+        placed.set_mtid(CHRQ_MIRROR_TID);
+        placed.set_mpos(100);
+        records.push(placed);
+        let mut unplaced = create_mock_record(CHRQ_MIRROR_TID, 100, "unplaced");
+        unplaced.set_paired();
+        unplaced.set_mate_unmapped();
+        unplaced.set_mtid(-1);
+        unplaced.set_mpos(-1);
+        records.push(unplaced);
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let written = |keep_singletons: Option<bool>| {
+            let config = Config {
+                flank: 0,
+                fold_coverage: 12,
+                keep_singletons,
+                ..Config::default()
+            };
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                None,
+                &config,
+                None,
+            )
+            .unwrap();
+            writer
+                .records()
+                .iter()
+                .map(|r| String::from_utf8(r.qname().to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let paired = (0..10).map(|i| format!("q{i}")).collect::<Vec<_>>();
+        assert_eq!(
+            written(None),
+            [paired.clone(), vec!["placed".to_string()]].concat()
+        );
+        assert_eq!(
+            written(Some(true)),
+            [
+                paired.clone(),
+                vec!["placed".to_string(), "unplaced".to_string()]
+            ]
+            .concat()
+        );
+        assert_eq!(written(Some(false)), paired);
+    }
+
+    #[test]
+    fn test_calibrate_with_timings() {
+        let mut records = Vec::new();
+        for i in 0..5 {
+            records.push(create_mock_record(CHR1_TID, 100, &format!("s{i}")));
+        }
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            ..Config::default()
+        };
+        let stage_names = |sample_regions: Option<&[Region]>| {
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            let mut timings = Timings::new();
+            calibrate_with_timings(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                sample_regions,
+                &config,
+                None,
+                Some(&mut timings),
+            )
+            .unwrap();
+            timings
+                .stages()
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(stage_names(None), ["selection", "write"]);
+        assert_eq!(
+            stage_names(Some(&sample_regions)),
+            ["probabilities", "selection", "write"]
+        );
+    }
+
+    #[test]
+    fn test_select_reads_single_pass_matches_two_pass() {
+        let mut records = Vec::new();
+        for i in 0..60 {
+            // Pairs within the first region, across both regions and within
+            // the second.
+            let (pos, mpos) = match i % 3 {
+                0 => (100 + i, 150 + i),
+                1 => (120 + i, 420 + i),
+                _ => (400 + i, 450 + i),
+            };
+            for (pos, mpos) in [(pos, mpos), (mpos, pos)] {
+                let mut record = create_mock_record(CHRQ_MIRROR_TID, pos, &format!("pair{i}"));
+                record.set_mtid(CHRQ_MIRROR_TID);
+                record.set_mpos(mpos);
+                records.push(record);
+            }
+        }
+        records.sort_by_key(|r| r.pos());
+        let mut targeted = Region::new("chrQ_mirror", 400, 600, "region2");
+        targeted.target = Some(TargetCoverage(1000.0));
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 300, "region1"), targeted];
+        let sequin_tids = HashSet::from([CHRQ_MIRROR_TID]);
+        let fold_coverage = FoldCoverage::PerRegion(15.0);
+
+        let mut reader = create_mock_reader_with_records(records);
+        let (mut keep, mut read_probabilities) = (HashSet::new(), HashMap::new());
+        let probabilities = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            fold_coverage,
+            false,
+            None,
+        );
+        // The second region's target is above its coverage.
+        assert!(probabilities.is_err());
+        let selection = Selection {
+            keep: &mut keep,
+            sequin_tids: &sequin_tids,
+            seed: 42,
+            keep_singletons: None,
+            read_probabilities: Some(&mut read_probabilities),
+            progress: None,
+        };
+        assert!(select_reads_single_pass(
+            &mut reader,
+            &target_regions,
+            fold_coverage,
+            false,
+            selection
+        )
+        .is_err());
+
+        for fold_coverage in [FoldCoverage::All(5.0), FoldCoverage::All(10.0)] {
+            let two_pass = {
+                let (mut keep, mut read_probabilities) = (HashSet::new(), HashMap::new());
+                let probabilities = determine_downsampling_probabilities(
+                    &mut reader,
+                    &target_regions,
+                    None,
+                    None,
+                    fold_coverage,
+                    false,
+                    None,
+                )
+                .unwrap();
+                let selection = Selection {
+                    keep: &mut keep,
+                    sequin_tids: &sequin_tids,
+                    seed: 42,
+                    keep_singletons: None,
+                    read_probabilities: Some(&mut read_probabilities),
+                    progress: None,
+                };
+                select_reads(&mut reader, &target_regions, &probabilities, selection).unwrap();
+                (keep, read_probabilities)
+            };
+            let (mut keep, mut read_probabilities) = (HashSet::new(), HashMap::new());
+            let selection = Selection {
+                keep: &mut keep,
+                sequin_tids: &sequin_tids,
+                seed: 42,
+                keep_singletons: None,
+                read_probabilities: Some(&mut read_probabilities),
+                progress: None,
+            };
+            select_reads_single_pass(
+                &mut reader,
+                &target_regions,
+                fold_coverage,
+                false,
+                selection,
+            )
+            .unwrap();
+            assert!(!keep.is_empty());
+            assert!(keep.len() < 60);
+            assert_eq!((keep, read_probabilities), two_pass);
         }
     }
-    Ok(n)
-}
 
-/// Retrieves all records that start within a specified region.
-///
-/// # Arguments
-/// - `reader`: A mutable reference to a BAM reader.
-/// - `contig`: The contig name.
-/// - `beg`: Start position.
-/// - `end`: End position.
-///
-/// # Returns
-/// A `Result` containing a vector of BAM records.
-fn records_that_start_in_region<R: BamReader>(
-    reader: &mut R,
-    contig: &str,
-    beg: u64,
-    end: u64,
-) -> Result<Vec<Record>> {
-    let mut records = Vec::new();
-    reader.fetch((contig, beg, end))?;
-    for result in reader.records() {
-        let record = result?;
-        let pos = record.pos();
-        if pos < beg as i64 || pos > end as i64 {
-            continue;
+    #[test]
+    fn test_calibrate_with_config_allow_upsample() {
+        let mut records = Vec::new();
+        for i in 0..20 {
+            for _ in 0..2 {
+                let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}"));
+                record.set_mtid(CHRQ_MIRROR_TID);
+                record.set_mpos(100);
+                records.push(record);
+            }
         }
-        records.push(record);
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 100,
+            ..Config::default()
+        };
+        let calibrate = |config: &Config| {
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                None,
+                config,
+                None,
+            )
+            .map(|_| writer.records().to_vec())
+        };
+        // A coverage of 40 cannot be brought up to 100 without upsampling.
+        assert!(calibrate(&config).is_err());
+
+        let config = Config {
+            allow_upsample: true,
+            ..config
+        };
+        let written = calibrate(&config).unwrap();
+        assert_eq!(written, calibrate(&config).unwrap());
+        let (copies, originals): (Vec<_>, Vec<_>) = written.iter().partition(|r| r.is_duplicate());
+        assert_eq!(originals.len(), 40);
+        // Each pair is written 2.5 times on average: with 1 or 2 extra copies
+        // of both mates.
+        assert!((40..=80).contains(&copies.len()), "{}", copies.len());
+        let mut copies_by_name: HashMap<&[u8], usize> = HashMap::new();
+        for copy in &copies {
+            let name = std::str::from_utf8(copy.qname()).unwrap();
+            assert!(name.ends_with("_up1") || name.ends_with("_up2"), "{name}");
+            *copies_by_name.entry(copy.qname()).or_default() += 1;
+        }
+        // Both mates of a pair are copied together.
+        assert!(copies_by_name.values().all(|&n| n == 2));
     }
-    Ok(records)
-}
 
-/// Randomly selects a subset of indices from a range.
-///
-/// If `n` is greater than `size`, all indices are returned.
-///
-/// # Arguments
-/// - `size`: The total number of items.
-/// - `n`: The number to select.
-/// - `seed`: Random seed.
-///
-/// # Returns
-/// A `Result` containing the selected indices.
-fn choose_from(size: u64, n: u64, seed: u64) -> Vec<u64> {
-    let mut rng = Pcg32::seed_from_u64(seed);
-    (0..size).sample(&mut rng, n as usize)
-}
+    #[test]
+    fn test_report_with_config_target_above_coverage() {
+        let records = (0..40)
+            .map(|i| create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")))
+            .collect();
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 60,
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records);
+        let err =
+            report_with_config(&mut reader, &target_regions, None, &config, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "calibration error: Target mean coverage for region region1 is less than sample mean coverage (40 < 60)"
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::bam::{MockBamReader, MockBamWriter};
-    use crate::region::Region;
-    use rust_htslib::bam::record::{Cigar, CigarString};
-    use rust_htslib::bam::Record;
-    use std::collections::HashMap;
+    #[test]
+    fn test_report_with_config_matches_calibration() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        for i in 0..40 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let config = Config {
+            flank: 0,
+            fold_coverage: 10,
+            ..Config::default()
+        };
+        let mut reader = create_mock_reader_with_records(records.clone());
+        let reports =
+            report_with_config(&mut reader, &target_regions, None, &config, None).unwrap();
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!(report.mean_coverage, 40.0);
+        assert_eq!(report.probability, 0.25);
+        assert_eq!(report.expected_coverage(), 10.0);
+        assert_eq!(report.reads, 40);
 
-    const CHR1_TID: i32 = 0;
-    const CHRQ_MIRROR_TID: i32 = 3;
+        let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            None,
+            &config,
+            None,
+        )
+        .unwrap();
+        let written = writer
+            .records()
+            .iter()
+            .filter(|r| r.tid() == CHRQ_MIRROR_TID)
+            .count();
+        assert_eq!(report.kept_reads, written as u64);
 
-    /// Helper function to create a mock BAM record
-    fn create_mock_record(tid: i32, pos: i64, qname: &str) -> Record {
-        let mut record = Record::new();
-        record.set_tid(tid);
-        record.set_pos(pos);
-        record.unset_unmapped();
-        record.set_qname(qname.as_bytes());
-        // let seq = b"A".repeat(100);
-        let cigar_string = CigarString(vec![Cigar::Match(100)]);
-        record.set_cigar(Some(&cigar_string));
-        record.set_mtid(tid);
-        record
+        let mut output = Vec::new();
+        write_report(&reports, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "name,chrom,beg,end,mean,probability,expected_mean,reads,kept_reads\n\
+                 region1,chrQ_mirror,100,200,40.00,0.2500,10.00,40,{written}\n"
+            )
+        );
+
+        let profile = Config {
+            experimental: true,
+            ..config
+        };
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+        assert!(report_with_config(
+            &mut reader,
+            &target_regions,
+            Some(&sample_regions),
+            &profile,
+            None
+        )
+        .is_err());
     }
 
-    /// Helper function to create a mock reader with records
-    fn create_mock_reader_with_records(records: Vec<Record>) -> MockBamReader {
-        // Use default header to avoid header format issues
-        MockBamReader::new(records, None)
+    #[test]
+    fn test_calibrate_with_config_excludes_uncalibrated_reads() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+        for experimental in [false, true] {
+            let config = Config {
+                experimental,
+                min_mapq: 0,
+                exclude_uncalibrated_reads: true,
+                ..Config::default()
+            };
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                Some(&sample_regions),
+                &config,
+                None,
+            )
+            .unwrap();
+            assert!(
+                writer.records().iter().all(|r| r.tid() == CHRQ_MIRROR_TID),
+                "experimental = {experimental}"
+            );
+        }
     }
 
     #[test]
-    fn test_calibrate_fixed_coverage_mode() {
-        let records = vec![
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read1"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read2"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read3"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read4"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read5"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read6"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read7"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read8"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read9"),
-            create_mock_record(CHRQ_MIRROR_TID, 100, "read10"),
-        ];
+    fn test_calibrate_sample_profile_mode_tags() {
+        let mut records = vec![create_mock_record(CHR1_TID, 100, "sample")];
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+        let config = Config {
+            experimental: true,
+            min_mapq: 0,
+            tag_origin: true,
+            tag_calibrated: true,
+            ..Config::default()
+        };
         let mut reader = create_mock_reader_with_records(records);
         let mut writer = MockBamWriter::new();
+        calibrate_with_config(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            Some(&sample_regions),
+            &config,
+            None,
+        )
+        .unwrap();
+        let (selected, copied): (Vec<_>, Vec<_>) = writer
+            .records()
+            .iter()
+            .partition(|r| r.tid() == CHRQ_MIRROR_TID);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(
+            selected[0].aux(ORIGIN_TAG).unwrap(),
+            Aux::Char(ORIGIN_CALIBRATED)
+        );
+        assert_eq!(selected[0].aux(CALIBRATED_TAG).unwrap(), Aux::I32(1));
+        assert_eq!(copied.len(), 1);
+        assert_eq!(
+            copied[0].aux(ORIGIN_TAG).unwrap(),
+            Aux::Char(ORIGIN_UNCALIBRATED)
+        );
+        assert!(copied[0].aux(CALIBRATED_TAG).is_err());
+    }
+
+    #[test]
+    fn test_resolve_seed() {
+        assert_eq!(resolve_seed(1234).unwrap(), 1234);
+
+        let seed = resolve_seed(0).unwrap();
+        assert_ne!(seed, 0);
 
+        // The resolved seed reproduces the run.
+        let records = (0..100)
+            .map(|i| create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")))
+            .collect::<Vec<_>>();
         let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
-        let mode = CalibrationMode::FixedCoverage {
-            fold_coverage: 10,
-            seed: 42,
+        let config = Config {
+            flank: 0,
+            seed,
+            ..Config::default()
+        };
+        let run = || {
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                None,
+                &config,
+                None,
+            )
+            .unwrap();
+            writer
+                .records()
+                .iter()
+                .map(|r| r.qname().to_vec())
+                .collect::<Vec<_>>()
         };
+        let first = run();
+        assert!(!first.is_empty() && first.len() < records.len());
+        assert_eq!(run(), first);
+    }
 
-        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false);
-        assert!(result.is_ok());
+    #[test]
+    fn test_salted_seed() {
+        assert_eq!(salted_seed(1234, 0), 1234);
+        assert_ne!(salted_seed(1234, 1), 1234);
+        assert_ne!(salted_seed(1234, 1), salted_seed(1234, 2));
+        assert_eq!(salted_seed(1234, 7), salted_seed(1234, 7));
 
-        // Should have processed records (may be 0 or more)
-        let _ = writer.records();
+        let records = (0..100)
+            .map(|i| create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")))
+            .collect::<Vec<_>>();
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let run = |seed_salt| {
+            let config = Config {
+                flank: 0,
+                seed_salt,
+                ..Config::default()
+            };
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            calibrate_with_config(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                None,
+                &config,
+                None,
+            )
+            .unwrap();
+            writer
+                .records()
+                .iter()
+                .map(|r| r.qname().to_vec())
+                .collect::<HashSet<_>>()
+        };
+        let unsalted = run(0);
+        let first = run(1);
+        assert!(!first.is_empty() && first.len() < records.len());
+        assert_eq!(run(1), first);
+        assert_ne!(run(2), first);
+        assert_ne!(unsalted, first);
+    }
+
+    #[test]
+    fn test_calibrate_propagates_write_errors() {
+        let mut records = (0..10)
+            .map(|i| create_mock_record(CHR1_TID, 100 + i, &format!("sample{i}")))
+            .collect::<Vec<_>>();
+        for i in 0..50 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let mode = CalibrationMode::FixedCoverage {
+            fold_coverage: 40,
+            seed: 1,
+        };
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::failing_after(3);
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false, None);
+        assert!(matches!(
+            result,
+            Err(Error::Hts(rust_htslib::errors::Error::WriteRecord))
+        ));
+        assert_eq!(writer.records().len(), 3);
     }
 
     #[test]
@@ -710,7 +3483,7 @@ mod tests {
             fold_coverage: 5,
             seed: 42,
         };
-        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false);
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false, None);
         assert!(result.is_ok(), "Result: {:?}", result);
         assert_eq!(writer.records().len(), 4);
         // We should not have any records with mates on chr1
@@ -750,7 +3523,7 @@ mod tests {
             seed: 42,
         };
 
-        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, true);
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, true, None);
         assert!(result.is_ok());
         let records = writer.records();
         assert_eq!(records.len(), 5, "Records: ({}) {records:?}", records.len());
@@ -786,15 +3559,174 @@ mod tests {
             sample_regions: &sample_regions,
             window_size: 10,
             min_mapq: 20,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            profile_json: None,
+            profile_csv: None,
             seed: 42,
         };
 
-        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false);
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false, None);
         assert!(result.is_ok());
         // let records = writer.records();
         // assert_eq!(records.len(), 10);
     }
 
+    #[test]
+    fn test_calibrate_sample_profile_mode_is_sorted() {
+        // Profile matching selects the sequin reads before the final pass,
+        // but they must still be written in input order, after the reads of
+        // the contigs before them.
+        let mut records = (0..10)
+            .map(|i| create_mock_record(CHR1_TID, 100 + i, &format!("s{i}")))
+            .collect::<Vec<_>>();
+        records.push(create_mock_record(CHR1_TID + 1, 0, "chr2"));
+        records.extend(
+            (0..20).map(|i| create_mock_record(CHRQ_MIRROR_TID, 100 + i * 5, &format!("q{i}"))),
+        );
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+        let mode = CalibrationMode::SampleProfile {
+            sample_regions: &sample_regions,
+            window_size: 50,
+            min_mapq: 0,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            profile_json: None,
+            profile_csv: None,
+            seed: 42,
+        };
+        calibrate(&mut reader, &mut writer, &target_regions, mode, false, None).unwrap();
+
+        let written = writer
+            .records()
+            .iter()
+            .map(|r| (r.tid(), r.pos()))
+            .collect::<Vec<_>>();
+        assert_eq!(written.first(), Some(&(CHR1_TID, 100)));
+        assert!(written.contains(&(CHR1_TID + 1, 0)));
+        assert_eq!(written.last().map(|&(tid, _)| tid), Some(CHRQ_MIRROR_TID));
+        assert!(written.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_calibrate_contig_mean_coverage_mode() {
+        // chr1 (5000bp) has 100 x 100bp reads: a mean depth of 2x. The sequin
+        // region has 10x coverage, so 20% of its reads should be kept.
+        let mut records = (0..100)
+            .map(|i| create_mock_record(CHR1_TID, (i % 50) * 100, &format!("s{i}")))
+            .collect::<Vec<_>>();
+        for i in 0..10 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")));
+        }
+        let mut reader = create_mock_reader_with_records(records);
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+
+        let depth = coverage::contig_mean_depth(&mut reader, "chr1", 0).unwrap();
+        assert_eq!(depth, 2.0);
+        let probabilities = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            FoldCoverage::All(depth),
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(probabilities["region1"], 0.2);
+
+        let mut writer = MockBamWriter::new();
+        let mode = CalibrationMode::ContigMeanCoverage {
+            contig: "chr1",
+            seed: 42,
+        };
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, true, None);
+        assert!(result.is_ok(), "Result: {:?}", result);
+        assert!(writer.records().iter().all(|r| r.tid() == CHRQ_MIRROR_TID));
+        assert!(writer.records().len() < 10);
+    }
+
+    #[test]
+    fn test_calibrate_fixed_read_count_mode() {
+        // region1 has 1000 pairs and region2 50, both mates inside the
+        // region. Asked for 200 pairs, region1 keeps about 200 and region2
+        // keeps all of its pairs.
+        let pairs = |beg: i64, n: i64, prefix: &str| {
+            (0..n)
+                .flat_map(|i| {
+                    let name = format!("{prefix}{i}");
+                    [
+                        create_mock_record(CHRQ_MIRROR_TID, beg + i % 500, &name),
+                        create_mock_record(CHRQ_MIRROR_TID, beg + i % 500 + 200, &name),
+                    ]
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut records = pairs(0, 1000, "a");
+        records.extend(pairs(5000, 50, "b"));
+        let target_regions = vec![
+            Region::new("chrQ_mirror", 0, 1000, "region1"),
+            Region::new("chrQ_mirror", 5000, 6000, "region2"),
+        ];
+        let mut reader = create_mock_reader_with_records(records);
+        let sequin_tids = HashSet::from([CHRQ_MIRROR_TID]);
+        let probabilities =
+            read_count_probabilities(&mut reader, &target_regions, 200, &sequin_tids, None)
+                .unwrap();
+        assert_eq!(probabilities["region1"], 0.2);
+        assert_eq!(probabilities["region2"], 1.0);
+
+        let mut writer = MockBamWriter::new();
+        let mode = CalibrationMode::FixedReadCount {
+            target_pairs: 200,
+            seed: 42,
+        };
+        calibrate(&mut reader, &mut writer, &target_regions, mode, true, None).unwrap();
+        let kept = |prefix: &str| {
+            writer
+                .records()
+                .iter()
+                .filter(|r| r.qname().starts_with(prefix.as_bytes()))
+                .map(|r| r.qname().to_vec())
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        assert!((160..=240).contains(&kept("a")), "kept {}", kept("a"));
+        assert_eq!(kept("b"), 50);
+        // Both mates of every kept pair are written.
+        assert_eq!(writer.records().len(), 2 * (kept("a") + kept("b")));
+    }
+
+    #[test]
+    fn test_config_target_reads() {
+        let config = Config {
+            target_reads: Some(500),
+            seed: 1,
+            ..Config::default()
+        };
+        assert!(matches!(
+            config.mode(None).unwrap(),
+            CalibrationMode::FixedReadCount {
+                target_pairs: 500,
+                seed: 1
+            }
+        ));
+        let sample_regions = vec![Region::new("chr1", 0, 100, "region1")];
+        assert!(config.mode(Some(&sample_regions)).is_err());
+        let config = Config {
+            target_contig: Some("chr20".to_string()),
+            ..config
+        };
+        assert!(config.mode(None).is_err());
+    }
+
     #[test]
     fn test_calibrate_empty_target_regions() {
         let records = vec![];
@@ -807,7 +3739,7 @@ mod tests {
             seed: 42,
         };
 
-        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false);
+        let result = calibrate(&mut reader, &mut writer, &target_regions, mode, false, None);
         assert!(result.is_ok());
     }
 
@@ -830,7 +3762,15 @@ mod tests {
 
         let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
 
-        let result = determine_downsampling_probabilities(&mut reader, &target_regions, None, 5);
+        let result = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            FoldCoverage::All(5.0),
+            false,
+            None,
+        );
         assert!(result.is_ok(), "Expected Ok, got {:?}", result.err());
 
         let probabilities = result.unwrap();
@@ -849,7 +3789,15 @@ mod tests {
 
         let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
 
-        let result = determine_downsampling_probabilities(&mut reader, &target_regions, None, 10);
+        let result = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            FoldCoverage::All(10.0),
+            false,
+            None,
+        );
         assert!(result.is_err());
     }
 
@@ -882,7 +3830,10 @@ mod tests {
             &mut reader,
             &target_regions,
             Some(&sample_regions),
-            10,
+            None,
+            FoldCoverage::All(10.0),
+            false,
+            None,
         );
         assert!(result.is_ok());
 
@@ -892,6 +3843,48 @@ mod tests {
         assert!(p >= 0.0 && p <= 1.0, "Probability out of range: {}", p);
     }
 
+    #[test]
+    fn test_determine_downsampling_probabilities_sample_names_differ() {
+        let mut reader = create_mock_reader_with_records(vec![]);
+        let target_regions = vec![
+            Region::new("chrQ_mirror", 0, 100, "region1"),
+            Region::new("chrQ_mirror", 200, 300, "region2"),
+            Region::new("chrQ_mirror", 400, 500, "region3"),
+        ];
+        let sample_regions = vec![
+            Region::new("chr1", 0, 100, "region1"),
+            Region::new("chr1", 400, 500, "region3"),
+        ];
+        let err = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            Some(&sample_regions),
+            None,
+            FoldCoverage::All(0.0),
+            false,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "calibration error: Target and sample region names differ \
+             (target regions with no sample region: region2)"
+        );
+
+        let sample_regions = vec![
+            Region::new("chr1", 0, 100, "region1"),
+            Region::new("chr1", 200, 300, "region4"),
+            Region::new("chr1", 400, 500, "region5"),
+        ];
+        let err = check_sample_names(&target_regions, &sample_regions).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "calibration error: Target and sample region names differ \
+             (target regions with no sample region: region2, region3; \
+             sample regions with no target region: region4, region5)"
+        );
+    }
+
     #[test]
     fn test_determine_downsampling_probabilities_zero_coverage() {
         let records = vec![]; // No records means zero coverage
@@ -899,7 +3892,15 @@ mod tests {
 
         let target_regions = vec![Region::new("chrQ_mirror", 0, 1000, "region1")];
 
-        let result = determine_downsampling_probabilities(&mut reader, &target_regions, None, 10);
+        let result = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            None,
+            None,
+            FoldCoverage::All(10.0),
+            false,
+            None,
+        );
         // Should fail because target coverage is zero
         assert!(result.is_err());
     }
@@ -914,7 +3915,7 @@ mod tests {
 
         let regions = vec![Region::new("chrQ_mirror", 0, 1000, "region1")];
 
-        let result = regions_coverage(&mut reader, &regions);
+        let result = regions_coverage(&mut reader, &regions, None);
         assert!(result.is_ok());
 
         let coverage = result.unwrap();
@@ -922,6 +3923,47 @@ mod tests {
         assert!(coverage["region1"] >= 0.0);
     }
 
+    #[test]
+    fn test_regions_coverage_at_contig_start() {
+        // Reads at 0-99 and 50-149: the first 100 bases have a mean of 1.5.
+        let records = vec![
+            create_mock_record(CHR1_TID, 0, "read1"),
+            create_mock_record(CHR1_TID, 50, "read2"),
+        ];
+        let mut reader = create_mock_reader_with_records(records);
+        let regions = vec![Region::new("chr1", 0, 100, "start")];
+        let means = regions_coverage(&mut reader, &regions, None).unwrap();
+        assert_eq!(means["start"], 1.5);
+    }
+
+    #[test]
+    fn test_regions_coverage_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "sequintools-regions-coverage-cache-{}",
+            std::process::id()
+        ));
+        let bam = dir.join("sample.bam");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&bam, b"bam").unwrap();
+        let cache = CoverageCache::new(&dir.join("cache"), &bam).unwrap();
+        let regions = vec![Region::new("chrQ_mirror", 0, 1000, "region1")];
+        let records = vec![
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read1"),
+            create_mock_record(CHRQ_MIRROR_TID, 200, "read2"),
+        ];
+
+        let mut reader = create_mock_reader_with_records(records.clone());
+        let first = regions_coverage(&mut reader, &regions, Some(&cache)).unwrap();
+        assert_eq!(reader.fetch_count(), 1);
+
+        let mut reader = create_mock_reader_with_records(records);
+        let second = regions_coverage(&mut reader, &regions, Some(&cache)).unwrap();
+        assert_eq!(reader.fetch_count(), 0);
+        assert_eq!(first, second);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_regions_coverage_empty_regions() {
         let records = vec![];
@@ -929,7 +3971,7 @@ mod tests {
 
         let regions = vec![];
 
-        let result = regions_coverage(&mut reader, &regions);
+        let result = regions_coverage(&mut reader, &regions, None);
         assert!(result.is_ok());
 
         let coverage = result.unwrap();
@@ -980,13 +4022,28 @@ mod tests {
 
         let region = Region::new("chrQ_mirror", 0, 300, "region1");
 
-        let result = window_starts(&mut reader, &region, 100, 0);
+        let result = window_starts(&mut reader, &region, 100, 0, MateMapqPolicy::Either);
         assert!(result.is_ok());
 
         let starts = result.unwrap();
         assert_eq!(starts.len(), 3); // 3 windows of size 100
     }
 
+    #[test]
+    fn test_smooth_counts() {
+        let counts = vec![0, 10, 0, 10, 0];
+        assert_eq!(smooth_counts(&counts, 3), vec![5, 3, 7, 3, 5]);
+        assert_eq!(smooth_counts(&counts, 5), vec![3, 5, 4, 5, 3]);
+    }
+
+    #[test]
+    fn test_smooth_counts_width_one_is_identity() {
+        let counts = vec![4, 0, 9, 1];
+        assert_eq!(smooth_counts(&counts, 1), counts);
+        assert_eq!(smooth_counts(&counts, 0), counts);
+        assert!(smooth_counts(&[], 3).is_empty());
+    }
+
     #[test]
     fn test_starts_in() {
         let records = vec![
@@ -998,13 +4055,55 @@ mod tests {
 
         let region = Region::new("chrQ_mirror", 0, 200, "region1");
 
-        let result = starts_in(&mut reader, &region, 0);
+        let result = starts_in(&mut reader, &region, 0, MateMapqPolicy::Either);
         assert!(result.is_ok());
 
         let count = result.unwrap();
         assert_eq!(count, 2); // 2 reads in the region
     }
 
+    #[test]
+    fn test_negative_pos_records_skipped() {
+        let records = vec![
+            create_mock_record(CHRQ_MIRROR_TID, -1, "negative"),
+            create_mock_record(CHRQ_MIRROR_TID, 0, "read1"),
+            create_mock_record(CHRQ_MIRROR_TID, 50, "read2"),
+        ];
+        let mut reader = create_mock_reader_with_records(records);
+        let region = Region::new("chrQ_mirror", 0, 100, "region1");
+
+        assert_eq!(
+            starts_in(&mut reader, &region, 0, MateMapqPolicy::Either).unwrap(),
+            2
+        );
+        let starts = records_that_start_in_region(&mut reader, "chrQ_mirror", 0, 100).unwrap();
+        let names = starts.iter().map(|r| r.qname()).collect::<Vec<_>>();
+        assert_eq!(names, vec![b"read1", b"read2"]);
+
+        let sample_region_map = HashMap::from([("region1".to_string(), &region)]);
+        let params = SampleProfileParams {
+            window_size: 50,
+            min_mapq: 0,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
+            seed: 42,
+        };
+        let mut writer = MockBamWriter::new();
+        calibrate_regions(
+            &mut reader,
+            &mut writer,
+            std::slice::from_ref(&region),
+            &sample_region_map,
+            &params,
+            None,
+        )
+        .unwrap();
+        assert!(writer.records().iter().all(|r| r.qname() != b"negative"));
+    }
+
     #[test]
     fn test_starts_in_with_min_mapq() {
         let mut record1 = create_mock_record(CHRQ_MIRROR_TID, 100, "read1");
@@ -1016,13 +4115,46 @@ mod tests {
 
         let region = Region::new("chrQ_mirror", 0, 200, "region1");
 
-        let result = starts_in(&mut reader, &region, 20);
+        let result = starts_in(&mut reader, &region, 20, MateMapqPolicy::Either);
         assert!(result.is_ok());
 
         let count = result.unwrap();
         assert_eq!(count, 1); // Only 1 read meets the MAPQ threshold
     }
 
+    #[test]
+    fn test_starts_in_mate_mapq_policy() {
+        let mate = |pos, mapq, mate_mapq| {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, pos, "pair");
+            record.set_paired();
+            record.set_mapq(mapq);
+            record.push_aux(b"MQ", Aux::U8(mate_mapq)).unwrap();
+            record
+        };
+        // The first mate passes, its mate at 150 does not. A single-end read
+        // needs no MQ tag.
+        let mut single = create_mock_record(CHRQ_MIRROR_TID, 120, "single");
+        single.set_mapq(30);
+        let records = vec![mate(100, 30, 5), single, mate(150, 5, 30)];
+        let mut reader = create_mock_reader_with_records(records);
+        let region = Region::new("chrQ_mirror", 0, 200, "region1");
+
+        let either = starts_in(&mut reader, &region, 20, MateMapqPolicy::Either).unwrap();
+        assert_eq!(either, 2);
+        let both = starts_in(&mut reader, &region, 20, MateMapqPolicy::Both).unwrap();
+        assert_eq!(both, 1);
+
+        let mut untagged = create_mock_record(CHRQ_MIRROR_TID, 100, "untagged");
+        untagged.set_paired();
+        untagged.set_mapq(30);
+        let mut reader = create_mock_reader_with_records(vec![untagged]);
+        assert_eq!(
+            starts_in(&mut reader, &region, 20, MateMapqPolicy::Either).unwrap(),
+            1
+        );
+        assert!(starts_in(&mut reader, &region, 20, MateMapqPolicy::Both).is_err());
+    }
+
     #[test]
     fn test_records_that_start_in_region() {
         let records = vec![
@@ -1070,6 +4202,64 @@ mod tests {
         assert!(result.is_empty());
     }
 
+    #[test]
+    fn test_determine_downsampling_probabilities_sample_percentile() {
+        // Target region1 is at 20x.
+        let mut records = (0..20)
+            .map(|i| create_mock_record(CHRQ_MIRROR_TID, 100, &format!("q{i}")))
+            .collect::<Vec<_>>();
+        // The sample region is at 4x for its first half and 8x for the second,
+        // so its mean (6x) and 25th percentile (4x) differ.
+        for i in 0..4 {
+            records.push(create_mock_record(CHR1_TID, 100, &format!("a{i}")));
+            records.push(create_mock_record(CHR1_TID, 150, &format!("b{i}")));
+        }
+        let mut reader = create_mock_reader_with_records(records);
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let sample_regions = vec![Region::new("chr1", 100, 200, "region1")];
+
+        let sample = coverage::coverage_for_region(
+            &mut reader,
+            &sample_regions[0],
+            0,
+            0,
+            &coverage::ReadFilters::default(),
+        )
+        .unwrap();
+        let percentile = sample.percentile(25.0).unwrap() as f64;
+        assert_eq!(percentile, 4.0);
+
+        let probabilities = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            Some(&sample_regions),
+            Some(25.0),
+            FoldCoverage::All(0.0),
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(probabilities["region1"], percentile / 20.0);
+
+        let config = Config {
+            target_percentile: Some(25.0),
+            ..Config::default()
+        };
+        assert!(matches!(
+            config.mode(Some(&sample_regions)).unwrap(),
+            CalibrationMode::SamplePercentileCoverage {
+                percentile: 25.0,
+                ..
+            }
+        ));
+        assert!(config.mode(None).is_err());
+        let config = Config {
+            target_percentile: Some(101.0),
+            ..Config::default()
+        };
+        assert!(config.mode(Some(&sample_regions)).is_err());
+    }
+
     #[test]
     fn test_calibrate_by_fixed_coverage() {
         let records = vec![
@@ -1089,18 +4279,127 @@ mod tests {
         let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
         let mut keep = HashSet::new();
         let sequin_tids = [CHRQ_MIRROR_TID].iter().cloned().collect::<HashSet<_>>();
+        let selection = Selection {
+            keep: &mut keep,
+            sequin_tids: &sequin_tids,
+            seed: 42,
+            keep_singletons: None,
+            read_probabilities: None,
+            progress: None,
+        };
         let result = calibrate_by_fixed_coverage(
             &mut reader,
             &target_regions,
+            CoverageGoal::Fold(FoldCoverage::All(5.0)),
+            false,
+            None,
+            selection,
             None,
-            &mut keep,
-            &sequin_tids,
-            5,
-            42,
         );
         assert!(result.is_ok(), "Expected Ok, got Err: {:?}", result.err());
     }
 
+    #[test]
+    fn test_calibrate_by_fixed_coverage_fast_path_matches_subsample() {
+        let mut records = Vec::new();
+        for i in 0..20 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 100, &format!("a{i}")));
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 1100, &format!("b{i}")));
+        }
+        // Pairs spanning both regions exercise the `considered` handling.
+        for i in 0..5 {
+            records.push(create_mock_record(CHRQ_MIRROR_TID, 1100, &format!("a{i}")));
+        }
+        // Sample coverage for region2 is 10x against a target of 25x.
+        for i in 0..10 {
+            records.push(create_mock_record(CHR1_TID, 0, &format!("s{i}")));
+        }
+        let mut reader = create_mock_reader_with_records(records);
+        let target_regions = vec![
+            Region::new("chrQ_mirror", 100, 200, "region1"),
+            Region::new("chrQ_mirror", 1100, 1200, "region2"),
+        ];
+        let sequin_tids = HashSet::from([CHRQ_MIRROR_TID]);
+        let probabilities = determine_downsampling_probabilities(
+            &mut reader,
+            &target_regions,
+            Some(&[
+                Region::new("chrQ_mirror", 100, 200, "region1"),
+                Region::new("chr1", 0, 100, "region2"),
+            ]),
+            None,
+            FoldCoverage::All(0.0),
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(probabilities["region1"], 1.0);
+        assert_eq!(probabilities["region2"], 0.4);
+
+        // Reference result: every read goes through `subsample`.
+        let mut expected = HashSet::new();
+        let mut rng = Pcg32::seed_from_u64(42);
+        let mut considered = HashSet::new();
+        for region in &target_regions {
+            reader
+                .fetch((&region.contig, region.beg, region.end))
+                .unwrap();
+            for record in reader.records() {
+                let record = record.unwrap();
+                let p = probabilities[&region.name];
+                subsample(&record, &mut expected, &considered, p, &mut rng);
+                considered.insert(record.qname().to_vec());
+            }
+        }
+
+        let mut keep = HashSet::new();
+        let sample_regions = [
+            Region::new("chrQ_mirror", 100, 200, "region1"),
+            Region::new("chr1", 0, 100, "region2"),
+        ];
+        let selection = Selection {
+            keep: &mut keep,
+            sequin_tids: &sequin_tids,
+            seed: 42,
+            keep_singletons: None,
+            read_probabilities: None,
+            progress: None,
+        };
+        let goal = CoverageGoal::Sample {
+            regions: &sample_regions,
+            percentile: None,
+        };
+        calibrate_by_fixed_coverage(
+            &mut reader,
+            &target_regions,
+            goal,
+            false,
+            None,
+            selection,
+            None,
+        )
+        .unwrap();
+        assert_eq!(keep, expected);
+        assert!((0..20).all(|i| keep.contains(format!("a{i}").as_bytes())));
+    }
+
+    #[test]
+    fn test_keep_unconditionally() {
+        let record = create_mock_record(CHRQ_MIRROR_TID, 100, "read1");
+        let mut hash = HashSet::new();
+        let mut considered = HashSet::new();
+        assert!(keep_unconditionally(&record, &mut hash, &considered));
+        assert!(hash.contains(b"read1".as_slice()));
+        // Already kept: no draw needed.
+        assert!(!keep_unconditionally(&record, &mut hash, &considered));
+
+        // Previously rejected reads stay rejected.
+        let record2 = create_mock_record(CHRQ_MIRROR_TID, 100, "read2");
+        considered.insert(b"read2".to_vec());
+        assert!(!keep_unconditionally(&record2, &mut hash, &considered));
+        assert!(!hash.contains(b"read2".as_slice()));
+    }
+
     #[test]
     fn test_calibrate_by_sample_profile() {
         let records = vec![
@@ -1128,6 +4427,11 @@ mod tests {
         let params = SampleProfileParams {
             window_size: 100,
             min_mapq: 20,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
             seed: 42,
         };
 
@@ -1137,10 +4441,65 @@ mod tests {
             &target_regions,
             &sample_regions,
             &params,
+            None,
         );
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_calibrate_by_sample_profile_two_contigs() {
+        let records = vec![
+            create_mock_record(CHR1_TID, 100, "sample1"),
+            create_mock_record(CHR1_TID, 300, "target2"),
+            create_mock_record(CHR1_TID, 500, "sample2"),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "target1"),
+        ];
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+
+        let target_regions = vec![
+            Region::new("chrQ_mirror", 100, 200, "region1"),
+            Region::new("chr1", 300, 400, "region2"),
+        ];
+        let sample_regions = vec![
+            Region::new("chr1", 100, 200, "region1"),
+            Region::new("chr1", 500, 600, "region2"),
+        ];
+        let params = SampleProfileParams {
+            window_size: 100,
+            min_mapq: 0,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
+            seed: 42,
+        };
+        // Twice the target length, so that calibrating every region more than
+        // once would show as complete.
+        let mut progress = Progress::new(400);
+
+        let profiles = calibrate_by_sample_profile(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            &sample_regions,
+            &params,
+            Some(&mut progress),
+        )
+        .unwrap();
+        // One profile per region, in header contig order.
+        let names = profiles.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["region2", "region1"]);
+        assert_eq!(progress.fraction(), 0.5);
+        let written = writer
+            .records()
+            .iter()
+            .map(|r| r.qname().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(written, [b"target2".to_vec(), b"target1".to_vec()]);
+    }
+
     #[test]
     fn test_calibrate_regions() {
         let records = vec![
@@ -1154,18 +4513,380 @@ mod tests {
         let sample_region = Region::new("chrQ_mirror", 0, 300, "sample1");
         let sample_region_map = HashMap::from([("region1".to_string(), &sample_region)]);
 
+        let params = SampleProfileParams {
+            window_size: 100,
+            min_mapq: 20,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
+            seed: 42,
+        };
         let result = calibrate_regions(
             &mut reader,
             &mut writer,
             &target_regions,
             &sample_region_map,
-            100,
-            20,
-            42,
+            &params,
+            None,
         );
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_calibrate_regions_uniform_profile() {
+        // All 40 sample reads start in the first window, so the sample profile
+        // is maximally skewed; a uniform target spreads them evenly.
+        let mut records = (0..40)
+            .map(|i| create_mock_record(CHR1_TID, 10, &format!("sample{i}")))
+            .collect::<Vec<_>>();
+        for window in 0..4 {
+            for j in 0..40 {
+                records.push(create_paired_mock_record(
+                    CHRQ_MIRROR_TID,
+                    window * 100 + j,
+                    &format!("q{window}_{j}"),
+                ));
+            }
+        }
+        let target_regions = vec![Region::new("chrQ_mirror", 0, 400, "region1")];
+        let sample_region = Region::new("chr1", 0, 400, "region1");
+        let sample_region_map = HashMap::from([("region1".to_string(), &sample_region)]);
+        let per_window = |profile_target| {
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            let params = SampleProfileParams {
+                window_size: 100,
+                min_mapq: 0,
+                profile_smoothing: 1,
+                keep_mates: false,
+                profile_target,
+                mate_mapq_policy: MateMapqPolicy::Either,
+                sample_starts: None,
+                seed: 42,
+            };
+            calibrate_regions(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                &sample_region_map,
+                &params,
+                None,
+            )
+            .unwrap();
+            let mut counts = vec![0; 4];
+            for record in writer.records() {
+                counts[record.pos() as usize / 100] += 1;
+            }
+            counts
+        };
+        // Mirrored, the sample's first window is the target's last.
+        assert_eq!(per_window(ProfileTarget::Sample), vec![0, 0, 0, 20]);
+        assert_eq!(per_window(ProfileTarget::Uniform), vec![5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_calibrate_regions_profile() {
+        // Sample reads start in the first, second and fourth windows.
+        let mut records = [(10, 4), (110, 8), (310, 2)]
+            .iter()
+            .flat_map(|&(pos, n)| {
+                (0..n).map(move |i| create_mock_record(CHR1_TID, pos, &format!("s{pos}_{i}")))
+            })
+            .collect::<Vec<_>>();
+        for window in 0..4 {
+            for j in 0..10 {
+                records.push(create_paired_mock_record(
+                    CHRQ_MIRROR_TID,
+                    window * 100 + j,
+                    &format!("q{window}_{j}"),
+                ));
+            }
+        }
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        let target_regions = vec![Region::new("chrQ_mirror", 0, 400, "region1")];
+        let sample_region = Region::new("chr1", 0, 400, "region1");
+        let sample_region_map = HashMap::from([("region1".to_string(), &sample_region)]);
+        let params = SampleProfileParams {
+            window_size: 100,
+            min_mapq: 0,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
+            seed: 42,
+        };
+        let profiles = calibrate_regions(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            &sample_region_map,
+            &params,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            profiles,
+            vec![RegionProfile {
+                name: "region1".to_string(),
+                sample_starts: vec![4, 8, 0, 2],
+                rev_sample_starts: vec![2, 0, 8, 4],
+                selected: vec![1, 0, 4, 2],
+            }]
+        );
+        assert_eq!(writer.records().len(), 7);
+
+        let mut json = Vec::new();
+        write_profile_json(&profiles, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"regions\":[{\"name\":\"region1\",\"sample_starts\":[4,8,0,2],\
+             \"rev_sample_starts\":[2,0,8,4],\"selected\":[1,0,4,2]}]}\n"
+        );
+    }
+
+    #[test]
+    fn test_calibrate_regions_profile_csv() {
+        // No sample reads in the BAM: the profile comes from the CSV, so the
+        // selection matches test_calibrate_regions_profile.
+        let mut records = Vec::new();
+        for window in 0..4 {
+            for j in 0..10 {
+                records.push(create_paired_mock_record(
+                    CHRQ_MIRROR_TID,
+                    window * 100 + j,
+                    &format!("q{window}_{j}"),
+                ));
+            }
+        }
+        let csv = "region,window_index,starts\n\
+                   region1,1,8\n\
+                   region1,0,4\n\
+                   region1,2,0\n\
+                   region1,3,2\n";
+        let precomputed = load_profile_csv(csv.as_bytes()).unwrap();
+        let target_regions = vec![Region::new("chrQ_mirror", 0, 400, "region1")];
+        let sample_region = Region::new("chr1", 0, 400, "region1");
+        let sample_region_map = HashMap::from([("region1".to_string(), &sample_region)]);
+        let calibrate_with = |window_size| {
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            let params = SampleProfileParams {
+                window_size,
+                min_mapq: 0,
+                profile_smoothing: 1,
+                keep_mates: false,
+                profile_target: ProfileTarget::Sample,
+                mate_mapq_policy: MateMapqPolicy::Either,
+                sample_starts: Some(&precomputed),
+                seed: 42,
+            };
+            calibrate_regions(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                &sample_region_map,
+                &params,
+                None,
+            )
+            .map(|profiles| (profiles, writer.records().len()))
+        };
+        let (profiles, written) = calibrate_with(100).unwrap();
+        assert_eq!(profiles[0].sample_starts, vec![4, 8, 0, 2]);
+        assert_eq!(profiles[0].selected, vec![1, 0, 4, 2]);
+        assert_eq!(written, 7);
+
+        // 200-base windows split the region into 2, not the CSV's 4.
+        let err = calibrate_with(200).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Profile CSV has 4 windows for region region1, but it has 2"));
+    }
+
+    #[test]
+    fn test_load_profile_csv() {
+        let csv = "region,window_index,starts\nr1,0,3\nr2,1,5\nr1,1,0\n\nr2,0,7\n";
+        let profiles = load_profile_csv(csv.as_bytes()).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles["r1"], vec![3, 0]);
+        assert_eq!(profiles["r2"], vec![7, 5]);
+
+        for (csv, msg) in [
+            ("name,window,starts\n", "Profile CSV header"),
+            ("", "Profile CSV header"),
+            (
+                "region,window_index,starts\nr1,0\n",
+                "Invalid profile CSV line 2",
+            ),
+            (
+                "region,window_index,starts\nr1,0,-1\n",
+                "Invalid profile CSV line 2",
+            ),
+            (
+                "region,window_index,starts\nr1,0,1\nr1,2,1\n",
+                "windows of region r1 must be numbered 0 to 1",
+            ),
+            (
+                "region,window_index,starts\nr1,0,1\nr1,0,2\n",
+                "windows of region r1 must be numbered 0 to 1",
+            ),
+        ] {
+            let err = load_profile_csv(csv.as_bytes()).unwrap_err();
+            assert!(err.to_string().contains(msg), "{csv:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_calibrate_single_end_reads() {
+        // 40 single-end reads give the region a mean coverage of 40; a target
+        // of 10 keeps about a quarter of them, not none or half as many.
+        let records = (0..40)
+            .map(|i| {
+                let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, &format!("se{i}"));
+                record.set_mtid(-1);
+                record.set_mpos(-1);
+                record
+            })
+            .collect::<Vec<_>>();
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        let target_regions = vec![Region::new("chrQ_mirror", 100, 200, "region1")];
+        let mode = CalibrationMode::FixedCoverage {
+            fold_coverage: 10,
+            seed: 42,
+        };
+        calibrate(&mut reader, &mut writer, &target_regions, mode, true, None).unwrap();
+        let kept = writer.records().len();
+        assert!((5..=15).contains(&kept), "kept {kept}");
+    }
+
+    #[test]
+    fn test_calibrate_regions_single_end() {
+        // Every sample read starts in the first window. Without mates, the
+        // target's last window selects as many reads as the sample has.
+        let mut records = (0..40)
+            .map(|i| create_mock_record(CHR1_TID, 10, &format!("sample{i}")))
+            .collect::<Vec<_>>();
+        for window in 0..4 {
+            for j in 0..60 {
+                let mut record = create_mock_record(
+                    CHRQ_MIRROR_TID,
+                    window * 100 + j,
+                    &format!("q{window}_{j}"),
+                );
+                record.set_mtid(-1);
+                records.push(record);
+            }
+        }
+        let mut reader = create_mock_reader_with_records(records);
+        let mut writer = MockBamWriter::new();
+        let target_regions = vec![Region::new("chrQ_mirror", 0, 400, "region1")];
+        let sample_region = Region::new("chr1", 0, 400, "region1");
+        let sample_region_map = HashMap::from([("region1".to_string(), &sample_region)]);
+        let params = SampleProfileParams {
+            window_size: 100,
+            min_mapq: 0,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
+            seed: 42,
+        };
+        let profiles = calibrate_regions(
+            &mut reader,
+            &mut writer,
+            &target_regions,
+            &sample_region_map,
+            &params,
+            None,
+        )
+        .unwrap();
+        assert_eq!(profiles[0].selected, vec![0, 0, 0, 40]);
+        assert_eq!(writer.records().len(), 40);
+    }
+
+    #[test]
+    fn test_records_to_select() {
+        let paired = create_paired_mock_record(CHRQ_MIRROR_TID, 0, "paired");
+        let single = create_mock_record(CHRQ_MIRROR_TID, 0, "single");
+        assert_eq!(records_to_select(9, &[&paired, &paired]), 4);
+        assert_eq!(records_to_select(9, &[&single, &single]), 9);
+        // Half paired: each selected record writes 1.5 reads on average.
+        assert_eq!(records_to_select(9, &[&paired, &single]), 6);
+        assert_eq!(records_to_select(9, &[]), 4);
+    }
+
+    #[test]
+    fn test_uniform_counts() {
+        assert_eq!(uniform_counts(&[40, 0, 0, 0]), vec![10, 10, 10, 10]);
+        assert_eq!(uniform_counts(&[1, 2]), vec![2, 2]);
+        assert!(uniform_counts(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_calibrate_regions_keep_mates() {
+        // pair1 starts in region1 and its mate in region2. The sample profile
+        // selects the only read in region1 and nothing in region2.
+        let records = vec![
+            create_mock_record(CHR1_TID, 10, "sample1"),
+            create_mock_record(CHR1_TID, 20, "sample2"),
+            create_mock_record(CHRQ_MIRROR_TID, 50, "pair1"),
+            create_mock_record(CHRQ_MIRROR_TID, 150, "pair1"),
+            create_mock_record(CHRQ_MIRROR_TID, 160, "other"),
+        ];
+        let target_regions = vec![
+            Region::new("chrQ_mirror", 0, 100, "region1"),
+            Region::new("chrQ_mirror", 100, 200, "region2"),
+        ];
+        let sample1 = Region::new("chr1", 0, 100, "region1");
+        let sample2 = Region::new("chr1", 200, 300, "region2");
+        let sample_region_map = HashMap::from([
+            ("region1".to_string(), &sample1),
+            ("region2".to_string(), &sample2),
+        ]);
+
+        let mut written = Vec::new();
+        for keep_mates in [false, true] {
+            let mut reader = create_mock_reader_with_records(records.clone());
+            let mut writer = MockBamWriter::new();
+            let params = SampleProfileParams {
+                window_size: 100,
+                min_mapq: 0,
+                profile_smoothing: 1,
+                keep_mates,
+                profile_target: ProfileTarget::Sample,
+                mate_mapq_policy: MateMapqPolicy::Either,
+                sample_starts: None,
+                seed: 42,
+            };
+            calibrate_regions(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                &sample_region_map,
+                &params,
+                None,
+            )
+            .unwrap();
+            written.push(
+                writer
+                    .records()
+                    .iter()
+                    .map(|r| (r.qname().to_vec(), r.pos()))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(written[0], vec![(b"pair1".to_vec(), 50)]);
+        assert_eq!(
+            written[1],
+            vec![(b"pair1".to_vec(), 50), (b"pair1".to_vec(), 150)]
+        );
+    }
+
     #[test]
     fn test_calibrate_regions_missing_sample() {
         let records = vec![];
@@ -1175,14 +4896,23 @@ mod tests {
         let target_regions = vec![Region::new("chrQ_mirror", 0, 300, "region1")];
         let sample_region_map = HashMap::new(); // Empty map
 
+        let params = SampleProfileParams {
+            window_size: 100,
+            min_mapq: 20,
+            profile_smoothing: 1,
+            keep_mates: false,
+            profile_target: ProfileTarget::Sample,
+            mate_mapq_policy: MateMapqPolicy::Either,
+            sample_starts: None,
+            seed: 42,
+        };
         let result = calibrate_regions(
             &mut reader,
             &mut writer,
             &target_regions,
             &sample_region_map,
-            100,
-            20,
-            42,
+            &params,
+            None,
         );
         assert!(result.is_err()); // Should fail due to missing sample region
     }
@@ -1,16 +1,35 @@
-use crate::bam::{BamReader, HtslibBamReader};
+use crate::bam::{is_singleton, record_start_u64, BamReader, HtslibBamReader};
 use crate::errors::{Error, Result};
 use crate::region;
 use crate::region::Region;
+use crate::timing::{self, Timings};
 use rayon::prelude::*;
-use rust_htslib::bam::record::Cigar;
+use rust_htslib::bam::record::{Aux, Cigar};
+use rust_htslib::bam::{FetchDefinition, Record};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::Write;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
 
+/// The per-base coverage of a region, from [`coverage_for_region`].
 #[derive(Debug)]
-pub(crate) struct RegionCoverage {
+pub struct RegionCoverage {
     pub(crate) region: Region,
     pub(crate) coverage: Vec<u32>,
+    /// Number of reads counted towards the coverage of this region.
+    pub(crate) reads: u64,
+    /// Number of secondary and supplementary records that would otherwise
+    /// count towards coverage (mapping quality and read filters pass).
+    pub(crate) secondary_reads: u64,
+    /// Number of primary, mapped reads overlapping the region, before any
+    /// other filtering.
+    pub(crate) overlapping_reads: u64,
+    /// Number of `overlapping_reads` flagged as duplicates.
+    pub(crate) duplicate_reads: u64,
+    /// Number of counted reads containing a reference skip (`N`) operation.
+    pub(crate) spliced_reads: u64,
 }
 
 impl RegionCoverage {
@@ -19,22 +38,37 @@ impl RegionCoverage {
         Self {
             region: Region::new(contig, start, end, name),
             coverage,
+            reads: 0,
+            secondary_reads: 0,
+            overlapping_reads: 0,
+            duplicate_reads: 0,
+            spliced_reads: 0,
         }
     }
 
+    /// The region the coverage was counted over.
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+
+    /// The coverage of each base of the region, in order.
+    pub fn depths(&self) -> &[u32] {
+        &self.coverage
+    }
+
     /// Get the minimum coverage value.
-    pub(crate) fn min(&self) -> Option<&u32> {
+    pub fn min(&self) -> Option<&u32> {
         self.coverage.iter().min()
     }
 
     /// Get the maximum coverage value.
-    pub(crate) fn max(&self) -> Option<&u32> {
+    pub fn max(&self) -> Option<&u32> {
         self.coverage.iter().max()
     }
 
     /// Calculate the mean of the coverage.
-    pub(crate) fn mean(&self) -> Option<f32> {
-        let total = self.coverage.iter().sum::<u32>() as f32;
+    pub fn mean(&self) -> Option<f32> {
+        let total = self.coverage.iter().map(|&v| v as u64).sum::<u64>() as f32;
         let n = self.coverage.len() as f32;
         if n == 0.0 {
             return None;
@@ -42,142 +76,771 @@ impl RegionCoverage {
         Some(total / n)
     }
 
-    /// Calculate the standard deviation of the coverage.
-    pub(crate) fn std(&self) -> Option<f32> {
-        match (self.mean(), self.coverage.len()) {
-            (Some(mu), n) if n > 0 => {
-                let variance = self
-                    .coverage
-                    .iter()
-                    .map(|v| {
-                        let diff = mu - (*v as f32);
-                        diff * diff
-                    })
-                    .sum::<f32>()
-                    / n as f32;
-                Some(variance.sqrt())
-            }
-            _ => None,
+    /// Calculate the mean of the coverage after discarding the lowest and
+    /// highest `fraction` of per-base depths.
+    ///
+    /// `fraction` must be in `[0, 0.5)`; a fraction of zero gives the plain
+    /// mean.
+    pub(crate) fn trimmed_mean(&self, fraction: f64) -> Option<f32> {
+        if !(0.0..0.5).contains(&fraction) || self.coverage.is_empty() {
+            return None;
+        }
+        let mut sorted = self.coverage.clone();
+        sorted.sort_unstable();
+        let k = (sorted.len() as f64 * fraction).floor() as usize;
+        let kept = &sorted[k..sorted.len() - k];
+        let total = kept.iter().map(|&v| v as u64).sum::<u64>() as f32;
+        Some(total / kept.len() as f32)
+    }
+
+    /// Calculate the `p`-th percentile of the per-base depths, interpolating
+    /// linearly between the closest ranks.
+    ///
+    /// `p` must be in `[0, 100]`.
+    pub(crate) fn percentile(&self, p: f64) -> Option<f32> {
+        if !(0.0..=100.0).contains(&p) || self.coverage.is_empty() {
+            return None;
         }
+        let mut sorted = self.coverage.clone();
+        sorted.sort_unstable();
+        let rank = p / 100.0 * (sorted.len() - 1) as f64;
+        let (lo, hi) = (sorted[rank.floor() as usize], sorted[rank.ceil() as usize]);
+        Some((lo as f64 + (hi as f64 - lo as f64) * rank.fract()) as f32)
+    }
+
+    /// Calculate the standard deviation of the coverage.
+    /// Reports use [`RegionCoverage::summary`] instead.
+    pub fn std(&self) -> Option<f32> {
+        self.moments().map(|(_, sd)| sd)
     }
 
     /// Calculate the coefficient of variation (CV) of the coverage.
-    pub(crate) fn cv(&self) -> Option<f32> {
-        match (self.std(), self.mean()) {
-            (Some(sd), Some(mu)) if mu > 0.0 => Some(sd / mu),
-            _ => None,
+    /// Reports use [`RegionCoverage::summary`] instead.
+    pub fn cv(&self) -> Option<f32> {
+        cv(self.moments())
+    }
+
+    /// The mean and standard deviation of the coverage, each computed once.
+    ///
+    /// The deviations are summed in a second pass about the mean, exactly as
+    /// [`RegionCoverage::mean`] and [`RegionCoverage::std`] always have, so
+    /// the results are bit-identical to computing them separately.
+    fn moments(&self) -> Option<(f32, f32)> {
+        let mu = self.mean()?;
+        let variance = self
+            .coverage
+            .iter()
+            .map(|v| {
+                let diff = mu - (*v as f32);
+                diff * diff
+            })
+            .sum::<f32>()
+            / self.coverage.len() as f32;
+        Some((mu, variance.sqrt()))
+    }
+
+    /// Calculate every summary statistic of the coverage at once, computing
+    /// the mean and standard deviation only once.
+    pub fn summary(&self) -> CoverageSummary {
+        let moments = self.moments();
+        CoverageSummary {
+            min: self.min().copied(),
+            max: self.max().copied(),
+            mean: moments.map(|(mu, _)| mu),
+            std: moments.map(|(_, sd)| sd),
+            cv: cv(moments),
+            sem: sem(moments, self.coverage.len()),
+            breadth: self.percent_above_threshold(1, ThresholdMode::Ge),
+        }
+    }
+
+    /// The number of reads in this region, counted according to `mode`.
+    pub(crate) fn read_count(&self, mode: CountMode) -> u64 {
+        match mode {
+            CountMode::Primary => self.reads,
+            CountMode::All => self.reads + self.secondary_reads,
+        }
+    }
+
+    /// The fraction of overlapping reads flagged as duplicates.
+    pub(crate) fn duplicate_rate(&self) -> Option<f64> {
+        if self.overlapping_reads == 0 {
+            return None;
         }
+        Some(self.duplicate_reads as f64 / self.overlapping_reads as f64)
     }
 
-    /// Calculate the percentage of bases above a certain coverage threshold.
-    pub(crate) fn percent_above_threshold(&self, threshold: u32) -> Option<f64> {
+    /// Calculate the fraction of bases whose coverage passes `threshold`,
+    /// compared according to `mode`.
+    pub fn percent_above_threshold(&self, threshold: u32, mode: ThresholdMode) -> Option<f64> {
         let n = self.coverage.len();
         if n == 0 {
             return None;
         }
-        let x = self.coverage.iter().filter(|&&x| x >= threshold).count();
+        let x = self
+            .coverage
+            .iter()
+            .filter(|&&x| mode.passes(x, threshold))
+            .count();
         Some(x as f64 / n as f64)
     }
 }
 
+/// The coefficient of variation from a mean and standard deviation, undefined
+/// for a zero mean.
+fn cv(moments: Option<(f32, f32)>) -> Option<f32> {
+    match moments {
+        Some((mu, sd)) if mu > 0.0 => Some(sd / mu),
+        _ => None,
+    }
+}
+
+/// The standard error of the mean of `n` values from their mean and standard
+/// deviation, undefined for fewer than two values.
+fn sem(moments: Option<(f32, f32)>, n: usize) -> Option<f32> {
+    match moments {
+        Some((_, sd)) if n > 1 => Some(sd / (n as f32).sqrt()),
+        _ => None,
+    }
+}
+
+/// Summary statistics of a region's coverage, from
+/// [`RegionCoverage::summary`]. Each is `None` where it is undefined, such as
+/// every statistic of an empty region or the CV of a region with zero mean.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoverageSummary {
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    pub mean: Option<f32>,
+    pub std: Option<f32>,
+    pub cv: Option<f32>,
+    /// Standard error of the mean, undefined for fewer than two bases.
+    pub sem: Option<f32>,
+    /// Fraction of bases covered by at least one read.
+    pub breadth: Option<f64>,
+}
+
+/// How a base's coverage is compared with a `--thresholds` value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThresholdMode {
+    /// Coverage greater than or equal to the threshold; columns `pct_ge_N`.
+    #[default]
+    Ge,
+    /// Coverage strictly greater than the threshold; columns `pct_gt_N`.
+    Gt,
+}
+
+impl ThresholdMode {
+    /// Whether `depth` passes `threshold` in this mode.
+    fn passes(self, depth: u32, threshold: u32) -> bool {
+        match self {
+            ThresholdMode::Ge => depth >= threshold,
+            ThresholdMode::Gt => depth > threshold,
+        }
+    }
+
+    /// The report column name for `threshold`.
+    fn column(self, threshold: u32) -> String {
+        match self {
+            ThresholdMode::Ge => format!("pct_ge_{threshold}"),
+            ThresholdMode::Gt => format!("pct_gt_{threshold}"),
+        }
+    }
+}
+
+/// An aux tag and the value it must have, parsed from `TAG=VALUE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagFilter {
+    pub tag: [u8; 2],
+    pub value: String,
+}
+
+impl TagFilter {
+    /// Returns true if the record carries the tag with this value. Integer
+    /// tags are compared numerically; string and character tags textually.
+    fn matches(&self, record: &Record) -> bool {
+        let as_int = || self.value.parse::<i64>().ok();
+        match record.aux(&self.tag) {
+            Ok(Aux::String(s)) => s == self.value,
+            Ok(Aux::Char(c)) => self.value.as_bytes() == [c],
+            Ok(Aux::I8(v)) => as_int() == Some(v as i64),
+            Ok(Aux::U8(v)) => as_int() == Some(v as i64),
+            Ok(Aux::I16(v)) => as_int() == Some(v as i64),
+            Ok(Aux::U16(v)) => as_int() == Some(v as i64),
+            Ok(Aux::I32(v)) => as_int() == Some(v as i64),
+            Ok(Aux::U32(v)) => as_int() == Some(v as i64),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TagFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Bedcov {
+            msg: format!("Invalid tag filter '{s}', expected TAG=VALUE"),
+        };
+        let (tag, value) = s.split_once('=').ok_or_else(invalid)?;
+        let tag: [u8; 2] = tag.as_bytes().try_into().map_err(|_| invalid())?;
+        Ok(Self {
+            tag,
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Returns the number of reference bases spanned by an alignment: the sum of
+/// the lengths of its reference-consuming CIGAR operations (`M`, `=`, `X`,
+/// `D` and `N`).
+pub(crate) fn record_ref_span(record: &Record) -> u64 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match *op {
+            Cigar::Match(len)
+            | Cigar::Equal(len)
+            | Cigar::Diff(len)
+            | Cigar::Del(len)
+            | Cigar::RefSkip(len) => len as u64,
+            Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => 0,
+        })
+        .sum()
+}
+
+/// Filters applied to reads before they count towards coverage.
+///
+/// The default excludes duplicates and applies no other filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadFilters {
+    /// Reads must match every one of these tags.
+    pub require_tags: Vec<TagFilter>,
+    /// Reads matching any of these tags are excluded.
+    pub exclude_tags: Vec<TagFilter>,
+    /// Minimum absolute insert size of properly-paired reads.
+    pub min_insert_size: Option<u64>,
+    /// Maximum absolute insert size of properly-paired reads.
+    pub max_insert_size: Option<u64>,
+    /// Exclude reads that are not properly paired.
+    pub require_proper_pair: bool,
+    /// Minimum aligned reference span, see [`record_ref_span`].
+    pub min_align_len: Option<u64>,
+    /// Exclude reads flagged as PCR or optical duplicates.
+    pub exclude_duplicates: bool,
+    /// Exclude singletons, paired reads whose mate is unmapped.
+    pub exclude_singletons: bool,
+}
+
+impl Default for ReadFilters {
+    fn default() -> Self {
+        Self {
+            require_tags: Vec::new(),
+            exclude_tags: Vec::new(),
+            min_insert_size: None,
+            max_insert_size: None,
+            require_proper_pair: false,
+            min_align_len: None,
+            exclude_duplicates: true,
+            exclude_singletons: false,
+        }
+    }
+}
+
+impl ReadFilters {
+    /// Filters that accept every read, duplicates included, for the coverage
+    /// calibration measures.
+    pub(crate) fn none() -> Self {
+        Self {
+            exclude_duplicates: false,
+            ..Self::default()
+        }
+    }
+
+    fn accepts(&self, record: &Record) -> bool {
+        self.require_tags.iter().all(|f| f.matches(record))
+            && !(self.exclude_duplicates && record.is_duplicate())
+            && !self.exclude_tags.iter().any(|f| f.matches(record))
+            && self.accepts_insert_size(record)
+            && self.accepts_mate(record)
+            && self
+                .min_align_len
+                .is_none_or(|min| record_ref_span(record) >= min)
+    }
+
+    /// Singletons, reads whose mate is unmapped, are only excluded when
+    /// `exclude_singletons` is set.
+    fn accepts_mate(&self, record: &Record) -> bool {
+        !self.exclude_singletons || !is_singleton(record)
+    }
+
+    /// Reads without a meaningful insert size (TLEN 0, or a mate on another
+    /// contig) are only excluded when proper pairs are required.
+    fn accepts_insert_size(&self, record: &Record) -> bool {
+        if !record.is_proper_pair() {
+            return !self.require_proper_pair;
+        }
+        let tlen = record.insert_size().unsigned_abs();
+        if tlen == 0 || record.mtid() != record.tid() {
+            return true;
+        }
+        self.min_insert_size.is_none_or(|min| tlen >= min)
+            && self.max_insert_size.is_none_or(|max| tlen <= max)
+    }
+}
+
+/// Returns true if a record contributes to coverage: it is a mapped, primary
+/// alignment with a mapping quality of at least `min_mapq` that passes the read
+/// filters.
+fn counts_toward_coverage(record: &Record, min_mapq: u8, read_filters: &ReadFilters) -> bool {
+    !(record.is_unmapped() || record.is_secondary() || record.is_supplementary())
+        && record.mapq() >= min_mapq
+        && read_filters.accepts(record)
+}
+
+/// Which records contribute to read counts. Coverage is always computed from
+/// primary alignments only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CountMode {
+    /// Count primary alignments only, i.e. the reads counted towards coverage.
+    #[default]
+    Primary,
+    /// Also count secondary and supplementary records passing the same mapping
+    /// quality and read filters.
+    All,
+}
+
+/// What each position's "coverage" counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoverageUnit {
+    /// Per-base depth: the number of reads with an aligned base (`M`, `=` or
+    /// `X`) at the position. Deletions and reference skips are not covered.
+    #[default]
+    Base,
+    /// Read overlap: the number of reads whose alignment spans the position,
+    /// from its first to its last aligned reference base, including any
+    /// deletions and reference skips in between.
+    Read,
+}
+
+/// How reads that cover the same position count towards its coverage, a
+/// single choice between double counting overlapping mates, counting each
+/// fragment once and reporting breadth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Every read counts, so both mates of an overlapping pair do.
+    #[default]
+    Reads,
+    /// The two mates of a proper pair count the bases they share once.
+    Fragments,
+    /// A position counts once if any read covers it, so the coverage is 0 or
+    /// 1 and the mean is the fraction of the region covered.
+    UniqueBases,
+}
+
+impl OverlapPolicy {
+    /// Whether the mates of a proper pair count their overlap once.
+    pub fn no_double_count(self) -> bool {
+        self == OverlapPolicy::Fragments
+    }
+
+    /// The depth cap to count with, given the requested `max_depth` (0 is
+    /// unlimited).
+    pub fn max_depth(self, max_depth: u32) -> u32 {
+        match self {
+            OverlapPolicy::UniqueBases => 1,
+            _ => max_depth,
+        }
+    }
+}
+
+/// Counts the per-base depth of `region` from the primary alignments with a
+/// mapping quality of at least `min_mapq` that pass `read_filters`.
+/// [`ReadFilters::default`] excludes duplicates, as `bedcov` does unless given
+/// `--keep-duplicates`.
+///
+/// `flank` bases are trimmed from each end of the region before counting,
+/// but the returned coverage still reports the untrimmed region. New callers
+/// should trim regions themselves with [`Region::trim`] and pass a `flank`
+/// of 0, as the argument is expected to go.
+///
+/// ```no_run
+/// use sequintools::bam::HtslibBamReader;
+/// use sequintools::coverage::ReadFilters;
+/// use sequintools::region::Region;
+///
+/// let mut reader = HtslibBamReader::from_path("sample.bam")?;
+/// let region = Region::new("chrQ_mirror", 200, 3200, "variant_1").trim(100)?;
+/// let coverage =
+///     sequintools::coverage_for_region(&mut reader, &region, 10, 0, &ReadFilters::default())?;
+/// println!("{:?} {:?}", coverage.mean(), coverage.cv());
+/// # Ok::<(), sequintools::errors::Error>(())
+/// ```
 // TODO: this shouldn't be accepting a `flank` argument. The regions should be trimmed prior to calling this function.
-pub(crate) fn coverage_for_region<T: BamReader>(
+pub fn coverage_for_region<T: BamReader>(
     bam_reader: &mut T,
     region: &Region,
     min_mapq: u8,
     flank: u64,
+    read_filters: &ReadFilters,
 ) -> Result<RegionCoverage> {
-    let beg = region.beg.checked_add(flank).ok_or_else(|| Error::Bedcov {
-        msg: format!(
-            "Region start + flank overflowed for region: {}:{}-{}",
-            region.contig, region.beg, region.end
-        ),
-    })?;
-    let end = region.end.checked_sub(flank).ok_or_else(|| Error::Bedcov {
-        msg: format!(
-            "Region end - flank underflowed for region: {}:{}-{}",
-            region.contig, region.beg, region.end
-        ),
-    })?;
-    if beg >= end {
-        return Err(Error::Bedcov {
-            msg: format!(
-                "Region start >= end after applying flank for region: {}:{}-{}",
-                region.contig, region.beg, region.end
-            ),
-        });
-    }
+    let options = CountOptions::bases(min_mapq, flank, read_filters);
+    coverage_for_region_in_unit(bam_reader, region, &options)
+}
+
+/// How [`coverage_for_region_in_unit`] counts coverage.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CountOptions<'a> {
+    pub(crate) min_mapq: u8,
+    pub(crate) flank: u64,
+    pub(crate) read_filters: &'a ReadFilters,
+    pub(crate) unit: CoverageUnit,
+    /// Cap on the depth counted at any position; 0 is unlimited.
+    pub(crate) max_depth: u32,
+    pub(crate) no_double_count: bool,
+    /// Minimum base quality of a counted base; 0 counts every base.
+    pub(crate) min_baseq: u8,
+}
 
-    let mut coverage = vec![0u32; (end - beg) as usize];
+impl<'a> CountOptions<'a> {
+    /// Per-base depth of the reads with a mapping quality of at least
+    /// `min_mapq` that pass `read_filters`, as [`coverage_for_region`] counts
+    /// it.
+    pub(crate) fn bases(min_mapq: u8, flank: u64, read_filters: &'a ReadFilters) -> Self {
+        Self {
+            min_mapq,
+            flank,
+            read_filters,
+            unit: CoverageUnit::Base,
+            max_depth: 0,
+            no_double_count: false,
+            min_baseq: 0,
+        }
+    }
+}
 
+/// Like [`coverage_for_region`], counting coverage as `options` say: in
+/// `unit`, with further reads not counted at a position once it reaches a
+/// depth of `max_depth` (0 leaves the depth unlimited).
+///
+/// In [`CoverageUnit::Base`], only aligned bases with a base quality of at
+/// least `min_baseq` are counted; 0 counts every base. Reads without
+/// qualities are counted in full.
+///
+/// With `no_double_count`, the two mates of a proper pair count each position
+/// they both cover once: the second mate seen skips the positions the first
+/// already counted. This holds the covered intervals of every first mate
+/// until its pair is seen, so memory grows with the number of fragments that
+/// overlap the region at once, instead of staying constant.
+pub(crate) fn coverage_for_region_in_unit<T: BamReader>(
+    bam_reader: &mut T,
+    region: &Region,
+    options: &CountOptions,
+) -> Result<RegionCoverage> {
+    let trimmed = region.trim(options.flank)?;
+    crate::bam::check_has_references(bam_reader.header())?;
     let tid = bam_reader
         .header()
         .tid(region.contig.as_bytes())
         .ok_or_else(|| Error::Bedcov {
             msg: format!("Chromosome {} not found in BAM header", region.contig),
         })?;
-    bam_reader.fetch((tid, beg, end))?;
+    bam_reader.fetch((tid, trimmed.beg, trimmed.end))?;
 
+    let mut accumulator = CoverageAccumulator::new(&trimmed, options);
     for result in bam_reader.records() {
-        let record = result?;
-        if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
-            continue;
+        accumulator.add(&result?);
+    }
+    Ok(accumulator.finish(region))
+}
+
+/// [`coverage_for_region`] of `region` from reads already fetched, with no
+/// minimum mapping quality, flank or read filters.
+///
+/// `records` must be the reads fetched from the region: reads outside it add
+/// no coverage but are still counted.
+pub(crate) fn coverage_for_records<'a, I>(records: I, region: &Region) -> RegionCoverage
+where
+    I: IntoIterator<Item = &'a Record>,
+{
+    let read_filters = ReadFilters::none();
+    let mut accumulator =
+        CoverageAccumulator::new(region, &CountOptions::bases(0, 0, &read_filters));
+    for record in records {
+        accumulator.add(record);
+    }
+    accumulator.finish(region)
+}
+
+/// Counts the coverage of a region read by read, for
+/// [`coverage_for_region_in_unit`].
+struct CoverageAccumulator<'a> {
+    beg: u64,
+    end: u64,
+    min_mapq: u8,
+    read_filters: &'a ReadFilters,
+    unit: CoverageUnit,
+    max_depth: u32,
+    no_double_count: bool,
+    min_baseq: u8,
+    coverage: Vec<u32>,
+    reads: u64,
+    secondary_reads: u64,
+    overlapping_reads: u64,
+    duplicate_reads: u64,
+    spliced_reads: u64,
+    // Reference intervals counted for the current read, and for each first
+    // mate whose pair is still to come.
+    blocks: Vec<(i64, i64)>,
+    open_mates: HashMap<Vec<u8>, Vec<(i64, i64)>>,
+}
+
+impl<'a> CoverageAccumulator<'a> {
+    /// Starts counting the coverage of `region`, which is already trimmed, so
+    /// the flank of `options` is not used.
+    fn new(region: &Region, options: &CountOptions<'a>) -> Self {
+        Self {
+            beg: region.beg,
+            end: region.end,
+            min_mapq: options.min_mapq,
+            read_filters: options.read_filters,
+            unit: options.unit,
+            max_depth: options.max_depth,
+            no_double_count: options.no_double_count,
+            min_baseq: options.min_baseq,
+            coverage: vec![0u32; (region.end - region.beg) as usize],
+            reads: 0,
+            secondary_reads: 0,
+            overlapping_reads: 0,
+            duplicate_reads: 0,
+            spliced_reads: 0,
+            blocks: Vec::new(),
+            open_mates: HashMap::new(),
         }
-        if record.mapq() < min_mapq {
-            continue;
+    }
+
+    /// Counts `record` towards the coverage.
+    fn add(&mut self, record: &Record) {
+        let (beg, end, min_mapq) = (self.beg, self.end, self.min_mapq);
+        let Some(read_start) = record_start_u64(record) else {
+            return;
+        };
+        if !(record.is_unmapped() || record.is_secondary() || record.is_supplementary()) {
+            self.overlapping_reads += 1;
+            if record.is_duplicate() {
+                self.duplicate_reads += 1;
+            }
+        }
+        if !counts_toward_coverage(record, min_mapq, self.read_filters) {
+            if !record.is_unmapped()
+                && (record.is_secondary() || record.is_supplementary())
+                && record.mapq() >= min_mapq
+                && self.read_filters.accepts(record)
+            {
+                self.secondary_reads += 1;
+            }
+            return;
         }
-        let read_start = record.pos();
+        self.reads += 1;
+        let read_start = read_start as i64;
         let mut ref_pos = read_start;
+        let mut read_pos = 0;
+        let mut spliced = false;
+        let blocks = &mut self.blocks;
+        blocks.clear();
 
         for &cigar_op in record.cigar().iter() {
             match cigar_op {
                 Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
-                    for i in 0..len {
-                        let pos = ref_pos + i as i64;
-                        if pos >= beg as i64 && pos < end as i64 {
-                            let idx = (pos - beg as i64) as usize;
-                            coverage[idx] += 1;
+                    if self.unit == CoverageUnit::Base {
+                        if self.min_baseq == 0 {
+                            blocks.push((ref_pos, ref_pos + len as i64));
+                        } else {
+                            push_quality_blocks(
+                                blocks,
+                                record.qual(),
+                                ref_pos,
+                                read_pos,
+                                len,
+                                self.min_baseq,
+                            );
                         }
                     }
+                    ref_pos += len as i64;
+                    read_pos += len as usize;
+                }
+                Cigar::Del(len) => ref_pos += len as i64,
+                Cigar::RefSkip(len) => {
+                    // Intronic bases are skipped over without contributing
+                    // coverage.
+                    spliced = true;
                     ref_pos += len as i64
                 }
-                Cigar::Del(len) | Cigar::RefSkip(len) => ref_pos += len as i64,
-                Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {
-                    // These do not consume reference positions
+                Cigar::Ins(len) | Cigar::SoftClip(len) => {
+                    // These consume read bases but not reference positions
+                    read_pos += len as usize;
+                }
+                Cigar::HardClip(_) | Cigar::Pad(_) => {
+                    // These consume neither
+                }
+            }
+        }
+        if self.unit == CoverageUnit::Read {
+            blocks.push((read_start, ref_pos));
+        }
+        let mate_blocks = if self.no_double_count && is_same_contig_proper_pair(record) {
+            match self.open_mates.remove(record.qname()) {
+                Some(mate_blocks) => mate_blocks,
+                None => {
+                    self.open_mates
+                        .insert(record.qname().to_vec(), blocks.clone());
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        for &(block_beg, block_end) in blocks.iter() {
+            for pos in block_beg.max(beg as i64)..block_end.min(end as i64) {
+                if mate_blocks.iter().any(|&(b, e)| b <= pos && pos < e) {
+                    continue;
                 }
+                add_read(
+                    &mut self.coverage[(pos - beg as i64) as usize],
+                    self.max_depth,
+                );
+            }
+        }
+        if spliced {
+            self.spliced_reads += 1;
+        }
+    }
+
+    /// The coverage counted so far, reported against the untrimmed `region`.
+    fn finish(self, region: &Region) -> RegionCoverage {
+        let mut region_coverage = RegionCoverage::new(
+            region.contig.as_str(),
+            region.beg,
+            region.end,
+            region.name.as_str(),
+            self.coverage,
+        );
+        region_coverage.reads = self.reads;
+        region_coverage.secondary_reads = self.secondary_reads;
+        region_coverage.overlapping_reads = self.overlapping_reads;
+        region_coverage.duplicate_reads = self.duplicate_reads;
+        region_coverage.spliced_reads = self.spliced_reads;
+        region_coverage
+    }
+}
+
+/// Calculates the mean depth across an entire contig.
+///
+/// Unlike [`coverage_for_region`] this does not hold per-base depths in memory,
+/// so it is suitable for whole chromosomes. Aligned bases from reads passing the
+/// same filters are summed and divided by the contig length from the header.
+pub(crate) fn contig_mean_depth<T: BamReader>(
+    bam_reader: &mut T,
+    contig: &str,
+    min_mapq: u8,
+) -> Result<f64> {
+    let header = bam_reader.header();
+    let tid = header
+        .tid(contig.as_bytes())
+        .ok_or_else(|| Error::Calibration {
+            msg: format!("Chromosome {contig} not found in BAM header"),
+        })?;
+    let len = header.target_len(tid).unwrap_or(0);
+    if len == 0 {
+        return Err(Error::Calibration {
+            msg: format!("Chromosome {contig} has zero length in BAM header"),
+        });
+    }
+    bam_reader.fetch(FetchDefinition::CompleteTid(tid as i32))?;
+
+    let mut aligned_bases = 0u64;
+    for result in bam_reader.records() {
+        let record = result?;
+        if !counts_toward_coverage(&record, min_mapq, &ReadFilters::none()) {
+            continue;
+        }
+        for &cigar_op in record.cigar().iter() {
+            if let Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) = cigar_op {
+                aligned_bases += n as u64;
+            }
+        }
+    }
+    Ok(aligned_bases as f64 / len as f64)
+}
+
+/// Counts the unplaced unmapped reads, i.e. those returned by fetching `*`.
+pub(crate) fn count_unmapped<T: BamReader>(bam_reader: &mut T) -> Result<u64> {
+    bam_reader.fetch(FetchDefinition::Unmapped)?;
+    let mut n = 0;
+    for result in bam_reader.records() {
+        if result?.is_unmapped() {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Appends to `blocks` the runs of the `len` aligned bases starting at
+/// reference position `ref_beg` and read offset `read_beg` with a base quality
+/// of at least `min_baseq`. Bases without a quality pass.
+fn push_quality_blocks(
+    blocks: &mut Vec<(i64, i64)>,
+    qual: &[u8],
+    ref_beg: i64,
+    read_beg: usize,
+    len: u32,
+    min_baseq: u8,
+) {
+    let mut run_beg = None;
+    for i in 0..len as usize {
+        let passes = qual.get(read_beg + i).is_none_or(|&q| q >= min_baseq);
+        match (passes, run_beg) {
+            (true, None) => run_beg = Some(i),
+            (false, Some(beg)) => {
+                blocks.push((ref_beg + beg as i64, ref_beg + i as i64));
+                run_beg = None;
             }
+            _ => {}
         }
     }
-    Ok(RegionCoverage::new(
-        region.contig.as_str(),
-        region.beg,
-        region.end,
-        region.name.as_str(),
-        coverage,
-    ))
+    if let Some(beg) = run_beg {
+        blocks.push((ref_beg + beg as i64, ref_beg + len as i64));
+    }
 }
 
+/// Returns true if a record is one mate of a proper pair whose other mate is
+/// on the same contig, and so may overlap it.
+fn is_same_contig_proper_pair(record: &Record) -> bool {
+    record.is_paired() && record.is_proper_pair() && record.mtid() == record.tid()
+}
+
+/// Counts one more read at a position, unless its depth has reached
+/// `max_depth` (0 is unlimited).
+fn add_read(depth: &mut u32, max_depth: u32) {
+    if max_depth == 0 || *depth < max_depth {
+        *depth += 1;
+    }
+}
+
+/// Computes the coverage of every region in parallel. The results are in
+/// `regions` order.
 fn calculate_coverage(
     bam_path: &PathBuf,
     regions: &[Region],
     reference: Option<&PathBuf>,
-    min_mapq: u8,
-    flank: u64,
+    index: Option<&PathBuf>,
+    io_threads: Option<usize>,
+    options: &CountOptions,
 ) -> Result<Vec<RegionCoverage>> {
     let result = regions
         .par_iter()
         .map(|region| {
-            // rust_htslib::bam::IndexedReader is not Send + Sync (thread
-            // safe). Each thread needs its own copy (I think).
-            let mut bam_reader = HtslibBamReader::from_path(bam_path)?;
-            let ncpus = std::thread::available_parallelism()
-                .map(|n| n.get())
-                .unwrap_or(1);
-            bam_reader.set_threads(ncpus)?;
-            if let Some(reference) = reference {
-                bam_reader.set_reference(reference)?;
-            }
-            let coverage = coverage_for_region(&mut bam_reader, region, min_mapq, flank)?;
+            let mut bam_reader = open_reader(bam_path, reference, index, io_threads)?;
+            let coverage = coverage_for_region_in_unit(&mut bam_reader, region, options)?;
             Ok(coverage)
         })
         .collect::<Result<Vec<_>>>()?;
@@ -185,10 +848,214 @@ fn calculate_coverage(
     Ok(result)
 }
 
+/// Opens a new reader for `bam_path`, configured with threads and an optional
+/// reference.
+///
+/// If `index` is `None` the index is located with
+/// [`crate::bam::resolve_index`]. `io_threads` is the number of htslib
+/// decompression threads; `None` uses one per CPU.
+pub(crate) fn open_reader(
+    bam_path: &PathBuf,
+    reference: Option<&PathBuf>,
+    index: Option<&PathBuf>,
+    io_threads: Option<usize>,
+) -> Result<HtslibBamReader> {
+    // rust_htslib::bam::IndexedReader is not Send + Sync (thread
+    // safe). Each thread needs its own copy (I think).
+    let mut bam_reader = match index {
+        Some(index) => HtslibBamReader::from_path_and_index(bam_path, index)?,
+        None => HtslibBamReader::from_path(bam_path)?,
+    };
+    let threads = io_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    bam_reader.set_threads(threads)?;
+    if let Some(reference) = reference {
+        bam_reader.set_reference(reference)?;
+    }
+    Ok(bam_reader)
+}
+
+/// Counts the absolute template length (TLEN) of proper pairs overlapping a
+/// region.
+///
+/// Each pair is counted once, from whichever of its reads is seen first, so
+/// that a fragment counts whether one or both of its reads overlap the region.
+/// Reads are filtered in the same way as [`coverage_for_region`].
+///
+/// # Returns
+/// A map from absolute TLEN to the number of pairs with that length.
+pub(crate) fn tlen_histogram_for_region<T: BamReader>(
+    bam_reader: &mut T,
+    region: &Region,
+    min_mapq: u8,
+    flank: u64,
+    read_filters: &ReadFilters,
+) -> Result<BTreeMap<u64, u64>> {
+    let Region { beg, end, .. } = region.trim(flank)?;
+    crate::bam::check_has_references(bam_reader.header())?;
+    let tid = bam_reader
+        .header()
+        .tid(region.contig.as_bytes())
+        .ok_or_else(|| Error::Bedcov {
+            msg: format!("Chromosome {} not found in BAM header", region.contig),
+        })?;
+    bam_reader.fetch((tid, beg, end))?;
+
+    let mut histogram = BTreeMap::new();
+    // Names of the pairs counted so far whose other read may still be fetched.
+    let mut counted: HashSet<Vec<u8>> = HashSet::new();
+    for result in bam_reader.records() {
+        let record = result?;
+        if !counts_toward_coverage(&record, min_mapq, read_filters) {
+            continue;
+        }
+        if !record.is_proper_pair() || counted.remove(record.qname()) {
+            continue;
+        }
+        counted.insert(record.qname().to_vec());
+        *histogram
+            .entry(record.insert_size().unsigned_abs())
+            .or_insert(0) += 1;
+    }
+    Ok(histogram)
+}
+
+/// Counts the TLEN histogram of every region in parallel, filtering reads by
+/// the mapping quality, flank and read filters of `options`.
+fn calculate_tlen_histograms(
+    bam_path: &PathBuf,
+    regions: &[Region],
+    reference: Option<&PathBuf>,
+    index: Option<&PathBuf>,
+    io_threads: Option<usize>,
+    options: &CountOptions,
+) -> Result<Vec<BTreeMap<u64, u64>>> {
+    regions
+        .par_iter()
+        .map(|region| {
+            let mut bam_reader = open_reader(bam_path, reference, index, io_threads)?;
+            tlen_histogram_for_region(
+                &mut bam_reader,
+                region,
+                options.min_mapq,
+                options.flank,
+                options.read_filters,
+            )
+        })
+        .collect()
+}
+
+/// Write per-region TLEN histograms as `region,tlen,count` CSV rows.
+fn write_tlen_histogram<W: Write>(
+    regions: &[Region],
+    histograms: &[BTreeMap<u64, u64>],
+    mut dest: W,
+) -> Result<()> {
+    writeln!(dest, "region,tlen,count")?;
+    for (region, histogram) in regions.iter().zip(histograms) {
+        for (tlen, count) in histogram {
+            writeln!(dest, "{},{tlen},{count}", region.name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bins the per-region read counts, counted according to `mode`, into a
+/// histogram of regions per read count.
+///
+/// Bin `i` covers read counts in `[i * bin_width, (i + 1) * bin_width)`. Every
+/// bin from zero up to the one holding the largest count is returned, including
+/// empty ones.
+///
+/// # Returns
+/// The number of regions in each bin, or an empty vector if there are no
+/// regions.
+pub(crate) fn read_count_histogram(
+    coverages: &[RegionCoverage],
+    bin_width: u64,
+    mode: CountMode,
+) -> Vec<u64> {
+    let Some(max) = coverages.iter().map(|c| c.read_count(mode)).max() else {
+        return vec![];
+    };
+    let mut bins = vec![0; (max / bin_width) as usize + 1];
+    for coverage in coverages {
+        bins[(coverage.read_count(mode) / bin_width) as usize] += 1;
+    }
+    bins
+}
+
+/// Write a read-count histogram as `reads_beg,reads_end,regions` CSV rows.
+///
+/// `reads_end` is exclusive.
+fn write_read_count_histogram<W: Write>(bins: &[u64], bin_width: u64, mut dest: W) -> Result<()> {
+    writeln!(dest, "reads_beg,reads_end,regions")?;
+    for (i, regions) in bins.iter().enumerate() {
+        let beg = i as u64 * bin_width;
+        writeln!(dest, "{beg},{},{regions}", beg + bin_width)?;
+    }
+    Ok(())
+}
+
+/// Multiplies a depth statistic by the report's `scale`, if any.
+fn scaled(value: Option<f32>, scale: Option<f64>) -> Option<f64> {
+    value.map(|v| f64::from(v) * scale.unwrap_or(1.0))
+}
+
+/// The statistics written to a `bedcov` report, and how.
+#[derive(Debug, Clone, Copy)]
+struct ReportOptions<'a> {
+    thresholds: Option<&'a [u32]>,
+    threshold_mode: ThresholdMode,
+    trim_fraction: Option<f64>,
+    /// Which records the `reads` column counts.
+    count_mode: CountMode,
+    duplicate_rate: bool,
+    sem: bool,
+    scale: Option<f64>,
+    /// Written for undefined statistics in the CSV report.
+    na: &'a str,
+    shape: ReportShape,
+}
+
+impl Default for ReportOptions<'_> {
+    fn default() -> Self {
+        Self {
+            thresholds: None,
+            threshold_mode: ThresholdMode::Ge,
+            trim_fraction: None,
+            count_mode: CountMode::Primary,
+            duplicate_rate: false,
+            sem: false,
+            scale: None,
+            na: "",
+            shape: ReportShape::Wide,
+        }
+    }
+}
+
 /// Write coverage results to CSV format.
 ///
-/// This function writes the coverage results to a CSV file, including
-/// optional thresholds.
+/// This function writes the coverage results to a CSV file, including the
+/// optional columns of `options`: thresholds, compared according to
+/// `threshold_mode`, and a trimmed mean column. When `unmapped` is given, a
+/// `reads` column, counted according to `count_mode`, and a final `unmapped`
+/// row holding that count are added.
+/// When `duplicate_rate` is set, a `dup_rate` column is added, and when `sem`
+/// is set a `sem` column with the standard error of the mean.
+/// When `unflanked` is given it must hold the same regions computed without a
+/// flank; every statistic column is then written twice, prefixed `flank_` for
+/// `coverages` and `noflank_` for `unflanked`.
+/// With a `scale`, every depth statistic is multiplied by it and written
+/// with two decimals; the CV, duplicate rate and threshold fractions are
+/// unchanged.
+/// Statistics that are undefined for a region (e.g. the
+/// CV of a region with zero mean coverage) are written as `na`.
+/// With [`ReportShape::Long`] the same values are written one per row, see
+/// [`write_long_csv`].
 ///
 /// # Example
 /// ```ignore
@@ -196,69 +1063,879 @@ fn calculate_coverage(
 ///     RegionCoverage::new("chr1", 100, 200, "region1", vec![1, 2, 3]),
 ///     RegionCoverage::new("chr1", 200, 300, "region2", vec![4, 5, 6]),
 /// ];
-/// let thresholds = Some(vec![2, 4]);
-/// write_csv(&coverages, thresholds, std::io::stdout())?;
+/// let options = ReportOptions {
+///     thresholds: Some(&[2, 4]),
+///     trim_fraction: Some(0.1),
+///     na: "NA",
+///     ..ReportOptions::default()
+/// };
+/// write_csv(&coverages, None, None, &options, std::io::stdout())?;
 /// ```
 fn write_csv<W: Write>(
     coverages: &[RegionCoverage],
-    thresholds: Option<Vec<u32>>,
-    mut dest: W,
+    unflanked: Option<&[RegionCoverage]>,
+    unmapped: Option<u64>,
+    options: &ReportOptions,
+    dest: W,
 ) -> Result<()> {
-    let mut columns: Vec<String> = [
-        "name", "chrom", "beg", "end", "min", "max", "mean", "std", "cv",
-    ]
-    .iter()
-    .map(|s| s.to_string())
-    .collect();
-    if let Some(thresholds) = &thresholds {
-        for threshold in thresholds {
-            columns.push(format!("pct_gt_{threshold}"));
-        }
+    let (columns, rows) = csv_table(coverages, unflanked, unmapped, options);
+    match options.shape {
+        ReportShape::Wide => write_wide_csv(&columns, &rows, dest),
+        ReportShape::Long => write_long_csv(&columns, &rows, dest),
     }
+}
+
+/// Write the header and rows of the CSV report, one row per region.
+fn write_wide_csv<W: Write>(columns: &[String], rows: &[Vec<String>], mut dest: W) -> Result<()> {
     writeln!(dest, "{}", columns.join(","))?;
-    for coverage in coverages.iter() {
-        let min = coverage.min().unwrap_or(&0);
-        let max = coverage.max().unwrap_or(&0);
-        let mean = coverage.mean().unwrap_or(0.0);
-        let std = coverage.std().unwrap_or(0.0);
-        let cv = coverage.cv().unwrap_or(0.0);
-        let mut row = format!(
-            "{},{},{},{},{min},{max},{mean:.2},{std:.2},{cv:.2}",
-            coverage.region.name, coverage.region.contig, coverage.region.beg, coverage.region.end,
-        );
-        if let Some(thresholds) = &thresholds {
+    for row in rows {
+        writeln!(dest, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+/// Write the CSV report long, for tidy data tools: a
+/// `name,chrom,beg,end,metric,value` header, then one row for each region and
+/// statistic column of the wide report, holding the same values in the same
+/// order.
+fn write_long_csv<W: Write>(columns: &[String], rows: &[Vec<String>], mut dest: W) -> Result<()> {
+    writeln!(dest, "name,chrom,beg,end,metric,value")?;
+    for row in rows {
+        for (metric, value) in columns.iter().zip(row).skip(4) {
+            writeln!(dest, "{},{metric},{value}", row[..4].join(","))?;
+        }
+    }
+    Ok(())
+}
+
+/// The header and rows of the CSV report written by [`write_csv`].
+fn csv_table(
+    coverages: &[RegionCoverage],
+    unflanked: Option<&[RegionCoverage]>,
+    unmapped: Option<u64>,
+    options: &ReportOptions,
+) -> (Vec<String>, Vec<Vec<String>>) {
+    let ReportOptions {
+        thresholds,
+        threshold_mode,
+        trim_fraction,
+        count_mode,
+        duplicate_rate,
+        sem,
+        scale,
+        na,
+        ..
+    } = *options;
+    let fmt = |value: Option<f64>| value.map_or_else(|| na.to_string(), |v| format!("{v:.2}"));
+    let depth = |value: Option<f32>| fmt(scaled(value, scale));
+    let extreme = |value: Option<u32>| match scale {
+        Some(_) => fmt(scaled(value.map(|v| v as f32), scale)),
+        None => value.map_or_else(|| na.to_string(), |v| v.to_string()),
+    };
+    let mut stat_columns: Vec<String> = ["min", "max", "mean", "std", "cv"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    if sem {
+        stat_columns.push("sem".to_string());
+    }
+    if trim_fraction.is_some() {
+        stat_columns.push("trimmed_mean".to_string());
+    }
+    if duplicate_rate {
+        stat_columns.push("dup_rate".to_string());
+    }
+    if let Some(thresholds) = thresholds {
+        for threshold in thresholds {
+            stat_columns.push(threshold_mode.column(*threshold));
+        }
+    }
+    let stats = |coverage: &RegionCoverage| {
+        let summary = coverage.summary();
+        let mut row = vec![
+            extreme(summary.min),
+            extreme(summary.max),
+            depth(summary.mean),
+            depth(summary.std),
+            fmt(summary.cv.map(f64::from)),
+        ];
+        if sem {
+            row.push(depth(summary.sem));
+        }
+        if let Some(fraction) = trim_fraction {
+            row.push(depth(coverage.trimmed_mean(fraction)));
+        }
+        if duplicate_rate {
+            row.push(fmt(coverage.duplicate_rate()));
+        }
+        if let Some(thresholds) = thresholds {
             for thresh in thresholds {
-                let pct = coverage.percent_above_threshold(*thresh).unwrap_or(0.0);
-                row.push_str(&format!(",{:.2}", pct));
+                row.push(fmt(
+                    coverage.percent_above_threshold(*thresh, threshold_mode)
+                ));
+            }
+        }
+        row
+    };
+
+    let mut columns: Vec<String> = ["name", "chrom", "beg", "end"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    if unmapped.is_some() {
+        columns.push("reads".to_string());
+    }
+    if unflanked.is_some() {
+        for prefix in ["flank", "noflank"] {
+            columns.extend(stat_columns.iter().map(|c| format!("{prefix}_{c}")));
+        }
+    } else {
+        columns.extend(stat_columns);
+    }
+    let mut rows = Vec::new();
+    for (i, coverage) in coverages.iter().enumerate() {
+        let mut row = vec![
+            coverage.region.name.clone(),
+            coverage.region.contig.clone(),
+            coverage.region.beg.to_string(),
+            coverage.region.end.to_string(),
+        ];
+        if unmapped.is_some() {
+            row.push(coverage.read_count(count_mode).to_string());
+        }
+        row.extend(stats(coverage));
+        if let Some(unflanked) = unflanked {
+            row.extend(stats(&unflanked[i]));
+        }
+        rows.push(row);
+    }
+    if let Some(count) = unmapped {
+        // Unmapped reads have no position, so every column other than the
+        // read count is undefined.
+        let mut row = vec![na.to_string(); columns.len()];
+        row[0] = "unmapped".to_string();
+        row[1] = "*".to_string();
+        row[4] = count.to_string();
+        rows.push(row);
+    }
+    (columns, rows)
+}
+
+/// A depth in a JSON row: a count of reads, or a scaled value.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonDepth {
+    Count(u32),
+    Scaled(f64),
+}
+
+/// The statistics of one region, as written by [`write_json`]. Optional
+/// columns are `Some` when requested, holding `None` (`null`) when undefined.
+#[derive(Serialize)]
+struct JsonRow<'a> {
+    name: &'a str,
+    chrom: &'a str,
+    beg: Option<u64>,
+    end: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reads: Option<u64>,
+    min: Option<JsonDepth>,
+    max: Option<JsonDepth>,
+    mean: Option<f64>,
+    std: Option<f64>,
+    cv: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sem: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trimmed_mean: Option<Option<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dup_rate: Option<Option<f64>>,
+    #[serde(flatten)]
+    thresholds: JsonThresholds,
+}
+
+/// Threshold columns, serialized as fields in threshold order.
+struct JsonThresholds(Vec<(String, Option<f64>)>);
+
+impl Serialize for JsonThresholds {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (column, value) in &self.0 {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Rounds `value` to two decimal places, as the CSV columns are written.
+/// Values that are not finite are undefined.
+fn json_number(value: Option<f64>) -> Option<f64> {
+    value
+        .filter(|v| v.is_finite())
+        .map(|v| (v * 100.0).round() / 100.0)
+}
+
+/// Write coverage as a JSON array with one object per region, holding the
+/// same statistics as the CSV columns, scaled as [`write_csv`] scales them.
+/// Undefined statistics are `null`. When `unmapped` is given, each object
+/// has a `reads` count, as the CSV column, and a final `unmapped` object
+/// holds that count with every other statistic `null`. The `na` and shape
+/// of `options` are not used.
+fn write_json<W: Write>(
+    coverages: &[RegionCoverage],
+    unmapped: Option<u64>,
+    options: &ReportOptions,
+    mut dest: W,
+) -> Result<()> {
+    let ReportOptions {
+        thresholds,
+        threshold_mode,
+        trim_fraction,
+        count_mode,
+        duplicate_rate,
+        sem,
+        scale,
+        ..
+    } = *options;
+    let thresholds = thresholds.unwrap_or_default();
+    let depth = |value: Option<f32>| json_number(scaled(value, scale));
+    let mut rows = coverages
+        .iter()
+        .map(|coverage| {
+            let summary = coverage.summary();
+            let count = |value: Option<u32>| match scale {
+                Some(_) => depth(value.map(|v| v as f32)).map(JsonDepth::Scaled),
+                None => value.map(JsonDepth::Count),
+            };
+            let thresholds = thresholds
+                .iter()
+                .map(|&threshold| {
+                    let percent = coverage.percent_above_threshold(threshold, threshold_mode);
+                    (threshold_mode.column(threshold), json_number(percent))
+                })
+                .collect();
+            JsonRow {
+                name: &coverage.region.name,
+                chrom: &coverage.region.contig,
+                beg: Some(coverage.region.beg),
+                end: Some(coverage.region.end),
+                reads: unmapped.map(|_| coverage.read_count(count_mode)),
+                min: count(summary.min),
+                max: count(summary.max),
+                mean: depth(summary.mean),
+                std: depth(summary.std),
+                cv: json_number(summary.cv.map(f64::from)),
+                sem: sem.then(|| depth(summary.sem)),
+                trimmed_mean: trim_fraction.map(|fraction| depth(coverage.trimmed_mean(fraction))),
+                dup_rate: duplicate_rate.then(|| json_number(coverage.duplicate_rate())),
+                thresholds: JsonThresholds(thresholds),
+            }
+        })
+        .collect::<Vec<_>>();
+    if let Some(count) = unmapped {
+        // Unmapped reads have no position, so every statistic other than the
+        // read count is undefined.
+        let thresholds = thresholds
+            .iter()
+            .map(|&threshold| (threshold_mode.column(threshold), None))
+            .collect();
+        rows.push(JsonRow {
+            name: "unmapped",
+            chrom: "*",
+            beg: None,
+            end: None,
+            reads: Some(count),
+            min: None,
+            max: None,
+            mean: None,
+            std: None,
+            cv: None,
+            sem: sem.then_some(None),
+            trimmed_mean: trim_fraction.map(|_| None),
+            dup_rate: duplicate_rate.then_some(None),
+            thresholds: JsonThresholds(thresholds),
+        });
+    }
+    serde_json::to_writer_pretty(&mut dest, &rows)?;
+    writeln!(dest)?;
+    Ok(())
+}
+
+/// Write coverage in the layout of mosdepth's `regions.bed.gz`: one
+/// `chrom\tbeg\tend\tname\tmean` line per region, with 0-based half-open
+/// coordinates. The mean is multiplied by `scale`, if given.
+fn write_mosdepth<W: Write>(
+    coverages: &[RegionCoverage],
+    scale: Option<f64>,
+    na: &str,
+    mut dest: W,
+) -> Result<()> {
+    for coverage in coverages {
+        let mean =
+            scaled(coverage.mean(), scale).map_or_else(|| na.to_string(), |v| format!("{v:.2}"));
+        writeln!(
+            dest,
+            "{}\t{}\t{}\t{}\t{mean}",
+            coverage.region.contig, coverage.region.beg, coverage.region.end, coverage.region.name
+        )?;
+    }
+    Ok(())
+}
+
+/// Write per-base depth as bedGraph: one `chrom\tbeg\tend\tdepth` line, with
+/// 0-based half-open coordinates, per run of bases with the same non-zero
+/// depth. Bases with no coverage are omitted.
+///
+/// The coverage of each region starts `flank` bases after its BED start.
+fn write_bedgraph<W: Write>(coverages: &[RegionCoverage], flank: u64, mut dest: W) -> Result<()> {
+    for coverage in coverages {
+        if coverage.coverage.is_empty() {
+            continue;
+        }
+        let contig = &coverage.region.contig;
+        let mut run_beg = coverage.region.trim(flank)?.beg;
+        for run in coverage.coverage.chunk_by(|a, b| a == b) {
+            let run_end = run_beg + run.len() as u64;
+            if run[0] > 0 {
+                writeln!(dest, "{contig}\t{run_beg}\t{run_end}\t{}", run[0])?;
             }
+            run_beg = run_end;
         }
-        writeln!(dest, "{row}")?;
     }
     Ok(())
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Cuts each region to its first `bases` bases after the start flank: once
+/// `flank` bases are trimmed from each end, the coverage is counted over
+/// `[beg + flank, min(beg + flank + bases, end - flank))`.
+fn head_regions(regions: &[Region], bases: u64, flank: u64) -> Vec<Region> {
+    let length = bases.saturating_add(flank.saturating_mul(2));
+    regions.iter().map(|region| region.head(length)).collect()
+}
+
+/// Layout of the `bedcov` report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// CSV with a header and one column per statistic.
+    Csv,
+    /// mosdepth-compatible regions BED.
+    Mosdepth,
+    /// Per-base depth as bedGraph.
+    Bedgraph,
+    /// A JSON array of per-region statistics.
+    Json,
+}
+
+/// Shape of the CSV `bedcov` report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportShape {
+    /// One row per region and one column per statistic.
+    Wide,
+    /// One row per region and statistic, see [`write_long_csv`].
+    Long,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct BedcovArgs {
-    pub bam_path: PathBuf,
+    /// BAM/CRAM files to report on. With more than one, a table of the mean
+    /// coverage of every region in each file is written instead of the
+    /// report; see [`write_sample_csv`].
+    pub bam_paths: Vec<PathBuf>,
     pub bed_path: PathBuf,
     pub reference: Option<PathBuf>,
+    /// Without `reference`, use the one named in the input's `@SQ UR:` tag.
+    pub reference_from_header: bool,
+    pub index: Option<PathBuf>,
     pub min_mapq: u8,
     pub flank: u64,
+    /// Only count coverage over the first this many bases of each region
+    /// after the flank, see [`head_regions`].
+    pub head_bases: Option<u64>,
     pub thresholds: Option<Vec<u32>>,
+    pub threshold_mode: ThresholdMode,
+    pub trim_fraction: Option<f64>,
+    pub report_unmapped: bool,
+    /// Which records the `reads` column and read-count histogram count.
+    pub count_mode: CountMode,
+    pub duplicate_rate: bool,
+    /// Add the standard error of the mean to the report.
+    pub sem: bool,
+    /// Multiply every reported depth statistic by this factor, see
+    /// [`write_csv`].
+    pub scale: Option<f64>,
+    pub read_filters: ReadFilters,
+    pub coverage_unit: CoverageUnit,
+    /// Cap on the depth counted at any position; 0 is unlimited.
+    pub max_depth: u32,
+    /// Count the positions both mates of a proper pair cover once, see
+    /// [`coverage_for_region_in_unit`].
+    pub no_double_count: bool,
+    /// Minimum base quality of an aligned base for it to count towards
+    /// coverage; 0 counts every base. Only used with [`CoverageUnit::Base`].
+    pub min_baseq: u8,
+    pub rna: bool,
+    pub tlen_histogram: Option<PathBuf>,
+    /// Also report every statistic computed without `flank`.
+    pub flank_report: bool,
+    /// Bin width of the read-count histogram; `None` disables it.
+    pub read_count_bin_width: Option<u64>,
+    /// Where to write the read-count histogram (default standard error).
+    pub read_count_histogram: Option<PathBuf>,
+    pub check_only: bool,
+    pub na_string: String,
+    pub format: ReportFormat,
+    /// Shape of the CSV report; only [`ReportShape::Wide`] is supported
+    /// with the other formats.
+    pub report_shape: ReportShape,
+    pub bgzip: bool,
+    /// Size of the thread pool regions are processed on; `None` uses rayon's
+    /// default of one thread per CPU.
+    pub compute_threads: Option<usize>,
+    /// Number of htslib decompression threads given to each reader; `None`
+    /// uses one per CPU.
+    pub io_threads: Option<usize>,
+    /// Only report the regions whose name starts with this prefix.
+    pub name_prefix: Option<String>,
+    /// Report the time of each stage of the run to standard error.
+    pub verbose_timing: bool,
+    /// Only split BED columns on tabs, see [`region::BedOptions`].
+    pub strict_tabs: bool,
+}
+
+impl BedcovArgs {
+    /// How coverage is counted.
+    fn count_options(&self) -> CountOptions<'_> {
+        CountOptions {
+            min_mapq: self.min_mapq,
+            flank: self.flank,
+            read_filters: &self.read_filters,
+            unit: self.coverage_unit,
+            max_depth: self.max_depth,
+            no_double_count: self.no_double_count,
+            min_baseq: self.min_baseq,
+        }
+    }
+
+    /// The statistics written to the report.
+    fn report_options(&self) -> ReportOptions<'_> {
+        ReportOptions {
+            thresholds: self.thresholds.as_deref(),
+            threshold_mode: self.threshold_mode,
+            trim_fraction: self.trim_fraction,
+            count_mode: self.count_mode,
+            duplicate_rate: self.duplicate_rate,
+            sem: self.sem,
+            scale: self.scale,
+            na: &self.na_string,
+            shape: self.report_shape,
+        }
+    }
 }
 
+/// Entry point for the `bedcov` subcommand.
+///
+/// Regions are processed in parallel, but every report lists them in BED
+/// order and no statistic depends on the order regions finish in, so the
+/// output is byte-identical whatever `compute_threads` and `io_threads` are.
 pub fn run(args: &BedcovArgs) -> Result<()> {
-    let file = std::fs::File::open(&args.bed_path)?;
-    let mut reader = std::io::BufReader::new(file);
-    let regions = region::load_from_bed(&mut reader)?;
+    with_compute_threads(args.compute_threads, || run_report(args))
+}
+
+/// Runs `f` on a rayon pool of `threads` threads, or on the global pool if
+/// `threads` is `None`.
+fn with_compute_threads<T>(
+    threads: Option<usize>,
+    f: impl FnOnce() -> Result<T> + Send,
+) -> Result<T>
+where
+    T: Send,
+{
+    match threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| Error::Bedcov {
+                msg: format!("Failed to start {threads} threads: {e}"),
+            })?
+            .install(f),
+        None => f(),
+    }
+}
+
+fn run_report(args: &BedcovArgs) -> Result<()> {
+    let filters = &args.read_filters;
+    if let (Some(min), Some(max)) = (filters.min_insert_size, filters.max_insert_size) {
+        if min > max {
+            return Err(Error::Bedcov {
+                msg: format!("Minimum insert size {min} is greater than maximum {max}"),
+            });
+        }
+    }
+    if args.flank_report && args.format != ReportFormat::Csv {
+        return Err(Error::Bedcov {
+            msg: "--flank-report is only supported with the CSV format".to_string(),
+        });
+    }
+    if args.report_shape != ReportShape::Wide && args.format != ReportFormat::Csv {
+        return Err(Error::Bedcov {
+            msg: "--report-shape long is only supported with the CSV format".to_string(),
+        });
+    }
+    if matches!(args.format, ReportFormat::Mosdepth | ReportFormat::Bedgraph) {
+        let unsupported = [
+            ("--report-unmapped", args.report_unmapped),
+            ("--duplicate-rate", args.duplicate_rate),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(option, _)| *option)
+        .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            return Err(Error::Bedcov {
+                msg: format!(
+                    "{} can only be used with the CSV and JSON formats",
+                    unsupported.join(", ")
+                ),
+            });
+        }
+    }
+    if args.min_baseq > 0 && args.coverage_unit != CoverageUnit::Base {
+        return Err(Error::Bedcov {
+            msg: "--min-BQ can only be used with --coverage-unit base".to_string(),
+        });
+    }
+    if args.head_bases == Some(0) {
+        return Err(Error::Bedcov {
+            msg: "--head-bases must be at least 1".to_string(),
+        });
+    }
+    if args.read_count_bin_width == Some(0) {
+        return Err(Error::Bedcov {
+            msg: "Read-count histogram bin width must be at least 1".to_string(),
+        });
+    }
+    if let Some(fraction) = args.trim_fraction {
+        if !(0.0..0.5).contains(&fraction) {
+            return Err(Error::Bedcov {
+                msg: format!("Trim fraction must be in [0, 0.5), got {fraction}"),
+            });
+        }
+    }
+    if let Some(scale) = args.scale {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(Error::Bedcov {
+                msg: format!("Scale must be a positive number, got {scale}"),
+            });
+        }
+        if args.format == ReportFormat::Bedgraph {
+            return Err(Error::Bedcov {
+                msg: "--scale is not supported with --format bedgraph".to_string(),
+            });
+        }
+    }
+    let mut timings = args.verbose_timing.then(Timings::new);
+    let mut regions = timing::time(timings.as_mut(), "load BED", || {
+        let file = std::fs::File::open(&args.bed_path)?;
+        let mut reader = std::io::BufReader::new(file);
+        let options = region::BedOptions {
+            strict_tabs: args.strict_tabs,
+            ..region::BedOptions::default()
+        };
+        region::load_from_bed_with_options(&mut reader, &options)
+    })?;
+    if let Some(prefix) = &args.name_prefix {
+        regions = region::partition_by_name_prefix(&regions, prefix).0;
+    }
+    if let Some(bases) = args.head_bases {
+        regions = head_regions(&regions, bases, args.flank);
+    }
+    let bam_path = match args.bam_paths.as_slice() {
+        [] => {
+            return Err(Error::Bedcov {
+                msg: "No BAM/CRAM files given".to_string(),
+            })
+        }
+        [bam_path] => bam_path,
+        _ => return run_samples(args, &regions),
+    };
+    let index = args
+        .index
+        .clone()
+        .or_else(|| crate::bam::resolve_index(bam_path));
+    if let Some(index) = &index {
+        eprintln!("Using index {}", index.display());
+    }
+    let index = index.as_ref();
+    let reference = match &args.reference {
+        Some(reference) => Some(reference.clone()),
+        None if args.reference_from_header => {
+            let bam_reader = open_reader(bam_path, None, index, args.io_threads)?;
+            let reference = crate::bam::reference_from_header(bam_reader.header())?;
+            eprintln!("Using reference {} from the header", reference.display());
+            Some(reference)
+        }
+        None => None,
+    };
+    if args.check_only {
+        let bam_reader = open_reader(bam_path, reference.as_ref(), index, args.io_threads)?;
+        region::check_contigs(&regions, bam_reader.header())?;
+        println!(
+            "OK: {} ({} contigs) covers all {} regions in {}",
+            bam_path.display(),
+            bam_reader.header().target_count(),
+            regions.len(),
+            args.bed_path.display()
+        );
+        return Ok(());
+    }
+    let coverage_start = Instant::now();
+    let counting = args.count_options();
     let coverages = calculate_coverage(
-        &args.bam_path,
+        bam_path,
         &regions,
-        args.reference.as_ref(),
-        args.min_mapq,
-        args.flank,
+        reference.as_ref(),
+        index,
+        args.io_threads,
+        &counting,
     )?;
-    write_csv(&coverages, args.thresholds.clone(), std::io::stdout())?;
+    let unflanked = if args.flank_report {
+        Some(calculate_coverage(
+            bam_path,
+            &regions,
+            reference.as_ref(),
+            index,
+            args.io_threads,
+            &CountOptions {
+                flank: 0,
+                ..counting
+            },
+        )?)
+    } else {
+        None
+    };
+    if let Some(timings) = timings.as_mut() {
+        timings.record("coverage", coverage_start.elapsed());
+    }
+    let spliced_reads = coverages.iter().map(|c| c.spliced_reads).sum::<u64>();
+    if spliced_reads > 0 && !args.rna {
+        eprintln!(
+            "Warning: {spliced_reads} reads with spliced (N) alignments were found. \
+            Skipped intronic bases are not counted as covered; pass --rna if this is RNA-seq data."
+        );
+    }
+    if let Some(bin_width) = args.read_count_bin_width {
+        let bins = read_count_histogram(&coverages, bin_width, args.count_mode);
+        match &args.read_count_histogram {
+            Some(path) => {
+                let file = std::fs::File::create(path)?;
+                write_read_count_histogram(&bins, bin_width, std::io::BufWriter::new(file))?;
+            }
+            None => write_read_count_histogram(&bins, bin_width, std::io::stderr())?,
+        }
+    }
+    let unmapped = if args.report_unmapped {
+        let mut bam_reader = open_reader(bam_path, reference.as_ref(), index, args.io_threads)?;
+        Some(count_unmapped(&mut bam_reader)?)
+    } else {
+        None
+    };
+    let write_start = Instant::now();
+    let mut dest = report_dest(args.bgzip)?;
+    let options = args.report_options();
+    match args.format {
+        ReportFormat::Csv => write_csv(
+            &coverages,
+            unflanked.as_deref(),
+            unmapped,
+            &options,
+            &mut dest,
+        )?,
+        ReportFormat::Mosdepth => {
+            write_mosdepth(&coverages, args.scale, &args.na_string, &mut dest)?
+        }
+        ReportFormat::Bedgraph => write_bedgraph(&coverages, args.flank, &mut dest)?,
+        ReportFormat::Json => write_json(&coverages, unmapped, &options, &mut dest)?,
+    }
+    // Dropping the writer finishes the BGZF stream with its EOF block.
+    drop(dest);
+    if let Some(timings) = timings.as_mut() {
+        timings.record("write report", write_start.elapsed());
+    }
+    if let Some(path) = &args.tlen_histogram {
+        timing::time(timings.as_mut(), "insert sizes", || {
+            let histograms = calculate_tlen_histograms(
+                bam_path,
+                &regions,
+                reference.as_ref(),
+                index,
+                args.io_threads,
+                &counting,
+            )?;
+            let file = std::fs::File::create(path)?;
+            write_tlen_histogram(&regions, &histograms, std::io::BufWriter::new(file))
+        })?;
+    }
+    if let Some(timings) = &timings {
+        timings.report();
+    }
+    Ok(())
+}
+
+/// Opens standard output for the report, BGZF-compressed if `bgzip` is set.
+fn report_dest(bgzip: bool) -> Result<Box<dyn Write>> {
+    Ok(if bgzip {
+        Box::new(rust_htslib::bgzf::Writer::from_stdout()?)
+    } else {
+        Box::new(std::io::stdout())
+    })
+}
+
+/// Returns the column name of each BAM/CRAM file in the multi-sample table:
+/// its file name without the extension.
+///
+/// # Errors
+/// Returns an error if two files would get the same column name.
+fn sample_names(bam_paths: &[PathBuf]) -> Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for path in bam_paths {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        if names.contains(&name) {
+            return Err(Error::Bedcov {
+                msg: format!(
+                    "More than one BAM/CRAM file is named {name}; sample columns need distinct file names"
+                ),
+            });
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// The `bedcov` entry point for more than one BAM/CRAM file: writes the
+/// mean coverage of each region in every file.
+fn run_samples(args: &BedcovArgs, regions: &[Region]) -> Result<()> {
+    let single_only = [
+        ("--index", args.index.is_some()),
+        ("--thresholds", args.thresholds.is_some()),
+        ("--trim-fraction", args.trim_fraction.is_some()),
+        ("--report-unmapped", args.report_unmapped),
+        ("--duplicate-rate", args.duplicate_rate),
+        ("--sem", args.sem),
+        ("--scale", args.scale.is_some()),
+        ("--tlen-histogram", args.tlen_histogram.is_some()),
+        ("--flank-report", args.flank_report),
+        (
+            "--read-count-histogram",
+            args.read_count_bin_width.is_some(),
+        ),
+        ("--format", args.format != ReportFormat::Csv),
+        ("--report-shape", args.report_shape != ReportShape::Wide),
+        ("--verbose-timing", args.verbose_timing),
+    ]
+    .iter()
+    .filter(|(_, set)| *set)
+    .map(|(option, _)| *option)
+    .collect::<Vec<_>>();
+    if !single_only.is_empty() {
+        return Err(Error::Bedcov {
+            msg: format!(
+                "{} can only be used with a single BAM/CRAM file",
+                single_only.join(", ")
+            ),
+        });
+    }
+    let names = sample_names(&args.bam_paths)?;
+    let mut samples = Vec::new();
+    for bam_path in &args.bam_paths {
+        let reference = match &args.reference {
+            Some(reference) => Some(reference.clone()),
+            None if args.reference_from_header => {
+                let bam_reader = open_reader(bam_path, None, None, args.io_threads)?;
+                let reference = crate::bam::reference_from_header(bam_reader.header())?;
+                eprintln!(
+                    "Using reference {} from the header of {}",
+                    reference.display(),
+                    bam_path.display()
+                );
+                Some(reference)
+            }
+            None => None,
+        };
+        if args.check_only {
+            let bam_reader = open_reader(bam_path, reference.as_ref(), None, args.io_threads)?;
+            region::check_contigs(regions, bam_reader.header())?;
+            println!(
+                "OK: {} ({} contigs) covers all {} regions in {}",
+                bam_path.display(),
+                bam_reader.header().target_count(),
+                regions.len(),
+                args.bed_path.display()
+            );
+            continue;
+        }
+        samples.push(calculate_coverage(
+            bam_path,
+            regions,
+            reference.as_ref(),
+            None,
+            args.io_threads,
+            &args.count_options(),
+        )?);
+    }
+    if args.check_only {
+        return Ok(());
+    }
+    let spliced_reads = samples
+        .iter()
+        .flatten()
+        .map(|c| c.spliced_reads)
+        .sum::<u64>();
+    if spliced_reads > 0 && !args.rna {
+        eprintln!(
+            "Warning: {spliced_reads} reads with spliced (N) alignments were found. \
+            Skipped intronic bases are not counted as covered; pass --rna if this is RNA-seq data."
+        );
+    }
+    let mut dest = report_dest(args.bgzip)?;
+    write_sample_csv(regions, &names, &samples, &args.na_string, &mut dest)?;
+    // Dropping the writer finishes the BGZF stream with its EOF block.
+    drop(dest);
+    Ok(())
+}
+
+/// Write the mean coverage of each region in several samples as CSV: the
+/// region's `name,chrom,beg,end` followed by one column per sample, headed
+/// with its name. `samples[i]` holds the coverage of `regions`, in order, in
+/// the sample named `names[i]`.
+fn write_sample_csv<W: Write>(
+    regions: &[Region],
+    names: &[String],
+    samples: &[Vec<RegionCoverage>],
+    na: &str,
+    mut dest: W,
+) -> Result<()> {
+    writeln!(dest, "name,chrom,beg,end,{}", names.join(","))?;
+    for (i, region) in regions.iter().enumerate() {
+        let mut row = vec![
+            region.name.clone(),
+            region.contig.clone(),
+            region.beg.to_string(),
+            region.end.to_string(),
+        ];
+        row.extend(samples.iter().map(|coverages| {
+            coverages[i]
+                .mean()
+                .map_or_else(|| na.to_string(), |v| format!("{v:.2}"))
+        }));
+        writeln!(dest, "{}", row.join(","))?;
+    }
     Ok(())
 }
 
@@ -271,6 +1948,71 @@ mod tests {
 
     const CHRQ_MIRROR_TID: i32 = 3;
 
+    #[test]
+    fn test_summary_matches_accessors() {
+        let coverages = [
+            RegionCoverage::new("chr1", 0, 5, "varied", vec![0, 2, 3, 5, 0]),
+            RegionCoverage::new("chr1", 0, 3, "zero", vec![0, 0, 0]),
+            RegionCoverage::new("chr1", 0, 0, "empty", vec![]),
+        ];
+        for coverage in &coverages {
+            let summary = coverage.summary();
+            assert_eq!(summary.min, coverage.min().copied());
+            assert_eq!(summary.max, coverage.max().copied());
+            assert_eq!(summary.mean, coverage.mean());
+            assert_eq!(summary.std, coverage.std());
+            assert_eq!(summary.cv, coverage.cv());
+            assert_eq!(
+                summary.breadth,
+                coverage.percent_above_threshold(1, ThresholdMode::Ge)
+            );
+        }
+        assert_eq!(coverages[0].summary().breadth, Some(0.6));
+        assert_eq!(coverages[1].summary().cv, None);
+        assert_eq!(
+            coverages[2].summary(),
+            CoverageSummary {
+                min: None,
+                max: None,
+                mean: None,
+                std: None,
+                cv: None,
+                sem: None,
+                breadth: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_moments_match_separate_passes() {
+        // The statistics as they were computed before `moments`, each
+        // recomputing the mean.
+        fn mean(v: &[u32]) -> Option<f32> {
+            (!v.is_empty()).then(|| v.iter().sum::<u32>() as f32 / v.len() as f32)
+        }
+        fn std(v: &[u32]) -> Option<f32> {
+            let mu = mean(v)?;
+            let sum = v.iter().map(|&x| (mu - x as f32) * (mu - x as f32));
+            Some((sum.sum::<f32>() / v.len() as f32).sqrt())
+        }
+        fn cv(v: &[u32]) -> Option<f32> {
+            match (std(v), mean(v)) {
+                (Some(sd), Some(mu)) if mu > 0.0 => Some(sd / mu),
+                _ => None,
+            }
+        }
+        let depths = (0..10_000u32).map(|i| (i * 7919) % 113).collect::<Vec<_>>();
+        for v in [vec![], vec![0, 0], vec![3], vec![1, 2, 3, 4, 100], depths] {
+            let coverage = RegionCoverage::new("chr1", 0, v.len() as u64, "r", v.clone());
+            let summary = coverage.summary();
+            assert_eq!(summary.mean, mean(&v));
+            assert_eq!(summary.std, std(&v));
+            assert_eq!(summary.cv, cv(&v));
+            assert_eq!(coverage.std(), std(&v));
+            assert_eq!(coverage.cv(), cv(&v));
+        }
+    }
+
     #[test]
     fn test_write_csv() {
         let coverages = vec![
@@ -278,7 +2020,13 @@ mod tests {
             RegionCoverage::new("chr1", 200, 300, "region2", vec![4, 5, 6]),
         ];
         let mut output = Vec::new();
-        let result = write_csv(&coverages, None, &mut output);
+        let result = write_csv(
+            &coverages,
+            None,
+            None,
+            &ReportOptions::default(),
+            &mut output,
+        );
         assert!(result.is_ok());
 
         let expected = "\
@@ -296,24 +2044,98 @@ region2,chr1,200,300,4,6,5.00,0.82,0.16";
         ];
         let thresholds = Some(vec![2, 4]);
         let mut output = Vec::new();
-        let result = write_csv(&coverages, thresholds, &mut output);
+        let result = write_csv(
+            &coverages,
+            None,
+            None,
+            &ReportOptions {
+                thresholds: thresholds.as_deref(),
+                ..ReportOptions::default()
+            },
+            &mut output,
+        );
         assert!(result.is_ok());
 
         let expected = "\
-name,chrom,beg,end,min,max,mean,std,cv,pct_gt_2,pct_gt_4
+name,chrom,beg,end,min,max,mean,std,cv,pct_ge_2,pct_ge_4
 region1,chr1,100,200,1,3,2.00,0.82,0.41,0.67,0.00
 region2,chr1,200,300,4,6,5.00,0.82,0.16,1.00,1.00";
         assert_eq!(String::from_utf8(output).unwrap().trim(), expected.trim());
     }
 
+    #[test]
+    fn test_write_long_csv() {
+        let coverages = vec![
+            RegionCoverage::new("chr1", 100, 200, "region1", vec![1, 2, 3]),
+            RegionCoverage::new("chr1", 200, 300, "region2", vec![4, 5, 6]),
+        ];
+        let report = |shape| {
+            let mut output = Vec::new();
+            write_csv(
+                &coverages,
+                None,
+                None,
+                &ReportOptions {
+                    thresholds: Some(&[2]),
+                    shape,
+                    ..ReportOptions::default()
+                },
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let wide = report(ReportShape::Wide);
+        let long = report(ReportShape::Long);
+
+        let expected = "\
+name,chrom,beg,end,metric,value
+region1,chr1,100,200,min,1
+region1,chr1,100,200,max,3
+region1,chr1,100,200,mean,2.00
+region1,chr1,100,200,std,0.82
+region1,chr1,100,200,cv,0.41
+region1,chr1,100,200,pct_ge_2,0.67
+region2,chr1,200,300,min,4
+region2,chr1,200,300,max,6
+region2,chr1,200,300,mean,5.00
+region2,chr1,200,300,std,0.82
+region2,chr1,200,300,cv,0.16
+region2,chr1,200,300,pct_ge_2,1.00
+";
+        assert_eq!(long, expected);
+
+        // Every long row holds the value of its metric's wide column.
+        let mut wide_lines = wide.lines().map(|l| l.split(',').collect::<Vec<_>>());
+        let header = wide_lines.next().unwrap();
+        let wide_rows = wide_lines.collect::<Vec<_>>();
+        for line in long.lines().skip(1) {
+            let fields = line.split(',').collect::<Vec<_>>();
+            let row = wide_rows.iter().find(|r| r[0] == fields[0]).unwrap();
+            let column = header.iter().position(|c| *c == fields[4]).unwrap();
+            assert_eq!(row[..4], fields[..4]);
+            assert_eq!(row[column], fields[5]);
+        }
+        assert_eq!(
+            long.lines().count(),
+            1 + wide_rows.len() * (header.len() - 4)
+        );
+    }
+
     #[test]
     fn test_write_csv_no_coverage() {
         let coverages = vec![RegionCoverage::new("chr1", 100, 200, "region1", vec![])];
         let expected = "\
 name,chrom,beg,end,min,max,mean,std,cv
-region1,chr1,100,200,0,0,0.00,0.00,0.00";
+region1,chr1,100,200,,,,,";
         let mut output = Vec::new();
-        let result = write_csv(&coverages, None, &mut output);
+        let result = write_csv(
+            &coverages,
+            None,
+            None,
+            &ReportOptions::default(),
+            &mut output,
+        );
         assert!(result.is_ok());
         let output_str = String::from_utf8(output).unwrap();
         assert_eq!(
@@ -323,12 +2145,517 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         );
     }
 
+    #[test]
+    fn test_write_csv_zero_coverage_na_string() {
+        let coverages = vec![RegionCoverage::new(
+            "chr1",
+            100,
+            200,
+            "region1",
+            vec![0, 0, 0],
+        )];
+        let mut output = Vec::new();
+        write_csv(
+            &coverages,
+            None,
+            None,
+            &ReportOptions {
+                thresholds: Some(&[1]),
+                na: "NA",
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        // The mean of a zero-coverage region is a genuine zero, but its CV is
+        // undefined.
+        let expected = "\
+name,chrom,beg,end,min,max,mean,std,cv,pct_ge_1
+region1,chr1,100,200,0,0,0.00,0.00,NA,0.00";
+        assert_eq!(String::from_utf8(output).unwrap().trim(), expected);
+    }
+
+    #[test]
+    fn test_trimmed_mean() {
+        // Two spikes at the edges inflate the plain mean well above the
+        // typical depth of 10.
+        let mut depths = vec![10; 18];
+        depths.push(500);
+        depths.push(0);
+        let coverage = RegionCoverage::new("chr1", 0, 20, "spiky", depths);
+        assert!((coverage.mean().unwrap() - 34.0).abs() < 1e-6);
+        assert!((coverage.trimmed_mean(0.1).unwrap() - 10.0).abs() < 1e-6);
+        assert_eq!(coverage.trimmed_mean(0.0), coverage.mean());
+        assert!(coverage.trimmed_mean(0.5).is_none());
+        assert!(coverage.trimmed_mean(-0.1).is_none());
+    }
+
+    #[test]
+    fn test_write_csv_threshold_modes() {
+        // Coverage exactly at the threshold passes `ge` but not `gt`.
+        let coverages = vec![RegionCoverage::new(
+            "chr1",
+            0,
+            4,
+            "region1",
+            vec![1, 2, 2, 3],
+        )];
+        let csv = |mode| {
+            let mut output = Vec::new();
+            write_csv(
+                &coverages,
+                None,
+                None,
+                &ReportOptions {
+                    thresholds: Some(&[2]),
+                    threshold_mode: mode,
+                    ..ReportOptions::default()
+                },
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            csv(ThresholdMode::Ge),
+            "name,chrom,beg,end,min,max,mean,std,cv,pct_ge_2\n\
+             region1,chr1,0,4,1,3,2.00,0.71,0.35,0.75\n"
+        );
+        assert_eq!(
+            csv(ThresholdMode::Gt),
+            "name,chrom,beg,end,min,max,mean,std,cv,pct_gt_2\n\
+             region1,chr1,0,4,1,3,2.00,0.71,0.35,0.25\n"
+        );
+    }
+
+    #[test]
+    fn test_percentile() {
+        let coverage = RegionCoverage::new("chr1", 0, 5, "region1", vec![40, 10, 30, 20, 50]);
+        assert_eq!(coverage.percentile(0.0), Some(10.0));
+        assert_eq!(coverage.percentile(25.0), Some(20.0));
+        assert_eq!(coverage.percentile(50.0), Some(30.0));
+        assert_eq!(coverage.percentile(62.5), Some(35.0));
+        assert_eq!(coverage.percentile(100.0), Some(50.0));
+        assert!(coverage.percentile(100.1).is_none());
+        assert!(RegionCoverage::new("chr1", 0, 0, "empty", vec![])
+            .percentile(50.0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_write_csv_trimmed_mean() {
+        let coverages = vec![RegionCoverage::new(
+            "chr1",
+            100,
+            105,
+            "region1",
+            vec![1, 5, 5, 5, 100],
+        )];
+        let mut output = Vec::new();
+        write_csv(
+            &coverages,
+            None,
+            None,
+            &ReportOptions {
+                thresholds: Some(&[5]),
+                trim_fraction: Some(0.2),
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let expected = "\
+name,chrom,beg,end,min,max,mean,std,cv,trimmed_mean,pct_ge_5
+region1,chr1,100,105,1,100,23.20,38.43,1.66,5.00,0.80";
+        assert_eq!(String::from_utf8(output).unwrap().trim(), expected);
+    }
+
+    #[test]
+    fn test_coverage_for_region_exclude_singletons() {
+        let mut records = Vec::new();
+        for i in 0..3 {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, &format!("read{i}"));
+            record.set_paired();
+            if i == 0 {
+                record.set_mate_unmapped();
+            }
+            records.push(record);
+        }
+        // An unpaired read is not a singleton.
+        records.push(create_mock_record(CHRQ_MIRROR_TID, 100, "unpaired"));
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let mut mock = MockBamReader::new(records.clone(), None);
+        let coverage =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(coverage.reads, 4);
+        assert_eq!(coverage.mean(), Some(4.0));
+
+        let mut mock = MockBamReader::new(records, None);
+        let filters = ReadFilters {
+            exclude_singletons: true,
+            ..Default::default()
+        };
+        let coverage = coverage_for_region(&mut mock, &region, 0, 0, &filters).unwrap();
+        assert_eq!(coverage.reads, 3);
+        assert_eq!(coverage.mean(), Some(3.0));
+    }
+
+    #[test]
+    fn test_duplicate_rate() {
+        let mut records = Vec::new();
+        for i in 0..8 {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, &format!("read{i}"));
+            if i < 2 {
+                record.set_duplicate();
+            }
+            records.push(record);
+        }
+        // Secondary alignments are not reads in their own right.
+        let mut secondary = create_mock_record(CHRQ_MIRROR_TID, 100, "read0");
+        secondary.set_secondary();
+        secondary.set_duplicate();
+        records.push(secondary);
+        let mut mock = MockBamReader::new(records.clone(), None);
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let filters = ReadFilters {
+            exclude_duplicates: false,
+            ..Default::default()
+        };
+        let coverage = coverage_for_region(&mut mock, &region, 0, 0, &filters).unwrap();
+        assert_eq!(coverage.overlapping_reads, 8);
+        assert_eq!(coverage.duplicate_reads, 2);
+        assert_eq!(coverage.duplicate_rate(), Some(0.25));
+        assert_eq!(coverage.reads, 8);
+
+        // By default duplicates are dropped from the coverage and read count,
+        // but not from the duplicate rate.
+        let mut mock = MockBamReader::new(records.clone(), None);
+        let deduplicated =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(deduplicated.reads, 6);
+        assert_eq!(deduplicated.mean(), Some(6.0));
+        assert_eq!(deduplicated.duplicate_rate(), Some(0.25));
+
+        let mut output = Vec::new();
+        write_csv(
+            &[coverage],
+            None,
+            None,
+            &ReportOptions {
+                duplicate_rate: true,
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        assert!(lines.next().unwrap().ends_with(",cv,dup_rate"));
+        assert!(lines.next().unwrap().ends_with(",0.25"));
+
+        let empty = RegionCoverage::new("chr1", 0, 0, "empty", vec![]);
+        assert_eq!(empty.duplicate_rate(), None);
+    }
+
+    #[test]
+    fn test_write_mosdepth() {
+        let coverages = vec![
+            RegionCoverage::new("chr1", 100, 103, "region1", vec![1, 2, 4]),
+            RegionCoverage::new("chr2", 0, 0, "empty", vec![]),
+        ];
+        let mut output = Vec::new();
+        write_mosdepth(&coverages, None, "NA", &mut output).unwrap();
+        // The same layout as a line of mosdepth's regions.bed.gz.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "chr1\t100\t103\tregion1\t2.33\nchr2\t0\t0\tempty\tNA\n"
+        );
+    }
+
+    #[test]
+    fn test_sem() {
+        // Mean 4, population variance (4 + 0 + 4 + 16 + 16) / 5 = 8, so the
+        // SEM is sqrt(8) / sqrt(5).
+        let coverage = RegionCoverage::new("chr1", 0, 5, "a", vec![2, 4, 6, 0, 8]);
+        let expected = (8.0f32 / 5.0).sqrt();
+        let sem = coverage.summary().sem.unwrap();
+        assert!((sem - expected).abs() < 1e-6, "{sem} != {expected}");
+
+        let single = RegionCoverage::new("chr1", 0, 1, "b", vec![7]);
+        assert_eq!(single.summary().sem, None);
+        let empty = RegionCoverage::new("chr1", 0, 0, "c", vec![]);
+        assert_eq!(empty.summary().sem, None);
+
+        let mut output = Vec::new();
+        write_csv(
+            &[coverage, single],
+            None,
+            None,
+            &ReportOptions {
+                sem: true,
+                na: "na",
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,chrom,beg,end,min,max,mean,std,cv,sem\n\
+             a,chr1,0,5,0,8,4.00,2.83,0.71,1.26\n\
+             b,chr1,0,1,7,7,7.00,0.00,0.00,na\n"
+        );
+    }
+
+    #[test]
+    fn test_write_csv_scale() {
+        let coverage = RegionCoverage::new("chr1", 0, 5, "a", vec![0, 2, 4, 6, 8]);
+        let mut output = Vec::new();
+        write_csv(
+            &[coverage],
+            None,
+            None,
+            &ReportOptions {
+                scale: Some(2.0),
+                na: "na",
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        // Mean, std and the extremes double; the CV is unchanged.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,chrom,beg,end,min,max,mean,std,cv\n\
+             a,chr1,0,5,0.00,16.00,8.00,5.66,0.71\n"
+        );
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut duplicated = RegionCoverage::new("chr1", 100, 104, "a,\"b\"", vec![1, 2, 3, 6]);
+        duplicated.overlapping_reads = 4;
+        duplicated.duplicate_reads = 1;
+        let coverages = [
+            duplicated,
+            RegionCoverage::new("chr2", 0, 3, "zero", vec![0, 0, 0]),
+        ];
+        let mut output = Vec::new();
+        write_json(
+            &coverages,
+            None,
+            &ReportOptions {
+                thresholds: Some(&[2]),
+                duplicate_rate: true,
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let output = serde_json::from_slice::<serde_json::Value>(&output).unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!([
+                {
+                    "name": "a,\"b\"", "chrom": "chr1", "beg": 100, "end": 104,
+                    "min": 1, "max": 6, "mean": 3.0, "std": 1.87, "cv": 0.62,
+                    "dup_rate": 0.25, "pct_ge_2": 0.75,
+                },
+                {
+                    "name": "zero", "chrom": "chr2", "beg": 0, "end": 3,
+                    "min": 0, "max": 0, "mean": 0.0, "std": 0.0, "cv": null,
+                    "dup_rate": null, "pct_ge_2": 0.0,
+                },
+            ])
+        );
+
+        // Scaled depths are numbers, and the empty region's are null.
+        let coverages = [
+            RegionCoverage::new("chr1", 100, 103, "a", vec![1, 2, 3]),
+            RegionCoverage::new("chr2", 0, 0, "empty", vec![]),
+        ];
+        let mut output = Vec::new();
+        write_json(
+            &coverages,
+            None,
+            &ReportOptions {
+                thresholds: Some(&[2, 10]),
+                threshold_mode: ThresholdMode::Gt,
+                sem: true,
+                scale: Some(0.5),
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.find("pct_gt_2").unwrap() < text.find("pct_gt_10").unwrap());
+        let output = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!([
+                {
+                    "name": "a", "chrom": "chr1", "beg": 100, "end": 103,
+                    "min": 0.5, "max": 1.5, "mean": 1.0, "std": 0.41, "cv": 0.41,
+                    "sem": 0.24, "pct_gt_2": 0.33, "pct_gt_10": 0.0,
+                },
+                {
+                    "name": "empty", "chrom": "chr2", "beg": 0, "end": 0,
+                    "min": null, "max": null, "mean": null, "std": null, "cv": null,
+                    "sem": null, "pct_gt_2": null, "pct_gt_10": null,
+                },
+            ])
+        );
+
+        let mut output = Vec::new();
+        write_json(&[], None, &ReportOptions::default(), &mut output).unwrap();
+        assert_eq!(output, b"[]\n");
+
+        // Unmapped reads add a read count to every object and an object of
+        // their own.
+        let mut covered = RegionCoverage::new("chr1", 100, 103, "a", vec![1, 2, 3]);
+        covered.reads = 5;
+        let mut output = Vec::new();
+        write_json(
+            &[covered],
+            Some(7),
+            &ReportOptions {
+                thresholds: Some(&[2]),
+                sem: true,
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let output = serde_json::from_slice::<serde_json::Value>(&output).unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!([
+                {
+                    "name": "a", "chrom": "chr1", "beg": 100, "end": 103, "reads": 5,
+                    "min": 1, "max": 3, "mean": 2.0, "std": 0.82, "cv": 0.41,
+                    "sem": 0.47, "pct_ge_2": 0.67,
+                },
+                {
+                    "name": "unmapped", "chrom": "*", "beg": null, "end": null, "reads": 7,
+                    "min": null, "max": null, "mean": null, "std": null, "cv": null,
+                    "sem": null, "pct_ge_2": null,
+                },
+            ])
+        );
+
+        assert_eq!(json_number(Some(f64::NAN)), None);
+        assert_eq!(json_number(Some(f64::INFINITY)), None);
+    }
+
+    #[test]
+    fn test_write_sample_csv() {
+        let regions = vec![
+            Region::new("chr1", 100, 103, "region1"),
+            Region::new("chr2", 0, 2, "region2"),
+        ];
+        let samples = vec![
+            vec![
+                RegionCoverage::new("chr1", 100, 103, "region1", vec![1, 2, 4]),
+                RegionCoverage::new("chr2", 0, 2, "region2", vec![0, 1]),
+            ],
+            vec![
+                RegionCoverage::new("chr1", 100, 103, "region1", vec![3, 3, 3]),
+                RegionCoverage::new("chr2", 0, 0, "region2", vec![]),
+            ],
+        ];
+        let names = vec!["a".to_string(), "b".to_string()];
+        let mut output = Vec::new();
+        write_sample_csv(&regions, &names, &samples, "NA", &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,chrom,beg,end,a,b\nregion1,chr1,100,103,2.33,3.00\nregion2,chr2,0,2,0.50,NA\n"
+        );
+    }
+
+    #[test]
+    fn test_sample_names() {
+        let paths = [
+            PathBuf::from("cohort/s1.bam"),
+            PathBuf::from("s2.sorted.cram"),
+        ];
+        assert_eq!(sample_names(&paths).unwrap(), vec!["s1", "s2.sorted"]);
+        let paths = [PathBuf::from("a/s1.bam"), PathBuf::from("b/s1.cram")];
+        assert!(sample_names(&paths).is_err());
+    }
+
+    #[test]
+    fn test_write_bedgraph() {
+        let coverages = [
+            RegionCoverage::new("chr1", 100, 108, "region1", vec![0, 2, 2, 3, 0, 0, 3, 3]),
+            RegionCoverage::new("chr2", 0, 0, "empty", vec![]),
+            // A flank of 1 leaves bases 11..13 of the BED region.
+            RegionCoverage::new("chr2", 10, 14, "flanked", vec![5, 5]),
+        ];
+        let mut output = Vec::new();
+        write_bedgraph(&coverages[..2], 0, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "chr1\t101\t103\t2\nchr1\t103\t104\t3\nchr1\t106\t108\t3\n"
+        );
+        let mut output = Vec::new();
+        write_bedgraph(&coverages[2..], 1, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "chr2\t11\t13\t5\n");
+    }
+
+    #[test]
+    fn test_write_csv_unmapped() {
+        let mut coverage = RegionCoverage::new("chr1", 100, 103, "region1", vec![1, 2, 3]);
+        coverage.reads = 3;
+        let mut output = Vec::new();
+        write_csv(
+            &[coverage],
+            None,
+            Some(7),
+            &ReportOptions {
+                thresholds: Some(&[2]),
+                na: "NA",
+                ..ReportOptions::default()
+            },
+            &mut output,
+        )
+        .unwrap();
+        let expected = "\
+name,chrom,beg,end,reads,min,max,mean,std,cv,pct_ge_2
+region1,chr1,100,103,3,1,3,2.00,0.82,0.41,0.67
+unmapped,*,NA,NA,7,NA,NA,NA,NA,NA,NA";
+        assert_eq!(String::from_utf8(output).unwrap().trim(), expected);
+    }
+
+    #[test]
+    fn test_count_unmapped() {
+        let mut records = vec![
+            create_mock_record(CHRQ_MIRROR_TID, 100, "mapped1"),
+            create_mock_record(CHRQ_MIRROR_TID, 200, "mapped2"),
+        ];
+        for i in 0..3 {
+            let mut record = Record::new();
+            record.set_tid(-1);
+            record.set_pos(-1);
+            record.set_qname(format!("unmapped{i}").as_bytes());
+            record.set_unmapped();
+            records.push(record);
+        }
+        let mut mock = MockBamReader::new(records, None);
+        assert_eq!(count_unmapped(&mut mock).unwrap(), 3);
+
+        let region = Region::new("chrQ_mirror", 0, 1000, "test_region");
+        let coverage =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(coverage.reads, 2);
+    }
+
     #[test]
     fn test_coverage_for_region_empty() {
         let records = vec![];
         let mut mock = MockBamReader::new(records, None);
         let region = Region::new("chrQ_mirror", 100, 200, "test_region");
-        let result = coverage_for_region(&mut mock, &region, 0, 0);
+        let result = coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default());
         assert!(result.is_ok());
         let coverage = result.unwrap();
         let max = coverage.max().expect("should have max");
@@ -346,7 +2673,7 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         record.unset_unmapped();
         let mut mock = MockBamReader::new(vec![record], None);
         let region = Region::new("chrQ_mirror", 100, 200, "test_region");
-        let result = coverage_for_region(&mut mock, &region, 0, 0);
+        let result = coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default());
         assert!(result.is_ok());
         let coverage = result.unwrap();
         let max = coverage.max().expect("should have max");
@@ -365,7 +2692,7 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         record.unset_unmapped();
         let mut mock = MockBamReader::new(vec![record], None);
         let region = Region::new("chrX", 100, 200, "test_region");
-        let result = coverage_for_region(&mut mock, &region, 0, 0);
+        let result = coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default());
         assert!(result.is_err());
     }
 
@@ -379,7 +2706,7 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         let mut mock = MockBamReader::new(vec![record1, record2, record3], None);
         let region = Region::new("chrQ_mirror", 100, 200, "test_region");
         let min_mapq = 20;
-        let result = coverage_for_region(&mut mock, &region, min_mapq, 0);
+        let result = coverage_for_region(&mut mock, &region, min_mapq, 0, &ReadFilters::default());
         assert!(result.is_ok());
         let coverage = result.unwrap();
         let max = coverage.max().expect("should have max");
@@ -387,11 +2714,708 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         assert_eq!(coverage.mean().unwrap(), 2.0);
     }
 
+    #[test]
+    fn test_coverage_for_region_spliced_read() {
+        // 50M100N50M starting at 100: exons at [100, 150) and [250, 300).
+        let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, "read1");
+        record.set_cigar(Some(&CigarString(vec![
+            Cigar::Match(50),
+            Cigar::RefSkip(100),
+            Cigar::Match(50),
+        ])));
+        let mut mock = MockBamReader::new(vec![record], None);
+        let region = Region::new("chrQ_mirror", 100, 400, "test_region");
+        let coverage =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(coverage.spliced_reads, 1);
+        assert!(coverage.coverage[0..50].iter().all(|&c| c == 1));
+        assert!(coverage.coverage[50..150].iter().all(|&c| c == 0));
+        assert!(coverage.coverage[150..200].iter().all(|&c| c == 1));
+        assert!(coverage.coverage[200..300].iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn test_coverage_for_region_spliced_read_at_boundary() {
+        // 50M100N50M starting at 50: the intron [100, 200) starts exactly at
+        // the region start and the second exon is [200, 250).
+        let mut record = create_mock_record(CHRQ_MIRROR_TID, 50, "read1");
+        record.set_cigar(Some(&CigarString(vec![
+            Cigar::Match(50),
+            Cigar::RefSkip(100),
+            Cigar::Match(50),
+        ])));
+        let mut mock = MockBamReader::new(vec![record], None);
+        let region = Region::new("chrQ_mirror", 100, 300, "test_region");
+        let coverage =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert!(coverage.coverage[0..100].iter().all(|&c| c == 0));
+        assert!(coverage.coverage[100..150].iter().all(|&c| c == 1));
+        assert!(coverage.coverage[150..200].iter().all(|&c| c == 0));
+    }
+
+    fn create_pair_record(pos: i64, qname: &str, tlen: i64, first: bool) -> Record {
+        let mut record = create_mock_record(CHRQ_MIRROR_TID, pos, qname);
+        record.set_paired();
+        record.set_proper_pair();
+        if first {
+            record.set_first_in_template();
+        } else {
+            record.set_last_in_template();
+        }
+        record.set_insert_size(tlen);
+        record
+    }
+
+    #[test]
+    fn test_tlen_histogram_for_region() {
+        let mut improper = create_pair_record(120, "read4", 500, true);
+        improper.unset_proper_pair();
+        let records = vec![
+            create_pair_record(100, "read1", 300, true),
+            create_pair_record(300, "read1", -300, false),
+            create_pair_record(110, "read2", 300, true),
+            create_pair_record(150, "read3", -250, true),
+            improper,
+            // Only the second read of read5 overlaps the region.
+            create_pair_record(0, "read5", 450, true),
+            create_pair_record(350, "read5", -450, false),
+        ];
+        let mut mock = MockBamReader::new(records, None);
+        let region = Region::new("chrQ_mirror", 100, 400, "test_region");
+        let histogram =
+            tlen_histogram_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(histogram, BTreeMap::from([(250, 1), (300, 2), (450, 1)]));
+
+        let mut output = Vec::new();
+        write_tlen_histogram(&[region], &[histogram], &mut output).unwrap();
+        let expected = "\
+region,tlen,count
+test_region,250,1
+test_region,300,2
+test_region,450,1
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_csv_flank_report() {
+        // Reads cover only the middle of the region, so its edges have no
+        // coverage.
+        let records = (0..5)
+            .map(|i| create_mock_record(0, 150, &format!("read{i}")))
+            .collect::<Vec<_>>();
+        let region = Region::new("chr1", 100, 300, "region1");
+        let filters = ReadFilters::default();
+        let mut mock = MockBamReader::new(records, None);
+        let flanked = coverage_for_region(&mut mock, &region, 0, 50, &filters).unwrap();
+        let unflanked = coverage_for_region(&mut mock, &region, 0, 0, &filters).unwrap();
+
+        let mut output = Vec::new();
+        write_csv(
+            &[flanked],
+            Some(&[unflanked]),
+            None,
+            &ReportOptions::default(),
+            &mut output,
+        )
+        .unwrap();
+        let expected = "\
+name,chrom,beg,end,flank_min,flank_max,flank_mean,flank_std,flank_cv,noflank_min,noflank_max,noflank_mean,noflank_std,noflank_cv
+region1,chr1,100,300,5,5,5.00,0.00,0.00,0,5,2.50,2.50,1.00
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_read_count_histogram() {
+        let coverages = [0, 9, 10, 25, 3]
+            .into_iter()
+            .enumerate()
+            .map(|(i, reads)| {
+                let mut coverage =
+                    RegionCoverage::new("chr1", 0, 100, &format!("region{i}"), vec![0; 100]);
+                coverage.reads = reads;
+                coverage
+            })
+            .collect::<Vec<_>>();
+        let bins = read_count_histogram(&coverages, 10, CountMode::Primary);
+        assert_eq!(bins, vec![3, 1, 1]);
+        assert_eq!(
+            read_count_histogram(&coverages, 1, CountMode::Primary).len(),
+            26
+        );
+        assert!(read_count_histogram(&[], 10, CountMode::Primary).is_empty());
+
+        let mut output = Vec::new();
+        write_read_count_histogram(&bins, 10, &mut output).unwrap();
+        let expected = "\
+reads_beg,reads_end,regions
+0,10,3
+10,20,1
+20,30,1
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_contig_mean_depth() {
+        // chr1 is 5000bp in the mock header: 40 x 100bp reads gives 0.8x.
+        let mut records = (0..40)
+            .map(|i| create_mock_record(0, i * 100, &format!("read{i}")))
+            .collect::<Vec<_>>();
+        let mut low_mapq = create_mock_record(0, 0, "low");
+        low_mapq.set_mapq(0);
+        records.push(low_mapq);
+        records.push(create_mock_record(CHRQ_MIRROR_TID, 100, "sequin"));
+        let mut mock = MockBamReader::new(records, None);
+        let depth = contig_mean_depth(&mut mock, "chr1", 10).unwrap();
+        assert!((depth - 0.8).abs() < 1e-9, "depth = {depth}");
+        assert!(contig_mean_depth(&mut mock, "chrZ", 10).is_err());
+    }
+
+    #[test]
+    fn test_tag_filter_from_str() {
+        let filter = "XT:keep".parse::<TagFilter>();
+        assert!(filter.is_err());
+        assert!("XTZ=keep".parse::<TagFilter>().is_err());
+        let filter = "XT=a=b".parse::<TagFilter>().unwrap();
+        assert_eq!(&filter.tag, b"XT");
+        assert_eq!(filter.value, "a=b");
+    }
+
+    #[test]
+    fn test_coverage_for_region_read_filters() {
+        let tagged = |qname: &str, tag: &[u8], value: Aux| {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, qname);
+            record.push_aux(tag, value).unwrap();
+            record
+        };
+        let records = vec![
+            tagged("keep_str", b"XT", Aux::String("keep")),
+            tagged("drop_str", b"XT", Aux::String("drop")),
+            tagged("keep_int", b"XN", Aux::U8(1)),
+            tagged("drop_int", b"XN", Aux::I32(2)),
+            create_mock_record(CHRQ_MIRROR_TID, 100, "untagged"),
+        ];
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let reads_with = |filters: ReadFilters| {
+            let mut mock = MockBamReader::new(records.clone(), None);
+            coverage_for_region(&mut mock, &region, 0, 0, &filters)
+                .unwrap()
+                .reads
+        };
+        let parse = |s: &str| s.parse::<TagFilter>().unwrap();
+
+        assert_eq!(reads_with(ReadFilters::default()), 5);
+        let require_str = ReadFilters {
+            require_tags: vec![parse("XT=keep")],
+            ..Default::default()
+        };
+        assert_eq!(reads_with(require_str), 1);
+        let require_int = ReadFilters {
+            require_tags: vec![parse("XN=1")],
+            ..Default::default()
+        };
+        assert_eq!(reads_with(require_int), 1);
+        let exclude = ReadFilters {
+            exclude_tags: vec![parse("XT=drop"), parse("XN=2")],
+            ..Default::default()
+        };
+        assert_eq!(reads_with(exclude), 3);
+        let both = ReadFilters {
+            require_tags: vec![parse("XT=keep")],
+            exclude_tags: vec![parse("XT=keep")],
+            ..Default::default()
+        };
+        assert_eq!(reads_with(both), 0);
+    }
+
+    #[test]
+    fn test_coverage_for_region_insert_size_filters() {
+        let pair = |qname: &str, tlen: i64, proper: bool| {
+            let mut record = create_pair_record(100, qname, tlen, true);
+            record.set_mtid(CHRQ_MIRROR_TID);
+            if !proper {
+                record.unset_proper_pair();
+            }
+            record
+        };
+        let mut cross_contig = pair("cross_contig", 500, true);
+        cross_contig.set_mtid(0);
+        let records = vec![
+            pair("short", 50, true),
+            pair("inside", 300, true),
+            pair("inside_reverse", -300, true),
+            pair("long", 5000, true),
+            pair("zero_tlen", 0, true),
+            cross_contig,
+            pair("improper", 5000, false),
+        ];
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let reads_with = |filters: ReadFilters| {
+            let mut mock = MockBamReader::new(records.clone(), None);
+            coverage_for_region(&mut mock, &region, 0, 0, &filters)
+                .unwrap()
+                .reads
+        };
+
+        assert_eq!(reads_with(ReadFilters::default()), 7);
+        let window = ReadFilters {
+            min_insert_size: Some(100),
+            max_insert_size: Some(1000),
+            ..Default::default()
+        };
+        // short and long are dropped; TLEN 0, cross-contig and improper
+        // pairs pass through.
+        assert_eq!(reads_with(window.clone()), 5);
+        let proper_only = ReadFilters {
+            require_proper_pair: true,
+            ..window
+        };
+        assert_eq!(reads_with(proper_only), 4);
+    }
+
+    #[test]
+    fn test_min_align_len_filter() {
+        let long = create_mock_record(CHRQ_MIRROR_TID, 100, "long");
+        let mut short = create_mock_record(CHRQ_MIRROR_TID, 100, "short");
+        short.set_cigar(Some(&CigarString(vec![
+            Cigar::SoftClip(70),
+            Cigar::Match(20),
+            Cigar::Del(5),
+            Cigar::Match(5),
+        ])));
+        assert_eq!(record_ref_span(&long), 100);
+        assert_eq!(record_ref_span(&short), 30);
+
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let filters = ReadFilters {
+            min_align_len: Some(50),
+            ..Default::default()
+        };
+        let mut mock = MockBamReader::new(vec![long, short], None);
+        let coverage = coverage_for_region(&mut mock, &region, 0, 0, &filters).unwrap();
+        assert_eq!(coverage.reads, 1);
+        assert_eq!(coverage.max(), Some(&1));
+
+        let filters = ReadFilters {
+            min_align_len: Some(30),
+            ..Default::default()
+        };
+        let coverage = coverage_for_region(&mut mock, &region, 0, 0, &filters).unwrap();
+        assert_eq!(coverage.reads, 2);
+    }
+
+    #[test]
+    fn test_coverage_unit() {
+        // A spliced read: 50 aligned bases, a 100 base intron, then 50 more.
+        let mut spliced = create_mock_record(CHRQ_MIRROR_TID, 100, "spliced");
+        spliced.set_cigar(Some(&CigarString(vec![
+            Cigar::Match(50),
+            Cigar::RefSkip(100),
+            Cigar::Match(50),
+        ])));
+        let region = Region::new("chrQ_mirror", 100, 300, "test_region");
+        let coverage_in = |unit| {
+            let mut mock = MockBamReader::new(vec![spliced.clone()], None);
+            coverage_for_region_in_unit(
+                &mut mock,
+                &region,
+                &CountOptions {
+                    unit,
+                    ..CountOptions::bases(0, 0, &ReadFilters::default())
+                },
+            )
+            .unwrap()
+        };
+
+        let base = coverage_in(CoverageUnit::Base);
+        assert_eq!(base.mean(), Some(0.5));
+        assert_eq!(base.min(), Some(&0));
+        let read = coverage_in(CoverageUnit::Read);
+        assert_eq!(read.mean(), Some(1.0));
+        assert_eq!(read.min(), Some(&1));
+        assert_eq!(base.reads, read.reads);
+    }
+
+    #[test]
+    fn test_coverage_for_region_no_double_count() {
+        // Mates of a 150 base fragment overlapping by 50 bases, and an
+        // unpaired read over the overlap.
+        let mate = |pos, flags| {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, pos, "fragment");
+            record.set_flags(flags);
+            record.set_mtid(CHRQ_MIRROR_TID);
+            record
+        };
+        let records = vec![
+            mate(100, 99),
+            create_mock_record(CHRQ_MIRROR_TID, 120, "single"),
+            mate(150, 147),
+        ];
+        let region = Region::new("chrQ_mirror", 100, 300, "test_region");
+        let coverage_with = |unit, no_double_count| {
+            let mut mock = MockBamReader::new(records.clone(), None);
+            coverage_for_region_in_unit(
+                &mut mock,
+                &region,
+                &CountOptions {
+                    unit,
+                    no_double_count,
+                    ..CountOptions::bases(0, 0, &ReadFilters::default())
+                },
+            )
+            .unwrap()
+        };
+
+        let doubled = coverage_with(CoverageUnit::Base, false);
+        assert_eq!(doubled.coverage[60], 3);
+        for unit in [CoverageUnit::Base, CoverageUnit::Read] {
+            let once = coverage_with(unit, true);
+            assert_eq!(once.coverage[10], 1);
+            // The overlap counts the fragment once and the unpaired read.
+            assert_eq!(once.coverage[60], 2);
+            assert_eq!(once.coverage[149], 1);
+            assert_eq!(once.coverage[150], 0);
+            assert_eq!(once.reads, 3);
+        }
+    }
+
+    #[test]
+    fn test_overlap_policy() {
+        // The mates of a fragment overlap over 150..200, and an unpaired read
+        // covers 120..220.
+        let mate = |pos, flags| {
+            let mut record = create_mock_record(CHRQ_MIRROR_TID, pos, "fragment");
+            record.set_flags(flags);
+            record.set_mtid(CHRQ_MIRROR_TID);
+            record
+        };
+        let records = vec![
+            mate(100, 99),
+            create_mock_record(CHRQ_MIRROR_TID, 120, "single"),
+            mate(150, 147),
+        ];
+        let region = Region::new("chrQ_mirror", 100, 300, "test_region");
+        let coverage_with = |policy: OverlapPolicy| {
+            let mut mock = MockBamReader::new(records.clone(), None);
+            coverage_for_region_in_unit(
+                &mut mock,
+                &region,
+                &CountOptions {
+                    max_depth: policy.max_depth(0),
+                    no_double_count: policy.no_double_count(),
+                    ..CountOptions::bases(0, 0, &ReadFilters::default())
+                },
+            )
+            .unwrap()
+        };
+
+        let reads = coverage_with(OverlapPolicy::Reads);
+        let fragments = coverage_with(OverlapPolicy::Fragments);
+        let unique = coverage_with(OverlapPolicy::UniqueBases);
+        assert_eq!(reads.coverage[60], 3);
+        assert_eq!(fragments.coverage[60], 2);
+        assert_eq!(unique.coverage[60], 1);
+        assert_eq!(reads.coverage[10], 1);
+        assert_eq!(fragments.coverage[10], 1);
+        assert_eq!(unique.coverage[10], 1);
+        // Every read covers 150 bases of the region.
+        assert_eq!(unique.mean(), Some(0.75));
+        assert!(unique.coverage.iter().all(|&depth| depth <= 1));
+        assert_eq!(OverlapPolicy::Fragments.max_depth(100), 100);
+        assert_eq!(OverlapPolicy::UniqueBases.max_depth(100), 1);
+    }
+
+    #[test]
+    fn test_coverage_for_region_min_baseq() {
+        // Soft-clipped and inserted bases have no reference position but
+        // still move the read offset the qualities are looked up at.
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(5),
+            Cigar::Match(10),
+            Cigar::Ins(2),
+            Cigar::Match(10),
+        ]);
+        let mut qual = vec![0; 5];
+        qual.extend([30; 5]);
+        qual.extend([10; 5]);
+        qual.extend([0; 2]);
+        qual.extend([30; 8]);
+        qual.extend([10; 2]);
+        let mut record = create_mock_record(CHRQ_MIRROR_TID, 100, "read1");
+        record.set(b"read1", Some(&cigar), &[b'A'; 27], &qual);
+        record.set_pos(100);
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let coverage_with = |min_baseq| {
+            let mut mock = MockBamReader::new(vec![record.clone()], None);
+            coverage_for_region_in_unit(
+                &mut mock,
+                &region,
+                &CountOptions {
+                    min_baseq,
+                    ..CountOptions::bases(0, 0, &ReadFilters::default())
+                },
+            )
+            .unwrap()
+        };
+
+        let all = coverage_with(0);
+        assert_eq!(all.coverage[..20], [1; 20]);
+        let filtered = coverage_with(20);
+        assert_eq!(filtered.coverage[..5], [1; 5]);
+        assert_eq!(filtered.coverage[5..10], [0; 5]);
+        assert_eq!(filtered.coverage[10..18], [1; 8]);
+        assert_eq!(filtered.coverage[18..20], [0; 2]);
+        assert_eq!(filtered.reads, 1);
+    }
+
+    #[test]
+    fn test_coverage_for_region_no_reference_sequences() {
+        let records = vec![create_mock_record(0, 100, "read1")];
+        let mut mock = MockBamReader::new(records, Some(&[]));
+        let region = Region::new("chrQ_mirror", 100, 300, "test_region");
+        let err =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "BAM header contains no reference sequences"
+        );
+        let err = region::check_contigs(&[region], mock.header()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "BAM header contains no reference sequences"
+        );
+    }
+
+    #[test]
+    fn test_coverage_for_region_max_depth() {
+        let records = (0..5)
+            .map(|i| create_mock_record(CHRQ_MIRROR_TID, 100 + i * 10, &format!("read{i}")))
+            .collect::<Vec<_>>();
+        let region = Region::new("chrQ_mirror", 100, 300, "test_region");
+        let coverage_with = |unit, max_depth| {
+            let mut mock = MockBamReader::new(records.clone(), None);
+            coverage_for_region_in_unit(
+                &mut mock,
+                &region,
+                &CountOptions {
+                    unit,
+                    max_depth,
+                    ..CountOptions::bases(0, 0, &ReadFilters::default())
+                },
+            )
+            .unwrap()
+        };
+
+        let unlimited = coverage_with(CoverageUnit::Base, 0);
+        assert_eq!(unlimited.max(), Some(&5));
+        for unit in [CoverageUnit::Base, CoverageUnit::Read] {
+            let capped = coverage_with(unit, 3);
+            assert_eq!(capped.max(), Some(&3));
+            // Positions below the cap are unaffected, and every read is
+            // still counted.
+            assert_eq!(capped.coverage[0], 1);
+            assert_eq!(capped.coverage[25], 3);
+            assert_eq!(capped.coverage[45], 3);
+            assert_eq!(capped.reads, 5);
+        }
+    }
+
+    #[test]
+    fn test_count_mode() {
+        let mut secondary = create_mock_record(CHRQ_MIRROR_TID, 100, "read1");
+        secondary.set_secondary();
+        let mut supplementary = create_mock_record(CHRQ_MIRROR_TID, 150, "read2");
+        supplementary.set_supplementary();
+        let mut low_mapq = create_mock_record(CHRQ_MIRROR_TID, 150, "read3");
+        low_mapq.set_secondary();
+        low_mapq.set_mapq(5);
+        let records = vec![
+            create_mock_record(CHRQ_MIRROR_TID, 100, "read1"),
+            create_mock_record(CHRQ_MIRROR_TID, 120, "read2"),
+            secondary,
+            supplementary,
+            low_mapq,
+        ];
+        let mut mock = MockBamReader::new(records, None);
+        let region = Region::new("chrQ_mirror", 100, 200, "test_region");
+        let coverage =
+            coverage_for_region(&mut mock, &region, 10, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(coverage.read_count(CountMode::Primary), 2);
+        assert_eq!(coverage.read_count(CountMode::All), 4);
+        // Coverage is from the primary alignments in both modes.
+        assert_eq!(coverage.coverage[0], 1);
+        assert_eq!(coverage.coverage[99], 2);
+
+        let csv = |mode| {
+            let mut output = Vec::new();
+            write_csv(
+                std::slice::from_ref(&coverage),
+                None,
+                Some(0),
+                &ReportOptions {
+                    count_mode: mode,
+                    na: "NA",
+                    ..ReportOptions::default()
+                },
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert!(csv(CountMode::Primary).contains("\ntest_region,chrQ_mirror,100,200,2,"));
+        assert!(csv(CountMode::All).contains("\ntest_region,chrQ_mirror,100,200,4,"));
+    }
+
+    #[test]
+    fn test_coverage_for_region_skips_negative_pos() {
+        let records = vec![
+            create_mock_record(0, -1, "negative"),
+            create_mock_record(0, 50, "read1"),
+        ];
+        let mut mock = MockBamReader::new(records, None);
+        let region = Region::new("chr1", 0, 100, "start");
+        let coverage =
+            coverage_for_region(&mut mock, &region, 0, 0, &ReadFilters::default()).unwrap();
+        assert_eq!(coverage.reads, 1);
+        assert_eq!(coverage.overlapping_reads, 1);
+        assert_eq!(coverage.coverage[0], 0);
+        assert_eq!(coverage.coverage[50], 1);
+    }
+
+    #[test]
+    fn test_coverage_for_head_regions() {
+        // Depth 1 over 0-49, 2 over 50-99, 1 over 100-149 and 0 after.
+        let records = vec![
+            create_mock_record(0, 0, "read1"),
+            create_mock_record(0, 50, "read2"),
+        ];
+        let mut mock = MockBamReader::new(records, None);
+        let filters = ReadFilters::default();
+        let regions = [
+            Region::new("chr1", 0, 200, "long"),
+            Region::new("chr1", 40, 70, "short"),
+        ];
+
+        let heads = head_regions(&regions, 60, 0);
+        assert_eq!(heads[0], Region::new("chr1", 0, 60, "long"));
+        // A region shorter than the head is kept whole.
+        assert_eq!(heads[1], regions[1]);
+        let coverage = coverage_for_region(&mut mock, &heads[0], 0, 0, &filters).unwrap();
+        assert_eq!(coverage.coverage.len(), 60);
+        assert_eq!(coverage.min(), Some(&1));
+        assert_eq!(coverage.max(), Some(&2));
+        assert_eq!(coverage.mean(), Some(70.0 / 60.0));
+
+        // The head starts after the flank: bases 10-49.
+        let heads = head_regions(&regions, 40, 10);
+        assert_eq!(heads[0], Region::new("chr1", 0, 60, "long"));
+        let coverage = coverage_for_region(&mut mock, &heads[0], 0, 10, &filters).unwrap();
+        assert_eq!(coverage.coverage.len(), 40);
+        assert_eq!(coverage.mean(), Some(1.0));
+        assert_eq!(coverage.std(), Some(0.0));
+        // The end flank still applies to a region shorter than the head.
+        let coverage = coverage_for_region(&mut mock, &heads[1], 0, 10, &filters).unwrap();
+        assert_eq!(coverage.coverage.len(), 10);
+    }
+
+    #[test]
+    fn test_coverage_for_region_at_contig_start() {
+        // One read covering 0-99 and one covering 50-149.
+        let records = vec![
+            create_mock_record(0, 0, "read1"),
+            create_mock_record(0, 50, "read2"),
+        ];
+        let mut mock = MockBamReader::new(records, None);
+        let filters = ReadFilters::default();
+
+        let region = Region::new("chr1", 0, 100, "start");
+        let coverage = coverage_for_region(&mut mock, &region, 0, 0, &filters).unwrap();
+        assert_eq!(coverage.coverage.len(), 100);
+        assert_eq!(coverage.coverage[0], 1);
+        assert_eq!(coverage.coverage[49], 1);
+        assert_eq!(coverage.coverage[50], 2);
+        assert_eq!(coverage.coverage[99], 2);
+        assert_eq!(coverage.reads, 2);
+        assert_eq!(coverage.mean(), Some(1.5));
+
+        // With a flank the first `flank` bases are dropped, not shifted.
+        let coverage = coverage_for_region(&mut mock, &region, 0, 10, &filters).unwrap();
+        assert_eq!(coverage.coverage.len(), 80);
+        assert_eq!(coverage.coverage[0], 1);
+        assert_eq!(coverage.coverage[40], 2);
+
+        // Every aligned base of a read at position 0 counts towards the
+        // contig mean: 200 bases over 5000.
+        let depth = contig_mean_depth(&mut mock, "chr1", 0).unwrap();
+        assert!((depth - 0.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_calculate_coverage_at_contig_start() {
+        let bam_path = PathBuf::from("testdata/calibrated.bam");
+        let regions = [
+            Region::new("chrQ_mirror", 0, 400, "from_zero"),
+            Region::new("chrQ_mirror", 1, 400, "from_one"),
+        ];
+        let coverage = calculate_coverage(
+            &bam_path,
+            &regions,
+            None,
+            None,
+            None,
+            &CountOptions::bases(0, 0, &ReadFilters::default()),
+        )
+        .unwrap();
+        assert_eq!(coverage[0].coverage.len(), 400);
+        assert_eq!(coverage[0].coverage[1..], coverage[1].coverage[..]);
+        assert!(coverage[0].max().is_some_and(|&max| max > 0));
+    }
+
+    #[test]
+    fn test_calculate_coverage_threads() {
+        let bam_path = PathBuf::from("testdata/calibrated.bam");
+        let regions = [
+            Region::new("chrQ_mirror", 0, 400, "a"),
+            Region::new("chrQ_mirror", 1000, 1400, "b"),
+        ];
+        let coverage_with = |compute_threads, io_threads| {
+            with_compute_threads(compute_threads, || {
+                if let Some(threads) = compute_threads {
+                    assert_eq!(rayon::current_num_threads(), threads);
+                }
+                calculate_coverage(
+                    &bam_path,
+                    &regions,
+                    None,
+                    None,
+                    io_threads,
+                    &CountOptions::bases(0, 0, &ReadFilters::default()),
+                )
+            })
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.coverage, c.reads))
+            .collect::<Vec<_>>()
+        };
+        let expected = coverage_with(None, None);
+        assert_eq!(coverage_with(Some(1), Some(1)), expected);
+        assert_eq!(coverage_with(Some(3), Some(2)), expected);
+    }
+
     #[test]
     fn test_calculate_coverage() {
         let bam_path = PathBuf::from("testdata/calibrated.bam");
         let region = Region::new("chrQ_mirror", 100, 200, "test_region");
-        let result = calculate_coverage(&bam_path, &[region], None, 0, 0);
+        let result = calculate_coverage(
+            &bam_path,
+            &[region],
+            None,
+            None,
+            None,
+            &CountOptions::bases(0, 0, &ReadFilters::default()),
+        );
         assert!(result.is_ok());
     }
 
@@ -401,7 +3425,7 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         let mut mock = MockBamReader::new(records, None);
         let region = Region::new("chrQ_mirror", 100, 200, "test_region");
         let flank = u64::MAX;
-        let result = coverage_for_region(&mut mock, &region, 0, flank);
+        let result = coverage_for_region(&mut mock, &region, 0, flank, &ReadFilters::default());
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert!(err
@@ -415,7 +3439,7 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         let mut mock = MockBamReader::new(records, None);
         let region = Region::new("chrQ_mirror", u64::MIN, 200, "test_region");
         let flank = u64::MAX - 10;
-        let result = coverage_for_region(&mut mock, &region, 0, flank);
+        let result = coverage_for_region(&mut mock, &region, 0, flank, &ReadFilters::default());
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert!(err
@@ -429,7 +3453,7 @@ region1,chr1,100,200,0,0,0.00,0.00,0.00";
         let mut mock = MockBamReader::new(records, None);
         let region = Region::new("chrQ_mirror", 100, 200, "test_region");
         let flank = 150;
-        let result = coverage_for_region(&mut mock, &region, 0, flank);
+        let result = coverage_for_region(&mut mock, &region, 0, flank, &ReadFilters::default());
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert!(err
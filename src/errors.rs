@@ -4,6 +4,8 @@ use thiserror::Error;
 pub enum Error {
     #[error("BAM file not found")]
     BamFileNotFound,
+    #[error("BAM header contains no reference sequences")]
+    NoReferenceSequences,
     #[error("Invalid region specified")]
     InvalidRegion,
     #[error("An unknown error occurred: {0}")]
@@ -18,14 +20,26 @@ pub enum Error {
     #[error("UTF-8 error: {0}")]
     Utf8Error(#[from] std::str::Utf8Error),
 
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("invalid BED record: {msg}")]
     BedInvalidRecord { msg: String },
 
+    #[error("invalid region: {msg}")]
+    Region { msg: String },
+
     #[error("bedcov error: {msg}")]
     Bedcov { msg: String },
 
     #[error("calibration error: {msg}")]
     Calibration { msg: String },
+
+    #[error("merge error: {msg}")]
+    Merge { msg: String },
+
+    #[error("reference error: {msg}")]
+    Reference { msg: String },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -1,6 +1,12 @@
 pub mod errors;
 pub use errors::Result;
 pub mod bam;
+pub mod cache;
 pub mod calibration;
 pub mod coverage;
+pub use coverage::{coverage_for_region, RegionCoverage};
+pub mod progress;
 pub mod region;
+pub mod stats;
+pub mod timing;
+pub mod windows;
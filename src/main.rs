@@ -1,37 +1,124 @@
-use anyhow::{anyhow, bail, Result};
-use clap::{Args, Parser, Subcommand};
-use rust_htslib::bam;
-use sequintools::bam::{BamReader, BamWriter, HtslibBamReader, HtslibBamWriter};
-use sequintools::calibration::{self, CalibrationMode};
+use anyhow::{bail, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use rust_htslib::bam::{self, Read as _};
+use sequintools::bam::{
+    self as seqbam, BamReader, BamWriter, HtslibBamReader, HtslibBamWriter, MergingWriter,
+    RegionSplitWriter,
+};
+use sequintools::cache::CoverageCache;
+use sequintools::calibration;
+use sequintools::coverage::{
+    CountMode, CoverageUnit, OverlapPolicy, ReadFilters, ReportFormat, ReportShape, TagFilter,
+    ThresholdMode,
+};
 use sequintools::region;
+use sequintools::stats::StatsFormat;
+use sequintools::timing::{self, Timings};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 #[derive(Parser, Debug)]
 #[clap(version = env!("GIT_VERSION"))]
 pub struct App {
+    /// Number of threads to use, for shared machines where only some of the
+    /// cores are ours. 0 uses one per CPU. Sets the htslib reader and writer
+    /// threads and the number of regions processed in parallel.
+    #[arg(long = "threads", value_name = "N", global = true, default_value_t = 0)]
+    threads: usize,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl App {
+    /// The number of threads requested with `--threads`, or `None` for one
+    /// per CPU.
+    fn threads(&self) -> Option<usize> {
+        (self.threads > 0).then_some(self.threads)
+    }
+}
+
 #[derive(Args, Debug)]
 pub struct CalibrateArgs {
     /// flanking regions to omit from analysis (due to sequencing edge affects)
     #[arg(long, default_value_t = 500)]
     flank: u64,
 
+    /// Random seed for downsampling. 0 chooses a random seed; the seed used is
+    /// always printed to standard error.
     #[arg(short, long, default_value_t = 5678)]
     seed: u64,
 
+    /// Draw replicate N of the run seeded with --seed: each replicate keeps a
+    /// different, reproducible, set of reads. 0 uses --seed unchanged.
+    #[arg(long = "seed-salt", alias = "replicate", default_value_t = 0)]
+    seed_salt: u64,
+
     /// target fold-coverage
     #[arg(short, long, default_value_t = 40)]
     fold_coverage: u64,
 
+    /// Keep about N read pairs from each sequin region instead of calibrating
+    /// to a fold coverage. Regions with fewer pairs keep all of them.
+    #[arg(
+        long = "target-reads",
+        value_name = "N",
+        conflicts_with_all = ["fold_coverage", "sample_bed", "target_contig", "target_column", "experimental"]
+    )]
+    target_reads: Option<u64>,
+
     /// Size of sliding window when matching sample data coverage
     #[arg(short, long, default_value_t = 100)]
     window_size: u64,
 
+    /// Width of the moving average applied to sample window counts in the
+    /// experimental profile mode (1 disables smoothing)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    profile_smoothing: u64,
+
+    /// In the experimental profile mode, write both mates of a selected pair
+    /// even when the mate starts outside the region it was selected in
+    #[arg(long = "keep-read-if-any-mate-selected", default_value_t = false)]
+    keep_read_if_any_mate_selected: bool,
+
+    /// In the experimental profile mode, the profile to select reads to:
+    /// `sample` mirrors the sample region's coverage profile, `uniform` gives
+    /// every window the sample region's mean number of reads for a flat
+    /// profile.
+    #[arg(
+        long = "profile-target",
+        value_enum,
+        default_value_t = CalibrateProfileTarget::Sample,
+        requires = "experimental"
+    )]
+    profile_target: CalibrateProfileTarget,
+
+    /// Which mates of a sample read must pass --min-MQ for it to count in
+    /// profile matching. `both` also requires the mate to pass, read from the
+    /// `MQ` tag (added by `samtools fixmate -m`).
+    #[arg(
+        long = "mate-mapq-policy",
+        value_enum,
+        default_value_t = CalibrateMateMapqPolicy::Either,
+        requires = "experimental"
+    )]
+    mate_mapq_policy: CalibrateMateMapqPolicy,
+
+    /// Write each target region's sample window read starts, mirrored
+    /// profile and per-window selected read counts to FILE as JSON, for
+    /// debugging profile matching.
+    #[arg(long = "profile-json", value_name = "FILE", requires = "experimental")]
+    profile_json: Option<PathBuf>,
+
+    /// Read each sample region's window read starts from FILE, a CSV with a
+    /// `region,window_index,starts` header, instead of counting them in the
+    /// BAM. The windows must match the --window-size layout of each region.
+    #[arg(long = "profile-csv", value_name = "FILE", requires = "experimental")]
+    profile_csv: Option<PathBuf>,
+
     /// Only consider reads in the sample regions with a mapQ greater than this
     #[arg(short = 'q', long = "min-MQ", default_value_t = 10)]
     min_mapq: u8,
@@ -40,19 +127,142 @@ pub struct CalibrateArgs {
     #[arg(long = "write-index", default_value_t = false)]
     write_index: bool,
 
-    /// Exclude uncalibrated (i.e., sample) reads from the output
+    /// Write an MD5 checksum of the output file to `{output}.md5`
+    #[arg(long = "output-md5", default_value_t = false)]
+    output_md5: bool,
+
+    /// Directory in which to cache per-region mean coverage between runs. The
+    /// cache is keyed on the input path, its modification time, the regions
+    /// and the settings their coverage is counted with.
+    #[arg(long = "coverage-cache")]
+    coverage_cache: Option<PathBuf>,
+
+    /// Exclude uncalibrated (i.e., sample) mapped reads from the output
     #[arg(short = 'x', long, default_value_t = false)]
     exclude_uncalibrated_reads: bool,
 
+    /// Do not copy unmapped reads to the output, except the mates of
+    /// calibrated reads. Independent of --exclude-uncalibrated-reads.
+    #[arg(long = "no-copy-unmapped", default_value_t = false)]
+    no_copy_unmapped: bool,
+
+    /// Only copy the uncalibrated reads of this contig, dropping those of any
+    /// contig not listed, such as decoy and alt contigs. May be repeated or
+    /// given as a comma-separated list. By default every contig is copied.
+    #[arg(
+        long = "passthrough-contigs",
+        value_name = "CONTIG",
+        value_delimiter = ',',
+        conflicts_with = "decoy_only"
+    )]
+    passthrough_contigs: Vec<String>,
+
+    /// Output only the calibrated reads on the sequin contigs: no sample
+    /// reads, no reads from uncalibrated regions and no unmapped reads, even
+    /// if they share a name with a calibrated read.
+    #[arg(
+        long = "decoy-only",
+        default_value_t = false,
+        conflicts_with = "merge_with"
+    )]
+    decoy_only: bool,
+
+    /// Tag each calibrated sequin read with its region's downsampling
+    /// probability as `ds:f:<probability>`.
+    #[arg(
+        long = "annotate-probability",
+        default_value_t = false,
+        conflicts_with = "experimental"
+    )]
+    annotate_probability: bool,
+
+    /// Tag every written read with `cb:A:C` if it was kept by calibration,
+    /// including the reads --experimental profile matching selects, or
+    /// `cb:A:U` if it was copied uncalibrated. Reads from --merge-with are
+    /// written as they are.
+    #[arg(long = "output-reads-tag", default_value_t = false)]
+    output_reads_tag: bool,
+
+    /// Tag every read kept by calibration with `XC:i:1`. Reads copied
+    /// uncalibrated, and mates of calibrated reads that are unmapped or on
+    /// another contig, are written without it.
+    #[arg(long = "tag-calibrated", default_value_t = false)]
+    tag_calibrated: bool,
+
+    /// Instead of failing when a region has less coverage than its target,
+    /// write extra copies of its reads until the target is about reached.
+    /// Copies are flagged as duplicates and named `<read name>_up<N>`.
+    #[arg(
+        long = "allow-upsample",
+        default_value_t = false,
+        conflicts_with = "experimental"
+    )]
+    allow_upsample: bool,
+
+    /// Whether singleton reads (paired reads whose mate is unmapped) in the
+    /// target regions can be selected. `false` drops them from the calibrated
+    /// regions; `true` selects them like any other read. By default a
+    /// singleton is only selected if its unmapped mate is placed next to it.
+    #[arg(
+        long = "keep-singletons",
+        value_name = "BOOL",
+        conflicts_with = "experimental"
+    )]
+    keep_singletons: Option<bool>,
+
     /// Regions in the reference genome corresponding to the sequins, the name
     /// of each region must match those in the sequin BED file.
     #[arg(short = 'S', long = "sample-bed")]
     sample_bed: Option<PathBuf>,
 
+    /// Calibrate all sequin regions to the mean depth of this main-genome
+    /// contig (e.g. chr20) instead of a fixed fold-coverage.
+    #[arg(long = "target-contig", conflicts_with = "sample_bed")]
+    target_contig: Option<String>,
+
+    /// With --sample-bed, calibrate each sequin region to this percentile
+    /// (0-100) of its sample region's per-base coverage instead of the mean,
+    /// e.g. 25 to model low-input samples.
+    #[arg(
+        long = "target-percentile",
+        requires = "sample_bed",
+        conflicts_with = "experimental"
+    )]
+    target_percentile: Option<f64>,
+
+    /// Calibrate each region to the fold coverage in this column of --bed
+    /// (5 is the BED score column). Regions with no value, or `.`, are
+    /// calibrated to --fold-coverage.
+    #[arg(
+        long = "target-column",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(5..),
+        conflicts_with_all = ["sample_bed", "target_contig"]
+    )]
+    target_column: Option<usize>,
+
     /// BED file specifying regions in which alignment coverage is calibrated.
     #[arg(short, long)]
     bed: PathBuf,
 
+    /// Only split BED columns on tabs, so region names may contain spaces. By
+    /// default any whitespace separates columns.
+    #[arg(long = "strict-tabs", default_value_t = false)]
+    strict_tabs: bool,
+
+    /// Sort the --bed regions by the input's header contig order and start
+    /// before calibrating, so that each region is fetched in file order.
+    #[arg(long = "sort-bed", default_value_t = false)]
+    sort_bed: bool,
+
+    /// Exit with an error if the --bed regions are not sorted by the input's
+    /// header contig order and start.
+    #[arg(
+        long = "fail-if-unsorted-bed",
+        default_value_t = false,
+        conflicts_with = "sort_bed"
+    )]
+    fail_if_unsorted_bed: bool,
+
     // Kept temporarily so older commands that still pass --summary-report do not fail.
     // The summary report output is no longer implemented.
     #[arg(long, hide = true)]
@@ -63,6 +273,26 @@ pub struct CalibrateArgs {
     #[arg(long, default_value_t = false)]
     experimental: bool,
 
+    /// Merge the calibrated sequin reads with every read of this
+    /// coordinate-sorted sample BAM/CRAM, writing a single sorted output with
+    /// the union of both headers' contigs. Contigs in both headers must be in
+    /// the same order. Implies --exclude-uncalibrated-reads and
+    /// --no-copy-unmapped.
+    #[arg(long = "merge-with", conflicts_with = "experimental")]
+    merge_with: Option<PathBuf>,
+
+    /// Only calibrate the target regions whose name starts with PREFIX. Reads
+    /// in the other target regions are copied uncalibrated, and dropped by
+    /// --exclude-uncalibrated-reads.
+    #[arg(long = "name-prefix", value_name = "PREFIX")]
+    name_prefix: Option<String>,
+
+    /// Also write the calibrated reads overlapping each target region to
+    /// `{DIR}/{region_name}.bam`, for debugging individual sequins. Region
+    /// names must be usable as file names.
+    #[arg(long = "output-per-region-bams", value_name = "DIR")]
+    output_per_region_bams: Option<PathBuf>,
+
     /// Write output to file (default standard output)
     #[arg(short, long)]
     output: Option<PathBuf>,
@@ -71,36 +301,545 @@ pub struct CalibrateArgs {
     #[arg(short = 'T', long = "reference")]
     reference: Option<PathBuf>,
 
-    /// Write output as CRAM (requires --reference)
+    /// Without --reference, use the reference named by the `UR:` tag of the
+    /// input's first `@SQ` header line.
+    #[arg(long = "reference-from-header", default_value_t = false)]
+    reference_from_header: bool,
+
+    /// Index of the input file. By default a BAM input uses `<input>.csi` if
+    /// it is newer than `<input>.bai`, otherwise the BAI index, and a CRAM
+    /// input uses `<input>.crai`.
+    #[arg(long)]
+    index: Option<PathBuf>,
+
+    /// Write output as CRAM (requires --reference). Shorthand for
+    /// `--output-format cram`.
     #[arg(short = 'C', long = "cram", default_value_t = false)]
     cram: bool,
 
+    /// Output format. `auto` writes the format of the input.
+    #[arg(long = "output-format", value_enum, default_value_t = OutputFormat::Auto)]
+    output_format: OutputFormat,
+
+    /// Only check that the input opens with an index and that every BED
+    /// contig is in its header, then exit without reading any alignments.
+    /// Every malformed BED line is reported, not just the first.
+    #[arg(long = "check-only", default_value_t = false)]
+    check_only: bool,
+
+    /// After writing, check that the output is coordinate-sorted and that
+    /// every read and mate contig is in its header.
+    #[arg(long = "validate-output", default_value_t = false)]
+    validate_output: bool,
+
+    /// Only work out how each target region would be calibrated, writing a
+    /// CSV of its downsampling probability, expected mean coverage and kept
+    /// read count to standard output. No BAM/CRAM is written.
+    #[arg(
+        long = "report-only",
+        alias = "dry-run",
+        default_value_t = false,
+        conflicts_with_all = ["experimental", "check_only"]
+    )]
+    report_only: bool,
+
+    /// Exit with an error if --validate-output finds any problems.
+    #[arg(long, default_value_t = false, requires = "validate_output")]
+    strict: bool,
+
+    /// Report progress through the target regions to standard error, with an
+    /// estimate of the time remaining.
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+
+    /// Report the wall-clock time of each stage (loading the BED files,
+    /// working out probabilities, selecting reads, writing and indexing) to
+    /// standard error at the end of the run.
+    #[arg(long = "verbose-timing", default_value_t = false)]
+    verbose_timing: bool,
+
     path: PathBuf,
 }
 
+impl CalibrateArgs {
+    /// Returns the library calibration configuration for these arguments.
+    fn config(&self) -> calibration::Config {
+        calibration::Config {
+            flank: self.flank,
+            seed: self.seed,
+            seed_salt: self.seed_salt,
+            fold_coverage: self.fold_coverage,
+            per_region_coverage: self.target_column.is_some(),
+            window_size: self.window_size,
+            min_mapq: self.min_mapq,
+            profile_smoothing: self.profile_smoothing,
+            keep_mates: self.keep_read_if_any_mate_selected,
+            profile_target: match self.profile_target {
+                CalibrateProfileTarget::Sample => calibration::ProfileTarget::Sample,
+                CalibrateProfileTarget::Uniform => calibration::ProfileTarget::Uniform,
+            },
+            mate_mapq_policy: match self.mate_mapq_policy {
+                CalibrateMateMapqPolicy::Either => calibration::MateMapqPolicy::Either,
+                CalibrateMateMapqPolicy::Both => calibration::MateMapqPolicy::Both,
+            },
+            profile_json: self.profile_json.clone(),
+            profile_csv: self.profile_csv.clone(),
+            exclude_uncalibrated_reads: self.exclude_uncalibrated_reads,
+            exclude_unmapped_reads: self.no_copy_unmapped,
+            passthrough_contigs: (!self.passthrough_contigs.is_empty())
+                .then(|| self.passthrough_contigs.clone()),
+            decoy_only: self.decoy_only,
+            annotate_probability: self.annotate_probability,
+            tag_origin: self.output_reads_tag,
+            tag_calibrated: self.tag_calibrated,
+            allow_upsample: self.allow_upsample,
+            keep_singletons: self.keep_singletons,
+            experimental: self.experimental,
+            target_contig: self.target_contig.clone(),
+            target_percentile: self.target_percentile,
+            target_reads: self.target_reads,
+            progress: self.progress,
+            name_prefix: self.name_prefix.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CalibrateProfileTarget {
+    Sample,
+    Uniform,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CalibrateMateMapqPolicy {
+    Either,
+    Both,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Auto,
+    Bam,
+    Cram,
+}
+
 #[derive(Args, Debug)]
 pub struct BedcovArgs {
     /// mapping quality threshold
     #[arg(short = 'Q', long = "min-MQ", default_value_t = 0)]
     min_mapq: u8,
 
+    /// Only count aligned bases with at least this base quality towards
+    /// coverage (requires --coverage-unit base).
+    #[arg(short = 'q', long = "min-BQ", default_value_t = 0)]
+    min_baseq: u8,
+
     /// Number of bases to omit from the start and end of each region.
     #[arg(short = 'f', long = "flank", default_value_t = 0)]
     flank: u64,
 
+    /// Only compute coverage over the first N bases of each region, such as
+    /// the core of a capture probe. With --flank, the N bases start after
+    /// the flank.
+    #[arg(long = "head-bases", value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(1..))]
+    head_bases: Option<u64>,
+
     /// Reference sequence FASTA file. Used when input is CRAM format.
     #[arg(short = 'T', long = "reference")]
     reference: Option<PathBuf>,
 
+    /// Without --reference, use the reference named by the `UR:` tag of the
+    /// input's first `@SQ` header line.
+    #[arg(long = "reference-from-header", default_value_t = false)]
+    reference_from_header: bool,
+
+    /// Index of the input file. By default a BAM input uses `<input>.csi` if
+    /// it is newer than `<input>.bai`, otherwise the BAI index, and a CRAM
+    /// input uses `<input>.crai`.
+    #[arg(long)]
+    index: Option<PathBuf>,
+
     /// List of coverage thresholds to include in the report. The report will include the
     /// percentage of bases in each region with coverage greater than or equal to each threshold.
     #[arg(short, long, value_delimiter = ',')]
     thresholds: Option<Vec<u32>>,
 
+    /// How coverage is compared with each threshold: `ge` counts bases with
+    /// coverage >= N (columns `pct_ge_N`), `gt` counts coverage > N (columns
+    /// `pct_gt_N`).
+    #[arg(long = "threshold-mode", value_enum, default_value_t = BedcovThresholdMode::Ge)]
+    threshold_mode: BedcovThresholdMode,
+
+    /// Add a `trimmed_mean` column: the mean depth after discarding this
+    /// fraction of the lowest and highest per-base depths. Must be in [0, 0.5).
+    #[arg(long = "trim-fraction")]
+    trim_fraction: Option<f64>,
+
+    /// Add a `reads` column and a final `unmapped` row counting the unplaced
+    /// unmapped reads in the input. Not supported with `--format mosdepth` or
+    /// `bedgraph`.
+    #[arg(long = "report-unmapped", default_value_t = false)]
+    report_unmapped: bool,
+
+    /// Which records the `reads` column and read-count histogram count:
+    /// `primary` counts the primary alignments that make up the coverage, `all`
+    /// also counts secondary and supplementary records passing the same
+    /// mapping quality and read filters. Coverage always uses primary
+    /// alignments only.
+    #[arg(long = "count-mode", value_enum, default_value_t = BedcovCountMode::Primary)]
+    count_mode: BedcovCountMode,
+
+    /// Add a `dup_rate` column: the fraction of primary reads overlapping each
+    /// region that are flagged as duplicates. Not supported with `--format
+    /// mosdepth` or `bedgraph`.
+    #[arg(long = "duplicate-rate", default_value_t = false)]
+    duplicate_rate: bool,
+
+    /// Add a `sem` column: the standard error of the mean coverage, the
+    /// standard deviation over the square root of the number of bases.
+    #[arg(long, default_value_t = false)]
+    sem: bool,
+
+    /// Multiply the reported depth statistics (min, max, mean, std, sem and
+    /// trimmed mean) by F, e.g. to normalise samples sequenced to different
+    /// depths. The CV is a ratio of two depths and so is not scaled.
+    #[arg(long = "scale", value_name = "F")]
+    scale: Option<f64>,
+
+    /// Only count reads carrying this aux tag value, given as TAG=VALUE.
+    /// Integer and string tags are supported. May be repeated.
+    #[arg(long = "require-tag")]
+    require_tag: Vec<TagFilter>,
+
+    /// Do not count reads carrying this aux tag value, given as TAG=VALUE.
+    /// May be repeated.
+    #[arg(long = "exclude-tag")]
+    exclude_tag: Vec<TagFilter>,
+
+    /// Only count properly-paired reads with an absolute insert size of at
+    /// least this. Reads with TLEN 0 or a mate on another contig are kept.
+    #[arg(long = "min-insert-size")]
+    min_insert_size: Option<u64>,
+
+    /// Only count properly-paired reads with an absolute insert size of at
+    /// most this. Reads with TLEN 0 or a mate on another contig are kept.
+    #[arg(long = "max-insert-size")]
+    max_insert_size: Option<u64>,
+
+    /// Only count properly-paired reads.
+    #[arg(long = "require-proper-pair", default_value_t = false)]
+    require_proper_pair: bool,
+
+    /// Only count reads whose alignment spans at least this many reference
+    /// bases (the summed length of M, =, X, D and N operations).
+    #[arg(long = "min-align-len")]
+    min_align_len: Option<u64>,
+
+    /// Count reads flagged as PCR or optical duplicates towards coverage. By
+    /// default they are excluded.
+    #[arg(long = "keep-duplicates", default_value_t = false)]
+    keep_duplicates: bool,
+
+    /// Whether singleton reads (paired reads whose mate is unmapped) count
+    /// towards coverage.
+    #[arg(long = "keep-singletons", value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    keep_singletons: bool,
+
+    /// What the coverage statistics count at each position. `base` is the
+    /// per-base depth of aligned bases; `read` is the number of reads whose
+    /// alignment spans the position, including deletions and introns. The two
+    /// differ most for spliced and long reads.
+    #[arg(long = "coverage-unit", value_enum, default_value_t = BedcovCoverageUnit::Base)]
+    coverage_unit: BedcovCoverageUnit,
+
+    /// Stop counting reads at a position once its depth reaches this value,
+    /// capping the coverage reported. 0 counts every read.
+    #[arg(short = 'd', long = "max-depth", default_value_t = 0)]
+    max_depth: u32,
+
+    /// Count each position covered by both mates of an overlapping proper
+    /// pair once, not twice. Uses more memory, holding each mate's aligned
+    /// intervals until its pair is seen.
+    #[arg(long = "no-double-count", default_value_t = false)]
+    no_double_count: bool,
+
+    /// How reads covering the same position count: `reads` counts every read,
+    /// so both mates of an overlapping pair; `fragments` counts each pair
+    /// once, like --no-double-count; `unique-bases` counts a position once if
+    /// any read covers it, reporting breadth.
+    #[arg(
+        long = "overlap-policy",
+        value_enum,
+        conflicts_with = "no_double_count"
+    )]
+    overlap_policy: Option<BedcovOverlapPolicy>,
+
+    /// Input is spliced RNA-seq data. Reads with reference skips (N) are
+    /// expected and no warning is printed for them.
+    #[arg(long, default_value_t = false)]
+    rna: bool,
+
+    /// Write the template length (TLEN) distribution of proper pairs in each
+    /// region to this file as `region,tlen,count` rows.
+    #[arg(long = "tlen-histogram")]
+    tlen_histogram: Option<PathBuf>,
+
+    /// Report every statistic both with --flank and without any flank, as
+    /// columns prefixed `flank_` and `noflank_`, to show edge effects.
+    #[arg(long = "flank-report", default_value_t = false)]
+    flank_report: bool,
+
+    /// Write a histogram of the number of regions per counted-read bin as
+    /// `reads_beg,reads_end,regions` rows, to standard error unless
+    /// --read-count-histogram-file is given.
+    #[arg(long = "read-count-histogram", default_value_t = false)]
+    read_count_histogram: bool,
+
+    /// Width of each --read-count-histogram bin, in reads.
+    #[arg(long = "read-count-bin-width", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    read_count_bin_width: u64,
+
+    /// Write the --read-count-histogram to this file instead of standard error.
+    #[arg(long = "read-count-histogram-file", requires = "read_count_histogram")]
+    read_count_histogram_file: Option<PathBuf>,
+
+    /// Only check that the input opens with an index and that every BED
+    /// contig is in its header, then exit without reading any alignments.
+    /// Every malformed BED line is reported, not just the first.
+    #[arg(long = "check-only", default_value_t = false)]
+    check_only: bool,
+
+    /// String written for statistics that are undefined for a region, such as
+    /// the CV of a region with zero mean coverage.
+    #[arg(long = "na-string", default_value = "")]
+    na_string: String,
+
+    /// Report layout. `mosdepth` writes `chrom, beg, end, name, mean` lines
+    /// like mosdepth's regions.bed.gz; the other statistics are omitted.
+    /// `bedgraph` writes `chrom, beg, end, depth` lines, one per run of
+    /// covered bases with the same depth, for genome browsers. `json` writes
+    /// an array with one object of statistics per region.
+    #[arg(long = "format", value_enum, default_value_t = BedcovFormat::Csv)]
+    format: BedcovFormat,
+
+    /// Shape of the CSV report. `long` writes `name, chrom, beg, end, metric,
+    /// value` rows, one per region and statistic, for tidy R or pandas
+    /// workflows.
+    #[arg(long = "report-shape", value_enum, default_value_t = BedcovReportShape::Wide)]
+    report_shape: BedcovReportShape,
+
+    /// Write per-base depth as bedGraph instead of the report. Shorthand for
+    /// `--format bedgraph`.
+    #[arg(long = "per-base", default_value_t = false, conflicts_with = "format")]
+    per_base: bool,
+
+    /// BGZF-compress the report.
+    #[arg(long, default_value_t = false)]
+    bgzip: bool,
+
+    /// Number of regions to process in parallel (default --threads, or one
+    /// per CPU). The report is identical whatever the number of threads.
+    #[arg(short = '@', long = "compute-threads", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    compute_threads: Option<usize>,
+
+    /// Number of htslib decompression threads for each BAM/CRAM reader
+    /// (default --threads, or one per CPU). Every region in flight has its
+    /// own reader, so lower this when processing many regions at once.
+    #[arg(long = "io-threads", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    io_threads: Option<usize>,
+
+    /// Only report the BED regions whose name starts with PREFIX.
+    #[arg(long = "name-prefix", value_name = "PREFIX")]
+    name_prefix: Option<String>,
+
+    /// Only split BED columns on tabs, so region names may contain spaces. By
+    /// default any whitespace separates columns.
+    #[arg(long = "strict-tabs", default_value_t = false)]
+    strict_tabs: bool,
+
+    /// Report the wall-clock time of each stage (loading the BED file,
+    /// calculating coverage and writing the report) to standard error at the
+    /// end of the run. Only supported with a single BAM/CRAM file.
+    #[arg(long = "verbose-timing", default_value_t = false)]
+    verbose_timing: bool,
+
+    bed_path: PathBuf,
+
+    /// BAM/CRAM files. With more than one, a table of each region's mean
+    /// coverage in every file is written instead, with one column per file
+    /// named after it (without the extension).
+    #[arg(required = true)]
+    bam_paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BedcovFormat {
+    Csv,
+    Mosdepth,
+    Bedgraph,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BedcovReportShape {
+    Wide,
+    Long,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BedcovCoverageUnit {
+    Base,
+    Read,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BedcovOverlapPolicy {
+    Reads,
+    Fragments,
+    UniqueBases,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BedcovCountMode {
+    Primary,
+    All,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BedcovThresholdMode {
+    Ge,
+    Gt,
+}
+
+#[derive(Args, Debug)]
+pub struct WindowsArgs {
+    /// mapping quality threshold
+    #[arg(short = 'Q', long = "min-MQ", default_value_t = 0)]
+    min_mapq: u8,
+
+    /// Reference sequence FASTA file. Used when input is CRAM format.
+    #[arg(short = 'T', long = "reference")]
+    reference: Option<PathBuf>,
+
+    /// Size of each bin in bases.
+    #[arg(short, long = "bin-size", default_value_t = 10000, value_parser = clap::value_parser!(u64).range(1..))]
+    bin_size: u64,
+
+    /// Contig to bin. May be repeated; defaults to every contig in the header.
+    #[arg(short, long = "contig")]
+    contigs: Vec<String>,
+
+    /// Two-column `contig<TAB>length` file (e.g. a UCSC chrom.sizes) whose
+    /// lengths replace the BAM header's when tiling, for headers with wrong
+    /// contig lengths. Every contig listed must be in the header.
+    #[arg(long = "chrom-sizes", value_name = "FILE")]
+    chrom_sizes: Option<PathBuf>,
+
+    bam_path: PathBuf,
+}
+
+impl From<WindowsArgs> for sequintools::windows::WindowsArgs {
+    fn from(args: WindowsArgs) -> Self {
+        sequintools::windows::WindowsArgs {
+            bam_path: args.bam_path,
+            reference: args.reference,
+            min_mapq: args.min_mapq,
+            bin_size: args.bin_size,
+            contigs: args.contigs,
+            chrom_sizes: args.chrom_sizes,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    /// mapping quality threshold for BED coverage
+    #[arg(short = 'Q', long = "min-MQ", default_value_t = 0)]
+    min_mapq: u8,
+
+    /// Reference sequence FASTA file. Used when input is CRAM format.
+    #[arg(short = 'T', long = "reference")]
+    reference: Option<PathBuf>,
+
+    /// Output format.
+    #[arg(long = "format", value_enum, default_value_t = StatsOutputFormat::Json)]
+    format: StatsOutputFormat,
+
+    /// Write a CSV of the mean coverage of each BED region instead of the
+    /// whole-file summary.
+    #[arg(
+        long = "per-region",
+        default_value_t = false,
+        conflicts_with = "format"
+    )]
+    per_region: bool,
+
+    /// Sample regions matching the BED regions by name, as given to
+    /// calibrate. Adds the coverage of each region's sample region and the
+    /// ratio of the two to the per-region report. Implies --per-region.
+    #[arg(short = 'S', long = "sample-bed", conflicts_with = "format")]
+    sample_bed: Option<PathBuf>,
+
+    /// Number of bases to omit from the start and end of each region in the
+    /// per-region report.
+    #[arg(short = 'f', long = "flank", default_value_t = 0)]
+    flank: u64,
+
     bed_path: PathBuf,
     bam_path: PathBuf,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StatsOutputFormat {
+    Json,
+    Csv,
+}
+
+impl From<StatsArgs> for sequintools::stats::StatsArgs {
+    fn from(args: StatsArgs) -> Self {
+        sequintools::stats::StatsArgs {
+            bam_path: args.bam_path,
+            bed_path: args.bed_path,
+            reference: args.reference,
+            min_mapq: args.min_mapq,
+            format: match args.format {
+                StatsOutputFormat::Json => StatsFormat::Json,
+                StatsOutputFormat::Csv => StatsFormat::Csv,
+            },
+            per_region: args.per_region,
+            sample_bed_path: args.sample_bed,
+            flank: args.flank,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CompletionsArgs {
+    /// Shell to write the completion script for
+    #[arg(value_enum)]
+    shell: CompletionShell,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+/// Writes the `shell` completion script for every subcommand and option of
+/// `App` to `dest`.
+fn write_completions(shell: CompletionShell, dest: &mut dyn std::io::Write) {
+    let shell = match shell {
+        CompletionShell::Bash => clap_complete::Shell::Bash,
+        CompletionShell::Zsh => clap_complete::Shell::Zsh,
+        CompletionShell::Fish => clap_complete::Shell::Fish,
+        CompletionShell::Powershell => clap_complete::Shell::PowerShell,
+    };
+    clap_complete::generate(shell, &mut App::command(), "sequintools", dest);
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Basic calibration of sequins
@@ -108,23 +847,111 @@ enum Commands {
     Calibrate(CalibrateArgs),
     /// read depth per BED region
     Bedcov(BedcovArgs),
+    /// mean read depth in fixed-size bins across contigs
+    Windows(WindowsArgs),
+    /// whole-file QC summary with the mean depth over a BED file
+    Stats(StatsArgs),
+    /// print a shell completion script to standard output
+    #[command(hide = true)]
+    Completions(CompletionsArgs),
 }
 
 impl From<BedcovArgs> for sequintools::coverage::BedcovArgs {
     fn from(args: BedcovArgs) -> Self {
+        let overlap_policy = match args.overlap_policy {
+            Some(BedcovOverlapPolicy::Reads) => OverlapPolicy::Reads,
+            Some(BedcovOverlapPolicy::Fragments) => OverlapPolicy::Fragments,
+            Some(BedcovOverlapPolicy::UniqueBases) => OverlapPolicy::UniqueBases,
+            None if args.no_double_count => OverlapPolicy::Fragments,
+            None => OverlapPolicy::Reads,
+        };
         sequintools::coverage::BedcovArgs {
             min_mapq: args.min_mapq,
             flank: args.flank,
             reference: args.reference,
+            reference_from_header: args.reference_from_header,
+            index: args.index,
+            head_bases: args.head_bases,
             thresholds: args.thresholds,
+            threshold_mode: match args.threshold_mode {
+                BedcovThresholdMode::Ge => ThresholdMode::Ge,
+                BedcovThresholdMode::Gt => ThresholdMode::Gt,
+            },
+            trim_fraction: args.trim_fraction,
+            report_unmapped: args.report_unmapped,
+            count_mode: match args.count_mode {
+                BedcovCountMode::Primary => CountMode::Primary,
+                BedcovCountMode::All => CountMode::All,
+            },
+            duplicate_rate: args.duplicate_rate,
+            sem: args.sem,
+            scale: args.scale,
+            read_filters: ReadFilters {
+                require_tags: args.require_tag,
+                exclude_tags: args.exclude_tag,
+                min_insert_size: args.min_insert_size,
+                max_insert_size: args.max_insert_size,
+                require_proper_pair: args.require_proper_pair,
+                min_align_len: args.min_align_len,
+                exclude_duplicates: !args.keep_duplicates,
+                exclude_singletons: !args.keep_singletons,
+            },
+            coverage_unit: match args.coverage_unit {
+                BedcovCoverageUnit::Base => CoverageUnit::Base,
+                BedcovCoverageUnit::Read => CoverageUnit::Read,
+            },
+            max_depth: overlap_policy.max_depth(args.max_depth),
+            no_double_count: overlap_policy.no_double_count(),
+            min_baseq: args.min_baseq,
+            rna: args.rna,
+            tlen_histogram: args.tlen_histogram,
+            flank_report: args.flank_report,
+            read_count_bin_width: args
+                .read_count_histogram
+                .then_some(args.read_count_bin_width),
+            read_count_histogram: args.read_count_histogram_file,
+            check_only: args.check_only,
+            na_string: args.na_string,
+            format: match args.format {
+                _ if args.per_base => ReportFormat::Bedgraph,
+                BedcovFormat::Csv => ReportFormat::Csv,
+                BedcovFormat::Mosdepth => ReportFormat::Mosdepth,
+                BedcovFormat::Bedgraph => ReportFormat::Bedgraph,
+                BedcovFormat::Json => ReportFormat::Json,
+            },
+            report_shape: match args.report_shape {
+                BedcovReportShape::Wide => ReportShape::Wide,
+                BedcovReportShape::Long => ReportShape::Long,
+            },
+            bgzip: args.bgzip,
+            compute_threads: args.compute_threads,
+            io_threads: args.io_threads,
+            name_prefix: args.name_prefix,
+            verbose_timing: args.verbose_timing,
+            strict_tabs: args.strict_tabs,
             bed_path: args.bed_path,
-            bam_path: args.bam_path,
+            bam_paths: args.bam_paths,
         }
     }
 }
 
+/// The library arguments for a `bedcov` run, with the compute and I/O threads
+/// not set on the command line defaulting to `threads`.
+fn bedcov_args(args: BedcovArgs, threads: Option<usize>) -> sequintools::coverage::BedcovArgs {
+    let mut args = sequintools::coverage::BedcovArgs::from(args);
+    args.compute_threads = args.compute_threads.or(threads);
+    args.io_threads = args.io_threads.or(threads);
+    args
+}
+
 fn main() -> Result<()> {
     let args = App::parse();
+    let threads = args.threads();
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
     match args.command {
         Commands::Calibrate(args) => {
             if args.summary_report.is_some() {
@@ -133,9 +960,12 @@ fn main() -> Result<()> {
                     Use the bedcov command for coverage information."
                 );
             }
-            run_calibrate(&args)?
+            run_calibrate(&args, threads)?
         }
-        Commands::Bedcov(args) => sequintools::coverage::run(&args.into())?,
+        Commands::Bedcov(args) => sequintools::coverage::run(&bedcov_args(args, threads))?,
+        Commands::Windows(args) => sequintools::windows::run(&args.into())?,
+        Commands::Stats(args) => sequintools::stats::run(&args.into())?,
+        Commands::Completions(args) => write_completions(args.shell, &mut std::io::stdout()),
     };
     Ok(())
 }
@@ -149,108 +979,245 @@ fn main() -> Result<()> {
 /// # Returns
 /// A vector of clipped regions.
 fn trim_regions(regions: &[region::Region], flank: u64) -> Result<Vec<region::Region>> {
-    regions
-        .iter()
-        .map(|r| {
-            let mut new_region = r.clone();
-            new_region.beg = new_region.beg.saturating_add(flank);
-            new_region.end = new_region.end.saturating_sub(flank);
-            if new_region.beg >= new_region.end {
-                return Err(anyhow!(
-                    "Region {} start is greater than or equal to end after trimming flanks {}",
-                    r.name,
-                    flank,
-                ));
-            }
-            Ok(new_region)
-        })
-        .collect()
+    regions.iter().map(|r| Ok(r.trim(flank)?)).collect()
 }
 
-fn run_calibrate(args: &CalibrateArgs) -> Result<()> {
-    if args.cram && args.reference.is_none() {
-        bail!("--cram output requires --reference to be supplied");
+/// Returns the `{dir}/{region_name}.bam` path of each region's
+/// `--output-per-region-bams` file.
+///
+/// # Errors
+/// Returns an error if a region name is not a plain file name (it is empty,
+/// `.` or `..`, or contains a path separator), or if two regions would write
+/// to the same file. Names differing only in case are treated as the same
+/// file, since they are on case-insensitive file systems.
+fn region_bam_paths(dir: &Path, regions: &[region::Region]) -> Result<Vec<PathBuf>> {
+    let mut seen: HashMap<String, &str> = HashMap::with_capacity(regions.len());
+    let mut paths = Vec::with_capacity(regions.len());
+    for region in regions {
+        let name = region.name.as_str();
+        if matches!(name, "" | "." | "..") || name.contains(['/', '\\', '\0']) {
+            bail!("region name {name:?} cannot be used as a --output-per-region-bams file name");
+        }
+        let file_name = format!("{name}.bam");
+        if let Some(other) = seen.insert(file_name.to_lowercase(), name) {
+            bail!("regions {other:?} and {name:?} would both be written to the same --output-per-region-bams file");
+        }
+        paths.push(dir.join(file_name));
     }
+    Ok(paths)
+}
 
-    let mut reader = HtslibBamReader::from_path(&args.path)?;
-    let ncpus = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(1);
-    reader.set_threads(ncpus)?;
-    if let Some(reference) = args.reference.as_ref() {
-        reader.set_reference(reference)?;
-    }
-    let mut hdr = bam::Header::from_template(reader.header());
-    let format = if args.cram {
-        bam::Format::Cram
+/// Resolves the output format for `calibrate`.
+///
+/// `--cram` takes precedence. In `auto` mode the output has the format of the
+/// input, whatever the output file is named.
+fn resolve_output_format(args: &CalibrateArgs) -> Result<bam::Format> {
+    let requested = if args.cram {
+        OutputFormat::Cram
     } else {
-        bam::Format::Bam
+        args.output_format
     };
+    let format = match requested {
+        OutputFormat::Bam => bam::Format::Bam,
+        OutputFormat::Cram => bam::Format::Cram,
+        OutputFormat::Auto => seqbam::detect_format(&args.path)?,
+    };
+    Ok(format)
+}
 
-    let vn = env!("GIT_VERSION");
-    let cl = std::env::args().collect::<Vec<String>>().join(" ");
-    let pg_record = format!("PG\tID:sequintools\tPN:sequintools\tVN:{vn}\tCL:{cl}");
-    hdr.push_record(&bam::header::HeaderRecord::new(pg_record.as_bytes()));
+fn run_calibrate(args: &CalibrateArgs, threads: Option<usize>) -> Result<()> {
+    let format = resolve_output_format(args)?;
 
-    let mut writer = if let Some(output) = &args.output {
-        HtslibBamWriter::from_path(output, &hdr, format)?
+    let mut config = args.config();
+    config.seed = calibration::resolve_seed(config.seed)?;
+    if config.seed_salt == 0 {
+        eprintln!("Using seed {}", config.seed);
     } else {
-        HtslibBamWriter::from_stdout(&hdr, format)?
-    };
-    writer.set_threads(ncpus)?;
-    if let Some(reference) = args.reference.as_ref() {
-        writer.set_reference(reference)?;
+        eprintln!("Using seed {} with salt {}", config.seed, config.seed_salt);
     }
 
-    let target_regions = region::load_from_bed(&mut BufReader::new(File::open(&args.bed)?))?;
+    let index = args
+        .index
+        .clone()
+        .or_else(|| seqbam::resolve_index(&args.path));
+    let mut reader = match &index {
+        Some(index) => {
+            eprintln!("Using index {}", index.display());
+            HtslibBamReader::from_path_and_index(&args.path, index)?
+        }
+        None => HtslibBamReader::from_path(&args.path)?,
+    };
+    let ncpus = threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    reader.set_threads(ncpus)?;
+    let reference = match &args.reference {
+        Some(reference) => Some(reference.clone()),
+        None if args.reference_from_header => {
+            let reference = seqbam::reference_from_header(reader.header())?;
+            eprintln!("Using reference {} from the header", reference.display());
+            Some(reference)
+        }
+        None => None,
+    };
+    if format == bam::Format::Cram && reference.is_none() {
+        bail!("CRAM output requires --reference to be supplied");
+    }
+    if let Some(reference) = reference.as_ref() {
+        reader.set_reference(reference)?;
+    }
+    // A check-only run reports every malformed BED line at once; a real run
+    // stops at the first.
+    let load_bed = |path: &PathBuf, target_column: Option<usize>| {
+        let mut reader = BufReader::new(File::open(path)?);
+        let options = region::BedOptions {
+            target_column,
+            strict_tabs: args.strict_tabs,
+            all_errors: args.check_only,
+            unique_names: true,
+        };
+        region::load_from_bed_with_options(&mut reader, &options)
+    };
+    let mut timings = args.verbose_timing.then(Timings::new);
+    let load_start = Instant::now();
+    let target_regions = load_bed(&args.bed, args.target_column)?;
 
     // Remove `args.flank` bases from each end of the target regions. We do this
     // here at the start to ensure the regions always have the requested flanks
     // removed. Passing down the `flank` value risks it being forgotten in some
     // code paths.
-    let target_regions = trim_regions(&target_regions, args.flank)?;
+    let mut target_regions = trim_regions(&target_regions, config.flank)?;
 
     let sample_regions = if let Some(sample_bed) = &args.sample_bed {
-        let regions = region::load_from_bed(&mut BufReader::new(File::open(sample_bed)?))?;
-        let regions = trim_regions(&regions, args.flank)?;
+        let regions = load_bed(sample_bed, None)?;
+        let regions = trim_regions(&regions, config.flank)?;
         Some(regions)
     } else {
         None
     };
+    if let Some(timings) = timings.as_mut() {
+        timings.record("load BED", load_start.elapsed());
+    }
 
-    // Determine the calibration mode based on the provided arguments
-    let mode = if args.experimental {
-        if let Some(sample_regions) = &sample_regions {
-            CalibrationMode::SampleProfile {
-                sample_regions,
-                window_size: args.window_size,
-                min_mapq: args.min_mapq,
-                seed: args.seed,
-            }
-        } else {
-            return Err(anyhow::anyhow!(
-                "SampleProfile mode requires sample regions. Please provide a sample BED file."
-            ));
+    region::check_contigs(&target_regions, reader.header())?;
+    if args.sort_bed {
+        region::sort_by_header(&mut target_regions, reader.header());
+    } else if args.fail_if_unsorted_bed {
+        region::check_sorted(&target_regions, reader.header())?;
+    }
+    if let Some(sample_regions) = &sample_regions {
+        region::check_contigs(sample_regions, reader.header())?;
+    }
+    if args.check_only {
+        println!(
+            "OK: {} ({} contigs) covers all {} target and {} sample regions",
+            args.path.display(),
+            reader.header().target_count(),
+            target_regions.len(),
+            sample_regions.as_ref().map_or(0, |r| r.len())
+        );
+        return Ok(());
+    }
+
+    let cache = args
+        .coverage_cache
+        .as_ref()
+        .map(|dir| CoverageCache::new(dir, &args.path))
+        .transpose()?;
+
+    if args.report_only {
+        let reports = calibration::report_with_config(
+            &mut reader,
+            &target_regions,
+            sample_regions.as_deref(),
+            &config,
+            cache.as_ref(),
+        )?;
+        calibration::write_report(&reports, std::io::stdout().lock())?;
+        return Ok(());
+    }
+
+    // The sample reads come from the merged file, so only the calibrated
+    // sequin reads are taken from the input.
+    let mut merge = None;
+    if let Some(merge_with) = &args.merge_with {
+        config.exclude_uncalibrated_reads = true;
+        config.exclude_unmapped_reads = true;
+        let mut sample_reader = bam::Reader::from_path(merge_with)?;
+        sample_reader.set_threads(ncpus)?;
+        if let Some(reference) = reference.as_ref() {
+            sample_reader.set_reference(reference)?;
         }
-    } else if let Some(sample_regions) = &sample_regions {
-        CalibrationMode::SampleMeanCoverage {
-            sample_regions,
-            seed: args.seed,
+        let (header, tid_map) = seqbam::merge_headers(reader.header(), sample_reader.header())?;
+        merge = Some((sample_reader, header, tid_map));
+    }
+
+    let mut hdr = match &merge {
+        Some((_, header, _)) => header.clone(),
+        None => bam::Header::from_template(reader.header()),
+    };
+
+    let cl = std::env::args().collect::<Vec<String>>().join(" ");
+    seqbam::push_program_record(&mut hdr, env!("GIT_VERSION"), &cl);
+    // The CRAM header is written when the writer is opened, before
+    // `set_reference`, so the reference must be named in the header itself.
+    if format == bam::Format::Cram {
+        if let Some(reference) = reference.as_ref() {
+            hdr = seqbam::header_with_reference(&hdr, reference)?;
         }
+    }
+
+    let mut writer = if let Some(output) = &args.output {
+        HtslibBamWriter::from_path(output, &hdr, format)?
     } else {
-        CalibrationMode::FixedCoverage {
-            fold_coverage: args.fold_coverage,
-            seed: args.seed,
-        }
+        HtslibBamWriter::from_stdout(&hdr, format)?
     };
+    writer.set_threads(ncpus)?;
+    if let Some(reference) = reference.as_ref() {
+        writer.set_reference(reference)?;
+    }
+
+    let mut region_writers = Vec::new();
+    if let Some(dir) = &args.output_per_region_bams {
+        let paths = region_bam_paths(dir, &target_regions)?;
+        std::fs::create_dir_all(dir)?;
+        for (region, path) in target_regions.iter().zip(paths) {
+            let region_writer = HtslibBamWriter::from_path(path, &hdr, bam::Format::Bam)?;
+            region_writers.push((region.clone(), region_writer));
+        }
+    }
 
-    calibration::calibrate(
-        &mut reader,
-        &mut writer,
-        &target_regions,
-        mode,
-        args.exclude_uncalibrated_reads,
-    )?;
+    let writer = match merge.as_mut() {
+        Some((sample_reader, _, tid_map)) => {
+            let writer = MergingWriter::new(writer, sample_reader.records(), tid_map.clone());
+            let mut writer = RegionSplitWriter::new(writer, reader.header(), region_writers)?;
+            calibration::calibrate_with_timings(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                sample_regions.as_deref(),
+                &config,
+                cache.as_ref(),
+                timings.as_mut(),
+            )?;
+            writer.into_inner().finish()?
+        }
+        None => {
+            let mut writer = RegionSplitWriter::new(writer, reader.header(), region_writers)?;
+            calibration::calibrate_with_timings(
+                &mut reader,
+                &mut writer,
+                &target_regions,
+                sample_regions.as_deref(),
+                &config,
+                cache.as_ref(),
+                timings.as_mut(),
+            )?;
+            writer.into_inner()
+        }
+    };
 
     // Minimum size for CSI indicies, 14 is the default used by samtools.
     let min_shift = 14;
@@ -266,13 +1233,100 @@ fn run_calibrate(args: &CalibrateArgs) -> Result<()> {
                 Some("cram") => bam::index::Type::Csi(min_shift),
                 _ => bail!("output file must have .bam or .cram extension to write index"),
             };
-            bam::index::build(output, None, format, ncpus as u32)?;
+            timing::time(timings.as_mut(), "index", || {
+                bam::index::build(output, None, format, ncpus as u32)
+            })?;
+        }
+    }
+
+    if args.validate_output {
+        if let Some(output) = &args.output {
+            timing::time(timings.as_mut(), "validate", || {
+                validate_output(output, reference.as_deref(), args.strict)
+            })?;
+        } else {
+            eprintln!("Warning: --validate-output is ignored when writing to standard output.");
+        }
+    }
+
+    if args.output_md5 {
+        if let Some(output) = &args.output {
+            write_md5_sidecar(output)?;
+        } else {
+            eprintln!("Warning: --output-md5 is ignored when writing to standard output.");
         }
     }
 
+    if let Some(timings) = &timings {
+        timings.report();
+    }
+
     Ok(())
 }
 
+/// Reports problems found in the calibrated output, failing if `strict`.
+fn validate_output(output: &Path, reference: Option<&Path>, strict: bool) -> Result<()> {
+    // Listing every problem in a badly broken file helps nobody.
+    const MAX_REPORTED: usize = 10;
+    let validation = seqbam::validate(output, reference, MAX_REPORTED)?;
+    if validation.passed() {
+        eprintln!("Validated {}: no problems found.", output.display());
+        return Ok(());
+    }
+    for issue in &validation.issues {
+        eprintln!("Validation: {issue}");
+    }
+    if validation.count > validation.issues.len() {
+        eprintln!(
+            "Validation: ... and {} more problems",
+            validation.count - validation.issues.len()
+        );
+    }
+    if strict {
+        bail!(
+            "{} failed validation with {} problems",
+            output.display(),
+            validation.count
+        );
+    }
+    Ok(())
+}
+
+/// Computes the MD5 digest of everything read from `reader` as a lowercase hex
+/// string.
+fn md5_hex<R: Read>(reader: &mut R) -> Result<String> {
+    let mut context = md5::Context::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buf[..n]);
+    }
+    Ok(format!("{:x}", context.finalize()))
+}
+
+/// Writes an `md5sum` compatible checksum file alongside `path`.
+///
+/// The sidecar is written to `{path}.md5` and contains the digest followed by
+/// the file name, so it can be verified with `md5sum -c`.
+///
+/// # Returns
+/// The path of the sidecar file.
+fn write_md5_sidecar(path: &Path) -> Result<PathBuf> {
+    let digest = md5_hex(&mut BufReader::new(File::open(path)?))?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".md5");
+    let sidecar = PathBuf::from(sidecar);
+    std::fs::write(&sidecar, format!("{digest}  {file_name}\n"))?;
+    Ok(sidecar)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,6 +1340,8 @@ mod tests {
             "1000",
             "--seed",
             "12345",
+            "--seed-salt",
+            "3",
             "--fold-coverage",
             "60",
             "--window-size",
@@ -307,11 +1363,13 @@ mod tests {
             Commands::Calibrate(calibrate_args) => {
                 assert_eq!(calibrate_args.flank, 1000);
                 assert_eq!(calibrate_args.seed, 12345);
+                assert_eq!(calibrate_args.seed_salt, 3);
                 assert_eq!(calibrate_args.fold_coverage, 60);
                 assert_eq!(calibrate_args.window_size, 150);
                 assert_eq!(calibrate_args.min_mapq, 20);
                 assert!(calibrate_args.write_index);
                 assert!(calibrate_args.exclude_uncalibrated_reads);
+                assert!(!calibrate_args.no_copy_unmapped);
                 assert_eq!(
                     calibrate_args.sample_bed.unwrap(),
                     PathBuf::from("sample.bed")
@@ -388,7 +1446,7 @@ mod tests {
                 assert_eq!(bedcov_args.min_mapq, 15);
                 assert_eq!(bedcov_args.flank, 250);
                 assert_eq!(bedcov_args.bed_path, PathBuf::from("regions.bed"));
-                assert_eq!(bedcov_args.bam_path, PathBuf::from("data.bam"));
+                assert_eq!(bedcov_args.bam_paths, vec![PathBuf::from("data.bam")]);
             }
             _ => panic!("Expected Bedcov command"),
         }
@@ -401,6 +1459,8 @@ mod tests {
             "bedcov",
             "-Q",
             "15",
+            "-q",
+            "20",
             "-f",
             "250",
             "regions.bed",
@@ -410,35 +1470,555 @@ mod tests {
         match args.command {
             Commands::Bedcov(bedcov_args) => {
                 assert_eq!(bedcov_args.min_mapq, 15);
+                assert_eq!(bedcov_args.min_baseq, 20);
                 assert_eq!(bedcov_args.flank, 250);
                 assert_eq!(bedcov_args.bed_path, PathBuf::from("regions.bed"));
-                assert_eq!(bedcov_args.bam_path, PathBuf::from("data.bam"));
+                assert_eq!(bedcov_args.bam_paths, vec![PathBuf::from("data.bam")]);
             }
             _ => panic!("Expected Bedcov command"),
         }
     }
 
+    #[test]
+    fn test_bedcov_per_base() {
+        let format = |argv: &[&str]| match App::parse_from(argv).command {
+            Commands::Bedcov(bedcov_args) => {
+                sequintools::coverage::BedcovArgs::from(bedcov_args).format
+            }
+            _ => panic!("Expected Bedcov command"),
+        };
+        assert_eq!(
+            format(&["app", "bedcov", "--per-base", "regions.bed", "data.bam"]),
+            ReportFormat::Bedgraph
+        );
+        assert_eq!(
+            format(&["app", "bedcov", "--format", "bedgraph", "r.bed", "d.bam"]),
+            ReportFormat::Bedgraph
+        );
+        assert!(App::try_parse_from([
+            "app",
+            "bedcov",
+            "--per-base",
+            "--format",
+            "csv",
+            "regions.bed",
+            "data.bam"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_bedcov_threads() {
+        let threads = |argv: &[&str]| {
+            let app = App::parse_from(argv);
+            let threads = app.threads();
+            match app.command {
+                Commands::Bedcov(args) => {
+                    let args = bedcov_args(args, threads);
+                    (args.compute_threads, args.io_threads)
+                }
+                _ => panic!("Expected Bedcov command"),
+            }
+        };
+        assert_eq!(threads(&["app", "bedcov", "r.bed", "d.bam"]), (None, None));
+        assert_eq!(
+            threads(&[
+                "app",
+                "bedcov",
+                "--compute-threads",
+                "4",
+                "--io-threads",
+                "2",
+                "r.bed",
+                "d.bam"
+            ]),
+            (Some(4), Some(2))
+        );
+        assert_eq!(
+            threads(&["app", "bedcov", "--threads", "3", "r.bed", "d.bam"]),
+            (Some(3), Some(3))
+        );
+        assert_eq!(
+            threads(&[
+                "app",
+                "--threads",
+                "3",
+                "bedcov",
+                "-@",
+                "8",
+                "r.bed",
+                "d.bam"
+            ]),
+            (Some(8), Some(3))
+        );
+        assert_eq!(
+            threads(&["app", "bedcov", "--threads", "0", "r.bed", "d.bam"]),
+            (None, None)
+        );
+        assert!(
+            App::try_parse_from(["app", "bedcov", "--io-threads", "0", "r.bed", "d.bam"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_bedcov_overlap_policy() {
+        let depth_options = |argv: &[&str]| match App::parse_from(argv).command {
+            Commands::Bedcov(bedcov_args) => {
+                let args = sequintools::coverage::BedcovArgs::from(bedcov_args);
+                (args.max_depth, args.no_double_count)
+            }
+            _ => panic!("Expected Bedcov command"),
+        };
+        let with_policy = |policy| {
+            depth_options(&[
+                "app",
+                "bedcov",
+                "-d",
+                "100",
+                "--overlap-policy",
+                policy,
+                "r.bed",
+                "d.bam",
+            ])
+        };
+        assert_eq!(with_policy("reads"), (100, false));
+        assert_eq!(with_policy("fragments"), (100, true));
+        assert_eq!(with_policy("unique-bases"), (1, false));
+        assert_eq!(
+            depth_options(&["app", "bedcov", "--no-double-count", "r.bed", "d.bam"]),
+            (0, true)
+        );
+        assert!(App::try_parse_from([
+            "app",
+            "bedcov",
+            "--no-double-count",
+            "--overlap-policy",
+            "reads",
+            "r.bed",
+            "d.bam",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_bedcov_keep_singletons() {
+        let exclude_singletons = |argv: &[&str]| match App::parse_from(argv).command {
+            Commands::Bedcov(bedcov_args) => {
+                sequintools::coverage::BedcovArgs::from(bedcov_args)
+                    .read_filters
+                    .exclude_singletons
+            }
+            _ => panic!("Expected Bedcov command"),
+        };
+        assert!(!exclude_singletons(&["app", "bedcov", "r.bed", "d.bam"]));
+        assert!(!exclude_singletons(&[
+            "app",
+            "bedcov",
+            "--keep-singletons",
+            "true",
+            "r.bed",
+            "d.bam"
+        ]));
+        assert!(exclude_singletons(&[
+            "app",
+            "bedcov",
+            "--keep-singletons",
+            "false",
+            "r.bed",
+            "d.bam"
+        ]));
+    }
+
+    #[test]
+    fn test_windows_command_parsing() {
+        let args = App::parse_from([
+            "app",
+            "windows",
+            "-b",
+            "500",
+            "-c",
+            "chr1",
+            "-c",
+            "chr2",
+            "-Q",
+            "20",
+            "--chrom-sizes",
+            "hg38.chrom.sizes",
+            "data.bam",
+        ]);
+        match args.command {
+            Commands::Windows(windows_args) => {
+                let windows_args = sequintools::windows::WindowsArgs::from(windows_args);
+                assert_eq!(windows_args.bin_size, 500);
+                assert_eq!(windows_args.contigs, vec!["chr1", "chr2"]);
+                assert_eq!(windows_args.min_mapq, 20);
+                assert_eq!(
+                    windows_args.chrom_sizes,
+                    Some(PathBuf::from("hg38.chrom.sizes"))
+                );
+                assert_eq!(windows_args.bam_path, PathBuf::from("data.bam"));
+            }
+            _ => panic!("Expected Windows command"),
+        }
+        assert!(App::try_parse_from(["app", "windows", "-b", "0", "data.bam"]).is_err());
+    }
+
+    #[test]
+    fn test_stats_command_parsing() {
+        let args = App::parse_from([
+            "app",
+            "stats",
+            "--format",
+            "csv",
+            "-Q",
+            "10",
+            "regions.bed",
+            "data.bam",
+        ]);
+        match args.command {
+            Commands::Stats(stats_args) => {
+                let stats_args = sequintools::stats::StatsArgs::from(stats_args);
+                assert_eq!(stats_args.format, StatsFormat::Csv);
+                assert_eq!(stats_args.min_mapq, 10);
+                assert_eq!(stats_args.bed_path, PathBuf::from("regions.bed"));
+                assert_eq!(stats_args.bam_path, PathBuf::from("data.bam"));
+                assert!(!stats_args.per_region);
+                assert_eq!(stats_args.sample_bed_path, None);
+            }
+            _ => panic!("Expected Stats command"),
+        }
+
+        let args = App::parse_from([
+            "app",
+            "stats",
+            "-S",
+            "sample.bed",
+            "-f",
+            "500",
+            "regions.bed",
+            "data.bam",
+        ]);
+        match args.command {
+            Commands::Stats(stats_args) => {
+                let stats_args = sequintools::stats::StatsArgs::from(stats_args);
+                assert_eq!(
+                    stats_args.sample_bed_path,
+                    Some(PathBuf::from("sample.bed"))
+                );
+                assert_eq!(stats_args.flank, 500);
+            }
+            _ => panic!("Expected Stats command"),
+        }
+        assert!(App::try_parse_from([
+            "app",
+            "stats",
+            "--per-region",
+            "--format",
+            "json",
+            "regions.bed",
+            "data.bam"
+        ])
+        .is_err());
+    }
+
     #[test]
     fn test_bedcovarg_from() {
         let input = BedcovArgs {
             min_mapq: 0,
             flank: 500,
+            head_bases: Some(100),
             reference: None,
+            reference_from_header: false,
+            index: None,
             thresholds: None,
+            threshold_mode: BedcovThresholdMode::Gt,
+            trim_fraction: None,
+            report_unmapped: false,
+            count_mode: BedcovCountMode::All,
+            duplicate_rate: false,
+            sem: true,
+            scale: Some(0.5),
+            require_tag: vec![],
+            exclude_tag: vec![],
+            min_insert_size: None,
+            max_insert_size: None,
+            require_proper_pair: false,
+            min_align_len: None,
+            keep_duplicates: true,
+            keep_singletons: true,
+            coverage_unit: BedcovCoverageUnit::Read,
+            max_depth: 8000,
+            no_double_count: true,
+            overlap_policy: None,
+            min_baseq: 13,
+            rna: false,
+            tlen_histogram: None,
+            flank_report: false,
+            read_count_histogram: true,
+            read_count_bin_width: 5,
+            read_count_histogram_file: None,
+            check_only: false,
+            na_string: String::new(),
+            format: BedcovFormat::Mosdepth,
+            report_shape: BedcovReportShape::Wide,
+            per_base: false,
+            bgzip: true,
+            compute_threads: Some(2),
+            io_threads: Some(1),
+            name_prefix: Some("SG_".to_string()),
+            verbose_timing: true,
+            strict_tabs: true,
             bed_path: PathBuf::from("my.bed"),
-            bam_path: PathBuf::from("my.bam"),
+            bam_paths: vec![PathBuf::from("my.bam")],
         };
         let expected = sequintools::coverage::BedcovArgs {
             min_mapq: 0,
             flank: 500,
+            head_bases: Some(100),
             reference: None,
+            reference_from_header: false,
+            index: None,
             thresholds: None,
+            threshold_mode: ThresholdMode::Gt,
+            trim_fraction: None,
+            report_unmapped: false,
+            count_mode: CountMode::All,
+            duplicate_rate: false,
+            sem: true,
+            scale: Some(0.5),
+            read_filters: ReadFilters {
+                exclude_duplicates: false,
+                ..ReadFilters::default()
+            },
+            coverage_unit: CoverageUnit::Read,
+            max_depth: 8000,
+            no_double_count: true,
+            min_baseq: 13,
+            rna: false,
+            tlen_histogram: None,
+            flank_report: false,
+            read_count_bin_width: Some(5),
+            read_count_histogram: None,
+            check_only: false,
+            na_string: String::new(),
+            format: ReportFormat::Mosdepth,
+            report_shape: ReportShape::Wide,
+            bgzip: true,
+            compute_threads: Some(2),
+            io_threads: Some(1),
+            name_prefix: Some("SG_".to_string()),
+            verbose_timing: true,
+            strict_tabs: true,
             bed_path: PathBuf::from("my.bed"),
-            bam_path: PathBuf::from("my.bam"),
+            bam_paths: vec![PathBuf::from("my.bam")],
         };
         assert_eq!(sequintools::coverage::BedcovArgs::from(input), expected);
     }
 
+    #[test]
+    fn test_completions() {
+        match App::parse_from(["app", "completions", "bash"]).command {
+            Commands::Completions(args) => assert_eq!(args.shell, CompletionShell::Bash),
+            _ => panic!("Expected Completions command"),
+        }
+        let mut script = Vec::new();
+        write_completions(CompletionShell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(!script.is_empty());
+        assert!(script.contains("calibrate"));
+        assert!(script.contains("--fold-coverage"));
+    }
+
+    fn calibrate_args(extra: &[&str]) -> CalibrateArgs {
+        let mut argv = vec!["app", "calibrate", "--bed", "regions.bed"];
+        argv.extend_from_slice(extra);
+        match App::parse_from(argv).command {
+            Commands::Calibrate(args) => args,
+            _ => panic!("Expected Calibrate command"),
+        }
+    }
+
+    #[test]
+    fn test_calibrate_args_config_defaults() {
+        let args = calibrate_args(&["my.bam"]);
+        assert_eq!(args.config(), calibration::Config::default());
+    }
+
+    #[test]
+    fn test_calibrate_args_decoy_only() {
+        assert!(
+            calibrate_args(&["--decoy-only", "my.bam"])
+                .config()
+                .decoy_only
+        );
+        assert!(App::try_parse_from([
+            "app",
+            "calibrate",
+            "--bed",
+            "regions.bed",
+            "--decoy-only",
+            "--merge-with",
+            "sample.bam",
+            "my.bam",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_calibrate_args_mate_mapq_policy() {
+        assert_eq!(
+            calibrate_args(&["my.bam"]).config().mate_mapq_policy,
+            calibration::MateMapqPolicy::Either
+        );
+        assert_eq!(
+            calibrate_args(&["--experimental", "--mate-mapq-policy", "both", "my.bam"])
+                .config()
+                .mate_mapq_policy,
+            calibration::MateMapqPolicy::Both
+        );
+    }
+
+    #[test]
+    fn test_calibrate_args_keep_singletons() {
+        let keep_singletons = |extra: &[&str]| calibrate_args(extra).config().keep_singletons;
+        assert_eq!(keep_singletons(&["my.bam"]), None);
+        assert_eq!(
+            keep_singletons(&["--keep-singletons", "true", "my.bam"]),
+            Some(true)
+        );
+        assert_eq!(
+            keep_singletons(&["--keep-singletons", "false", "my.bam"]),
+            Some(false)
+        );
+        let argv = [
+            "app",
+            "calibrate",
+            "--bed",
+            "r.bed",
+            "--keep-singletons",
+            "maybe",
+            "my.bam",
+        ];
+        assert!(App::try_parse_from(argv).is_err());
+    }
+
+    #[test]
+    fn test_calibrate_args_strict_tabs() {
+        assert!(calibrate_args(&["--strict-tabs", "my.bam"]).strict_tabs);
+        assert!(!calibrate_args(&["my.bam"]).strict_tabs);
+    }
+
+    #[test]
+    fn test_calibrate_args_dry_run() {
+        assert!(calibrate_args(&["--dry-run", "my.bam"]).report_only);
+        assert!(!calibrate_args(&["my.bam"]).report_only);
+    }
+
+    #[test]
+    fn test_calibrate_args_verbose_timing() {
+        assert!(calibrate_args(&["--verbose-timing", "my.bam"]).verbose_timing);
+        assert!(!calibrate_args(&["my.bam"]).verbose_timing);
+    }
+
+    #[test]
+    fn test_calibrate_args_target_column() {
+        let args = calibrate_args(&["--target-column", "5", "my.bam"]);
+        assert_eq!(args.target_column, Some(5));
+        assert!(args.config().per_region_coverage);
+        assert!(!calibrate_args(&["my.bam"]).config().per_region_coverage);
+        for extra in [
+            &["--target-column", "4"][..],
+            &["--target-column", "5", "--sample-bed", "sample.bed"],
+            &["--target-column", "5", "--target-contig", "chr20"],
+        ] {
+            let mut argv = vec!["app", "calibrate", "--bed", "regions.bed"];
+            argv.extend_from_slice(extra);
+            argv.push("my.bam");
+            assert!(App::try_parse_from(argv).is_err(), "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn test_calibrate_args_passthrough_contigs() {
+        let args = calibrate_args(&[
+            "--passthrough-contigs",
+            "chr1,chr2",
+            "--passthrough-contigs",
+            "chrX",
+            "my.bam",
+        ]);
+        assert_eq!(
+            args.config().passthrough_contigs,
+            Some(vec![
+                "chr1".to_string(),
+                "chr2".to_string(),
+                "chrX".to_string()
+            ])
+        );
+        assert_eq!(
+            calibrate_args(&["my.bam"]).config().passthrough_contigs,
+            None
+        );
+    }
+
+    #[test]
+    fn test_calibrate_args_target_reads() {
+        let args = calibrate_args(&["--target-reads", "1000", "my.bam"]);
+        assert_eq!(args.config().target_reads, Some(1000));
+        assert_eq!(calibrate_args(&["my.bam"]).config().target_reads, None);
+        for extra in [
+            &["--target-reads", "1000", "--fold-coverage", "30"][..],
+            &["--target-reads", "1000", "--sample-bed", "sample.bed"],
+            &["--target-reads", "1000", "--target-contig", "chr20"],
+        ] {
+            let mut argv = vec!["app", "calibrate", "--bed", "regions.bed"];
+            argv.extend_from_slice(extra);
+            argv.push("my.bam");
+            assert!(App::try_parse_from(argv).is_err(), "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_output_format() {
+        let cram_input = "testdata/uncalibrated.cram";
+        let bam_input = "testdata/uncalibrated.bam";
+        let cases = [
+            (vec![cram_input], bam::Format::Cram),
+            (vec![bam_input], bam::Format::Bam),
+            // The output file name does not change the format.
+            (vec!["-o", "out.bam", cram_input], bam::Format::Cram),
+            (vec!["-o", "out.cram", bam_input], bam::Format::Bam),
+            (vec!["--cram", bam_input], bam::Format::Cram),
+            (vec!["--output-format", "bam", cram_input], bam::Format::Bam),
+            (
+                vec!["--output-format", "cram", "-o", "out.bam", bam_input],
+                bam::Format::Cram,
+            ),
+        ];
+        for (extra, expected) in cases {
+            let args = calibrate_args(&extra);
+            assert_eq!(
+                resolve_output_format(&args).unwrap(),
+                expected,
+                "args: {extra:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_md5_hex() {
+        let mut input = std::io::Cursor::new(b"abc");
+        assert_eq!(
+            md5_hex(&mut input).unwrap(),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        let mut empty = std::io::Cursor::new(b"");
+        assert_eq!(
+            md5_hex(&mut empty).unwrap(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+    }
+
     #[test]
     fn test_trim_regions() {
         let regions = vec![
@@ -447,12 +2027,14 @@ mod tests {
                 name: "region1".to_string(),
                 beg: 100,
                 end: 500,
+                target: None,
             },
             region::Region {
                 contig: "chr2".to_string(),
                 name: "region2".to_string(),
                 beg: 200,
                 end: 800,
+                target: None,
             },
         ];
         let flank = 50;
@@ -464,6 +2046,35 @@ mod tests {
         assert_eq!(trimmed[1].end, 750);
     }
 
+    #[test]
+    fn test_region_bam_paths() {
+        let dir = Path::new("per-region");
+        let regions = vec![
+            region::Region::new("chrQ", 0, 100, "SG_1"),
+            region::Region::new("chrQ", 200, 300, "SG_2.v2"),
+        ];
+        assert_eq!(
+            region_bam_paths(dir, &regions).unwrap(),
+            vec![dir.join("SG_1.bam"), dir.join("SG_2.v2.bam")]
+        );
+
+        for name in ["", ".", "..", "../SG_1", "sub/SG_1", "sub\\SG_1"] {
+            let regions = vec![region::Region::new("chrQ", 0, 100, name)];
+            let err = region_bam_paths(dir, &regions).unwrap_err();
+            assert!(err.to_string().contains("cannot be used"), "{name}");
+        }
+
+        let regions = vec![
+            region::Region::new("chrQ", 0, 100, "SG_a"),
+            region::Region::new("chrQ", 200, 300, "SG_A"),
+        ];
+        let err = region_bam_paths(dir, &regions).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "regions \"SG_a\" and \"SG_A\" would both be written to the same --output-per-region-bams file"
+        );
+    }
+
     #[test]
     fn test_trim_regions_error() {
         let regions = vec![region::Region {
@@ -471,6 +2082,7 @@ mod tests {
             name: "region1".to_string(),
             beg: 100,
             end: 200,
+            target: None,
         }];
         let flank = 150;
         let result = trim_regions(&regions, flank);
@@ -0,0 +1,158 @@
+//! # Progress Module
+//!
+//! This module reports the progress of long-running, region-by-region work
+//! with an estimate of the time remaining.
+//!
+//! Progress is measured in bases: the lengths of all regions are summed up
+//! front, and each completed region advances the count by its length. The
+//! remaining time is extrapolated from the rate at which bases have been
+//! processed so far.
+//!
+//! ## Tests
+//!
+//! Contains unit tests for the fraction and ETA computations.
+use crate::region::Region;
+use std::time::{Duration, Instant};
+
+/// Width of the rendered progress bar, in characters.
+const BAR_WIDTH: usize = 30;
+
+/// Tracks bases processed against the total bases of a set of regions.
+#[derive(Debug)]
+pub struct Progress {
+    total: u64,
+    done: u64,
+    start: Instant,
+}
+
+impl Progress {
+    /// Creates a tracker for `total` bases, starting the clock now.
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            done: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Creates a tracker for the summed lengths of `regions`.
+    pub fn for_regions(regions: &[Region]) -> Self {
+        Self::new(regions.iter().map(Region::length).sum())
+    }
+
+    /// Records that `bases` more bases have been processed.
+    pub fn advance(&mut self, bases: u64) {
+        self.done = self.done.saturating_add(bases).min(self.total);
+    }
+
+    /// Fraction of the total bases processed, in `[0, 1]`. An empty total is
+    /// complete.
+    pub fn fraction(&self) -> f64 {
+        fraction(self.done, self.total)
+    }
+
+    /// Estimated time remaining, or `None` before any bases are processed.
+    pub fn eta(&self) -> Option<Duration> {
+        eta(self.done, self.total, self.start.elapsed())
+    }
+
+    /// Writes a progress bar with the current ETA to standard error.
+    pub fn report(&self, label: &str) {
+        let fraction = self.fraction();
+        let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+        let eta = match self.eta() {
+            Some(eta) => format_duration(eta),
+            None => "--".to_string(),
+        };
+        eprintln!(
+            "[{}{}] {:>3.0}% ETA {eta} {label}",
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+        );
+    }
+}
+
+/// Fraction of `total` represented by `done`, clamped to `[0, 1]`.
+pub(crate) fn fraction(done: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 1.0;
+    }
+    (done as f64 / total as f64).min(1.0)
+}
+
+/// Extrapolates the time remaining to process `total` bases given that `done`
+/// bases took `elapsed`. Returns `None` if nothing has been processed yet.
+pub(crate) fn eta(done: u64, total: u64, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        return if total == 0 {
+            Some(Duration::ZERO)
+        } else {
+            None
+        };
+    }
+    let remaining = total.saturating_sub(done);
+    Some(elapsed.mul_f64(remaining as f64 / done as f64))
+}
+
+/// Formats a duration as `HhMMmSSs`, dropping leading zero units.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}h{m:02}m{s:02}s")
+    } else if m > 0 {
+        format!("{m}m{s:02}s")
+    } else {
+        format!("{s}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fraction() {
+        assert_eq!(fraction(0, 200), 0.0);
+        assert_eq!(fraction(50, 200), 0.25);
+        assert_eq!(fraction(200, 200), 1.0);
+        assert_eq!(fraction(300, 200), 1.0);
+        assert_eq!(fraction(0, 0), 1.0);
+    }
+
+    #[test]
+    fn test_eta() {
+        assert_eq!(eta(0, 200, Duration::from_secs(5)), None);
+        assert_eq!(eta(0, 0, Duration::from_secs(5)), Some(Duration::ZERO));
+        // A quarter done in 10s leaves three quarters, 30s.
+        assert_eq!(
+            eta(50, 200, Duration::from_secs(10)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(eta(200, 200, Duration::from_secs(10)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_progress_for_regions() {
+        let regions = vec![
+            Region::new("chrQ", 0, 100, "a"),
+            Region::new("chrQ", 500, 800, "b"),
+        ];
+        let mut progress = Progress::for_regions(&regions);
+        assert_eq!(progress.fraction(), 0.0);
+        assert_eq!(progress.eta(), None);
+        progress.advance(100);
+        assert_eq!(progress.fraction(), 0.25);
+        progress.advance(1000);
+        assert_eq!(progress.fraction(), 1.0);
+        assert_eq!(progress.eta(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(7)), "7s");
+        assert_eq!(format_duration(Duration::from_secs(83)), "1m23s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h02m05s");
+    }
+}
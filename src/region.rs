@@ -5,22 +5,44 @@
 //! ## Structs
 //!
 //! - `Region`: Represents a genomic region with a contig name, start and end positions, and an identifier.
+//! - `TargetCoverage`: A region's target fold coverage, comparable so that `Region` is `Eq`.
 //!
 //! ## Implementations
 //!
+//! - `Region::try_new`: Creates a region, rejecting empty or inverted coordinates and those past
+//!   `MAX_POSITION`.
+//! - `Region::trim`: Removes flanking bases from both ends of a region.
+//! - `Region::length` and `Region::overlaps`: Region length and overlap tests.
 //! - Implements the `Display` trait for `Region` to enable formatted string representations.
 //!
 //! ## Functions
 //!
 //! - `load_from_bed`: Loads genomic regions from a BED file, parsing each line into a `Region` struct.
+//! - `load_from_bed_with_targets`: Like `load_from_bed`, also reading a per-region target
+//!   coverage from one column.
+//! - `load_from_bed_all_errors`: Like `load_from_bed`, but reports every malformed line instead of
+//!   only the first.
+//! - `load_from_bed_with_options`: Loads a BED file as set by `BedOptions`, which combines the
+//!   above and can require tab-delimited columns and unique region names.
+//! - `check_contigs`: Verifies that every region's contig is present in a BAM header.
+//! - `sort_by_header`: Sorts regions by header contig order, then start.
+//! - `check_sorted`: Verifies that regions are sorted as `sort_by_header` would sort them.
+//! - `partition_by_name_prefix`: Splits regions by whether their name starts with a prefix.
 //!
 //! ## Tests
 //!
 //! Contains unit tests for verifying the functionality of the `Region` struct and the `load_from_bed` function.
 use crate::errors::{Error, Result};
+use rust_htslib::bam::HeaderView;
+use std::collections::HashMap;
 use std::fmt;
 use std::io::Read;
 
+/// The largest coordinate a region may end at. htslib fetches positions as
+/// 32-bit integers, so a region ending past this would not be fetched as
+/// asked.
+pub const MAX_POSITION: u64 = i32::MAX as u64;
+
 /// Represents a genomic region within a contig.
 ///
 /// # Fields
@@ -28,24 +50,132 @@ use std::io::Read;
 /// - `beg`: The starting position of the region.
 /// - `end`: The ending position of the region.
 /// - `name`: The identifier name of the region.
+/// - `target`: The fold coverage to calibrate the region to, if the BED file
+///   gave one. See [`load_from_bed_with_targets`].
+///
+/// With the `serde` feature, `Region` implements `Serialize` and `Deserialize`
+/// using the field names above. `target` is omitted when it is `None`.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Region {
     pub contig: String,
     pub beg: u64,
     pub end: u64,
     pub name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub target: Option<TargetCoverage>,
+}
+
+/// The fold coverage a BED file gives a region to calibrate to.
+///
+/// Targets compare by their bit pattern, so that [`Region`] is `Eq`. The BED
+/// loaders only read finite, non-negative targets, which compare as numbers
+/// do, except that `-0` and `0` differ.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct TargetCoverage(pub f64);
+
+impl PartialEq for TargetCoverage {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
 }
 
+impl Eq for TargetCoverage {}
+
 impl Region {
     /// Create a new Region instance.
+    ///
+    /// The coordinates are not checked; use [`Region::try_new`] for regions
+    /// that come from user input.
     pub fn new(contig: &str, beg: u64, end: u64, name: &str) -> Self {
         Self {
             contig: contig.to_owned(),
             beg,
             end,
             name: name.to_owned(),
+            target: None,
+        }
+    }
+
+    /// Create a new Region instance, checking that it is not empty and that
+    /// htslib can fetch it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `beg` is not less than `end`, or if `end` is past
+    /// [`MAX_POSITION`].
+    pub fn try_new(contig: &str, beg: u64, end: u64, name: &str) -> Result<Self> {
+        if beg >= end {
+            return Err(Error::Region {
+                msg: format!("Beg must be less than end: beg = {beg}, end = {end}"),
+            });
+        }
+        if end > MAX_POSITION {
+            return Err(Error::Region {
+                msg: format!(
+                    "Region {name} ends at {end}, past the largest position htslib can fetch ({MAX_POSITION})"
+                ),
+            });
+        }
+        Ok(Self::new(contig, beg, end, name))
+    }
+
+    /// Returns the number of bases in the region, `end - beg`, or 0 if `end`
+    /// is before `beg`.
+    pub fn length(&self) -> u64 {
+        self.end.saturating_sub(self.beg)
+    }
+
+    /// Returns true if both regions are on the same contig and their half-open
+    /// intervals share at least one base. Touching regions do not overlap.
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.contig == other.contig && self.beg < other.end && other.beg < self.end
+    }
+
+    /// Returns a copy of the region holding only its first `bases` bases,
+    /// `[beg, min(beg + bases, end))`.
+    pub fn head(&self, bases: u64) -> Region {
+        Region {
+            end: self.end.min(self.beg.saturating_add(bases)),
+            ..self.clone()
         }
     }
+
+    /// Returns a copy of the region with `flank` bases removed from each end.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if applying the flank overflows or if the trimmed region
+    /// would be empty (start >= end).
+    pub fn trim(&self, flank: u64) -> Result<Region> {
+        let beg = self.beg.checked_add(flank).ok_or_else(|| Error::Region {
+            msg: format!("Region start + flank overflowed for region: {self}"),
+        })?;
+        let end = self.end.checked_sub(flank).ok_or_else(|| Error::Region {
+            msg: format!("Region end - flank underflowed for region: {self}"),
+        })?;
+        if beg >= end {
+            return Err(Error::Region {
+                msg: format!(
+                    "Region start >= end after applying flank for region: {self} ({}, flank = {flank})",
+                    self.name
+                ),
+            });
+        }
+        Ok(Region {
+            beg,
+            end,
+            ..self.clone()
+        })
+    }
 }
 
 /// Impl Display for Region
@@ -66,44 +196,281 @@ impl fmt::Display for Region {
 /// # Returns
 ///
 /// A `Result` containing a vector of `Region` structs if successful, or an error if parsing fails.
+///
+/// Blank lines, `#` comments and `track` and `browser` lines are skipped.
+/// Errors report the line number in the file, counting skipped lines.
 pub fn load_from_bed<R: Read>(reader: &mut R) -> Result<Vec<Region>> {
-    let mut result = Vec::new();
+    load_from_bed_with_options(reader, &BedOptions::default())
+}
+
+/// Like [`load_from_bed`], also reading each region's [`Region::target`]
+/// fold coverage from `column` (1-based, so 5 is the BED score column).
+///
+/// Rows without the column, or with `.` in it, have no target.
+///
+/// # Errors
+///
+/// As [`load_from_bed`], and if a target is not a non-negative number.
+pub fn load_from_bed_with_targets<R: Read>(reader: &mut R, column: usize) -> Result<Vec<Region>> {
+    let options = BedOptions {
+        target_column: Some(column),
+        ..BedOptions::default()
+    };
+    load_from_bed_with_options(reader, &options)
+}
+
+/// Returns the record lines of a BED file with their 1-based line numbers,
+/// skipping blank lines, `#` comments and `track` and `browser` lines.
+fn bed_records(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| {
+            let line = line.trim_start();
+            let first = line.split_whitespace().next();
+            !(line.is_empty()
+                || line.starts_with('#')
+                || first == Some("track")
+                || first == Some("browser"))
+        })
+}
+
+/// Loads genomic regions from a BED file, reporting every malformed line.
+///
+/// Unlike [`load_from_bed`], which stops at the first malformed line, this
+/// parses the whole file so that all problems can be fixed at once.
+///
+/// # Errors
+///
+/// Returns a single error listing the problem with each malformed line, in
+/// file order.
+pub fn load_from_bed_all_errors<R: Read>(reader: &mut R) -> Result<Vec<Region>> {
+    let options = BedOptions {
+        all_errors: true,
+        ..BedOptions::default()
+    };
+    load_from_bed_with_options(reader, &options)
+}
+
+/// How [`load_from_bed_with_options`] reads a BED file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BedOptions {
+    /// Read each region's [`Region::target`] from this column, as
+    /// [`load_from_bed_with_targets`] does.
+    pub target_column: Option<usize>,
+    /// Split columns on tabs only, so that a region name containing spaces is
+    /// kept whole. By default any run of whitespace separates columns.
+    pub strict_tabs: bool,
+    /// Report every malformed line, as [`load_from_bed_all_errors`] does,
+    /// instead of stopping at the first.
+    pub all_errors: bool,
+    /// Reject a BED file in which two regions share a name. Target and sample
+    /// regions are matched by name, so a repeated name would calibrate one of
+    /// its regions against the wrong sample region.
+    pub unique_names: bool,
+}
+
+/// Loads genomic regions from a BED file as set by `options`.
+///
+/// # Errors
+///
+/// As [`load_from_bed`], [`load_from_bed_with_targets`] and
+/// [`load_from_bed_all_errors`].
+pub fn load_from_bed_with_options<R: Read>(
+    reader: &mut R,
+    options: &BedOptions,
+) -> Result<Vec<Region>> {
     let mut contents = String::new();
     reader.read_to_string(&mut contents)?;
-    for (i, line) in contents.lines().enumerate() {
-        let bits: Vec<&str> = line.split_whitespace().collect();
-        let [contig, beg_str, end_str, name, ..] = bits[..] else {
+    let parse =
+        |(line_no, line)| parse_bed_line(line, line_no, options).map(|region| (line_no, region));
+    let records = if options.all_errors {
+        let mut records = Vec::new();
+        let mut errors = Vec::new();
+        for record in bed_records(&contents) {
+            match parse(record) {
+                Ok(record) => records.push(record),
+                Err(Error::BedInvalidRecord { msg }) => errors.push(msg),
+                Err(e) => return Err(e),
+            }
+        }
+        if !errors.is_empty() {
             return Err(Error::BedInvalidRecord {
-                msg: format!(
-                    "Incorrect number of columns detected, expected >= 4 found {} (line = {})",
-                    bits.len(),
-                    i + 1
-                ),
+                msg: format!("{} malformed lines:\n{}", errors.len(), errors.join("\n")),
             });
-        };
+        }
+        records
+    } else {
+        bed_records(&contents)
+            .map(parse)
+            .collect::<Result<Vec<_>>>()?
+    };
+    if options.unique_names {
+        check_unique_names(&records)?;
+    }
+    Ok(records.into_iter().map(|(_, region)| region).collect())
+}
 
-        let beg: u64 = beg_str.parse().map_err(|_| Error::BedInvalidRecord {
-            msg: format!(
-                "Beg column is not an integer: is {} (line = {})",
-                bits[1],
-                i + 1
-            ),
-        })?;
-        let end: u64 = end_str.parse().map_err(|_| Error::BedInvalidRecord {
+/// Checks that no two of the `(line number, region)` pairs share a region
+/// name.
+///
+/// # Errors
+///
+/// Returns an error listing, in file order, each line whose name was already
+/// used along with the line that first used it.
+fn check_unique_names(records: &[(usize, Region)]) -> Result<()> {
+    let mut first_line: HashMap<&str, usize> = HashMap::with_capacity(records.len());
+    let mut duplicates = Vec::new();
+    for (line_no, region) in records {
+        if let Some(first) = first_line.get(region.name.as_str()) {
+            duplicates.push(format!(
+                "{} on line {line_no} (first on line {first})",
+                region.name
+            ));
+        } else {
+            first_line.insert(&region.name, *line_no);
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(Error::Region {
+            msg: format!("Duplicate region names: {}", duplicates.join("; ")),
+        });
+    }
+    Ok(())
+}
+
+/// Parses line number `line_no` (1-based) of a BED file into a `Region`,
+/// with its target from the target column if `options` gives one.
+fn parse_bed_line(line: &str, line_no: usize, options: &BedOptions) -> Result<Region> {
+    let bits: Vec<&str> = if options.strict_tabs {
+        line.split('\t').collect()
+    } else {
+        line.split_whitespace().collect()
+    };
+    let [contig, beg_str, end_str, name, ..] = bits[..] else {
+        let hint = if options.strict_tabs && line.contains(' ') {
+            "; columns must be separated by tabs"
+        } else {
+            ""
+        };
+        return Err(Error::BedInvalidRecord {
             msg: format!(
-                "End column is not an integer: is {} (line = {})",
-                bits[2],
-                i + 1
+                "Incorrect number of columns detected, expected >= 4 found {}{hint} (line = {})",
+                bits.len(),
+                line_no
             ),
-        })?;
-        result.push(Region {
-            contig: contig.to_owned(),
-            beg,
-            end,
-            name: name.to_owned(),
         });
+    };
+
+    let beg: u64 = beg_str.parse().map_err(|_| Error::BedInvalidRecord {
+        msg: format!(
+            "Beg column is not an integer: is {} (line = {})",
+            bits[1], line_no
+        ),
+    })?;
+    let end: u64 = end_str.parse().map_err(|_| Error::BedInvalidRecord {
+        msg: format!(
+            "End column is not an integer: is {} (line = {})",
+            bits[2], line_no
+        ),
+    })?;
+    let mut region = Region::try_new(contig, beg, end, name).map_err(|e| match e {
+        Error::Region { msg } => Error::BedInvalidRecord {
+            msg: format!("{msg} (line = {line_no})"),
+        },
+        e => e,
+    })?;
+    if let Some(column) = options.target_column {
+        region.target = match bits.get(column - 1) {
+            None | Some(&".") => None,
+            Some(value) => match value.parse::<f64>() {
+                Ok(target) if target.is_finite() && target >= 0.0 => {
+                    Some(TargetCoverage(target))
+                }
+                _ => {
+                    return Err(Error::BedInvalidRecord {
+                        msg: format!(
+                            "Target coverage in column {column} is not a non-negative number: is {value} (line = {line_no})"
+                        ),
+                    })
+                }
+            },
+        };
     }
-    Ok(result)
+    Ok(region)
+}
+
+/// Splits `regions` into those whose name starts with `prefix` and the rest,
+/// each in their original order.
+pub fn partition_by_name_prefix(regions: &[Region], prefix: &str) -> (Vec<Region>, Vec<Region>) {
+    regions
+        .iter()
+        .cloned()
+        .partition(|region| region.name.starts_with(prefix))
+}
+
+/// Checks that the contig of every region is present in a BAM header.
+///
+/// # Errors
+///
+/// Returns an error if the header has no reference sequences, or listing each
+/// missing contig (once, in BED order) if any region refers to a contig not
+/// in the header.
+pub fn check_contigs(regions: &[Region], header: &HeaderView) -> Result<()> {
+    crate::bam::check_has_references(header)?;
+    let mut missing: Vec<&str> = Vec::new();
+    for region in regions {
+        if header.tid(region.contig.as_bytes()).is_none() && !missing.contains(&&*region.contig) {
+            missing.push(&region.contig);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(Error::Region {
+            msg: format!("Contigs not found in BAM header: {}", missing.join(", ")),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the sort key of a region: its contig's position in `header`, then
+/// its start. Contigs missing from the header sort last.
+fn header_sort_key(region: &Region, header: &HeaderView) -> (u32, u64) {
+    let tid = header.tid(region.contig.as_bytes()).unwrap_or(u32::MAX);
+    (tid, region.beg)
+}
+
+/// Sorts regions by the order of their contigs in a BAM header, then by start
+/// position, so that they are fetched in file order.
+///
+/// The sort is stable: regions with the same contig and start keep their BED
+/// order.
+pub fn sort_by_header(regions: &mut [Region], header: &HeaderView) {
+    regions.sort_by_key(|r| header_sort_key(r, header));
+}
+
+/// Checks that regions are sorted by the order of their contigs in a BAM
+/// header, then by start position.
+///
+/// # Errors
+///
+/// Returns an error naming the first region that is out of order.
+pub fn check_sorted(regions: &[Region], header: &HeaderView) -> Result<()> {
+    for (i, pair) in regions.windows(2).enumerate() {
+        if header_sort_key(&pair[1], header) < header_sort_key(&pair[0], header) {
+            return Err(Error::Region {
+                msg: format!(
+                    "BED is not sorted by header contig order and start: {} ({}) comes after {} ({}) (line = {})",
+                    pair[1].name,
+                    pair[1],
+                    pair[0].name,
+                    pair[0],
+                    i + 2
+                ),
+            });
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -111,6 +478,24 @@ mod tests {
     use super::*;
     use std::io::{self, Cursor};
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let region = Region::new("chrQ_mirror", 100, 200, "odd \"name\"");
+        let json = serde_json::to_string(&region).unwrap();
+        assert_eq!(
+            json,
+            r#"{"contig":"chrQ_mirror","beg":100,"end":200,"name":"odd \"name\""}"#
+        );
+        assert_eq!(serde_json::from_str::<Region>(&json).unwrap(), region);
+
+        let mut targeted = region;
+        targeted.target = Some(TargetCoverage(12.5));
+        let json = serde_json::to_string(&targeted).unwrap();
+        assert!(json.ends_with(r#","target":12.5}"#), "{json}");
+        assert_eq!(serde_json::from_str::<Region>(&json).unwrap(), targeted);
+    }
+
     #[test]
     fn display_region() {
         let region = Region {
@@ -118,6 +503,7 @@ mod tests {
             beg: 100,
             end: 200,
             name: "test_region".to_owned(),
+            target: None,
         };
         assert_eq!(region.to_string(), "chr1:100-200");
 
@@ -126,10 +512,80 @@ mod tests {
             beg: 0,
             end: 1000,
             name: "test_region2".to_owned(),
+            target: None,
         };
         assert_eq!(region2.to_string(), "chrX:0-1000");
     }
 
+    #[test]
+    fn region_length() {
+        assert_eq!(Region::new("chr1", 100, 250, "reg1").length(), 150);
+        assert_eq!(Region::new("chr1", 100, 100, "reg1").length(), 0);
+        assert_eq!(Region::new("chr1", 200, 100, "reg1").length(), 0);
+    }
+
+    #[test]
+    fn region_overlaps() {
+        let region = Region::new("chr1", 100, 200, "reg1");
+        // Adjacent regions touch but do not overlap.
+        assert!(!region.overlaps(&Region::new("chr1", 200, 300, "reg2")));
+        assert!(!region.overlaps(&Region::new("chr1", 0, 100, "reg2")));
+        // Partial and nested overlaps, in either order.
+        assert!(region.overlaps(&Region::new("chr1", 199, 300, "reg2")));
+        let nested = Region::new("chr1", 120, 130, "reg2");
+        assert!(region.overlaps(&nested));
+        assert!(nested.overlaps(&region));
+        assert!(region.overlaps(&region));
+        // Different contigs never overlap.
+        assert!(!region.overlaps(&Region::new("chr2", 100, 200, "reg2")));
+    }
+
+    #[test]
+    fn trim_region() {
+        let region = Region::new("chr1", 100, 500, "reg1");
+        let trimmed = region.trim(50).unwrap();
+        assert_eq!(trimmed, Region::new("chr1", 150, 450, "reg1"));
+        assert_eq!(region.trim(0).unwrap(), region);
+    }
+
+    #[test]
+    fn head_region() {
+        let region = Region::new("chr1", 100, 500, "reg1");
+        assert_eq!(region.head(50), Region::new("chr1", 100, 150, "reg1"));
+        // A head longer than the region leaves it unchanged.
+        assert_eq!(region.head(400), region);
+        assert_eq!(region.head(u64::MAX), region);
+    }
+
+    #[test]
+    fn trim_region_exact_boundary() {
+        // A flank of exactly half the region length leaves zero bases.
+        let region = Region::new("chr1", 100, 200, "reg1");
+        let err = region.trim(50).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Region start >= end after applying flank for region"));
+        // One base less leaves a single-base region.
+        let trimmed = Region::new("chr1", 100, 201, "reg1").trim(50).unwrap();
+        assert_eq!((trimmed.beg, trimmed.end), (150, 151));
+    }
+
+    #[test]
+    fn trim_region_too_large() {
+        let region = Region::new("chr1", 100, 200, "reg1");
+        assert!(region.trim(150).is_err());
+        let err = region.trim(u64::MAX).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Region start + flank overflowed for region"));
+        let err = Region::new("chr1", 0, 200, "reg1")
+            .trim(u64::MAX - 10)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Region end - flank underflowed for region"));
+    }
+
     #[test]
     fn load_bed_to_vec() {
         let data = b"chr1\t1\t10\treg1";
@@ -141,7 +597,8 @@ mod tests {
                 contig: "chr1".to_owned(),
                 beg: 1,
                 end: 10,
-                name: "reg1".to_owned()
+                name: "reg1".to_owned(),
+                target: None,
             }]
         );
     }
@@ -157,13 +614,15 @@ mod tests {
                     contig: "chr1".to_owned(),
                     beg: 1,
                     end: 10,
-                    name: "reg1".to_owned()
+                    name: "reg1".to_owned(),
+                    target: None,
                 },
                 Region {
                     contig: "chr2".to_owned(),
                     beg: 2,
                     end: 20,
-                    name: "reg2".to_owned()
+                    name: "reg2".to_owned(),
+                    target: None,
                 }
             ]
         );
@@ -189,6 +648,51 @@ mod tests {
             .contains("End column is not an integer: is xxx (line = 1)"));
     }
 
+    #[test]
+    fn try_new_rejects_empty_regions() {
+        let region = Region::try_new("chr1", 100, 200, "reg1").unwrap();
+        assert_eq!(region, Region::new("chr1", 100, 200, "reg1"));
+        let err = Region::try_new("chr1", 200, 100, "reg1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid region: Beg must be less than end: beg = 200, end = 100"
+        );
+        assert!(Region::try_new("chr1", 100, 100, "reg1").is_err());
+    }
+
+    #[test]
+    fn load_with_beg_not_before_end() {
+        let mut cursor = Cursor::new(b"chr1\t1\t10\treg1\nchr1\t200\t100\treg2");
+        let err = load_from_bed(&mut cursor).unwrap_err();
+        assert!(matches!(err, Error::BedInvalidRecord { .. }));
+        assert!(err
+            .to_string()
+            .contains("Beg must be less than end: beg = 200, end = 100 (line = 2)"));
+
+        // Empty regions are rejected too.
+        let mut cursor = Cursor::new(b"chr1\t100\t100\treg1");
+        let err = load_from_bed(&mut cursor).unwrap_err();
+        assert!(err.to_string().contains("beg = 100, end = 100 (line = 1)"));
+    }
+
+    #[test]
+    fn load_with_end_past_max_position() {
+        let mut cursor = Cursor::new(format!(
+            "chr1\t1\t10\treg1\nhuge\t100\t{}\treg2",
+            MAX_POSITION + 1
+        ));
+        let err = load_from_bed(&mut cursor).unwrap_err();
+        assert!(matches!(err, Error::BedInvalidRecord { .. }));
+        assert_eq!(
+            err.to_string(),
+            "invalid BED record: Region reg2 ends at 2147483648, past the largest \
+             position htslib can fetch (2147483647) (line = 2)"
+        );
+
+        let mut cursor = Cursor::new(format!("huge\t100\t{MAX_POSITION}\treg1"));
+        assert_eq!(load_from_bed(&mut cursor).unwrap()[0].end, MAX_POSITION);
+    }
+
     #[test]
     fn load_without_name() {
         let mut cursor = Cursor::new(b"chr1\t1\t10");
@@ -199,6 +703,220 @@ mod tests {
             .contains("Incorrect number of columns detected, expected >= 4 found 3"));
     }
 
+    #[test]
+    fn load_all_errors_reports_every_line() {
+        let mut cursor = Cursor::new(
+            b"chr1\t1\t10\treg1\nchr1\txxx\t10\treg2\nchr1\t1\t10\nchr1\t1\tyyy\treg4\n",
+        );
+        let err = load_from_bed_all_errors(&mut cursor).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("3 malformed lines"), "{msg}");
+        assert!(msg.contains("Beg column is not an integer: is xxx (line = 2)"));
+        assert!(msg.contains("expected >= 4 found 3 (line = 3)"));
+        assert!(msg.contains("End column is not an integer: is yyy (line = 4)"));
+
+        let mut cursor = Cursor::new(b"chr1\t1\t10\treg1\nchr2\t5\t20\treg2\n");
+        let regions = load_from_bed_all_errors(&mut cursor).unwrap();
+        assert_eq!(
+            regions,
+            load_from_bed(&mut Cursor::new(b"chr1\t1\t10\treg1\nchr2\t5\t20\treg2\n")).unwrap()
+        );
+    }
+
+    #[test]
+    fn load_with_targets() {
+        let mut cursor = Cursor::new(
+            b"chr1\t1\t10\treg1\t60\nchr1\t20\t30\treg2\nchr1\t40\t50\treg3\t.\nchr1\t60\t70\treg4\t12.5\n",
+        );
+        let regions = load_from_bed_with_targets(&mut cursor, 5).unwrap();
+        let targets = regions
+            .iter()
+            .map(|r| r.target.map(|t| t.0))
+            .collect::<Vec<_>>();
+        assert_eq!(targets, vec![Some(60.0), None, None, Some(12.5)]);
+
+        let mut cursor = Cursor::new(b"chr1\t1\t10\treg1\t60\nchr1\t20\t30\treg2\t-1\n");
+        let err = load_from_bed_with_targets(&mut cursor, 5).unwrap_err();
+        assert!(err.to_string().contains(
+            "Target coverage in column 5 is not a non-negative number: is -1 (line = 2)"
+        ));
+    }
+
+    #[test]
+    fn load_strict_tabs() {
+        let data = b"chr1\t1\t10\tmy region\nchr1 20 30 reg2\n";
+        let strict = BedOptions {
+            strict_tabs: true,
+            ..BedOptions::default()
+        };
+        let err = load_from_bed_with_options(&mut Cursor::new(data), &strict).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected >= 4 found 1; columns must be separated by tabs (line = 2)"));
+
+        let data = b"chr1\t1\t10\tmy region\t60\nchr1\t20\t30\treg2\n";
+        let regions = load_from_bed_with_options(&mut Cursor::new(data), &strict).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                Region::new("chr1", 1, 10, "my region"),
+                Region::new("chr1", 20, 30, "reg2"),
+            ]
+        );
+        let with_targets = BedOptions {
+            target_column: Some(5),
+            ..strict
+        };
+        let regions = load_from_bed_with_options(&mut Cursor::new(data), &with_targets).unwrap();
+        assert_eq!(regions[0].target, Some(TargetCoverage(60.0)));
+
+        // By default the name is split at the space.
+        let regions = load_from_bed(&mut Cursor::new(data)).unwrap();
+        assert_eq!(regions[0].name, "my");
+    }
+
+    #[test]
+    fn load_skips_comments_and_headers() {
+        let data = b"# sequin regions\n\
+            track name=sequins description=\"Sequin regions\"\n\
+            browser position chrQ_mirror:1-1000\n\
+            chr1\t1\t10\treg1\n\
+            \n\
+            \t\n\
+            # trailing comment\n\
+            chr2\t2\t20\treg2\n";
+        let expected = vec![
+            Region::new("chr1", 1, 10, "reg1"),
+            Region::new("chr2", 2, 20, "reg2"),
+        ];
+        assert_eq!(load_from_bed(&mut Cursor::new(data)).unwrap(), expected);
+        assert_eq!(
+            load_from_bed_all_errors(&mut Cursor::new(data)).unwrap(),
+            expected
+        );
+
+        // Errors still report the line in the file.
+        let mut cursor = Cursor::new(b"# comment\ntrack name=x\n\nchr1\txxx\t10\treg1\n");
+        let err = load_from_bed(&mut cursor).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Beg column is not an integer: is xxx (line = 4)"));
+
+        // Only whole `track` and `browser` tokens are headers.
+        let mut cursor = Cursor::new(b"tracks\t1\t10\treg1\n");
+        assert_eq!(
+            load_from_bed(&mut cursor).unwrap(),
+            vec![Region::new("tracks", 1, 10, "reg1")]
+        );
+    }
+
+    fn test_header() -> HeaderView {
+        let mut header = rust_htslib::bam::Header::new();
+        header.push_record(&rust_htslib::bam::header::HeaderRecord::new(
+            b"SQ\tSN:chr1\tLN:5000",
+        ));
+        header.push_record(&rust_htslib::bam::header::HeaderRecord::new(
+            b"SQ\tSN:chrQ_mirror\tLN:9800",
+        ));
+        HeaderView::from_header(&header)
+    }
+
+    #[test]
+    fn check_contigs_present() {
+        let regions = vec![
+            Region::new("chr1", 1, 10, "reg1"),
+            Region::new("chrQ_mirror", 1, 10, "reg2"),
+        ];
+        assert!(check_contigs(&regions, &test_header()).is_ok());
+    }
+
+    #[test]
+    fn check_contigs_missing() {
+        let regions = vec![
+            Region::new("chr1", 1, 10, "reg1"),
+            Region::new("chrZ", 1, 10, "reg2"),
+            Region::new("chrZ", 20, 30, "reg3"),
+            Region::new("chrY", 1, 10, "reg4"),
+        ];
+        let err = check_contigs(&regions, &test_header()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid region: Contigs not found in BAM header: chrZ, chrY"
+        );
+    }
+
+    #[test]
+    fn load_unique_names_lists_every_duplicate() {
+        let unique = BedOptions {
+            unique_names: true,
+            ..BedOptions::default()
+        };
+        let data = b"chrQ\t0\t100\tSG_1\nchrQ\t200\t300\tSG_2\nchrQ\t400\t500\tSG_3\n";
+        assert_eq!(
+            load_from_bed_with_options(&mut Cursor::new(data), &unique)
+                .unwrap()
+                .len(),
+            3
+        );
+        let data = b"chrQ\t0\t100\tSG_1\n\
+            # comment\n\
+            chrQ\t200\t300\tSG_2\n\
+            chrQ\t400\t500\tSG_3\n\
+            chrQ\t600\t700\tSG_2\n\
+            chrQ\t800\t900\tSG_1\n\
+            chrQ\t1000\t1100\tSG_1\n";
+        let err = load_from_bed_with_options(&mut Cursor::new(data), &unique).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid region: Duplicate region names: SG_2 on line 5 (first on line 3); \
+             SG_1 on line 6 (first on line 1); SG_1 on line 7 (first on line 1)"
+        );
+        let all_errors = BedOptions {
+            all_errors: true,
+            ..unique
+        };
+        assert!(load_from_bed_with_options(&mut Cursor::new(data), &all_errors).is_err());
+        // Repeated names are allowed unless asked otherwise.
+        assert_eq!(load_from_bed(&mut Cursor::new(data)).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn partition_by_name_prefix_keeps_order() {
+        let regions = vec![
+            Region::new("chrQ", 0, 100, "SG_1"),
+            Region::new("chrQ", 200, 300, "other"),
+            Region::new("chrQ", 400, 500, "SG_2"),
+        ];
+        let (matching, rest) = partition_by_name_prefix(&regions, "SG_");
+        let names = |r: &[Region]| r.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&matching), vec!["SG_1", "SG_2"]);
+        assert_eq!(names(&rest), vec!["other"]);
+        let (matching, rest) = partition_by_name_prefix(&regions, "");
+        assert_eq!(matching.len(), 3);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn sort_and_check_sorted() {
+        let header = test_header();
+        let mut regions = vec![
+            Region::new("chrQ_mirror", 1, 10, "reg1"),
+            Region::new("chr1", 50, 60, "reg2"),
+            Region::new("chr1", 5, 10, "reg3"),
+        ];
+        let err = check_sorted(&regions, &header).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid region: BED is not sorted by header contig order and start: \
+             reg2 (chr1:50-60) comes after reg1 (chrQ_mirror:1-10) (line = 2)"
+        );
+
+        sort_by_header(&mut regions, &header);
+        let names = regions.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["reg3", "reg2", "reg1"]);
+        assert!(check_sorted(&regions, &header).is_ok());
+    }
+
     struct ErrorReader;
     impl Read for ErrorReader {
         fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
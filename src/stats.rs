@@ -0,0 +1,459 @@
+//! # Stats Module
+//!
+//! This module produces a one-shot QC summary of a whole BAM/CRAM file:
+//! read counts from the index, duplicate and mapping quality figures from a
+//! single pass over the records, and the mean coverage over a BED file.
+//! Alternatively it reports the mean coverage of each BED region, compared
+//! with that of its sample region, to check a calibrated file against its
+//! targets.
+//!
+//! ## Functions
+//!
+//! - `compute_stats`: Collects the summary from a reader.
+//! - `compute_region_stats`: Collects the coverage of each region.
+//! - `run`: Entry point for the `stats` subcommand.
+//!
+//! ## Tests
+//!
+//! Contains unit tests of the combined summary and its JSON and CSV forms,
+//! and of the per-region report.
+use crate::bam::{record_start_u64, BamReader};
+use crate::coverage::{self, ReadFilters};
+use crate::errors::{Error, Result};
+use crate::region::{self, Region};
+use rust_htslib::bam::FetchDefinition;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Layout of the `stats` report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// A single JSON object.
+    Json,
+    /// CSV with a header and a single row.
+    Csv,
+}
+
+/// Whole-file QC summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Mapped records, including secondary and supplementary alignments, as
+    /// counted by the index.
+    pub mapped_reads: u64,
+    /// Unmapped records: those placed on a contig, from the index, plus the
+    /// unplaced ones.
+    pub unmapped_reads: u64,
+    /// Primary, mapped reads flagged as duplicates.
+    pub duplicate_reads: u64,
+    /// Mean mapping quality of primary, mapped reads.
+    pub mean_mapq: Option<f64>,
+    /// Number of BED regions.
+    pub regions: usize,
+    /// Total length of the BED regions.
+    pub region_bases: u64,
+    /// Mean depth over all bases of the BED regions.
+    pub mean_coverage: Option<f64>,
+}
+
+impl Stats {
+    /// Total records, mapped and unmapped.
+    pub fn total_reads(&self) -> u64 {
+        self.mapped_reads + self.unmapped_reads
+    }
+
+    const FIELDS: [&'static str; 8] = [
+        "total_reads",
+        "mapped_reads",
+        "unmapped_reads",
+        "duplicate_reads",
+        "mean_mapq",
+        "regions",
+        "region_bases",
+        "mean_coverage",
+    ];
+
+    /// The summary values in the order of [`Stats::FIELDS`]. Missing means
+    /// are `None`.
+    fn values(&self) -> [Option<String>; 8] {
+        let mean = |v: Option<f64>| v.map(|v| format!("{v:.2}"));
+        [
+            Some(self.total_reads().to_string()),
+            Some(self.mapped_reads.to_string()),
+            Some(self.unmapped_reads.to_string()),
+            Some(self.duplicate_reads.to_string()),
+            mean(self.mean_mapq),
+            Some(self.regions.to_string()),
+            Some(self.region_bases.to_string()),
+            mean(self.mean_coverage),
+        ]
+    }
+}
+
+/// Collects the QC summary of `bam_reader`, with the mean coverage of
+/// `regions` counted from non-duplicate reads with a mapping quality of at
+/// least `min_mapq`.
+pub(crate) fn compute_stats<T: BamReader>(
+    bam_reader: &mut T,
+    regions: &[Region],
+    min_mapq: u8,
+) -> Result<Stats> {
+    let (mapped_reads, mut unmapped_reads) = bam_reader
+        .index_stats()?
+        .into_iter()
+        .fold((0, 0), |(mapped, unmapped), (m, u, _)| {
+            (mapped + m, unmapped + u)
+        });
+
+    let mut duplicate_reads = 0;
+    let mut mapq_sum = 0u64;
+    let mut primary_reads = 0u64;
+    bam_reader.fetch(FetchDefinition::All)?;
+    for result in bam_reader.records() {
+        let record = result?;
+        if record.is_unmapped() {
+            // Placed unmapped reads are already in the index counts.
+            if record.tid() < 0 {
+                unmapped_reads += 1;
+            }
+            continue;
+        }
+        if record.is_secondary() || record.is_supplementary() || record_start_u64(&record).is_none()
+        {
+            continue;
+        }
+        primary_reads += 1;
+        mapq_sum += record.mapq() as u64;
+        if record.is_duplicate() {
+            duplicate_reads += 1;
+        }
+    }
+
+    let mut covered = 0u64;
+    let mut region_bases = 0u64;
+    for region in regions {
+        let coverage = coverage::coverage_for_region(
+            bam_reader,
+            region,
+            min_mapq,
+            0,
+            &ReadFilters::default(),
+        )?;
+        covered += coverage.coverage.iter().map(|&v| v as u64).sum::<u64>();
+        region_bases += coverage.coverage.len() as u64;
+    }
+
+    let mean = |sum: u64, n: u64| (n > 0).then(|| sum as f64 / n as f64);
+    Ok(Stats {
+        mapped_reads,
+        unmapped_reads,
+        duplicate_reads,
+        mean_mapq: mean(mapq_sum, primary_reads),
+        regions: regions.len(),
+        region_bases,
+        mean_coverage: mean(covered, region_bases),
+    })
+}
+
+/// Mean coverage of a BED region and, in a report with sample regions, of
+/// the sample region with the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionStats {
+    pub region: Region,
+    /// `None` if no bases are left after the flank is trimmed.
+    pub mean_coverage: Option<f64>,
+    pub sample_coverage: Option<f64>,
+}
+
+impl RegionStats {
+    /// The region's coverage as a fraction of its sample region's, or `None`
+    /// if either is missing or the sample region is uncovered.
+    pub fn ratio(&self) -> Option<f64> {
+        match (self.mean_coverage, self.sample_coverage) {
+            (Some(mean), Some(sample)) if sample > 0.0 => Some(mean / sample),
+            _ => None,
+        }
+    }
+}
+
+/// Collects the mean coverage of each of `regions`, and of the sample region
+/// with the same name if `sample_regions` are given, counting reads with a
+/// mapping quality of at least `min_mapq` and trimming `flank` bases from
+/// both ends of every region.
+///
+/// # Errors
+/// Returns an error if a region has no sample region.
+pub(crate) fn compute_region_stats<T: BamReader>(
+    bam_reader: &mut T,
+    regions: &[Region],
+    sample_regions: Option<&[Region]>,
+    min_mapq: u8,
+    flank: u64,
+) -> Result<Vec<RegionStats>> {
+    let mut mean_coverage = |region: &Region| {
+        let coverage = coverage::coverage_for_region(
+            bam_reader,
+            region,
+            min_mapq,
+            flank,
+            &ReadFilters::default(),
+        )?;
+        Ok::<_, Error>(coverage.mean().map(f64::from))
+    };
+    regions
+        .iter()
+        .map(|region| {
+            let sample_coverage = match sample_regions {
+                Some(sample_regions) => {
+                    let sample_region = sample_regions
+                        .iter()
+                        .find(|r| r.name == region.name)
+                        .ok_or_else(|| Error::Region {
+                            msg: format!(
+                                "No matching sample region found for target region {}",
+                                region.name
+                            ),
+                        })?;
+                    mean_coverage(sample_region)?
+                }
+                None => None,
+            };
+            Ok(RegionStats {
+                region: region.clone(),
+                mean_coverage: mean_coverage(region)?,
+                sample_coverage,
+            })
+        })
+        .collect()
+}
+
+/// Writes region stats as CSV, one row per region, with the sample coverage
+/// and ratio columns if `with_sample`. Missing values are empty.
+fn write_region_csv<W: Write>(stats: &[RegionStats], with_sample: bool, mut dest: W) -> Result<()> {
+    let mut header = "name,chrom,beg,end,mean_coverage".to_string();
+    if with_sample {
+        header.push_str(",sample_coverage,ratio");
+    }
+    writeln!(dest, "{header}")?;
+    let value = |v: Option<f64>, precision: usize| {
+        v.map(|v| format!("{v:.precision$}")).unwrap_or_default()
+    };
+    for stat in stats {
+        let region = &stat.region;
+        write!(
+            dest,
+            "{},{},{},{},{}",
+            region.name,
+            region.contig,
+            region.beg,
+            region.end,
+            value(stat.mean_coverage, 2)
+        )?;
+        if with_sample {
+            write!(
+                dest,
+                ",{},{}",
+                value(stat.sample_coverage, 2),
+                value(stat.ratio(), 4)
+            )?;
+        }
+        writeln!(dest)?;
+    }
+    Ok(())
+}
+
+/// Writes `stats` as a single JSON object. Missing means are `null`.
+fn write_json<W: Write>(stats: &Stats, mut dest: W) -> Result<()> {
+    let fields = Stats::FIELDS
+        .iter()
+        .zip(stats.values())
+        .map(|(field, value)| format!("\"{field}\":{}", value.as_deref().unwrap_or("null")))
+        .collect::<Vec<_>>();
+    writeln!(dest, "{{{}}}", fields.join(","))?;
+    Ok(())
+}
+
+/// Writes `stats` as a CSV header and row. Missing means are empty.
+fn write_csv<W: Write>(stats: &Stats, mut dest: W) -> Result<()> {
+    writeln!(dest, "{}", Stats::FIELDS.join(","))?;
+    let values = stats
+        .values()
+        .map(|value| value.unwrap_or_default())
+        .join(",");
+    writeln!(dest, "{values}")?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct StatsArgs {
+    pub bam_path: PathBuf,
+    pub bed_path: PathBuf,
+    pub reference: Option<PathBuf>,
+    pub min_mapq: u8,
+    pub format: StatsFormat,
+    /// Write the coverage of each BED region instead of the summary, see
+    /// [`compute_region_stats`].
+    pub per_region: bool,
+    /// Sample regions to compare the BED regions with. Implies `per_region`.
+    pub sample_bed_path: Option<PathBuf>,
+    /// Bases trimmed from both ends of each region in the per-region report.
+    pub flank: u64,
+}
+
+pub fn run(args: &StatsArgs) -> Result<()> {
+    let load_bed = |path: &PathBuf| {
+        let file = std::fs::File::open(path)?;
+        region::load_from_bed(&mut std::io::BufReader::new(file))
+    };
+    let regions = load_bed(&args.bed_path)?;
+    let mut bam_reader =
+        coverage::open_reader(&args.bam_path, args.reference.as_ref(), None, None)?;
+    if args.per_region || args.sample_bed_path.is_some() {
+        let sample_regions = args.sample_bed_path.as_ref().map(load_bed).transpose()?;
+        let stats = compute_region_stats(
+            &mut bam_reader,
+            &regions,
+            sample_regions.as_deref(),
+            args.min_mapq,
+            args.flank,
+        )?;
+        return write_region_csv(&stats, sample_regions.is_some(), std::io::stdout());
+    }
+    let stats = compute_stats(&mut bam_reader, &regions, args.min_mapq)?;
+    match args.format {
+        StatsFormat::Json => write_json(&stats, std::io::stdout())?,
+        StatsFormat::Csv => write_csv(&stats, std::io::stdout())?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::{create_mock_record, MockBamReader};
+
+    #[test]
+    fn test_compute_stats() {
+        let mut duplicate = create_mock_record(0, 100, "dup");
+        duplicate.set_duplicate();
+        let mut low_mapq = create_mock_record(0, 300, "low");
+        low_mapq.set_mapq(0);
+        let mut secondary = create_mock_record(0, 100, "secondary");
+        secondary.set_secondary();
+        let mut placed_unmapped = create_mock_record(0, 100, "placed");
+        placed_unmapped.set_unmapped();
+        let mut unplaced_unmapped = create_mock_record(-1, -1, "unplaced");
+        unplaced_unmapped.set_unmapped();
+        let records = vec![
+            create_mock_record(0, 100, "read1"),
+            duplicate,
+            secondary,
+            placed_unmapped,
+            low_mapq,
+            create_mock_record(3, 0, "read2"),
+            unplaced_unmapped,
+        ];
+        let mut mock = MockBamReader::new(records, None);
+        let regions = vec![
+            Region::new("chr1", 100, 200, "a"),
+            Region::new("chr1", 300, 400, "b"),
+        ];
+        let stats = compute_stats(&mut mock, &regions, 10).unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                mapped_reads: 5,
+                unmapped_reads: 2,
+                duplicate_reads: 1,
+                // 60, 60, 0 and 60 over four primary reads.
+                mean_mapq: Some(45.0),
+                regions: 2,
+                region_bases: 200,
+                // One read over the first region: the duplicate and, over the
+                // second, the low-quality read are not counted.
+                mean_coverage: Some(0.5),
+            }
+        );
+        assert_eq!(stats.total_reads(), 7);
+
+        let mut json = Vec::new();
+        write_json(&stats, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"total_reads\":7,\"mapped_reads\":5,\"unmapped_reads\":2,\"duplicate_reads\":1,\
+             \"mean_mapq\":45.00,\"regions\":2,\"region_bases\":200,\"mean_coverage\":0.50}\n"
+        );
+        let mut csv = Vec::new();
+        write_csv(&stats, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "total_reads,mapped_reads,unmapped_reads,duplicate_reads,mean_mapq,regions,region_bases,mean_coverage\n\
+             7,5,2,1,45.00,2,200,0.50\n"
+        );
+    }
+
+    #[test]
+    fn test_compute_region_stats() {
+        let mut records = Vec::new();
+        for i in 0..4 {
+            records.push(create_mock_record(0, 100, &format!("sample{i}")));
+        }
+        for i in 0..2 {
+            records.push(create_mock_record(3, 100, &format!("sequin{i}")));
+        }
+        let regions = vec![
+            Region::new("chrQ_mirror", 100, 200, "a"),
+            Region::new("chrQ_mirror", 300, 400, "b"),
+        ];
+        let sample_regions = vec![
+            Region::new("chr1", 300, 400, "b"),
+            Region::new("chr1", 100, 200, "a"),
+        ];
+        let mut mock = MockBamReader::new(records.clone(), None);
+        let stats = compute_region_stats(&mut mock, &regions, Some(&sample_regions), 0, 0).unwrap();
+        assert_eq!(stats[0].mean_coverage, Some(2.0));
+        assert_eq!(stats[0].sample_coverage, Some(4.0));
+        assert_eq!(stats[0].ratio(), Some(0.5));
+        // An uncovered sample region has no ratio.
+        assert_eq!(stats[1].sample_coverage, Some(0.0));
+        assert_eq!(stats[1].ratio(), None);
+
+        let mut csv = Vec::new();
+        write_region_csv(&stats, true, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "name,chrom,beg,end,mean_coverage,sample_coverage,ratio\n\
+             a,chrQ_mirror,100,200,2.00,4.00,0.5000\n\
+             b,chrQ_mirror,300,400,0.00,0.00,\n"
+        );
+
+        let mut mock = MockBamReader::new(records.clone(), None);
+        let stats = compute_region_stats(&mut mock, &regions[..1], None, 0, 0).unwrap();
+        let mut csv = Vec::new();
+        write_region_csv(&stats, false, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "name,chrom,beg,end,mean_coverage\na,chrQ_mirror,100,200,2.00\n"
+        );
+
+        let mut mock = MockBamReader::new(records, None);
+        let err = compute_region_stats(&mut mock, &regions, Some(&sample_regions[..1]), 0, 0)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No matching sample region found for target region a"));
+    }
+
+    #[test]
+    fn test_compute_stats_empty() {
+        let mut mock = MockBamReader::new(vec![], None);
+        let stats = compute_stats(&mut mock, &[], 0).unwrap();
+        assert_eq!(stats.total_reads(), 0);
+        assert_eq!(stats.mean_mapq, None);
+        assert_eq!(stats.mean_coverage, None);
+        let mut json = Vec::new();
+        write_json(&stats, &mut json).unwrap();
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains("\"mean_mapq\":null"));
+    }
+}
@@ -0,0 +1,95 @@
+//! # Timing Module
+//!
+//! This module records the wall-clock time of the stages of a run, such as
+//! loading the BED files or the final write pass, so that `--verbose-timing`
+//! can report where a large run spends its time.
+//!
+//! Stages are kept in the order they first ran. Timing a stage again adds to
+//! its total, so a stage that runs once per region is reported once.
+//!
+//! ## Tests
+//!
+//! Contains unit tests for accumulating and reporting stage times.
+use std::time::{Duration, Instant};
+
+/// Collects the elapsed time of named stages.
+#[derive(Debug, Default)]
+pub struct Timings {
+    stages: Vec<(String, Duration)>,
+}
+
+impl Timings {
+    /// Creates an empty collector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `elapsed` to the total of `stage`.
+    pub fn record(&mut self, stage: &str, elapsed: Duration) {
+        match self.stages.iter_mut().find(|(name, _)| name == stage) {
+            Some((_, total)) => *total += elapsed,
+            None => self.stages.push((stage.to_string(), elapsed)),
+        }
+    }
+
+    /// Runs `f`, adding its elapsed time to `stage`.
+    pub fn time<T>(&mut self, stage: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(stage, start.elapsed());
+        result
+    }
+
+    /// The stages timed so far with their totals, in the order they first ran.
+    pub fn stages(&self) -> &[(String, Duration)] {
+        &self.stages
+    }
+
+    /// Writes the time of each stage to standard error.
+    pub fn report(&self) {
+        for (stage, elapsed) in &self.stages {
+            eprintln!("{stage}: {:.3}s", elapsed.as_secs_f64());
+        }
+    }
+}
+
+/// Runs `f`, timing it as `stage` if there is a collector.
+pub fn time<T>(timings: Option<&mut Timings>, stage: &str, f: impl FnOnce() -> T) -> T {
+    match timings {
+        Some(timings) => timings.time(stage, f),
+        None => f(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_accumulates_in_first_run_order() {
+        let mut timings = Timings::new();
+        timings.record("load", Duration::from_millis(5));
+        timings.record("write", Duration::from_millis(10));
+        timings.record("load", Duration::from_millis(20));
+        assert_eq!(
+            timings.stages(),
+            &[
+                ("load".to_string(), Duration::from_millis(25)),
+                ("write".to_string(), Duration::from_millis(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_time() {
+        let mut timings = Timings::new();
+        assert_eq!(time(Some(&mut timings), "stage", || 42), 42);
+        assert_eq!(time(None, "other", || 7), 7);
+        let names = timings
+            .stages()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["stage"]);
+    }
+}
@@ -0,0 +1,330 @@
+//! # Windows Module
+//!
+//! This module computes mean read depth in fixed-size bins tiled across whole
+//! contigs, for genome-wide coverage plots.
+//!
+//! ## Functions
+//!
+//! - `bin_regions`: Tiles a contig with regions of a fixed size.
+//! - `load_chrom_sizes`: Loads contig lengths that override the BAM header's.
+//! - `contig_bins`: Tiles a contig with bins, using its overridden length if any.
+//! - `bin_means`: Computes the mean coverage of each bin.
+//! - `run`: Entry point for the `windows` subcommand.
+//!
+//! ## Tests
+//!
+//! Contains unit tests for bin tiling, chrom sizes and per-bin mean coverage.
+use crate::bam::BamReader;
+use crate::coverage::{self, ReadFilters};
+use crate::errors::{Error, Result};
+use crate::region::Region;
+use rayon::prelude::*;
+use rust_htslib::bam::HeaderView;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Tiles `0..len` of `contig` with bins of `bin_size` bases. The last bin is
+/// truncated at the end of the contig.
+pub(crate) fn bin_regions(contig: &str, len: u64, bin_size: u64) -> Vec<Region> {
+    (0..len)
+        .step_by(bin_size as usize)
+        .map(|beg| {
+            let end = (beg + bin_size).min(len);
+            Region::new(contig, beg, end, &format!("{contig}:{beg}-{end}"))
+        })
+        .collect()
+}
+
+/// Loads `contig<TAB>length` lines, such as a UCSC `chrom.sizes` file. Blank
+/// lines and `#` comments are skipped.
+///
+/// # Errors
+/// Returns an error naming the line if it does not have a contig and an
+/// integer length, or repeats a contig.
+pub(crate) fn load_chrom_sizes<R: BufRead>(reader: R) -> Result<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = i + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |msg: &str| Error::Bedcov {
+            msg: format!("Invalid chrom sizes line {line_no}: {msg}: {line}"),
+        };
+        let mut fields = line.split('\t');
+        let (Some(contig), Some(length)) = (fields.next(), fields.next()) else {
+            return Err(invalid("expected contig and length"));
+        };
+        let length = length
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| invalid("length is not a non-negative integer"))?;
+        if sizes.insert(contig.to_string(), length).is_some() {
+            return Err(invalid("contig is repeated"));
+        }
+    }
+    Ok(sizes)
+}
+
+/// Checks that every contig in `chrom_sizes` is present in `header`.
+///
+/// # Errors
+/// Returns an error listing the missing contigs, sorted.
+fn check_chrom_sizes(chrom_sizes: &HashMap<String, u64>, header: &HeaderView) -> Result<()> {
+    let mut missing = chrom_sizes
+        .keys()
+        .filter(|contig| header.tid(contig.as_bytes()).is_none())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort_unstable();
+        return Err(Error::Bedcov {
+            msg: format!(
+                "Chrom sizes contigs not found in BAM header: {}",
+                missing.join(", ")
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// The mean coverage of one bin.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BinMean {
+    pub(crate) region: Region,
+    /// `None` for an empty bin.
+    pub(crate) mean: Option<f32>,
+}
+
+/// Tiles `contig` with bins of `bin_size` bases. The contig's length is taken
+/// from `chrom_sizes` if present there, otherwise from `header`.
+fn contig_bins(
+    header: &HeaderView,
+    contig: &str,
+    bin_size: u64,
+    chrom_sizes: &HashMap<String, u64>,
+) -> Result<Vec<Region>> {
+    let tid = header.tid(contig.as_bytes()).ok_or_else(|| Error::Bedcov {
+        msg: format!("Chromosome {contig} not found in BAM header"),
+    })?;
+    let len = match chrom_sizes.get(contig) {
+        Some(&len) => len,
+        None => header.target_len(tid).unwrap_or(0),
+    };
+    Ok(bin_regions(contig, len, bin_size))
+}
+
+/// Computes the mean coverage of each of `bins`. Each bin's per-base depths
+/// are dropped once its mean is taken.
+fn bin_means<T: BamReader>(
+    bam_reader: &mut T,
+    bins: &[Region],
+    min_mapq: u8,
+) -> Result<Vec<BinMean>> {
+    bins.iter()
+        .map(|bin| {
+            let coverage = coverage::coverage_for_region(
+                bam_reader,
+                bin,
+                min_mapq,
+                0,
+                &ReadFilters::default(),
+            )?;
+            Ok(BinMean {
+                region: bin.clone(),
+                mean: coverage.mean(),
+            })
+        })
+        .collect()
+}
+
+/// Write the `chrom,beg,end,mean` CSV header.
+fn write_csv_header<W: Write>(dest: &mut W) -> Result<()> {
+    writeln!(dest, "chrom,beg,end,mean")?;
+    Ok(())
+}
+
+/// Write per-bin mean coverage as `chrom,beg,end,mean` CSV rows.
+fn write_csv_rows<W: Write>(bins: &[BinMean], dest: &mut W) -> Result<()> {
+    for bin in bins {
+        let mean = bin.mean.map_or_else(String::new, |v| format!("{v:.2}"));
+        writeln!(
+            dest,
+            "{},{},{},{mean}",
+            bin.region.contig, bin.region.beg, bin.region.end
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct WindowsArgs {
+    pub bam_path: PathBuf,
+    pub reference: Option<PathBuf>,
+    pub min_mapq: u8,
+    pub bin_size: u64,
+    /// Contigs to bin; all contigs in the header when empty.
+    pub contigs: Vec<String>,
+    /// `contig<TAB>length` file overriding the header's contig lengths.
+    pub chrom_sizes: Option<PathBuf>,
+}
+
+pub fn run(args: &WindowsArgs) -> Result<()> {
+    if args.bin_size == 0 {
+        return Err(Error::Bedcov {
+            msg: "Bin size must be greater than zero".to_string(),
+        });
+    }
+    let chrom_sizes = match &args.chrom_sizes {
+        Some(path) => load_chrom_sizes(std::io::BufReader::new(std::fs::File::open(path)?))?,
+        None => HashMap::new(),
+    };
+    let bam_reader = coverage::open_reader(&args.bam_path, args.reference.as_ref(), None, None)?;
+    check_chrom_sizes(&chrom_sizes, bam_reader.header())?;
+    let contigs = if args.contigs.is_empty() {
+        bam_reader
+            .header()
+            .target_names()
+            .iter()
+            .map(|name| Ok(std::str::from_utf8(name)?.to_string()))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        args.contigs.clone()
+    };
+    // Contigs are written in turn so that only one contig's bin means are
+    // held at once. Its bins are split into a chunk per thread, each with its
+    // own reader, as for bedcov regions.
+    let mut dest = std::io::BufWriter::new(std::io::stdout().lock());
+    write_csv_header(&mut dest)?;
+    for contig in &contigs {
+        let bins = contig_bins(bam_reader.header(), contig, args.bin_size, &chrom_sizes)?;
+        let chunk_len = bins.len().div_ceil(rayon::current_num_threads()).max(1);
+        let means = bins
+            .par_chunks(chunk_len)
+            .map(|chunk| {
+                let mut bam_reader =
+                    coverage::open_reader(&args.bam_path, args.reference.as_ref(), None, None)?;
+                bin_means(&mut bam_reader, chunk, args.min_mapq)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for chunk in &means {
+            write_csv_rows(chunk, &mut dest)?;
+        }
+    }
+    dest.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::{create_mock_record, MockBamReader};
+    use rust_htslib::bam::header::HeaderRecord;
+
+    fn bin_coverage(
+        mock: &mut MockBamReader,
+        contig: &str,
+        bin_size: u64,
+        min_mapq: u8,
+        chrom_sizes: &HashMap<String, u64>,
+    ) -> Result<Vec<BinMean>> {
+        let bins = contig_bins(mock.header(), contig, bin_size, chrom_sizes)?;
+        bin_means(mock, &bins, min_mapq)
+    }
+
+    #[test]
+    fn test_bin_regions() {
+        let bins = bin_regions("chr1", 250, 100);
+        let spans = bins.iter().map(|b| (b.beg, b.end)).collect::<Vec<_>>();
+        assert_eq!(spans, vec![(0, 100), (100, 200), (200, 250)]);
+        assert_eq!(bins[2].name, "chr1:200-250");
+    }
+
+    #[test]
+    fn test_bin_coverage() {
+        let header = [HeaderRecord::new(b"SQ\tSN:small\tLN:300")];
+        // Two 100bp reads in the first bin, one straddling the second and
+        // third, none in the rest of the third.
+        let records = vec![
+            create_mock_record(0, 0, "read1"),
+            create_mock_record(0, 0, "read2"),
+            create_mock_record(0, 150, "read3"),
+        ];
+        let mut mock = MockBamReader::new(records, Some(&header));
+        let coverages = bin_coverage(&mut mock, "small", 100, 0, &HashMap::new()).unwrap();
+        let means = coverages
+            .iter()
+            .map(|c| c.mean.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(means, vec![2.0, 0.5, 0.5]);
+
+        let mut output = Vec::new();
+        write_csv_header(&mut output).unwrap();
+        write_csv_rows(&coverages, &mut output).unwrap();
+        let expected = "\
+chrom,beg,end,mean
+small,0,100,2.00
+small,100,200,0.50
+small,200,300,0.50
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+        assert!(bin_coverage(&mut mock, "missing", 100, 0, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_bin_coverage_chrom_sizes() {
+        let header = [
+            HeaderRecord::new(b"SQ\tSN:small\tLN:300"),
+            HeaderRecord::new(b"SQ\tSN:other\tLN:500"),
+        ];
+        let mut mock = MockBamReader::new(vec![create_mock_record(0, 150, "read1")], Some(&header));
+        let spans = |mock: &mut MockBamReader, sizes: &HashMap<String, u64>| {
+            bin_coverage(mock, "small", 100, 0, sizes)
+                .unwrap()
+                .iter()
+                .map(|c| (c.region.beg, c.region.end))
+                .collect::<Vec<_>>()
+        };
+        // The header length ends the last bin at 300, the override at 250.
+        assert_eq!(
+            spans(&mut mock, &HashMap::new()),
+            vec![(0, 100), (100, 200), (200, 300)]
+        );
+        let sizes = load_chrom_sizes("# comment\nsmall\t250\n\nother\t500\n".as_bytes()).unwrap();
+        assert_eq!(
+            spans(&mut mock, &sizes),
+            vec![(0, 100), (100, 200), (200, 250)]
+        );
+        assert!(check_chrom_sizes(&sizes, mock.header()).is_ok());
+
+        let sizes = load_chrom_sizes("small\t250\nchrZ\t10\nchrY\t10\n".as_bytes()).unwrap();
+        let err = check_chrom_sizes(&sizes, mock.header()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bedcov error: Chrom sizes contigs not found in BAM header: chrY, chrZ"
+        );
+    }
+
+    #[test]
+    fn test_load_chrom_sizes_invalid() {
+        for (contents, msg) in [
+            (
+                "small\n",
+                "Invalid chrom sizes line 1: expected contig and length: small",
+            ),
+            (
+                "small\t300\nother\tlots\n",
+                "Invalid chrom sizes line 2: length is not a non-negative integer: other\tlots",
+            ),
+            (
+                "small\t300\nsmall\t250\n",
+                "Invalid chrom sizes line 2: contig is repeated: small\t250",
+            ),
+        ] {
+            let err = load_chrom_sizes(contents.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), format!("bedcov error: {msg}"));
+        }
+    }
+}
@@ -63,6 +63,61 @@ variant_3,chrQ_mirror,6600,9600,0,163,116.94,39.02,0.33";
     assert_eq!(contents.trim(), expected_output.trim());
 }
 
+#[test]
+fn test_cli_bedcov_multiple_samples() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "bedcov",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "testdata/calibrated.bam",
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+    assert!(
+        output.status.success(),
+        "Command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // Each column holds the mean of the single-file report for that file.
+    let expected_output = "\
+name,chrom,beg,end,calibrated,uncalibrated
+variant_1,chrQ_mirror,200,3200,116.84,116.84
+variant_2,chrQ_mirror,3400,6400,113.95,113.95
+variant_3,chrQ_mirror,6600,9600,116.94,116.94
+";
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected_output);
+}
+
+#[test]
+fn test_cli_bedcov_json() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "bedcov",
+            "--format",
+            "json",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "testdata/calibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+    assert!(
+        output.status.success(),
+        "Command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let regions: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let regions = regions.as_array().unwrap();
+    assert_eq!(regions.len(), 3);
+    // The same statistics as the CSV in test_cli_bedcov, as JSON numbers.
+    assert_eq!(regions[0]["name"], "variant_1");
+    assert_eq!(regions[0]["chrom"], "chrQ_mirror");
+    assert_eq!(regions[0]["beg"], 200);
+    assert_eq!(regions[0]["max"], 172);
+    assert_eq!(regions[0]["mean"], 116.84);
+    assert_eq!(regions[2]["cv"], 0.33);
+}
+
 #[test]
 fn test_calibrate_fixed_coverage() {
     let temp_dir = TempDir::new().unwrap();
@@ -93,6 +148,111 @@ fn test_calibrate_fixed_coverage() {
     assert!(index_path.exists(), "No such file: {index_path:?}");
 }
 
+#[test]
+fn test_calibrate_program_record() {
+    let version = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .arg("--version")
+        .output()
+        .expect("Failed to execute command");
+    let version = String::from_utf8(version.stdout).unwrap();
+    let version = version.trim().strip_prefix("sequintools ").unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "-o",
+            output_path.to_str().unwrap(),
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let reader = bam::Reader::from_path(&output_path).unwrap();
+    let header = String::from_utf8(reader.header().as_bytes().to_vec()).unwrap();
+    let pg = header
+        .lines()
+        .find(|line| line.starts_with("@PG\tID:sequintools\t"))
+        .unwrap_or_else(|| panic!("No @PG line in header:\n{header}"));
+    assert!(pg.contains("\tPN:sequintools\t"), "{pg}");
+    assert!(pg.contains(&format!("\tVN:{version}\t")), "{pg}");
+    let command_line = format!(
+        " calibrate --bed testdata/resources/sequin_regions.chrQ_mirror.bed -o {} testdata/uncalibrated.bam",
+        output_path.display()
+    );
+    assert!(pg.contains("\tCL:") && pg.ends_with(&command_line), "{pg}");
+}
+
+#[test]
+fn test_calibrate_decoy_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "--decoy-only",
+            "-o",
+            output_path.to_str().unwrap(),
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mut reader = bam::Reader::from_path(&output_path).unwrap();
+    let sequin_tid = reader.header().tid(b"chrQ_mirror").unwrap() as i32;
+    let mut n = 0;
+    for record in reader.records() {
+        let record = record.unwrap();
+        assert_eq!(record.tid(), sequin_tid);
+        assert!(!record.is_unmapped());
+        n += 1;
+    }
+    assert!(n > 0);
+}
+
+#[test]
+fn test_calibrate_output_md5() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "-o",
+            output_path.to_str().unwrap(),
+            "--output-md5",
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let md5_path = temp_dir.path().join("calibrated.bam.md5");
+    assert!(md5_path.exists(), "No such file: {md5_path:?}");
+    let expected = format!("{:x}", md5::compute(fs::read(&output_path).unwrap()));
+    let contents = fs::read_to_string(md5_path).unwrap();
+    assert_eq!(contents, format!("{expected}  calibrated.bam\n"));
+}
+
 #[test]
 fn test_calibrate_sample_mean_coverage() {
     let temp_dir = TempDir::new().unwrap();
@@ -146,10 +306,56 @@ fn test_calibrate_sample_coverage_profile() {
         String::from_utf8_lossy(&output.stderr)
     );
     let computed_md5 = calculate_md5_without_pg_records(&output_path);
-    let expected_md5 = "d399ae99545464c9631ace3258e57860";
+    let expected_md5 = "0129f199f7c27efb2414e38f9720d08e";
     assert_eq!(computed_md5, expected_md5, "MD5 checksum does not match");
 }
 
+#[test]
+fn test_calibrate_profile_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let profile_path = temp_dir.path().join("profile.json");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--experimental",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "--sample-bed",
+            "testdata/resources/sequin_regions.hg38.bed",
+            "--profile-json",
+            profile_path.to_str().unwrap(),
+            "-o",
+            output_path.to_str().unwrap(),
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let profile: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&profile_path).unwrap()).unwrap();
+    let regions = profile["regions"].as_array().unwrap();
+    let names = regions
+        .iter()
+        .map(|r| r["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["variant_1", "variant_2", "variant_3"]);
+    for region in regions {
+        // Each 3000 bp region less its 500 bp flanks is 20 windows of 100 bp.
+        for key in ["sample_starts", "rev_sample_starts", "selected"] {
+            assert_eq!(region[key].as_array().unwrap().len(), 20, "{key}");
+        }
+    }
+    // The profile does not change the calibrated output.
+    let computed_md5 = calculate_md5_without_pg_records(&output_path);
+    assert_eq!(computed_md5, "0129f199f7c27efb2414e38f9720d08e");
+}
+
 #[test]
 fn test_calibrate_cram_input() {
     let temp_dir = TempDir::new().unwrap();
@@ -161,6 +367,8 @@ fn test_calibrate_cram_input() {
             "testdata/resources/sequin_regions.chrQ_mirror.bed",
             "-o",
             output_path.to_str().unwrap(),
+            "--output-format",
+            "bam",
             "-T",
             "testdata/genome_with_sequins.fasta",
             "testdata/uncalibrated.cram",
@@ -177,6 +385,32 @@ fn test_calibrate_cram_input() {
     assert_eq!(computed_md5, expected_md5, "MD5 checksum does not match");
 }
 
+#[test]
+fn test_calibrate_cram_input_auto_cram_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.out");
+    let file = File::create(&output_path).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "-T",
+            "testdata/genome_with_sequins.fasta",
+            "testdata/uncalibrated.cram",
+        ])
+        .stdout(Stdio::from(file))
+        .output()
+        .expect("Failed to execute command");
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let contents = fs::read(&output_path).unwrap();
+    assert!(contents.starts_with(b"CRAM"), "Output is not CRAM");
+}
+
 // We can't check MD5 because the CRAM output may vary in non-deterministic ways
 // on different machines. This is due to the choice of compression level and
 // other factors. However, all output files (BAM and CRAM) should have the same
@@ -219,6 +453,70 @@ fn test_calibrate_cram_output() {
     assert!(index_path.exists(), "No such file: {index_path:?}");
 }
 
+#[test]
+fn test_calibrate_sample_profile_cram_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let reference = "testdata/genome_with_sequins.fasta";
+    let run = |output_path: &Path, extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+            .args([
+                "calibrate",
+                "--experimental",
+                "--bed",
+                "testdata/resources/sequin_regions.chrQ_mirror.bed",
+                "--sample-bed",
+                "testdata/resources/sequin_regions.hg38.bed",
+                "-o",
+                output_path.to_str().unwrap(),
+            ])
+            .args(extra)
+            .arg("testdata/uncalibrated.bam")
+            .output()
+            .expect("Failed to execute command");
+        assert!(
+            output.status.success(),
+            "Command failed with stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    };
+    let cram_path = temp_dir.path().join("calibrated.cram");
+    let output = run(&cram_path, &["-T", reference, "--cram", "--write-index"]);
+    // The reference is used rather than embedded in the output.
+    assert!(
+        !String::from_utf8_lossy(&output.stderr).contains("embed_ref"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let bam_path = temp_dir.path().join("calibrated.bam");
+    run(&bam_path, &[]);
+
+    let alignments = |reader: &mut bam::Reader| {
+        reader
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                (
+                    r.qname().to_vec(),
+                    r.flags(),
+                    r.tid(),
+                    r.pos(),
+                    r.seq().as_bytes(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut cram = bam::Reader::from_path(&cram_path).expect("Should be able to open output CRAM");
+    cram.set_reference(reference).unwrap();
+    let cram_alignments = alignments(&mut cram);
+    assert_eq!(cram_alignments.len(), 3844);
+    let mut bam = bam::Reader::from_path(&bam_path).unwrap();
+    assert_eq!(cram_alignments, alignments(&mut bam));
+    let mut index_path = cram_path.clone();
+    index_path.set_extension("cram.crai");
+    assert!(index_path.exists(), "No such file: {index_path:?}");
+}
+
 #[test]
 fn test_calibrate_cram_input_output() {
     let temp_dir = TempDir::new().unwrap();
@@ -279,3 +577,202 @@ fn test_calibrate_ignores_summary_report_option() {
     ));
     assert!(!summary_path.exists());
 }
+
+#[test]
+fn test_calibrate_check_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--check-only",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "--sample-bed",
+            "testdata/resources/sequin_regions.hg38.bed",
+            "-o",
+            output_path.to_str().unwrap(),
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("OK: "));
+    assert!(!output_path.exists());
+}
+
+#[test]
+fn test_calibrate_report_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--report-only",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "-f",
+            "40",
+            "-o",
+            output_path.to_str().unwrap(),
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(
+        lines.next(),
+        Some("name,chrom,beg,end,mean,probability,expected_mean,reads,kept_reads")
+    );
+    assert_eq!(lines.count(), 3);
+    assert!(!output_path.exists());
+}
+
+#[test]
+fn test_calibrate_validate_output_strict() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_path = temp_dir.path().join("calibrated.bam");
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "calibrate",
+            "--validate-output",
+            "--strict",
+            "--bed",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "-o",
+            output_path.to_str().unwrap(),
+            "testdata/uncalibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no problems found"));
+}
+
+#[test]
+fn test_bedcov_threads_identical_output() {
+    let run = |threads: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+            .arg("bedcov")
+            .args(threads)
+            .args([
+                "--thresholds",
+                "10,50",
+                "--report-unmapped",
+                "testdata/resources/sequin_regions.chrQ_mirror.bed",
+                "testdata/calibrated.bam",
+            ])
+            .output()
+            .expect("Failed to execute command");
+        assert!(
+            output.status.success(),
+            "Command failed with stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output.stdout
+    };
+    let single = run(&["--threads", "1"]);
+    assert!(!single.is_empty());
+    assert_eq!(single, run(&["--threads", "8"]));
+    assert_eq!(
+        single,
+        run(&["--compute-threads", "1", "--io-threads", "1"])
+    );
+    assert_eq!(
+        single,
+        run(&["--compute-threads", "8", "--io-threads", "4"])
+    );
+    assert_eq!(single, run(&["-@", "2", "--io-threads", "8"]));
+}
+
+#[test]
+fn test_bedcov_name_prefix() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "bedcov",
+            "--name-prefix",
+            "variant_2",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "testdata/calibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let names = stdout
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["variant_2"]);
+}
+
+#[test]
+fn test_cli_stats() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "stats",
+            "testdata/resources/sequin_regions.chrQ_mirror.bed",
+            "testdata/calibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(
+        output.status.success(),
+        "Command failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let expected = "{\"total_reads\":9280,\"mapped_reads\":9280,\"unmapped_reads\":0,\
+        \"duplicate_reads\":0,\"mean_mapq\":60.00,\"regions\":3,\"region_bases\":9000,\
+        \"mean_coverage\":115.91}";
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), expected);
+}
+
+#[test]
+fn test_bedcov_check_only_missing_contig() {
+    let temp_dir = TempDir::new().unwrap();
+    let bed_path = temp_dir.path().join("missing.bed");
+    fs::write(
+        &bed_path,
+        "chrQ_mirror\t200\t3200\tvariant_1\nchrZ\t1\t100\tmissing\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sequintools"))
+        .args([
+            "bedcov",
+            "--check-only",
+            bed_path.to_str().unwrap(),
+            "testdata/calibrated.bam",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("Contigs not found in BAM header: chrZ")
+    );
+    assert!(output.stdout.is_empty());
+}